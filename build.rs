@@ -105,7 +105,6 @@ fn link_objc(out_dir: &str) {
 }
 
 fn main() {
-    let out_dir = env::var("OUT_DIR").unwrap();
     let profile_out = env::var("PROFILE").unwrap();
     let dest_assets = Path::new("target").join(&profile_out).join("assets");
     copy_dir_all("assets", dest_assets).unwrap();
@@ -116,6 +115,7 @@ fn main() {
     }
     #[cfg(target_os = "macos")]
     {
+        let out_dir = env::var("OUT_DIR").unwrap();
         link_objc(&out_dir);
     }
 }
@@ -0,0 +1,9 @@
+#![no_main]
+
+use copyxross_core::clipboard::ClipboardData;
+use copyxross_core::encode::Transferable;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ClipboardData::deserialize(data);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use copyxross_core::encode::parse_message;
+use copyxross_core::encode::parse_signed_message;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_message(data);
+    let _ = parse_signed_message(data);
+});
@@ -0,0 +1,65 @@
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+use copyxross_core::debug_println;
+use copyxross_core::utils::attempt_get_lock;
+
+use super::ButtonData;
+use super::CallbackFn;
+use super::TaskMenuError;
+use super::TaskMenuOperations;
+
+/// There is no common tray API across Linux desktops, so the menu only keeps
+/// the app alive until it's stopped. Menu items are logged and ignored.
+pub struct TaskMenuBar {
+    stopped: Mutex<bool>,
+    stop_signal: Condvar,
+}
+
+impl TaskMenuOperations for TaskMenuBar {
+    fn init() -> Result<Self, TaskMenuError> {
+        Ok(TaskMenuBar {
+            stopped: Mutex::new(false),
+            stop_signal: Condvar::new(),
+        })
+    }
+    fn add_menu_item(
+        &self,
+        btn_data: ButtonData,
+        _on_click: CallbackFn,
+    ) -> Result<(), TaskMenuError> {
+        debug_println!("Menu item added: {}", btn_data.btn_title);
+        Ok(())
+    }
+    fn set_quit_button(&self) -> Result<(), TaskMenuError> {
+        Ok(())
+    }
+    fn remove_menu_item(&self, btn_data: ButtonData) -> Result<(), TaskMenuError> {
+        debug_println!("Menu item removed: {}", btn_data.btn_title);
+        Ok(())
+    }
+    fn remove_all_dyn(&self) -> Result<(), TaskMenuError> {
+        Ok(())
+    }
+    fn stop(&self) -> Result<(), TaskMenuError> {
+        let mut stopped = attempt_get_lock(&self.stopped)
+            .map_err(|err| TaskMenuError::Unexpected(format!("{:?}", err)))?;
+        *stopped = true;
+        self.stop_signal.notify_all();
+        Ok(())
+    }
+    fn run(&self) -> Result<(), TaskMenuError> {
+        let mut stopped = attempt_get_lock(&self.stopped)
+            .map_err(|err| TaskMenuError::Unexpected(format!("{:?}", err)))?;
+        while !*stopped {
+            stopped = self
+                .stop_signal
+                .wait(stopped)
+                .map_err(|err| TaskMenuError::Unexpected(format!("{:?}", err)))?;
+        }
+        Ok(())
+    }
+    fn set_autorun_button(&self) -> Result<(), TaskMenuError> {
+        Ok(())
+    }
+}
@@ -15,12 +15,12 @@ use objc::runtime::Sel;
 use objc::sel;
 use objc::sel_impl;
 
-use crate::debug_println;
-use crate::utils::attempt_get_lock;
-use crate::utils::get_asset_path;
-use crate::utils::macos::catch_and_log_exception;
-use crate::utils::macos::get_error;
-use crate::utils::macos::ObjectId;
+use copyxross_core::debug_println;
+use copyxross_core::utils::attempt_get_lock;
+use copyxross_core::utils::get_asset_path;
+use copyxross_core::utils::macos::catch_and_log_exception;
+use copyxross_core::utils::macos::get_error;
+use copyxross_core::utils::macos::ObjectId;
 
 use super::ButtonData;
 use super::ButtonFullData;
@@ -1,3 +1,5 @@
+#[cfg(target_os = "linux")]
+mod linux;
 #[cfg(target_os = "macos")]
 mod macos;
 #[cfg(target_os = "windows")]
@@ -10,6 +12,7 @@ pub enum TaskMenuError {
     Unexpected(String),
 }
 
+#[cfg(target_os = "macos")]
 struct ButtonFullData {
     handler: CallbackFn,
     btn_data: ButtonData,
@@ -59,6 +62,8 @@ pub trait TaskMenuOperations: Sized + Sync + Send {
     fn set_autorun_button(&self) -> Result<(), TaskMenuError>;
 }
 
+#[cfg(target_os = "linux")]
+use linux::TaskMenuBar as PlatformTaskBar;
 #[cfg(target_os = "macos")]
 use macos::TaskMenuBar as PlatformTaskBar;
 #[cfg(target_os = "windows")]
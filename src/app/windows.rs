@@ -68,11 +68,11 @@ use winapi::um::winuser::WM_RBUTTONDOWN;
 use winapi::um::winuser::WM_RBUTTONUP;
 use winapi::um::winuser::WNDCLASSW;
 
-use crate::debug_println;
-use crate::utils::attempt_get_lock;
-use crate::utils::get_asset_path;
-use crate::utils::log_into_file;
-use crate::utils::windows::WindowsError;
+use copyxross_core::debug_println;
+use copyxross_core::utils::attempt_get_lock;
+use copyxross_core::utils::get_asset_path;
+use copyxross_core::utils::log_into_file;
+use copyxross_core::utils::windows::WindowsError;
 
 use super::ButtonData;
 use super::ButtonFullData;
@@ -0,0 +1,366 @@
+//! Command line client for running instance
+//!
+//! ```text
+//! copyxross peers
+//! copyxross get <peer> > out
+//! echo hi | copyxross send <peer>
+//! copyxross send-file <peer> <path>...
+//! copyxross pair <peer>
+//! copyxross policy <peer> ask
+//! ```
+
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+
+use copyxross_core::clipboard::ClipboardData;
+use copyxross_core::clipboard::FileTree;
+use copyxross_core::clipboard::Inbox;
+use copyxross_core::clipboard::StringType;
+use copyxross_core::control::send_command;
+use copyxross_core::control::socket_path;
+use copyxross_core::control::ClipPayload;
+use copyxross_core::control::ControlCommand;
+use copyxross_core::control::ControlReply;
+use copyxross_core::engine::ConsentPolicy;
+
+const USAGE: &str = "Usage:
+    copyxross                          run tray app
+    copyxross --daemon                 run without tray
+    copyxross peers                    list discovered peers
+    copyxross get <peer>               print peer's clipboard
+    copyxross send <peer>              send stdin to peer
+    copyxross send-file <peer> <path>...
+                                       send files or directories to peer
+    copyxross pair <peer>              start pairing, prints code to compare
+    copyxross pair-confirm <peer>      confirm code shown for peer
+    copyxross pair-cancel <peer>       cancel pairing with peer
+    copyxross unpair <peer>            forget paired peer
+    copyxross pairings                 list paired peers and pairing codes
+    copyxross policy <peer> <allow|ask|deny>
+                                       set what happens when peer asks for clipboard
+    copyxross allow <peer>             let peer read clipboard this time
+    copyxross deny <peer>              refuse peer's request for clipboard
+    copyxross audit                    list recent requests for clipboard";
+
+#[derive(Debug, PartialEq)]
+enum CliCommand {
+    Peers,
+    Get(String),
+    Send(String),
+    SendFile((String, Vec<String>)),
+    Pair(String),
+    PairConfirm(String),
+    PairCancel(String),
+    Unpair(String),
+    Pairings,
+    Policy((String, ConsentPolicy)),
+    Allow(String),
+    Deny(String),
+    Audit,
+}
+
+fn parse_args(args: &[String]) -> Result<CliCommand, String> {
+    match args {
+        [cmd] if cmd == "peers" => Ok(CliCommand::Peers),
+        [cmd, peer] if cmd == "get" => Ok(CliCommand::Get(peer.clone())),
+        [cmd, peer] if cmd == "send" => Ok(CliCommand::Send(peer.clone())),
+        [cmd, peer, paths @ ..] if cmd == "send-file" && !paths.is_empty() => {
+            Ok(CliCommand::SendFile((peer.clone(), paths.to_vec())))
+        }
+        [cmd, peer] if cmd == "pair" => Ok(CliCommand::Pair(peer.clone())),
+        [cmd, peer] if cmd == "pair-confirm" => Ok(CliCommand::PairConfirm(peer.clone())),
+        [cmd, peer] if cmd == "pair-cancel" => Ok(CliCommand::PairCancel(peer.clone())),
+        [cmd, peer] if cmd == "unpair" => Ok(CliCommand::Unpair(peer.clone())),
+        [cmd] if cmd == "pairings" => Ok(CliCommand::Pairings),
+        [cmd, peer, policy] if cmd == "policy" => {
+            Ok(CliCommand::Policy((peer.clone(), policy.parse()?)))
+        }
+        [cmd, peer] if cmd == "allow" => Ok(CliCommand::Allow(peer.clone())),
+        [cmd, peer] if cmd == "deny" => Ok(CliCommand::Deny(peer.clone())),
+        [cmd] if cmd == "audit" => Ok(CliCommand::Audit),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+/// Returns true if args are meant for CLI rather than the app itself
+pub fn is_cli_command(args: &[String]) -> bool {
+    matches!(
+        args.first().map(|arg| arg.as_str()),
+        Some(
+            "peers"
+                | "get"
+                | "send"
+                | "send-file"
+                | "pair"
+                | "pair-confirm"
+                | "pair-cancel"
+                | "unpair"
+                | "pairings"
+                | "policy"
+                | "allow"
+                | "deny"
+                | "audit"
+                | "help"
+                | "--help"
+                | "-h"
+        )
+    )
+}
+
+/// Runs CLI command and returns process exit code
+pub fn run(args: &[String]) -> i32 {
+    let cmd = match parse_args(args) {
+        Ok(cmd) => cmd,
+        Err(usage) => {
+            eprintln!("{}", usage);
+            return 2;
+        }
+    };
+    match execute(cmd) {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("copyxross: {}", err);
+            1
+        }
+    }
+}
+
+fn execute(cmd: CliCommand) -> Result<(), String> {
+    match cmd {
+        CliCommand::Peers => {
+            let reply = request(&ControlCommand::Peers)?;
+            for peer in reply.peers.unwrap_or_default() {
+                println!("{}\t{}", peer.name, peer.addr);
+            }
+        }
+        CliCommand::Get(peer) => {
+            let reply = request(&ControlCommand::Get { peer })?;
+            let payload = reply.data.ok_or("Peer sent no data".to_string())?;
+            let mut stdout = std::io::stdout();
+            match for_terminal(ClipboardData::try_from(payload)?) {
+                ClipboardData::String((_, data)) => {
+                    stdout.write_all(&data).map_err(|err| err.to_string())?
+                }
+                ClipboardData::File((name, data)) => {
+                    eprintln!("Received file: {}", name);
+                    stdout.write_all(&data).map_err(|err| err.to_string())?
+                }
+                ClipboardData::Image(image) => {
+                    eprintln!(
+                        "Received image: {}x{} {}",
+                        image.width,
+                        image.height,
+                        image.format.to_string()
+                    );
+                    stdout
+                        .write_all(&image.data)
+                        .map_err(|err| err.to_string())?
+                }
+                ClipboardData::FileRef((name, path)) => {
+                    eprintln!("Received file: {}", name);
+                    // received file is spooled on disk, stream it and clean up
+                    let copied = fs::File::open(&path)
+                        .and_then(|mut file| std::io::copy(&mut file, &mut stdout));
+                    let _ = fs::remove_file(&path);
+                    copied.map_err(|err| err.to_string())?;
+                }
+                ClipboardData::Files(tree) => {
+                    // spooled files are moved into current directory, under
+                    // free names so nothing that is already there is replaced
+                    let inbox = Inbox {
+                        dir: std::env::current_dir().map_err(|err| err.to_string())?,
+                        per_peer: false,
+                        to_clipboard: false,
+                    };
+                    let spooled = tree.file_paths();
+                    let written = inbox.store(ClipboardData::Files(tree), None);
+                    for path in spooled {
+                        let _ = fs::remove_file(path);
+                    }
+                    for path in written.map_err(|err| format!("{:?}", err))? {
+                        eprintln!("Received: {}", path.display());
+                    }
+                }
+                ClipboardData::Multi(_) => return Err("Peer sent no data".to_string()),
+            }
+        }
+        CliCommand::Send(peer) => {
+            let mut data = vec![];
+            std::io::stdin()
+                .read_to_end(&mut data)
+                .map_err(|err| err.to_string())?;
+            let cp_data = ClipboardData::String((StringType::Utf8Plain, data));
+            send(peer, &cp_data)?;
+        }
+        CliCommand::SendFile((peer, paths)) => {
+            // daemon streams the files from disk itself
+            let paths = paths
+                .iter()
+                .map(|path| fs::canonicalize(path).map_err(|err| format!("{}: {}", path, err)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if let [path] = paths.as_slice() {
+                if path.is_file() {
+                    let name = path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .ok_or(format!("{}: not a file", path.display()))?;
+                    return send(peer, &ClipboardData::FileRef((name, path.clone())));
+                }
+            }
+            let tree = FileTree::from_paths(&paths).map_err(|err| err.to_string())?;
+            send(peer, &ClipboardData::Files(tree))?;
+        }
+        CliCommand::Pair(peer) => {
+            let reply = request(&ControlCommand::Pair { peer: peer.clone() })?;
+            let code = reply.code.ok_or("Peer sent no code".to_string())?;
+            println!("Pairing code: {}", code);
+            eprintln!(
+                "Check that {} shows the same code and run `copyxross pair-confirm <peer>` on both devices",
+                peer
+            );
+        }
+        CliCommand::PairConfirm(peer) => {
+            request(&ControlCommand::Confirm { peer })?;
+        }
+        CliCommand::PairCancel(peer) => {
+            request(&ControlCommand::Cancel { peer })?;
+        }
+        CliCommand::Unpair(peer) => {
+            request(&ControlCommand::Unpair { peer })?;
+        }
+        CliCommand::Pairings => {
+            let reply = request(&ControlCommand::Pairings)?;
+            for entry in reply.pairings.unwrap_or_default() {
+                let state = match (entry.paired, entry.code) {
+                    (true, _) => "paired".to_string(),
+                    (false, Some(code)) => format!("code {}", code),
+                    (false, None) => "pairing".to_string(),
+                };
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    entry.name,
+                    entry.addr.unwrap_or("offline".to_string()),
+                    entry.fingerprint,
+                    state
+                );
+            }
+        }
+        CliCommand::Policy((peer, policy)) => {
+            request(&ControlCommand::Policy { peer, policy })?;
+        }
+        CliCommand::Allow(peer) => {
+            request(&ControlCommand::Allow { peer })?;
+        }
+        CliCommand::Deny(peer) => {
+            request(&ControlCommand::Deny { peer })?;
+        }
+        CliCommand::Audit => {
+            let reply = request(&ControlCommand::Audit)?;
+            for entry in reply.audit.unwrap_or_default() {
+                println!("{}", entry);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Picks representation to print. Plain text is preferred, so piping
+/// copied web page gives its text rather than markup
+fn for_terminal(cp_data: ClipboardData) -> ClipboardData {
+    let mut reps = cp_data.into_representations();
+    let index = reps
+        .iter()
+        .position(|rep| matches!(rep, ClipboardData::String((StringType::Utf8Plain, _))))
+        .unwrap_or(0);
+    if index < reps.len() {
+        let picked = reps.swap_remove(index);
+        // the rest is not printed, their spooled files would be left behind
+        for path in reps.iter().flat_map(|rep| rep.file_paths()) {
+            let _ = fs::remove_file(path);
+        }
+        return picked;
+    }
+    ClipboardData::Multi(reps)
+}
+
+fn send(peer: String, cp_data: &ClipboardData) -> Result<(), String> {
+    request(&ControlCommand::Send {
+        peer,
+        data: ClipPayload::from(cp_data),
+    })
+    .map(|_| ())
+}
+
+fn request(cmd: &ControlCommand) -> Result<ControlReply, String> {
+    let path = socket_path();
+    let reply = send_command(&path, cmd).map_err(|err| {
+        format!(
+            "Cannot reach running instance at {}: {}",
+            path.display(),
+            err
+        )
+    })?;
+    if !reply.ok {
+        return Err(reply.error.unwrap_or("Unknown error".to_string()));
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&args(&["peers"])), Ok(CliCommand::Peers));
+        assert_eq!(
+            parse_args(&args(&["send-file", "office-pc", "a.txt"])),
+            Ok(CliCommand::SendFile((
+                "office-pc".to_string(),
+                vec!["a.txt".to_string()]
+            )))
+        );
+        assert_eq!(
+            parse_args(&args(&["send-file", "office-pc", "a.txt", "photos"])),
+            Ok(CliCommand::SendFile((
+                "office-pc".to_string(),
+                vec!["a.txt".to_string(), "photos".to_string()]
+            )))
+        );
+        assert!(parse_args(&args(&["send-file", "office-pc"])).is_err());
+        assert!(parse_args(&args(&["get"])).is_err());
+        assert_eq!(
+            parse_args(&args(&["pair-confirm", "office-pc"])),
+            Ok(CliCommand::PairConfirm("office-pc".to_string()))
+        );
+        assert_eq!(
+            parse_args(&args(&["policy", "office-pc", "Deny"])),
+            Ok(CliCommand::Policy((
+                "office-pc".to_string(),
+                ConsentPolicy::Deny
+            )))
+        );
+        assert!(parse_args(&args(&["policy", "office-pc", "sometimes"])).is_err());
+        assert!(is_cli_command(&args(&["get", "office-pc"])));
+        assert!(!is_cli_command(&args(&["--daemon"])));
+        assert!(!is_cli_command(&args(&[])));
+    }
+
+    #[test]
+    fn test_for_terminal_removes_unused_spooled_files() {
+        let spooled = std::env::temp_dir().join(format!("copyxross-cli-{}", std::process::id()));
+        fs::write(&spooled, b"data").unwrap();
+        let text = ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec()));
+        let cp_data = ClipboardData::Multi(vec![
+            ClipboardData::FileRef(("a.bin".to_string(), spooled.clone())),
+            text.clone(),
+        ]);
+        assert_eq!(for_terminal(cp_data), text);
+        assert!(!spooled.exists());
+    }
+}
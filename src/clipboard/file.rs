@@ -0,0 +1,326 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use super::files_from_paths;
+use super::Clipboard;
+use super::ClipboardData;
+use super::ClipboardError;
+use super::Image;
+use super::ImageFormat;
+use super::StringType;
+use crate::debug_println;
+use crate::utils::data_dir;
+use crate::utils::move_file;
+
+const PLAIN_FILE: &str = "plain.txt";
+const HTML_FILE: &str = "text.html";
+const RTF_FILE: &str = "text.rtf";
+/// Text representations, from the most preferred
+const TEXT_FILES: [(&str, StringType); 3] = [
+    (HTML_FILE, StringType::Html),
+    (RTF_FILE, StringType::Rtf),
+    (PLAIN_FILE, StringType::Utf8Plain),
+];
+/// Image is kept in the format it came in
+const IMAGE_FORMATS: [ImageFormat; 3] = [ImageFormat::Png, ImageFormat::Bmp, ImageFormat::Tiff];
+/// Text files modified this close to each other belong to one copy
+const SAME_COPY_WINDOW: Duration = Duration::from_secs(1);
+const FILES_DIR: &str = "files";
+/// Incoming file is parked here while old contents are cleared
+const INCOMING_FILE: &str = ".incoming";
+
+/// Clipboard stored in a directory, one file per representation:
+///
+/// - `plain.txt` - utf8 plain text
+/// - `text.html` - html string
+/// - `text.rtf` - rtf string
+/// - `image.png`, `image.bmp` or `image.tiff` - image
+/// - `files/<name>` - copied files and directories
+///
+/// Writing replaces all representations. When reading, the most recently
+/// modified one wins, so the directory can also be edited by hand or by
+/// scripts on machines without a desktop. Text files written together are
+/// read back as one payload with several representations.
+pub struct FileClipboard {
+    dir: PathBuf,
+}
+
+impl FileClipboard {
+    pub fn new(dir: PathBuf) -> Result<Self, ClipboardError> {
+        fs::create_dir_all(dir.join(FILES_DIR)).map_err(|err| {
+            ClipboardError::Init(format!(
+                "Could not create clipboard directory {:?}: {:?}",
+                dir, err
+            ))
+        })?;
+        Ok(FileClipboard { dir })
+    }
+
+    pub fn default_dir() -> PathBuf {
+        data_dir().join("clipboard")
+    }
+
+    fn image_file(format: ImageFormat) -> String {
+        format!("image.{}", format.extension())
+    }
+
+    fn newest_image(&self) -> Option<(PathBuf, ImageFormat, SystemTime)> {
+        IMAGE_FORMATS
+            .into_iter()
+            .filter_map(|format| {
+                let path = self.dir.join(FileClipboard::image_file(format));
+                let time = FileClipboard::modified(&path)?;
+                Some((path, format, time))
+            })
+            .max_by_key(|(_, _, time)| *time)
+    }
+
+    fn read_image(path: &Path, format: ImageFormat) -> Result<ClipboardData, ClipboardError> {
+        let bytes = fs::read(path)
+            .map_err(|err| ClipboardError::Read(format!("Could not read {:?}: {:?}", path, err)))?;
+        let image = Image::from_bytes(format, bytes)
+            .map_err(|err| ClipboardError::Read(format!("Invalid image {:?}: {:?}", path, err)))?;
+        Ok(ClipboardData::Image(image))
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|meta| meta.modified()).ok()
+    }
+
+    /// Top level entries of `files/` and time of the newest one
+    fn copied_files(&self) -> Result<Option<(Vec<PathBuf>, SystemTime)>, ClipboardError> {
+        let entries = fs::read_dir(self.dir.join(FILES_DIR))
+            .map_err(|err| ClipboardError::Read(format!("Could not list files: {:?}", err)))?;
+        let mut paths: Vec<PathBuf> = entries
+            .flatten()
+            .filter(|e| e.file_type().map(|t| !t.is_symlink()).unwrap_or(false))
+            .map(|e| e.path())
+            .collect();
+        paths.sort();
+        let newest = paths
+            .iter()
+            .filter_map(|path| FileClipboard::modified(path))
+            .max();
+        Ok(newest.map(|time| (paths, time)))
+    }
+
+    /// Moves file from disk into `files/`, replacing clipboard contents
+    fn move_in(&self, filename: &str, src: &Path) -> Result<(), ClipboardError> {
+        let incoming = self.dir.join(INCOMING_FILE);
+        let path = self.dir.join(FILES_DIR).join(filename);
+        let map_err = |err: std::io::Error| {
+            ClipboardError::Write(format!("Could not write {:?}: {:?}", path, err))
+        };
+        move_file(src, &incoming).map_err(map_err)?;
+        self.clear()?;
+        fs::rename(&incoming, &path).map_err(map_err)?;
+        debug_println!("Clipboard written into {:?}", path);
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), ClipboardError> {
+        let map_err =
+            |err| ClipboardError::Write(format!("Could not clear clipboard directory: {:?}", err));
+        let image_files = IMAGE_FORMATS.map(FileClipboard::image_file);
+        let text_files = TEXT_FILES.map(|(name, _)| name.to_string());
+        for name in text_files.iter().chain(image_files.iter()) {
+            let path = self.dir.join(name);
+            if path.exists() {
+                fs::remove_file(path).map_err(map_err)?;
+            }
+        }
+        let files_dir = self.dir.join(FILES_DIR);
+        for entry in fs::read_dir(&files_dir).map_err(map_err)?.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                fs::remove_dir_all(path).map_err(map_err)?;
+            } else {
+                fs::remove_file(path).map_err(map_err)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Clipboard for FileClipboard {
+    fn init() -> Result<Self, ClipboardError> {
+        FileClipboard::new(FileClipboard::default_dir())
+    }
+    fn write(&self, data: ClipboardData) -> Result<(), ClipboardError> {
+        let (texts, others) = data.split_text();
+        let mut contents = vec![];
+        for (s_type, bytes) in texts {
+            let (name, _) = TEXT_FILES.iter().find(|(_, t)| *t == s_type).unwrap();
+            contents.push((self.dir.join(name), bytes));
+        }
+        if contents.is_empty() {
+            match others.into_iter().next() {
+                Some(ClipboardData::File((filename, bytes))) => {
+                    contents.push((self.dir.join(FILES_DIR).join(filename), bytes))
+                }
+                Some(ClipboardData::FileRef((filename, src))) => {
+                    return self.move_in(&filename, &src)
+                }
+                Some(ClipboardData::Files(tree)) => {
+                    self.clear()?;
+                    let dir = self.dir.join(FILES_DIR);
+                    tree.write_to(&dir).map_err(|err| {
+                        ClipboardError::Write(format!("Could not write {:?}: {:?}", dir, err))
+                    })?;
+                    debug_println!("Clipboard written into {:?}", dir);
+                    return Ok(());
+                }
+                Some(ClipboardData::Image(image)) => contents.push((
+                    self.dir.join(FileClipboard::image_file(image.format)),
+                    image.data,
+                )),
+                _ => return Err(ClipboardError::Write("Nothing to write".to_string())),
+            }
+        }
+        self.clear()?;
+        for (path, bytes) in contents {
+            fs::write(&path, bytes).map_err(|err| {
+                ClipboardError::Write(format!("Could not write {:?}: {:?}", path, err))
+            })?;
+            debug_println!("Clipboard written into {:?}", path);
+        }
+        Ok(())
+    }
+    fn read(&self) -> Result<ClipboardData, ClipboardError> {
+        let mut texts: Vec<(PathBuf, StringType, SystemTime)> = vec![];
+        for (name, s_type) in TEXT_FILES {
+            let path = self.dir.join(name);
+            if let Some(time) = FileClipboard::modified(&path) {
+                texts.push((path, s_type, time));
+            }
+        }
+        let newest_text = texts.iter().map(|(_, _, time)| *time).max();
+        let newest_image = self.newest_image();
+        let image_time = newest_image.as_ref().map(|(_, _, time)| *time);
+        match (self.copied_files()?, newest_image) {
+            (Some((paths, time)), _) if Some(time) > newest_text.max(image_time) => {
+                return files_from_paths(paths)
+            }
+            (_, Some((path, format, time))) if Some(time) > newest_text => {
+                return FileClipboard::read_image(&path, format)
+            }
+            _ => {}
+        }
+
+        let newest_text =
+            newest_text.ok_or(ClipboardError::Read("Clipboard is empty".to_string()))?;
+        let mut reps = vec![];
+        for (path, s_type, time) in texts {
+            let age = newest_text.duration_since(time).unwrap_or_default();
+            if age > SAME_COPY_WINDOW {
+                continue;
+            }
+            let bytes = fs::read(&path).map_err(|err| {
+                ClipboardError::Read(format!("Could not read {:?}: {:?}", path, err))
+            })?;
+            reps.push(ClipboardData::String((s_type, bytes)));
+        }
+        ClipboardData::from_representations(reps)
+            .ok_or(ClipboardError::Read("Clipboard is empty".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::EntryContent;
+    use crate::clipboard::FileTree;
+    use crate::clipboard::TreeEntry;
+
+    #[test]
+    fn test_file_clipboard_roundtrip() {
+        let dir = std::env::temp_dir().join("copyxross_file_clipboard_test");
+        let _ = fs::remove_dir_all(&dir);
+        let cp = FileClipboard::new(dir.clone()).unwrap();
+        assert!(cp.read().is_err());
+
+        let html = ClipboardData::String((StringType::Html, b"<b>hi</b>".to_vec()));
+        cp.write(html.clone()).unwrap();
+        assert_eq!(cp.read().unwrap(), html);
+        assert!(!dir.join(PLAIN_FILE).exists());
+
+        let file = ClipboardData::File(("report.pdf".to_string(), vec![1, 2, 3]));
+        cp.write(file).unwrap();
+        let report = dir.join(FILES_DIR).join("report.pdf");
+        assert_eq!(
+            cp.read().unwrap(),
+            ClipboardData::FileRef(("report.pdf".to_string(), report.clone()))
+        );
+        assert_eq!(fs::read(&report).unwrap(), vec![1, 2, 3]);
+        assert!(!dir.join(HTML_FILE).exists());
+
+        // file on disk is moved in, replacing previous one
+        let spooled = std::env::temp_dir().join("copyxross_file_clipboard_test.part");
+        fs::write(&spooled, b"big").unwrap();
+        cp.write(ClipboardData::FileRef((
+            "video.mp4".to_string(),
+            spooled.clone(),
+        )))
+        .unwrap();
+        assert!(!spooled.exists() && !report.exists());
+        assert_eq!(
+            fs::read(dir.join(FILES_DIR).join("video.mp4")).unwrap(),
+            b"big"
+        );
+
+        // editing the directory by hand makes the newest representation win
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(dir.join(PLAIN_FILE), b"typed by hand").unwrap();
+        assert_eq!(
+            cp.read().unwrap(),
+            ClipboardData::String((StringType::Utf8Plain, b"typed by hand".to_vec()))
+        );
+
+        // text representations written together are read together
+        let multi = ClipboardData::Multi(vec![
+            ClipboardData::String((StringType::Html, b"<b>hi</b>".to_vec())),
+            ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec())),
+        ]);
+        cp.write(multi.clone()).unwrap();
+        assert_eq!(cp.read().unwrap(), multi);
+
+        let mut dib = vec![];
+        for field in [40u32, 1, 1, 1 | (24 << 16), 0, 4, 0, 0, 0, 0] {
+            dib.extend(field.to_le_bytes());
+        }
+        dib.extend([0, 0, 255, 0]);
+        let image = ClipboardData::Image(Image::from_dib(&dib).unwrap());
+        cp.write(image.clone()).unwrap();
+        assert_eq!(cp.read().unwrap(), image);
+        assert!(!dir.join(HTML_FILE).exists());
+        assert!(fs::read_dir(dir.join(FILES_DIR)).unwrap().next().is_none());
+
+        // directories are kept as they are and read back as a tree
+        let tree = FileTree {
+            entries: vec![
+                TreeEntry {
+                    path: "docs".to_string(),
+                    mode: 0o755,
+                    size: 0,
+                    content: EntryContent::Dir,
+                },
+                TreeEntry {
+                    path: "docs/a.txt".to_string(),
+                    mode: 0o644,
+                    size: 2,
+                    content: EntryContent::Data(b"hi".to_vec()),
+                },
+            ],
+        };
+        cp.write(ClipboardData::Files(tree)).unwrap();
+        let ClipboardData::Files(read) = cp.read().unwrap() else {
+            panic!("Expected file tree");
+        };
+        assert_eq!(read.entries[1].path, "docs/a.txt");
+        assert_eq!(read.size(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
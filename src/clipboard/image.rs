@@ -0,0 +1,525 @@
+//! Images on clipboard and conversion between formats platforms use
+//!
+//! Linux apps offer `image/png`, macOS pasteboard carries TIFF and Windows
+//! device independent bitmaps (BMP without its file header). Conversion goes
+//! through RGBA pixels and needs no native libraries.
+
+use std::io::Cursor;
+use std::str::FromStr;
+
+use tiff::decoder::DecodingResult;
+use tiff::encoder::colortype::RGBA8;
+use tiff::encoder::TiffEncoder;
+use tiff::ColorType;
+
+/// BMP file header that DIB lacks
+const BMP_FILE_HEADER_SIZE: usize = 14;
+/// BITMAPINFOHEADER, the smallest info header we read and the one we write
+const BMP_INFO_HEADER_SIZE: usize = 40;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ImageFormat {
+    Png,
+    Bmp,
+    Tiff,
+}
+
+impl ImageFormat {
+    pub fn to_string(&self) -> &str {
+        match self {
+            Self::Png => "PNG",
+            Self::Bmp => "BMP",
+            Self::Tiff => "TIFF",
+        }
+    }
+    pub fn extension(&self) -> &str {
+        match self {
+            Self::Png => "png",
+            Self::Bmp => "bmp",
+            Self::Tiff => "tiff",
+        }
+    }
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "png" => Some(Self::Png),
+            "bmp" => Some(Self::Bmp),
+            "tif" | "tiff" => Some(Self::Tiff),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = ImageError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PNG" => Ok(Self::Png),
+            "BMP" => Ok(Self::Bmp),
+            "TIFF" => Ok(Self::Tiff),
+            _ => Err(ImageError::Unsupported(format!(
+                "Unknown image format: {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ImageError {
+    Decode(String),
+    Encode(String),
+    Unsupported(String),
+}
+
+/// Encoded image with its dimensions
+#[derive(Debug, PartialEq, Clone)]
+pub struct Image {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Decoded image, 4 bytes per pixel, rows from the top
+struct Rgba {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Wraps encoded image, reading dimensions from its header
+    pub fn from_bytes(format: ImageFormat, data: Vec<u8>) -> Result<Self, ImageError> {
+        let (width, height) = match format {
+            ImageFormat::Png => {
+                let reader = png::Decoder::new(data.as_slice())
+                    .read_info()
+                    .map_err(decode_err)?;
+                (reader.info().width, reader.info().height)
+            }
+            ImageFormat::Bmp => {
+                let header = BmpHeader::parse(&data, BMP_FILE_HEADER_SIZE)?;
+                (header.width, header.height)
+            }
+            ImageFormat::Tiff => tiff::decoder::Decoder::new(Cursor::new(&data))
+                .and_then(|mut decoder| decoder.dimensions())
+                .map_err(decode_err)?,
+        };
+        Ok(Image {
+            format,
+            width,
+            height,
+            data,
+        })
+    }
+
+    /// Windows CF_DIB, which is BMP without file header
+    pub fn from_dib(dib: &[u8]) -> Result<Self, ImageError> {
+        let header = BmpHeader::parse(dib, 0)?;
+        let mut data = bmp_file_header(dib.len(), header.pixels_offset());
+        data.extend_from_slice(dib);
+        Image::from_bytes(ImageFormat::Bmp, data)
+    }
+
+    pub fn to_dib(&self) -> Result<Vec<u8>, ImageError> {
+        let bmp = self.convert(ImageFormat::Bmp)?;
+        Ok(bmp.data[BMP_FILE_HEADER_SIZE..].to_vec())
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("image.{}", self.format.extension())
+    }
+
+    /// Same image in another format
+    pub fn convert(&self, format: ImageFormat) -> Result<Image, ImageError> {
+        if self.format == format {
+            return Ok(self.clone());
+        }
+        let rgba = match self.format {
+            ImageFormat::Png => decode_png(&self.data)?,
+            ImageFormat::Bmp => decode_bmp(&self.data)?,
+            ImageFormat::Tiff => decode_tiff(&self.data)?,
+        };
+        let data = match format {
+            ImageFormat::Png => encode_png(&rgba)?,
+            ImageFormat::Bmp => encode_bmp(&rgba),
+            ImageFormat::Tiff => encode_tiff(&rgba)?,
+        };
+        Ok(Image {
+            format,
+            width: rgba.width,
+            height: rgba.height,
+            data,
+        })
+    }
+}
+
+fn decode_err(err: impl std::fmt::Debug) -> ImageError {
+    ImageError::Decode(format!("{:?}", err))
+}
+
+fn encode_err(err: impl std::fmt::Debug) -> ImageError {
+    ImageError::Encode(format!("{:?}", err))
+}
+
+/// Number of pixels, checked before anything is allocated, so bogus header
+/// does not allocate gigabytes
+fn pixel_count(width: u32, height: u32) -> Result<usize, ImageError> {
+    const MAX_PIXELS: u64 = 1 << 28;
+    let count = width as u64 * height as u64;
+    if count > MAX_PIXELS {
+        return Err(ImageError::Unsupported(format!(
+            "Image is too big: {}x{}",
+            width, height
+        )));
+    }
+    Ok(count as usize)
+}
+
+/// Buffer for decoded pixels
+fn pixel_buffer(width: u32, height: u32) -> Result<Vec<u8>, ImageError> {
+    Ok(Vec::with_capacity(pixel_count(width, height)? * 4))
+}
+
+fn decode_png(data: &[u8]) -> Result<Rgba, ImageError> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(decode_err)?;
+    // decoder limits do not cover the output buffer
+    pixel_count(reader.info().width, reader.info().height)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(decode_err)?;
+    buffer.truncate(frame.buffer_size());
+
+    let mut pixels = pixel_buffer(frame.width, frame.height)?;
+    match frame.color_type {
+        png::ColorType::Rgba => pixels = buffer,
+        png::ColorType::Rgb => buffer
+            .chunks_exact(3)
+            .for_each(|p| pixels.extend([p[0], p[1], p[2], 255])),
+        png::ColorType::GrayscaleAlpha => buffer
+            .chunks_exact(2)
+            .for_each(|p| pixels.extend([p[0], p[0], p[0], p[1]])),
+        png::ColorType::Grayscale => buffer.iter().for_each(|p| pixels.extend([*p, *p, *p, 255])),
+        color => {
+            return Err(ImageError::Unsupported(format!(
+                "PNG color type: {:?}",
+                color
+            )))
+        }
+    }
+    Ok(Rgba {
+        width: frame.width,
+        height: frame.height,
+        pixels,
+    })
+}
+
+fn encode_png(rgba: &Rgba) -> Result<Vec<u8>, ImageError> {
+    let mut out = vec![];
+    let mut encoder = png::Encoder::new(&mut out, rgba.width, rgba.height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(encode_err)?;
+    writer.write_image_data(&rgba.pixels).map_err(encode_err)?;
+    writer.finish().map_err(encode_err)?;
+    Ok(out)
+}
+
+fn decode_tiff(data: &[u8]) -> Result<Rgba, ImageError> {
+    let mut decoder = tiff::decoder::Decoder::new(Cursor::new(data)).map_err(decode_err)?;
+    let (width, height) = decoder.dimensions().map_err(decode_err)?;
+    let color = decoder.colortype().map_err(decode_err)?;
+    // 16-bit samples keep their high byte
+    let samples: Vec<u8> = match decoder.read_image().map_err(decode_err)? {
+        DecodingResult::U8(samples) => samples,
+        DecodingResult::U16(samples) => samples.iter().map(|s| (s >> 8) as u8).collect(),
+        _ => return Err(ImageError::Unsupported("TIFF sample format".to_string())),
+    };
+
+    let mut pixels = pixel_buffer(width, height)?;
+    match color {
+        ColorType::RGBA(_) => pixels = samples,
+        ColorType::RGB(_) => samples
+            .chunks_exact(3)
+            .for_each(|p| pixels.extend([p[0], p[1], p[2], 255])),
+        ColorType::GrayA(_) => samples
+            .chunks_exact(2)
+            .for_each(|p| pixels.extend([p[0], p[0], p[0], p[1]])),
+        ColorType::Gray(_) => samples
+            .iter()
+            .for_each(|p| pixels.extend([*p, *p, *p, 255])),
+        color => {
+            return Err(ImageError::Unsupported(format!(
+                "TIFF color type: {:?}",
+                color
+            )))
+        }
+    }
+    Ok(Rgba {
+        width,
+        height,
+        pixels,
+    })
+}
+
+fn encode_tiff(rgba: &Rgba) -> Result<Vec<u8>, ImageError> {
+    let mut out = Cursor::new(vec![]);
+    TiffEncoder::new(&mut out)
+        .and_then(|mut encoder| encoder.write_image::<RGBA8>(rgba.width, rgba.height, &rgba.pixels))
+        .map_err(encode_err)?;
+    Ok(out.into_inner())
+}
+
+/// Fields of BMP info header we need
+struct BmpHeader {
+    header_size: usize,
+    width: u32,
+    height: u32,
+    top_down: bool,
+    bit_count: u16,
+    compression: u32,
+    /// Red, green, blue and alpha masks of 32-bit bitfields
+    masks: [u32; 4],
+    /// Offset of info header in data
+    start: usize,
+}
+
+impl BmpHeader {
+    fn parse(data: &[u8], start: usize) -> Result<Self, ImageError> {
+        let u32_at = |offset: usize| -> Result<u32, ImageError> {
+            data.get(start + offset..start + offset + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or(ImageError::Decode("BMP header is truncated".to_string()))
+        };
+        if start > 0 && !data.starts_with(b"BM") {
+            return Err(ImageError::Decode("Not a BMP file".to_string()));
+        }
+        let header_size = u32_at(0)? as usize;
+        if header_size < BMP_INFO_HEADER_SIZE {
+            return Err(ImageError::Unsupported(format!(
+                "BMP header of size {}",
+                header_size
+            )));
+        }
+        if data.len() < start + header_size {
+            return Err(ImageError::Decode("BMP header is truncated".to_string()));
+        }
+        let width = u32_at(4)? as i32;
+        let height = u32_at(8)? as i32;
+        let bit_count = (u32_at(12)? >> 16) as u16;
+        let compression = u32_at(16)?;
+        let mut masks = [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0xff00_0000];
+        if compression == BI_BITFIELDS {
+            // masks follow the small header or are part of the bigger ones
+            for (i, mask) in masks.iter_mut().enumerate().take(3) {
+                *mask = u32_at(BMP_INFO_HEADER_SIZE + i * 4)?;
+            }
+            masks[3] = if header_size > BMP_INFO_HEADER_SIZE {
+                u32_at(BMP_INFO_HEADER_SIZE + 12)?
+            } else {
+                0
+            };
+        }
+        if width <= 0 || height == 0 {
+            return Err(ImageError::Decode(format!(
+                "Invalid BMP size: {}x{}",
+                width, height
+            )));
+        }
+        Ok(BmpHeader {
+            header_size,
+            width: width as u32,
+            height: height.unsigned_abs(),
+            top_down: height < 0,
+            bit_count,
+            compression,
+            masks,
+            start,
+        })
+    }
+
+    /// Offset of pixels from the start of info header
+    fn pixels_offset(&self) -> usize {
+        let masks = match (self.compression, self.header_size) {
+            (BI_BITFIELDS, BMP_INFO_HEADER_SIZE) => 12,
+            _ => 0,
+        };
+        self.header_size + masks
+    }
+}
+
+fn bmp_file_header(info_len: usize, pixels_offset: usize) -> Vec<u8> {
+    let mut out = b"BM".to_vec();
+    out.extend(((BMP_FILE_HEADER_SIZE + info_len) as u32).to_le_bytes());
+    out.extend([0; 4]);
+    out.extend(((BMP_FILE_HEADER_SIZE + pixels_offset) as u32).to_le_bytes());
+    out
+}
+
+fn decode_bmp(data: &[u8]) -> Result<Rgba, ImageError> {
+    let header = BmpHeader::parse(data, BMP_FILE_HEADER_SIZE)?;
+    let bytes_per_pixel = match (header.bit_count, header.compression) {
+        (24, BI_RGB) => 3,
+        (32, BI_RGB | BI_BITFIELDS) => 4,
+        (bits, compression) => {
+            return Err(ImageError::Unsupported(format!(
+                "BMP with {} bits per pixel, compression {}",
+                bits, compression
+            )))
+        }
+    };
+    let (width, height) = (header.width as usize, header.height as usize);
+    // rows are padded to 4 bytes
+    let stride = (width * bytes_per_pixel).div_ceil(4) * 4;
+    let start = header.start + header.pixels_offset();
+    let pixel_data = data
+        .get(start..)
+        .filter(|pixel_data| pixel_data.len() / stride >= height)
+        .ok_or(ImageError::Decode("BMP pixels are truncated".to_string()))?;
+
+    let channel = |pixel: u32, mask: u32| -> u8 {
+        if mask == 0 {
+            return 255;
+        }
+        ((pixel & mask) >> mask.trailing_zeros()) as u8
+    };
+    let mut pixels = pixel_buffer(header.width, header.height)?;
+    for y in 0..height {
+        let row = if header.top_down { y } else { height - 1 - y };
+        let row = &pixel_data[row * stride..row * stride + width * bytes_per_pixel];
+        for p in row.chunks_exact(bytes_per_pixel) {
+            if bytes_per_pixel == 3 {
+                pixels.extend([p[2], p[1], p[0], 255]);
+                continue;
+            }
+            let pixel = u32::from_le_bytes([p[0], p[1], p[2], p[3]]);
+            let [r, g, b, a] = header.masks;
+            pixels.extend([
+                channel(pixel, r),
+                channel(pixel, g),
+                channel(pixel, b),
+                channel(pixel, a),
+            ]);
+        }
+    }
+    // alpha byte of plain 32-bit BMP is usually left zero
+    if header.compression == BI_RGB && pixels.chunks_exact(4).all(|p| p[3] == 0) {
+        pixels.chunks_exact_mut(4).for_each(|p| p[3] = 255);
+    }
+    Ok(Rgba {
+        width: header.width,
+        height: header.height,
+        pixels,
+    })
+}
+
+/// Writes 32-bit top-down BMP, alpha is kept in the fourth byte
+fn encode_bmp(rgba: &Rgba) -> Vec<u8> {
+    let pixels_len = rgba.pixels.len();
+    let mut out = bmp_file_header(BMP_INFO_HEADER_SIZE + pixels_len, BMP_INFO_HEADER_SIZE);
+    out.extend((BMP_INFO_HEADER_SIZE as u32).to_le_bytes());
+    out.extend((rgba.width as i32).to_le_bytes());
+    out.extend((-(rgba.height as i32)).to_le_bytes());
+    out.extend(1u16.to_le_bytes());
+    out.extend(32u16.to_le_bytes());
+    out.extend(BI_RGB.to_le_bytes());
+    out.extend((pixels_len as u32).to_le_bytes());
+    // resolution and palette are left empty
+    out.extend([0; 16]);
+    for p in rgba.pixels.chunks_exact(4) {
+        out.extend([p[2], p[1], p[0], p[3]]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for byte in data {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn test_huge_png_header_is_refused() {
+        let rgba = Rgba {
+            width: 1,
+            height: 1,
+            pixels: vec![0, 0, 0, 255],
+        };
+        let forge = |side: u32| {
+            let mut png = encode_png(&rgba).unwrap();
+            png[16..20].copy_from_slice(&side.to_be_bytes());
+            png[20..24].copy_from_slice(&side.to_be_bytes());
+            let crc = crc32(&png[12..29]);
+            png[29..33].copy_from_slice(&crc.to_be_bytes());
+            png
+        };
+        // IHDR declaring 4 GiB of pixels, with valid checksum
+        assert!(matches!(
+            decode_png(&forge(1 << 15)),
+            Err(ImageError::Unsupported(err)) if err.contains("too big")
+        ));
+        assert!(decode_png(&forge(0x7FFF_FFFF)).is_err());
+    }
+
+    #[test]
+    fn test_convert_between_formats() {
+        // 3x2 with transparent pixel, odd width checks row padding
+        let pixels: Vec<u8> = vec![
+            255, 0, 0, 255, 0, 255, 0, 255, 0, 0, 255, 255, //
+            10, 20, 30, 255, 40, 50, 60, 128, 0, 0, 0, 0,
+        ];
+        let rgba = Rgba {
+            width: 3,
+            height: 2,
+            pixels: pixels.clone(),
+        };
+        let png = Image::from_bytes(ImageFormat::Png, encode_png(&rgba).unwrap()).unwrap();
+        assert_eq!((png.width, png.height), (3, 2));
+
+        for format in [ImageFormat::Bmp, ImageFormat::Tiff] {
+            let converted = png.convert(format).unwrap();
+            let parsed = Image::from_bytes(format, converted.data.clone()).unwrap();
+            assert_eq!(parsed, converted);
+            let back = converted.convert(ImageFormat::Png).unwrap();
+            assert_eq!(decode_png(&back.data).unwrap().pixels, pixels);
+        }
+
+        let dib = png.to_dib().unwrap();
+        let from_dib = Image::from_dib(&dib).unwrap();
+        assert_eq!((from_dib.width, from_dib.height), (3, 2));
+        assert_eq!(decode_bmp(&from_dib.data).unwrap().pixels, pixels);
+
+        // 24-bit bottom-up DIB, as older apps put it on Windows clipboard
+        let mut dib = vec![];
+        for field in [40u32, 1, 2, 1 | (24 << 16), BI_RGB, 8, 0, 0, 0, 0] {
+            dib.extend(field.to_le_bytes());
+        }
+        // bottom row is red, top one is blue
+        dib.extend([0, 0, 255, 0, 255, 0, 0, 0]);
+        let image = Image::from_dib(&dib).unwrap();
+        assert_eq!(
+            decode_bmp(&image.data).unwrap().pixels,
+            vec![0, 0, 255, 255, 255, 0, 0, 255]
+        );
+
+        // garbage is rejected without panicking
+        assert!(Image::from_bytes(ImageFormat::Png, vec![0x89, b'P', b'N', b'G']).is_err());
+        assert!(Image::from_dib(&dib[..20]).is_err());
+        assert!(Image::from_dib(&dib[..44])
+            .unwrap()
+            .convert(ImageFormat::Png)
+            .is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Directory where files received from peers end up
+//!
+//! Files are written under a temporary name next to their destination and
+//! renamed once complete, so a half written file never shows up under its
+//! real name. Names that are already taken get ` (1)`, ` (2)`... appended,
+//! and nothing that is already there is overwritten.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use super::ClipboardData;
+use super::ClipboardError;
+use super::FileTree;
+use crate::debug_println;
+use crate::utils::move_file;
+
+/// Env variable with directory for received files, Desktop by default
+const INBOX_ENV: &str = "COPYXROSS_INBOX";
+/// Set to `1` to keep files of each peer in a subdirectory named after it
+const INBOX_PER_PEER_ENV: &str = "COPYXROSS_INBOX_PER_PEER";
+/// Set to `1` to put received files onto clipboard, as if copied in file manager
+const FILES_TO_CLIPBOARD_ENV: &str = "COPYXROSS_FILES_TO_CLIPBOARD";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Inbox {
+    pub dir: PathBuf,
+    pub per_peer: bool,
+    pub to_clipboard: bool,
+}
+
+impl Inbox {
+    pub fn from_env() -> Result<Self, ClipboardError> {
+        let dir = match env::var_os(INBOX_ENV) {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => dirs_next::desktop_dir().ok_or(ClipboardError::Write(
+                "Could not find Desktop directory".to_string(),
+            ))?,
+        };
+        Ok(Inbox {
+            dir,
+            per_peer: env_flag(INBOX_PER_PEER_ENV),
+            to_clipboard: env_flag(FILES_TO_CLIPBOARD_ENV),
+        })
+    }
+
+    /// Directory for files of given peer
+    pub fn dir_for(&self, peer: Option<&str>) -> PathBuf {
+        match peer {
+            Some(peer) if self.per_peer => self.dir.join(peer_dir_name(peer)),
+            _ => self.dir.clone(),
+        }
+    }
+
+    /// Stores files of payload. Returns paths they ended up at
+    pub fn store(
+        &self,
+        data: ClipboardData,
+        peer: Option<&str>,
+    ) -> Result<Vec<PathBuf>, ClipboardError> {
+        let dir = self.dir_for(peer);
+        let map_err = |err: io::Error| {
+            ClipboardError::Write(format!("Could not store files in {:?}: {:?}", dir, err))
+        };
+        fs::create_dir_all(&dir).map_err(map_err)?;
+        let mut stored = vec![];
+        for rep in data.into_representations() {
+            let paths = match rep {
+                ClipboardData::File((filename, data)) => {
+                    store_file(&dir, &filename, |tmp| fs::write(tmp, &data))
+                }
+                ClipboardData::FileRef((filename, src)) => {
+                    store_file(&dir, &filename, |tmp| move_file(&src, tmp))
+                }
+                ClipboardData::Files(tree) => store_tree(&dir, &tree),
+                _ => continue,
+            };
+            stored.extend(paths.map_err(map_err)?);
+        }
+        if stored.is_empty() {
+            return Err(ClipboardError::Write("Nothing to write".to_string()));
+        }
+        debug_println!("Received files stored: {:?}", stored);
+        Ok(stored)
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    matches!(env::var(name).as_deref(), Ok("1" | "true" | "yes"))
+}
+
+/// Peer name that is safe to use as directory name
+fn peer_dir_name(peer: &str) -> String {
+    let name: String = peer
+        .chars()
+        .map(|c| match c {
+            c if c.is_alphanumeric() || "-_ ".contains(c) => c,
+            _ => '_',
+        })
+        .collect();
+    match name.trim() {
+        "" => "unknown".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Temporary path in the same directory, so the final rename is atomic
+fn temp_path(dir: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(".copyxross-{}-{}.part", process::id(), n))
+}
+
+/// `name.ext` for n = 0, otherwise `name (n).ext`
+fn candidate_path(dir: &Path, filename: &str, n: usize) -> PathBuf {
+    let (stem, ext) = match filename.rfind('.') {
+        Some(i) if i > 0 => filename.split_at(i),
+        _ => (filename, ""),
+    };
+    match n {
+        0 => dir.join(filename),
+        n => dir.join(format!("{} ({}){}", stem, n, ext)),
+    }
+}
+
+/// Moves file or directory to the first free candidate name. The name is
+/// taken by creating an empty file or directory there first, which fails if
+/// anything is already in its place, so nothing is ever overwritten
+fn place(from: &Path, dir: &Path, filename: &str) -> io::Result<PathBuf> {
+    let is_dir = from.symlink_metadata()?.is_dir();
+    let mut n = 0;
+    loop {
+        let dest = candidate_path(dir, filename, n);
+        let reserved = match is_dir {
+            true => fs::create_dir(&dest),
+            false => fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&dest)
+                .map(drop),
+        };
+        match reserved {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                n += 1;
+                continue;
+            }
+            Err(err) => return Err(err),
+        }
+        // replaces only the empty file or directory just created
+        return match fs::rename(from, &dest) {
+            Ok(()) => Ok(dest),
+            Err(err) => {
+                let _ = match is_dir {
+                    true => fs::remove_dir(&dest),
+                    false => fs::remove_file(&dest),
+                };
+                Err(err)
+            }
+        };
+    }
+}
+
+fn store_file(
+    dir: &Path,
+    filename: &str,
+    write: impl FnOnce(&Path) -> io::Result<()>,
+) -> io::Result<Vec<PathBuf>> {
+    let tmp = temp_path(dir);
+    let dest = write(&tmp).and_then(|_| place(&tmp, dir, filename));
+    if dest.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(vec![dest?])
+}
+
+/// Recreates tree in temporary directory and moves its top level entries out
+fn store_tree(dir: &Path, tree: &FileTree) -> io::Result<Vec<PathBuf>> {
+    let tmp = temp_path(dir);
+    let stored = tree.write_to(&tmp).and_then(|top| {
+        top.into_iter()
+            .map(|path| {
+                let filename = path.file_name().unwrap_or_default().to_string_lossy();
+                place(&path, dir, &filename)
+            })
+            .collect()
+    });
+    let _ = fs::remove_dir_all(&tmp);
+    stored
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::EntryContent;
+    use crate::clipboard::TreeEntry;
+
+    #[test]
+    fn test_store_with_unique_names() {
+        let dir = env::temp_dir().join(format!("copyxross-inbox-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let inbox = Inbox {
+            dir: dir.clone(),
+            per_peer: true,
+            to_clipboard: false,
+        };
+        let file = ClipboardData::File(("a.txt".to_string(), b"first".to_vec()));
+        let peer_dir = dir.join("office_pc");
+        assert_eq!(
+            inbox.store(file.clone(), Some("office/pc")).unwrap(),
+            [peer_dir.join("a.txt")]
+        );
+        assert_eq!(
+            inbox.store(file, Some("office/pc")).unwrap(),
+            [peer_dir.join("a (1).txt")]
+        );
+
+        let tree = FileTree {
+            entries: vec![
+                TreeEntry {
+                    path: "a.txt".to_string(),
+                    mode: 0,
+                    size: 3,
+                    content: EntryContent::Data(b"new".to_vec()),
+                },
+                TreeEntry {
+                    path: "docs".to_string(),
+                    mode: 0,
+                    size: 0,
+                    content: EntryContent::Dir,
+                },
+            ],
+        };
+        assert_eq!(
+            inbox
+                .store(ClipboardData::Files(tree), Some("office/pc"))
+                .unwrap(),
+            [peer_dir.join("a (2).txt"), peer_dir.join("docs")]
+        );
+        assert_eq!(fs::read(peer_dir.join("a.txt")).unwrap(), b"first");
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(&peer_dir).unwrap().count(), 4);
+
+        assert_eq!(candidate_path(&dir, ".bashrc", 1), dir.join(".bashrc (1)"));
+        let tmp = temp_path(&dir);
+        fs::write(&tmp, b"pc").unwrap();
+        assert_eq!(
+            place(&tmp, &dir, "office_pc").unwrap(),
+            dir.join("office_pc (1)")
+        );
+
+        // link to somewhere else is not followed nor replaced
+        #[cfg(unix)]
+        {
+            let target = dir.join("elsewhere");
+            std::os::unix::fs::symlink(&target, peer_dir.join("b.txt")).unwrap();
+            let file = ClipboardData::File(("b.txt".to_string(), b"b".to_vec()));
+            assert_eq!(
+                inbox.store(file, Some("office/pc")).unwrap(),
+                [peer_dir.join("b (1).txt")]
+            );
+            assert!(!target.exists());
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
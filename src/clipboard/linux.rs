@@ -0,0 +1,848 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use x11rb::connection::Connection;
+use x11rb::connection::RequestConnection;
+use x11rb::protocol::xproto::Atom;
+use x11rb::protocol::xproto::AtomEnum;
+use x11rb::protocol::xproto::ChangeWindowAttributesAux;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11rb::protocol::xproto::CreateWindowAux;
+use x11rb::protocol::xproto::EventMask;
+use x11rb::protocol::xproto::PropMode;
+use x11rb::protocol::xproto::Property;
+use x11rb::protocol::xproto::SelectionNotifyEvent;
+use x11rb::protocol::xproto::SelectionRequestEvent;
+use x11rb::protocol::xproto::Window;
+use x11rb::protocol::xproto::WindowClass;
+use x11rb::protocol::xproto::SELECTION_NOTIFY_EVENT;
+use x11rb::protocol::Event;
+use x11rb::rust_connection::RustConnection;
+use x11rb::wrapper::ConnectionExt as _;
+use x11rb::COPY_DEPTH_FROM_PARENT;
+use x11rb::CURRENT_TIME;
+use x11rb::NONE;
+
+use super::files_from_paths;
+use super::receive_files;
+use super::wayland::WaylandClipboard;
+use super::Clipboard;
+use super::ClipboardData;
+use super::ClipboardError;
+use super::Image;
+use super::ImageFormat;
+use super::StringType;
+use crate::debug_println;
+use crate::utils::extract_plain_str_from_html;
+use crate::utils::log_into_file;
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        CLIPBOARD,
+        TARGETS,
+        INCR,
+        XCOPY_DATA: b"XCOPYXROSS_DATA",
+    }
+}
+
+/// How long we wait for the selection owner to answer a conversion request
+pub(super) const CONVERT_TIMEOUT: Duration = Duration::from_secs(2);
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
+pub(super) enum SelectionTarget {
+    FILEPATH,
+    IMAGE,
+    HTML,
+    RTF,
+    TEXT,
+}
+
+/// Data offered to other apps by target (X11) or mime type (Wayland)
+pub(super) type SelectionContents = Vec<(&'static str, Vec<u8>)>;
+
+/// Targets (X11) and mime types (Wayland) we understand, from the most to the least preferred
+pub(super) const TARGET_PRIORITY: [(&str, SelectionTarget); 8] = [
+    ("text/uri-list", SelectionTarget::FILEPATH),
+    ("image/png", SelectionTarget::IMAGE),
+    ("text/html", SelectionTarget::HTML),
+    ("text/rtf", SelectionTarget::RTF),
+    ("text/plain;charset=utf-8", SelectionTarget::TEXT),
+    ("UTF8_STRING", SelectionTarget::TEXT),
+    ("text/plain", SelectionTarget::TEXT),
+    ("STRING", SelectionTarget::TEXT),
+];
+
+/// Targets under which plain text is offered to other apps
+const TEXT_TARGETS: [&str; 5] = [
+    "text/plain;charset=utf-8",
+    "UTF8_STRING",
+    "text/plain",
+    "STRING",
+    "TEXT",
+];
+
+/// One connection with a hidden window that is used as requestor or owner of
+/// CLIPBOARD selection.
+struct X11Session {
+    conn: RustConnection,
+    window: Window,
+    atoms: Atoms,
+}
+
+impl X11Session {
+    fn connect(display: Option<&str>) -> Result<Self, ClipboardError> {
+        let (conn, screen_num) = RustConnection::connect(display).map_err(|err| {
+            ClipboardError::Init(format!("Could not connect to X server: {:?}", err))
+        })?;
+        let screen = &conn.setup().roots[screen_num];
+        let window = conn
+            .generate_id()
+            .map_err(|err| ClipboardError::Init(format!("Could not create window: {:?}", err)))?;
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            window,
+            screen.root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            screen.root_visual,
+            &CreateWindowAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+        )
+        .map_err(|err| ClipboardError::Init(format!("Could not create window: {:?}", err)))?;
+
+        let atoms = Atoms::new(&conn)
+            .map_err(|err| ClipboardError::Init(format!("Could not intern atoms: {:?}", err)))?
+            .reply()
+            .map_err(|err| ClipboardError::Init(format!("Could not intern atoms: {:?}", err)))?;
+
+        Ok(X11Session {
+            conn,
+            window,
+            atoms,
+        })
+    }
+
+    fn wait_for_event<F>(&self, deadline: Instant, mut matches: F) -> Result<Event, ClipboardError>
+    where
+        F: FnMut(&Event) -> bool,
+    {
+        loop {
+            let event = self
+                .conn
+                .poll_for_event()
+                .map_err(|err| ClipboardError::Read(format!("X11 connection error: {:?}", err)))?;
+            match event {
+                Some(event) if matches(&event) => return Ok(event),
+                Some(_) => continue,
+                None => {
+                    if Instant::now() > deadline {
+                        return Err(ClipboardError::Read(
+                            "Timed out waiting for clipboard owner".to_string(),
+                        ));
+                    }
+                    thread::sleep(EVENT_POLL_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Asks current CLIPBOARD owner to convert selection into `target` and returns raw bytes
+    fn convert(&self, target: Atom) -> Result<Vec<u8>, ClipboardError> {
+        let atoms = self.atoms;
+        self.conn
+            .convert_selection(
+                self.window,
+                atoms.CLIPBOARD,
+                target,
+                atoms.XCOPY_DATA,
+                CURRENT_TIME,
+            )
+            .map_err(|err| {
+                ClipboardError::Read(format!("Failed to convert selection: {:?}", err))
+            })?;
+        self.conn
+            .flush()
+            .map_err(|err| ClipboardError::Read(format!("X11 connection error: {:?}", err)))?;
+
+        let deadline = Instant::now() + CONVERT_TIMEOUT;
+        let event = self.wait_for_event(
+            deadline,
+            |e| matches!(e, Event::SelectionNotify(n) if n.requestor == self.window),
+        )?;
+        if let Event::SelectionNotify(notify) = event {
+            if notify.property == NONE {
+                return Err(ClipboardError::Read(
+                    "Clipboard owner refused to convert selection".to_string(),
+                ));
+            }
+        }
+
+        let reply = self.get_property()?;
+        if reply.0 != atoms.INCR {
+            return Ok(reply.1);
+        }
+
+        // Data is too big to be sent at once, owner sends it in chunks
+        // every time we delete the property
+        debug_println!("Receiving clipboard data incrementally");
+        let mut data: Vec<u8> = vec![];
+        loop {
+            let deadline = Instant::now() + CONVERT_TIMEOUT;
+            self.wait_for_event(deadline, |e| {
+                matches!(e, Event::PropertyNotify(n)
+                    if n.window == self.window
+                    && n.atom == atoms.XCOPY_DATA
+                    && n.state == Property::NEW_VALUE)
+            })?;
+            let (_, chunk) = self.get_property()?;
+            if chunk.is_empty() {
+                break;
+            }
+            data.extend(chunk);
+        }
+        Ok(data)
+    }
+
+    /// Reads and deletes our transfer property. Returns property type and its bytes
+    fn get_property(&self) -> Result<(Atom, Vec<u8>), ClipboardError> {
+        let reply = self
+            .conn
+            .get_property(
+                true,
+                self.window,
+                self.atoms.XCOPY_DATA,
+                AtomEnum::ANY,
+                0,
+                u32::MAX / 4,
+            )
+            .map_err(|err| ClipboardError::Read(format!("Failed to read property: {:?}", err)))?
+            .reply()
+            .map_err(|err| ClipboardError::Read(format!("Failed to read property: {:?}", err)))?;
+        Ok((reply.type_, reply.value))
+    }
+
+    fn read_targets(&self) -> Result<Vec<Atom>, ClipboardError> {
+        let data = self.convert(self.atoms.TARGETS)?;
+        Ok(data
+            .chunks_exact(4)
+            .map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+            .collect())
+    }
+
+    fn intern(&self, name: &str) -> Result<Atom, ClipboardError> {
+        let atom = self
+            .conn
+            .intern_atom(false, name.as_bytes())
+            .map_err(|err| ClipboardError::Init(format!("Could not intern atom: {:?}", err)))?
+            .reply()
+            .map_err(|err| ClipboardError::Init(format!("Could not intern atom: {:?}", err)))?
+            .atom;
+        Ok(atom)
+    }
+
+    /// Known targets offered by selection owner, from the most preferred
+    fn offered_targets(&self) -> Result<Vec<&'static str>, ClipboardError> {
+        let targets = self.read_targets()?;
+        let mut offered = vec![];
+        for (name, _) in TARGET_PRIORITY {
+            if targets.contains(&self.intern(name)?) {
+                offered.push(name);
+            }
+        }
+        Ok(offered)
+    }
+
+    /// Takes CLIPBOARD ownership and serves conversion requests until someone
+    /// else owns the selection
+    fn serve(self, contents: HashMap<Atom, Vec<u8>>) {
+        let atoms = self.atoms;
+        let chunk_size = self.conn.maximum_request_bytes() / 4;
+        // (requestor, property) -> (target data, bytes already sent)
+        let mut incr_transfers: HashMap<(Window, Atom), (Atom, usize)> = HashMap::new();
+
+        loop {
+            let event = match self.conn.wait_for_event() {
+                Ok(event) => event,
+                Err(err) => {
+                    let _ = log_into_file(format!("X11 clipboard owner error: {:?}", err).as_str());
+                    return;
+                }
+            };
+            let res = match event {
+                Event::SelectionClear(e) if e.selection == atoms.CLIPBOARD => {
+                    debug_println!("Lost clipboard ownership");
+                    return;
+                }
+                Event::SelectionRequest(e) => {
+                    self.answer_request(&e, &contents, chunk_size, &mut incr_transfers)
+                }
+                Event::PropertyNotify(e) if e.state == Property::DELETE => {
+                    let key = (e.window, e.atom);
+                    if let Some((target, sent)) = incr_transfers.get(&key).copied() {
+                        let data = contents.get(&target).map(|d| d.as_slice()).unwrap_or(&[]);
+                        let end = usize::min(sent + chunk_size, data.len());
+                        if sent == data.len() {
+                            incr_transfers.remove(&key);
+                        } else {
+                            incr_transfers.insert(key, (target, end));
+                        }
+                        self.conn
+                            .change_property8(
+                                PropMode::REPLACE,
+                                e.window,
+                                e.atom,
+                                target,
+                                &data[sent..end],
+                            )
+                            .map(|_| ())
+                    } else {
+                        Ok(())
+                    }
+                }
+                _ => Ok(()),
+            };
+            if let Err(err) = res.and_then(|_| self.conn.flush()) {
+                let _ = log_into_file(format!("X11 clipboard owner error: {:?}", err).as_str());
+                return;
+            }
+        }
+    }
+
+    fn answer_request(
+        &self,
+        e: &SelectionRequestEvent,
+        contents: &HashMap<Atom, Vec<u8>>,
+        chunk_size: usize,
+        incr_transfers: &mut HashMap<(Window, Atom), (Atom, usize)>,
+    ) -> Result<(), x11rb::errors::ConnectionError> {
+        let atoms = self.atoms;
+        // obsolete clients may pass None as property
+        let property = if e.property == NONE {
+            e.target
+        } else {
+            e.property
+        };
+        let mut reply_property = property;
+
+        if e.target == atoms.TARGETS {
+            let mut targets: Vec<Atom> = contents.keys().copied().collect();
+            targets.push(atoms.TARGETS);
+            self.conn.change_property32(
+                PropMode::REPLACE,
+                e.requestor,
+                property,
+                AtomEnum::ATOM,
+                &targets,
+            )?;
+        } else if let Some(data) = contents.get(&e.target) {
+            if data.len() > chunk_size {
+                self.conn.change_window_attributes(
+                    e.requestor,
+                    &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE),
+                )?;
+                self.conn.change_property32(
+                    PropMode::REPLACE,
+                    e.requestor,
+                    property,
+                    atoms.INCR,
+                    &[data.len() as u32],
+                )?;
+                incr_transfers.insert((e.requestor, property), (e.target, 0));
+            } else {
+                self.conn.change_property8(
+                    PropMode::REPLACE,
+                    e.requestor,
+                    property,
+                    e.target,
+                    data,
+                )?;
+            }
+        } else {
+            reply_property = NONE;
+        }
+
+        let notify = SelectionNotifyEvent {
+            response_type: SELECTION_NOTIFY_EVENT,
+            sequence: 0,
+            time: e.time,
+            requestor: e.requestor,
+            selection: e.selection,
+            target: e.target,
+            property: reply_property,
+        };
+        self.conn
+            .send_event(false, e.requestor, EventMask::NO_EVENT, notify)?;
+        Ok(())
+    }
+}
+
+/// Percent-encodes path for file URI
+fn encode_uri_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for b in path.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Decodes percent-encoded characters of file URI
+fn decode_uri_path(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+            if let Ok(b) = u8::from_str_radix(hex, 16) {
+                decoded.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Reads uri-list. Several files or directories are read as a tree
+pub(super) fn read_file(data: &[u8]) -> Result<ClipboardData, ClipboardError> {
+    let uri_list = String::from_utf8_lossy(data);
+    let mut paths = vec![];
+    for uri in uri_list
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+    {
+        let path = uri
+            .strip_prefix("file://")
+            .ok_or(ClipboardError::Read(format!(
+                "Only local files can be copied: {}",
+                uri
+            )))?;
+        let path = decode_uri_path(path);
+        debug_println!("File: {}", path);
+        if !Path::new(&path).exists() {
+            return Err(ClipboardError::Read(format!(
+                "File does not exist: {}",
+                path
+            )));
+        }
+        paths.push(PathBuf::from(path.trim_end_matches('/')));
+    }
+    files_from_paths(paths)
+}
+
+/// Offers files on disk like file manager does on copy
+pub(super) fn file_list_contents(paths: &[PathBuf]) -> SelectionContents {
+    let uris: Vec<String> = paths
+        .iter()
+        .map(|path| format!("file://{}", encode_uri_path(&path.to_string_lossy())))
+        .collect();
+    let uri_list = uris.join("\r\n") + "\r\n";
+    let gnome = format!("copy\n{}", uris.join("\n"));
+    vec![
+        ("text/uri-list", uri_list.into_bytes()),
+        ("x-special/gnome-copied-files", gnome.into_bytes()),
+    ]
+}
+
+/// Targets to read, from the most preferred. File or image is read alone,
+/// text is read in every representation that is offered
+pub(super) fn choose_targets(offered: &[&str]) -> Vec<(&'static str, SelectionTarget)> {
+    let mut chosen: Vec<(&'static str, SelectionTarget)> = vec![];
+    for (name, s_target) in TARGET_PRIORITY {
+        if !offered.contains(&name) || chosen.iter().any(|(_, t)| *t == s_target) {
+            continue;
+        }
+        if matches!(s_target, SelectionTarget::FILEPATH | SelectionTarget::IMAGE) {
+            if chosen.is_empty() {
+                return vec![(name, s_target)];
+            }
+            continue;
+        }
+        chosen.push((name, s_target));
+    }
+    chosen
+}
+
+/// Representations under which text is offered to other apps. Without
+/// plain text html is accompanied by its plain version, so apps without html
+/// support can paste it too
+pub(super) fn text_representations(
+    texts: Vec<(StringType, Vec<u8>)>,
+) -> Result<SelectionContents, ClipboardError> {
+    let mut contents: Vec<(&'static str, Vec<u8>)> = vec![];
+    let mut plain_text: Option<String> = None;
+    let mut html_text: Option<String> = None;
+    for (s_type, text) in texts {
+        let text = String::from_utf8(text).map_err(|err| {
+            ClipboardError::Write(format!(
+                "Failed to write to clipboard. String is invalid: {:?}",
+                err
+            ))
+        })?;
+        let target = match s_type {
+            StringType::Html => {
+                html_text.get_or_insert_with(|| extract_plain_str_from_html(&text));
+                "text/html"
+            }
+            StringType::Rtf => "text/rtf",
+            StringType::Utf8Plain => {
+                plain_text.get_or_insert(text);
+                continue;
+            }
+        };
+        if !contents.iter().any(|(t, _)| *t == target) {
+            contents.push((target, text.into_bytes()));
+        }
+    }
+    if let Some(plain_text) = plain_text.or(html_text) {
+        for target in TEXT_TARGETS {
+            contents.push((target, plain_text.as_bytes().to_vec()));
+        }
+    }
+    Ok(contents)
+}
+
+/// Contents to offer on selection. Text goes in all its representations,
+/// otherwise only the most preferred one is used. Received files are stored
+/// in the inbox before, so they are not offered here
+pub(super) fn selection_contents(data: ClipboardData) -> Result<SelectionContents, ClipboardError> {
+    let (texts, others) = data.split_text();
+    let mut contents = match texts.is_empty() {
+        true => vec![],
+        false => text_representations(texts)?,
+    };
+    if let Some(ClipboardData::Image(image)) = others
+        .into_iter()
+        .find(|rep| matches!(rep, ClipboardData::Image(_)))
+    {
+        let png = image
+            .convert(ImageFormat::Png)
+            .map_err(|err| ClipboardError::Write(format!("Could not convert image: {:?}", err)))?;
+        contents.push(("image/png", png.data));
+    }
+    if contents.is_empty() {
+        return Err(ClipboardError::Write("Nothing to write".to_string()));
+    }
+    Ok(contents)
+}
+
+pub(super) fn into_clipboard_data(
+    s_target: SelectionTarget,
+    data: Vec<u8>,
+) -> Result<ClipboardData, ClipboardError> {
+    match s_target {
+        SelectionTarget::TEXT => Ok(ClipboardData::String((StringType::Utf8Plain, data))),
+        SelectionTarget::HTML => Ok(ClipboardData::String((StringType::Html, data))),
+        SelectionTarget::RTF => Ok(ClipboardData::String((StringType::Rtf, data))),
+        SelectionTarget::IMAGE => Image::from_bytes(ImageFormat::Png, data)
+            .map(ClipboardData::Image)
+            .map_err(|err| ClipboardError::Read(format!("Invalid image: {:?}", err))),
+        SelectionTarget::FILEPATH => read_file(&data),
+    }
+}
+
+pub struct X11Clipboard {
+    display: Option<String>,
+}
+
+impl X11Clipboard {
+    fn connect_to(display: Option<String>) -> Result<Self, ClipboardError> {
+        // make sure X server is reachable before we report clipboard as ready
+        X11Session::connect(display.as_deref())?;
+        Ok(X11Clipboard { display })
+    }
+
+    /// Spawns a thread that owns CLIPBOARD and serves `contents` to other apps
+    fn own_selection(&self, contents: Vec<(&str, Vec<u8>)>) -> Result<(), ClipboardError> {
+        let session = X11Session::connect(self.display.as_deref())?;
+        let mut by_atom: HashMap<Atom, Vec<u8>> = HashMap::new();
+        for (name, data) in contents {
+            by_atom.insert(session.intern(name)?, data);
+        }
+
+        let atoms = session.atoms;
+        session
+            .conn
+            .set_selection_owner(session.window, atoms.CLIPBOARD, CURRENT_TIME)
+            .map_err(|err| ClipboardError::Write(format!("Could not own clipboard: {:?}", err)))?;
+        let owner = session
+            .conn
+            .get_selection_owner(atoms.CLIPBOARD)
+            .map_err(|err| ClipboardError::Write(format!("Could not own clipboard: {:?}", err)))?
+            .reply()
+            .map_err(|err| ClipboardError::Write(format!("Could not own clipboard: {:?}", err)))?
+            .owner;
+        if owner != session.window {
+            return Err(ClipboardError::Write(
+                "Another client holds the clipboard".to_string(),
+            ));
+        }
+
+        thread::spawn(move || session.serve(by_atom));
+        Ok(())
+    }
+}
+
+impl Clipboard for X11Clipboard {
+    fn init() -> Result<Self, ClipboardError> {
+        X11Clipboard::connect_to(None)
+    }
+    fn write(&self, data: ClipboardData) -> Result<(), ClipboardError> {
+        self.own_selection(selection_contents(data)?)?;
+        debug_println!("Clipboard contents written");
+        Ok(())
+    }
+    fn read(&self) -> Result<ClipboardData, ClipboardError> {
+        let session = X11Session::connect(self.display.as_deref())?;
+        let mut reps = vec![];
+        for (name, s_target) in choose_targets(&session.offered_targets()?) {
+            let data = session.convert(session.intern(name)?)?;
+            reps.push(into_clipboard_data(s_target, data)?);
+        }
+        ClipboardData::from_representations(reps).ok_or(ClipboardError::Read(
+            "Could not find known types".to_string(),
+        ))
+    }
+}
+
+/// Wayland sessions use data-control when the compositor supports it,
+/// everything else (including XWayland fallback) goes through X11.
+pub enum LinuxClipboard {
+    Wayland(WaylandClipboard),
+    X11(X11Clipboard),
+}
+
+impl LinuxClipboard {
+    /// Puts files on disk onto clipboard
+    pub(super) fn write_paths(&self, paths: &[PathBuf]) -> Result<(), ClipboardError> {
+        let contents = file_list_contents(paths);
+        match self {
+            LinuxClipboard::Wayland(cp) => cp.set_contents(contents),
+            LinuxClipboard::X11(cp) => cp.own_selection(contents),
+        }
+    }
+}
+
+impl Clipboard for LinuxClipboard {
+    fn init() -> Result<Self, ClipboardError> {
+        if env::var_os("WAYLAND_DISPLAY").is_some() {
+            match WaylandClipboard::init() {
+                Ok(cp) => return Ok(LinuxClipboard::Wayland(cp)),
+                Err(err) => {
+                    let _ = log_into_file(
+                        format!(
+                            "Wayland clipboard unavailable, falling back to X11: {:?}",
+                            err
+                        )
+                        .as_str(),
+                    );
+                }
+            }
+        }
+        Ok(LinuxClipboard::X11(X11Clipboard::init()?))
+    }
+    fn write(&self, data: ClipboardData) -> Result<(), ClipboardError> {
+        if data.is_file_payload() {
+            return receive_files(self, data, None);
+        }
+        match self {
+            LinuxClipboard::Wayland(cp) => cp.write(data),
+            LinuxClipboard::X11(cp) => cp.write(data),
+        }
+    }
+    fn read(&self) -> Result<ClipboardData, ClipboardError> {
+        match self {
+            LinuxClipboard::Wayland(cp) => cp.read(),
+            LinuxClipboard::X11(cp) => cp.read(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::process::{Child, Command, Stdio};
+
+    /// Xvfb instance that is killed when test finishes
+    struct Xvfb {
+        child: Child,
+        display: String,
+    }
+
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+
+    /// Starts headless X server. Returns None if Xvfb is not installed
+    fn start_xvfb(display_num: u32) -> Option<Xvfb> {
+        let display = format!(":{}", display_num);
+        let child = Command::new("Xvfb")
+            .args([display.as_str(), "-nolisten", "tcp"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let Ok(child) = child else {
+            eprintln!("Xvfb is not available, skipping X11 clipboard test");
+            return None;
+        };
+        let socket = format!("/tmp/.X11-unix/X{}", display_num);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !Path::new(&socket).exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        Some(Xvfb { child, display })
+    }
+
+    #[test]
+    fn test_x11_text_roundtrip() {
+        let Some(xvfb) = start_xvfb(91) else { return };
+        let cp = X11Clipboard::connect_to(Some(xvfb.display.clone())).unwrap();
+
+        cp.write(ClipboardData::String((
+            StringType::Utf8Plain,
+            "Hello from X11".as_bytes().to_vec(),
+        )))
+        .unwrap();
+        assert_eq!(
+            cp.read().unwrap(),
+            ClipboardData::String((StringType::Utf8Plain, "Hello from X11".as_bytes().to_vec()))
+        );
+
+        cp.write(ClipboardData::String((
+            StringType::Html,
+            "<b>Bold</b>".as_bytes().to_vec(),
+        )))
+        .unwrap();
+        assert_eq!(
+            cp.read().unwrap(),
+            ClipboardData::String((StringType::Html, "<b>Bold</b>".as_bytes().to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_x11_incremental_transfer() {
+        let Some(xvfb) = start_xvfb(92) else { return };
+        let cp = X11Clipboard::connect_to(Some(xvfb.display.clone())).unwrap();
+
+        let big_text = "x".repeat(4 * 1024 * 1024).into_bytes();
+        cp.write(ClipboardData::String((
+            StringType::Utf8Plain,
+            big_text.clone(),
+        )))
+        .unwrap();
+        assert_eq!(
+            cp.read().unwrap(),
+            ClipboardData::String((StringType::Utf8Plain, big_text))
+        );
+    }
+
+    #[test]
+    fn test_x11_image_and_uri_list() {
+        let Some(xvfb) = start_xvfb(93) else { return };
+        let cp = X11Clipboard::connect_to(Some(xvfb.display.clone())).unwrap();
+
+        // 1x1 red pixel
+        let mut dib = vec![];
+        for field in [40u32, 1, 1, 1 | (24 << 16), 0, 4, 0, 0, 0, 0] {
+            dib.extend(field.to_le_bytes());
+        }
+        dib.extend([0, 0, 255, 0]);
+        let png = Image::from_dib(&dib)
+            .unwrap()
+            .convert(ImageFormat::Png)
+            .unwrap();
+        cp.own_selection(vec![("image/png", png.data.clone())])
+            .unwrap();
+        assert_eq!(cp.read().unwrap(), ClipboardData::Image(png.clone()));
+        cp.write(ClipboardData::Image(
+            png.convert(ImageFormat::Tiff).unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(cp.read().unwrap(), ClipboardData::Image(png));
+
+        let dir = std::env::temp_dir().join("copyxross x11 test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("note.txt");
+        std::fs::write(&file, b"file contents").unwrap();
+        let uri = format!(
+            "# comment\r\nfile://{}\r\n",
+            file.to_str().unwrap().replace(" ", "%20")
+        );
+        cp.own_selection(vec![("text/uri-list", uri.into_bytes())])
+            .unwrap();
+        assert_eq!(
+            cp.read().unwrap(),
+            ClipboardData::FileRef(("note.txt".to_string(), file.clone()))
+        );
+
+        // several files and directories come as a tree
+        let uri = format!("file://{}\nfile://{}/\n", file.display(), dir.display());
+        cp.own_selection(vec![(
+            "text/uri-list",
+            uri.replace(" ", "%20").into_bytes(),
+        )])
+        .unwrap();
+        let ClipboardData::Files(tree) = cp.read().unwrap() else {
+            panic!("Expected file tree");
+        };
+        assert_eq!(tree.entries[0].path, "note.txt");
+        assert_eq!(tree.entries[1].path, "copyxross x11 test");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_decode_uri_path() {
+        assert_eq!(
+            decode_uri_path("/home/me/My%20File.txt"),
+            "/home/me/My File.txt"
+        );
+        assert_eq!(decode_uri_path("/tmp/%D1%84.txt"), "/tmp/ф.txt");
+        assert_eq!(decode_uri_path("/tmp/100%"), "/tmp/100%");
+        let path = "/home/me/My File ф.txt";
+        assert_eq!(decode_uri_path(&encode_uri_path(path)), path);
+    }
+
+    #[test]
+    fn test_text_and_image_keep_both_targets() {
+        // 1x1 red pixel
+        let mut dib = vec![];
+        for field in [40u32, 1, 1, 1 | (24 << 16), 0, 4, 0, 0, 0, 0] {
+            dib.extend(field.to_le_bytes());
+        }
+        dib.extend([0, 0, 255, 0]);
+        let png = Image::from_dib(&dib)
+            .unwrap()
+            .convert(ImageFormat::Png)
+            .unwrap();
+        let cp_data = ClipboardData::Multi(vec![
+            ClipboardData::String((StringType::Utf8Plain, b"red pixel".to_vec())),
+            ClipboardData::Image(png.clone()),
+        ]);
+        let contents = selection_contents(cp_data).unwrap();
+        let targets: Vec<&str> = contents.iter().map(|(target, _)| *target).collect();
+        assert!(targets.contains(&"UTF8_STRING"));
+        assert!(contents.contains(&("image/png", png.data)));
+    }
+}
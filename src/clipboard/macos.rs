@@ -1,13 +1,14 @@
+use super::files_from_paths;
+use super::receive_files;
 use super::Clipboard;
 use super::ClipboardData;
 use super::ClipboardError;
+use super::Image;
+use super::ImageFormat;
 use super::StringType;
 use crate::debug_println;
-use crate::utils::create_file;
 use crate::utils::extract_plain_str_from_html;
 use crate::utils::macos::ObjectId;
-use crate::utils::open_file;
-use dirs_next::desktop_dir;
 use objc::class;
 use objc::msg_send;
 use objc::rc::autoreleasepool;
@@ -17,6 +18,7 @@ use objc::sel_impl;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::marker::PhantomData;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 #[link(name = "AppKit", kind = "framework")]
@@ -38,11 +40,12 @@ const NSUTF16Encoding: i32 = 10;
 const NSUTF8Encoding: i32 = 4;
 
 #[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq)]
 enum PasteboardType {
     FILEPATH,
     IMAGE,
     TEXT,
-    // RTF,
+    RTF,
     HTML,
 }
 
@@ -58,10 +61,10 @@ impl FromStr for PasteboardType {
             return Ok(PasteboardType::FILEPATH);
         }
 
-        // if input.contains(".rtf") {
-        //     return Ok(PasteboardType::RTF);
-        // }
-        //
+        if input == "public.rtf" {
+            return Ok(PasteboardType::RTF);
+        }
+
         if input.contains(".html") {
             return Ok(PasteboardType::HTML);
         }
@@ -86,29 +89,44 @@ unsafe impl Sync for MacosClipboard {}
 
 #[allow(unexpected_cfgs)]
 impl MacosClipboard {
+    /// Reads file URLs of all pasteboard items. Several files or
+    /// directories are read as a tree
     fn read_file(&self, first_type: *mut Object) -> Result<ClipboardData, ClipboardError> {
         let pb = self.p;
         autoreleasepool(|| unsafe {
-            let ns_url: *mut Object = msg_send![pb, propertyListForType: first_type];
-            if ns_url.is_null() {
-                return Err(ClipboardError::Read(
-                    "Failed to read file URL from clipboard".to_string(),
-                ));
-            }
-            let utf8: *const i8 = msg_send![ns_url, UTF8String];
-            let c_str = CStr::from_ptr(utf8).to_string_lossy();
-            let c_str = c_str.replace("%20", " ");
-            debug_println!("File: {}", c_str);
-            if c_str.ends_with("/") {
-                return Err(ClipboardError::Read(String::from("Cannot copy folders")));
+            let items: *mut Object = msg_send![pb, pasteboardItems];
+            let count: usize = if items.is_null() {
+                0
+            } else {
+                msg_send![items, count]
+            };
+            let mut paths = vec![];
+            for i in 0..count {
+                let item: *mut Object = msg_send![items, objectAtIndex: i];
+                let ns_url: *mut Object = msg_send![item, stringForType: first_type];
+                if ns_url.is_null() {
+                    continue;
+                }
+                let utf8: *const i8 = msg_send![ns_url, UTF8String];
+                let c_str = CStr::from_ptr(utf8).to_string_lossy();
+                let c_str = c_str.replace("%20", " ");
+                debug_println!("File: {}", c_str);
+                let path = c_str
+                    .strip_prefix("file://")
+                    .ok_or(ClipboardError::Read(format!(
+                        "Only local files can be copied: {}",
+                        c_str
+                    )))?;
+                let path = PathBuf::from(path.trim_end_matches('/'));
+                if !path.exists() {
+                    return Err(ClipboardError::Read(format!(
+                        "File does not exist: {:?}",
+                        path
+                    )));
+                }
+                paths.push(path);
             }
-            // slice file:// part
-            println!("{:?}", &c_str[7..]);
-            let file_buf = open_file(&c_str[7..]).map_err(|err| {
-                ClipboardError::Read(format!("Failed to read file from buffer: {:?}", err))
-            })?;
-            let filename = c_str.split("/").last().unwrap_or("unknown_file");
-            Ok(ClipboardData::File((String::from(filename), file_buf)))
+            files_from_paths(paths)
         })
     }
 
@@ -191,6 +209,35 @@ impl MacosClipboard {
     //         }
     //     })
     // }
+    fn read_rtf(&self, first_type: ObjectId) -> Result<ClipboardData, ClipboardError> {
+        Ok(ClipboardData::String((
+            StringType::Rtf,
+            self.read_image(first_type)?,
+        )))
+    }
+    /// Reads every text representation, from the most preferred
+    fn read_texts(&self) -> Result<ClipboardData, ClipboardError> {
+        let available = self.available_types();
+        let mut reps = vec![];
+        for wanted in [
+            PasteboardType::HTML,
+            PasteboardType::RTF,
+            PasteboardType::TEXT,
+        ] {
+            let Some((_, ns_type, _)) = available.iter().find(|(t, _, _)| *t == wanted) else {
+                continue;
+            };
+            let rep = match wanted {
+                PasteboardType::HTML => self.read_html(*ns_type)?,
+                PasteboardType::RTF => self.read_rtf(*ns_type)?,
+                _ => self.read_u8_str(*ns_type)?,
+            };
+            reps.push(rep);
+        }
+        ClipboardData::from_representations(reps).ok_or(ClipboardError::Read(
+            "Could not find known types".to_string(),
+        ))
+    }
     fn read_image(&self, first_type: ObjectId) -> Result<Vec<u8>, ClipboardError> {
         let pb = self.p;
         autoreleasepool(|| {
@@ -242,78 +289,126 @@ impl MacosClipboard {
         })
     }
 
-    fn write_file(&self, file_data: (String, Vec<u8>)) -> Result<(), ClipboardError> {
-        let (filename, data) = file_data;
-        let mime_type_opt = filename.split(".").last();
-        if mime_type_opt.is_none() {
-            return Err(ClipboardError::Write(
-                "Failed to write bytes into clipboard. Unknown mime_type".to_string(),
-            ));
-        }
-
-        let deskt_dir = desktop_dir();
-        if deskt_dir.is_none() {
-            return Err(ClipboardError::Write(
-                "Could not find Desktop directory".to_string(),
-            ));
-        }
-
-        let mut deskt_dir = deskt_dir.unwrap();
-        deskt_dir.push(&filename);
-
-        create_file(data.as_slice(), deskt_dir.to_str().unwrap_or(""))
-            .map_err(|err| ClipboardError::Write(format!("Could not write file: {:?}", err)))?;
-
-        debug_println!("Binary data written as {}", mime_type_opt.unwrap());
-        Ok(())
+    /// Puts files on disk onto pasteboard as file URLs, like Finder does on copy
+    pub(super) fn write_paths(&self, paths: &[PathBuf]) -> Result<(), ClipboardError> {
+        autoreleasepool(|| unsafe {
+            let mut urls: Vec<ObjectId> = vec![];
+            for path in paths {
+                let c_path = CString::new(path.to_string_lossy().as_bytes()).map_err(|err| {
+                    ClipboardError::Write(format!("Failed to create C string: {:?}", err))
+                })?;
+                let ns_path: ObjectId =
+                    msg_send![class!(NSString), stringWithUTF8String: c_path.as_ptr()];
+                let url: ObjectId = msg_send![class!(NSURL), fileURLWithPath: ns_path];
+                if url.is_null() {
+                    return Err(ClipboardError::Write(format!(
+                        "Failed to create file URL for {:?}",
+                        path
+                    )));
+                }
+                urls.push(url);
+            }
+            let array: ObjectId =
+                msg_send![class!(NSArray), arrayWithObjects: urls.as_ptr() count: urls.len()];
+            let _: () = msg_send![self.p, clearContents];
+            let success: bool = msg_send![self.p, writeObjects: array];
+            if !success {
+                return Err(ClipboardError::Write(
+                    "Failed to write files to clipboard".to_string(),
+                ));
+            }
+            Ok(())
+        })
     }
-    fn write_text(&self, text: &[u8], s_type: StringType) -> Result<(), ClipboardError> {
+    /// Writes all text representations at once. Without plain text html is
+    /// accompanied by its plain version, so apps without html support can paste it too
+    fn write_text(&self, texts: Vec<(StringType, Vec<u8>)>) -> Result<(), ClipboardError> {
         autoreleasepool(|| {
             unsafe {
-                let text = String::from_utf8(text.to_vec()).map_err(|err| {
-                    ClipboardError::Write(format!(
-                        "Failed to write to clipboard. String is invalid: {:?}",
-                        err
-                    ))
-                })?;
-                // Convert Rust `&str` to `NSString`
-                let mut c_text = CString::new(text.as_str()).map_err(|err| {
-                    ClipboardError::Write(format!("Failed to create C string: {:?}", err))
-                })?;
-
-                if s_type == StringType::Html {
-                    let plain_text = extract_plain_str_from_html(&text);
-                    c_text = CString::new(plain_text.as_str()).map_err(|err| {
-                        ClipboardError::Write(format!("Failed to create C string: {:?}", err))
+                let mut contents: Vec<(&str, String)> = vec![];
+                let mut plain_text: Option<String> = None;
+                let mut html_text: Option<String> = None;
+                for (s_type, text) in texts {
+                    let text = String::from_utf8(text).map_err(|err| {
+                        ClipboardError::Write(format!(
+                            "Failed to write to clipboard. String is invalid: {:?}",
+                            err
+                        ))
                     })?;
+                    match s_type {
+                        StringType::Html => {
+                            html_text.get_or_insert_with(|| extract_plain_str_from_html(&text));
+                            contents.push(("public.html", text));
+                        }
+                        StringType::Rtf => contents.push(("public.rtf", text)),
+                        StringType::Utf8Plain => {
+                            plain_text.get_or_insert(text);
+                        }
+                    }
                 }
-                let ns_string: ObjectId =
-                    msg_send![class!(NSString), stringWithUTF8String: c_text.as_ptr()];
-
-                if ns_string.is_null() {
-                    return Err(ClipboardError::Write(
-                        "Failed to create NSString".to_string(),
-                    ));
+                if let Some(plain_text) = plain_text.or(html_text) {
+                    contents.push(("public.utf8-plain-text", plain_text));
                 }
 
-                let _: () = msg_send![self.p, clearContents];
+                for (pb_type, text) in contents {
+                    // Convert Rust `&str` to `NSString`
+                    let c_text = CString::new(text.as_str()).map_err(|err| {
+                        ClipboardError::Write(format!("Failed to create C string: {:?}", err))
+                    })?;
+                    let ns_string: ObjectId =
+                        msg_send![class!(NSString), stringWithUTF8String: c_text.as_ptr()];
 
-                match s_type {
-                    StringType::Html => {
-                        self.write_str_into_clipboard("public.html", ns_string)?;
-                    }
-                    StringType::Utf8Plain => {
-                        // handled later
+                    if ns_string.is_null() {
+                        return Err(ClipboardError::Write(
+                            "Failed to create NSString".to_string(),
+                        ));
                     }
+                    self.write_str_into_clipboard(pb_type, ns_string)?;
+                    debug_println!("Text written to clipboard as {}", pb_type);
                 }
-
-                self.write_str_into_clipboard("public.utf8-plain-text", ns_string)?;
-
-                debug_println!("Text written to clipboard: {}", text);
                 Ok(())
             }
         })
     }
+    /// Puts image as PNG and TIFF, apps differ in which one they read
+    fn write_image(&self, image: &Image) -> Result<(), ClipboardError> {
+        let mut contents = vec![];
+        for (pb_type, format) in [
+            ("public.png", ImageFormat::Png),
+            ("public.tiff", ImageFormat::Tiff),
+        ] {
+            let converted = image.convert(format).map_err(|err| {
+                ClipboardError::Write(format!("Could not convert image: {:?}", err))
+            })?;
+            contents.push((pb_type, converted.data));
+        }
+        unsafe {
+            for (pb_type, data) in contents {
+                let c_type = CString::new(pb_type).map_err(|err| {
+                    ClipboardError::Write(format!("Failed to create C string: {:?}", err))
+                })?;
+                let ns_type: ObjectId =
+                    msg_send![class!(NSString), stringWithUTF8String: c_type.as_ptr()];
+                let ns_data: NSData =
+                    msg_send![class!(NSData), dataWithBytes: data.as_ptr() length: data.len()];
+                if ns_data.is_null() {
+                    return Err(ClipboardError::Write("Failed to create NSData".to_string()));
+                }
+                let success: bool = msg_send![self.p, setData: ns_data forType: ns_type];
+                if !success {
+                    return Err(ClipboardError::Write(
+                        "Failed to write image to clipboard".to_string(),
+                    ));
+                }
+            }
+        }
+        debug_println!(
+            "Image written to clipboard: {}x{}",
+            image.width,
+            image.height
+        );
+        Ok(())
+    }
     fn write_str_into_clipboard(
         &self,
         c_public_text_type: &str,
@@ -343,35 +438,38 @@ impl MacosClipboard {
             Ok(())
         }
     }
-    fn get_clipboard_type(&self) -> Result<(PasteboardType, NSType, String), ClipboardError> {
+    /// Known types on pasteboard, in the order the owner put them
+    fn available_types(&self) -> Vec<(PasteboardType, NSType, String)> {
+        let mut available = vec![];
         unsafe {
-            autoreleasepool(|| {
-                // Get all available types
-                let types: ObjectId = msg_send![self.p, types];
-
-                let count: usize = msg_send![types, count];
-
-                for i in 0..count {
-                    let ns_type: ObjectId = msg_send![types, objectAtIndex: i];
-                    let utf8_cstr: *const i8 = msg_send![ns_type, UTF8String];
-                    if !utf8_cstr.is_null() {
-                        let type_str = CStr::from_ptr(utf8_cstr).to_string_lossy().into_owned();
-                        let pb_type = PasteboardType::from_str(type_str.as_str());
-
-                        match pb_type {
-                            Err(_) => continue,
-                            _ => {
-                                return Ok((pb_type.unwrap(), ns_type, type_str));
-                            }
-                        }
+            // Get all available types
+            let types: ObjectId = msg_send![self.p, types];
+            if types.is_null() {
+                return available;
+            }
+
+            let count: usize = msg_send![types, count];
+
+            for i in 0..count {
+                let ns_type: ObjectId = msg_send![types, objectAtIndex: i];
+                let utf8_cstr: *const i8 = msg_send![ns_type, UTF8String];
+                if !utf8_cstr.is_null() {
+                    let type_str = CStr::from_ptr(utf8_cstr).to_string_lossy().into_owned();
+                    if let Ok(pb_type) = PasteboardType::from_str(type_str.as_str()) {
+                        available.push((pb_type, ns_type, type_str));
                     }
                 }
-
-                Err(ClipboardError::Read(
-                    "Could not find known types".to_string(),
-                ))
-            })
+            }
         }
+        available
+    }
+    fn get_clipboard_type(&self) -> Result<(PasteboardType, NSType, String), ClipboardError> {
+        self.available_types()
+            .into_iter()
+            .next()
+            .ok_or(ClipboardError::Read(
+                "Could not find known types".to_string(),
+            ))
     }
 }
 
@@ -393,14 +491,28 @@ impl Clipboard for MacosClipboard {
         })
     }
     fn write(&self, data: ClipboardData) -> Result<(), ClipboardError> {
+        if data.is_file_payload() {
+            return receive_files(self, data, None);
+        }
         autoreleasepool(|| {
-            match data {
-                ClipboardData::File(file_data) => self.write_file(file_data)?,
-                ClipboardData::String((s_type, data)) => {
-                    self.write_text(data.as_slice(), s_type)?
-                }
+            let (texts, others) = data.split_text();
+            let image = others.into_iter().find_map(|rep| match rep {
+                ClipboardData::Image(image) => Some(image),
+                _ => None,
+            });
+            if texts.is_empty() && image.is_none() {
+                return Err(ClipboardError::Write("Nothing to write".to_string()));
+            }
+            unsafe {
+                let _: () = msg_send![self.p, clearContents];
+            }
+            if !texts.is_empty() {
+                self.write_text(texts)?;
+            }
+            match image {
+                Some(image) => self.write_image(&image),
+                None => Ok(()),
             }
-            Ok(())
         })
     }
     fn read(&self) -> Result<ClipboardData, ClipboardError> {
@@ -424,17 +536,24 @@ impl Clipboard for MacosClipboard {
                 debug_println!("Pasteboard type: {:?}", type_str);
 
                 match p_type {
-                    PasteboardType::TEXT => self.read_u8_str(first_type),
                     PasteboardType::IMAGE => {
                         debug_println!("Image format detected: {}", type_str);
                         let mime_type = type_str.split(".").last().unwrap_or("png");
-                        let filename = format!("image.{}", mime_type);
                         let buff = self.read_image(first_type)?;
-                        Ok(ClipboardData::File((filename, buff)))
+                        match ImageFormat::from_extension(mime_type) {
+                            Some(format) => Image::from_bytes(format, buff)
+                                .map(ClipboardData::Image)
+                                .map_err(|err| {
+                                    ClipboardError::Read(format!("Invalid image: {:?}", err))
+                                }),
+                            // formats we can not convert still arrive as file
+                            None => Ok(ClipboardData::File((format!("image.{}", mime_type), buff))),
+                        }
                     }
                     PasteboardType::FILEPATH => self.read_file(first_type),
-                    PasteboardType::HTML => self.read_html(first_type),
-                    // PasteboardType::RTF => self.read_text_from_rtf(first_type),
+                    PasteboardType::TEXT | PasteboardType::HTML | PasteboardType::RTF => {
+                        self.read_texts()
+                    }
                 }
             }
         })
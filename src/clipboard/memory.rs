@@ -0,0 +1,79 @@
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use super::Clipboard;
+use super::ClipboardData;
+use super::ClipboardError;
+use crate::utils::attempt_get_lock;
+
+/// Clipboard that lives in process memory. Clones share the same contents,
+/// so a test can keep one handle and inspect what the app wrote into another.
+#[derive(Clone, Default)]
+pub struct MemoryClipboard {
+    contents: Arc<Mutex<Option<ClipboardData>>>,
+}
+
+impl MemoryClipboard {
+    pub fn contents(&self) -> Option<ClipboardData> {
+        attempt_get_lock(&self.contents).ok()?.clone()
+    }
+    pub fn set_contents(&self, data: Option<ClipboardData>) {
+        if let Ok(mut contents) = attempt_get_lock(&self.contents) {
+            *contents = data;
+        }
+    }
+}
+
+impl Clipboard for MemoryClipboard {
+    fn init() -> Result<Self, ClipboardError> {
+        Ok(MemoryClipboard::default())
+    }
+    fn write(&self, data: ClipboardData) -> Result<(), ClipboardError> {
+        let mut contents = attempt_get_lock(&self.contents)
+            .map_err(|_| ClipboardError::Write("Clipboard is locked".to_string()))?;
+        *contents = Some(data);
+        Ok(())
+    }
+    fn read(&self) -> Result<ClipboardData, ClipboardError> {
+        let contents = attempt_get_lock(&self.contents)
+            .map_err(|_| ClipboardError::Read("Clipboard is locked".to_string()))?;
+        contents
+            .clone()
+            .ok_or(ClipboardError::Read("Clipboard is empty".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::StringType;
+
+    #[test]
+    fn test_memory_clipboard_shares_contents() {
+        let cp = MemoryClipboard::init().unwrap();
+        let handle = cp.clone();
+        assert!(cp.read().is_err());
+
+        cp.write(ClipboardData::String((
+            StringType::Utf8Plain,
+            b"shared".to_vec(),
+        )))
+        .unwrap();
+        assert_eq!(
+            handle.contents(),
+            Some(ClipboardData::String((
+                StringType::Utf8Plain,
+                b"shared".to_vec()
+            )))
+        );
+
+        handle.set_contents(Some(ClipboardData::File((
+            "a.txt".to_string(),
+            b"a".to_vec(),
+        ))));
+        assert_eq!(
+            cp.read().unwrap(),
+            ClipboardData::File(("a.txt".to_string(), b"a".to_vec()))
+        );
+    }
+}
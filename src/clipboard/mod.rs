@@ -1,11 +1,40 @@
+#[cfg(target_os = "linux")]
+mod linux;
 #[cfg(target_os = "macos")]
 mod macos;
+#[cfg(target_os = "linux")]
+mod wayland;
 #[cfg(target_os = "windows")]
 mod windows;
+
+mod file;
+mod image;
+mod inbox;
+mod memory;
+mod sanitize;
+mod tree;
+
+use std::env;
+use std::path::PathBuf;
 use std::str::FromStr;
 
+use crate::utils::log_into_file;
 use crate::utils::Filename;
 
+pub use file::FileClipboard;
+pub use image::Image;
+pub use image::ImageError;
+pub use image::ImageFormat;
+pub use inbox::Inbox;
+pub use memory::MemoryClipboard;
+pub use sanitize::sanitize_filename;
+pub use sanitize::sanitize_tree_path;
+pub use sanitize::NameError;
+pub use sanitize::NamePolicy;
+pub use tree::EntryContent;
+pub use tree::FileTree;
+pub use tree::TreeEntry;
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum ClipboardError {
@@ -14,10 +43,11 @@ pub enum ClipboardError {
     Write(String),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum StringType {
     Html,
     Utf8Plain,
+    Rtf,
 }
 
 impl StringType {
@@ -25,6 +55,7 @@ impl StringType {
         match self {
             Self::Html => "HTML",
             Self::Utf8Plain => "UTF8P",
+            Self::Rtf => "RTF",
         }
     }
 }
@@ -35,21 +66,121 @@ impl FromStr for StringType {
         match s {
             "HTML" => Ok(StringType::Html),
             "UTF8P" => Ok(StringType::Utf8Plain),
+            "RTF" => Ok(StringType::Rtf),
             _ => Err(ClipboardError::Init(format!("Unknown string type: {}", s))),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ClipboardData {
     String((StringType, Vec<u8>)),
     File((Filename, Vec<u8>)),
+    /// File on disk, read only when it is sent
+    FileRef((Filename, PathBuf)),
+    Image(Image),
+    /// Several files or directories
+    Files(FileTree),
+    /// Same content in several representations, most preferred first.
+    /// Receiving clipboard gets all of them and target app picks the best
+    Multi(Vec<ClipboardData>),
+}
+
+impl ClipboardData {
+    /// Wraps representations into Multi unless there is only one
+    pub fn from_representations(mut reps: Vec<ClipboardData>) -> Option<ClipboardData> {
+        match reps.len() {
+            0 => None,
+            1 => reps.pop(),
+            _ => Some(ClipboardData::Multi(reps)),
+        }
+    }
+    /// Representations of payload, most preferred first
+    pub fn into_representations(self) -> Vec<ClipboardData> {
+        match self {
+            ClipboardData::Multi(reps) => reps,
+            data => vec![data],
+        }
+    }
+    /// Separates text representations, which can share one clipboard entry,
+    /// from images and files
+    pub fn split_text(self) -> (Vec<(StringType, Vec<u8>)>, Vec<ClipboardData>) {
+        let mut texts = vec![];
+        let mut others = vec![];
+        for rep in self.into_representations() {
+            match rep {
+                ClipboardData::String(text) => texts.push(text),
+                rep => others.push(rep),
+            }
+        }
+        (texts, others)
+    }
+    /// Payload carries files rather than text or image
+    pub fn is_file_payload(&self) -> bool {
+        self.clone().into_representations().iter().all(|rep| {
+            matches!(
+                rep,
+                ClipboardData::File(_) | ClipboardData::FileRef(_) | ClipboardData::Files(_)
+            )
+        })
+    }
+    /// Files on disk the payload refers to
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        match self {
+            ClipboardData::FileRef((_, path)) => vec![path.clone()],
+            ClipboardData::Files(tree) => tree.file_paths(),
+            ClipboardData::Multi(reps) => reps.iter().flat_map(|rep| rep.file_paths()).collect(),
+            _ => vec![],
+        }
+    }
 }
 
 pub trait Clipboard: Sized + Send + Sync {
     fn init() -> Result<Self, ClipboardError>;
     fn write(&self, data: ClipboardData) -> Result<(), ClipboardError>;
     fn read(&self) -> Result<ClipboardData, ClipboardError>;
+    /// Writes data received from peer. System clipboard stores files in
+    /// the inbox, so it may keep them per peer
+    fn receive(&self, data: ClipboardData, _peer: &str) -> Result<(), ClipboardError> {
+        self.write(data)
+    }
+}
+
+/// Stores received files in the inbox. With `COPYXROSS_FILES_TO_CLIPBOARD`
+/// they are put onto clipboard afterwards, as if copied in file manager
+fn receive_files(
+    cp: &PlatformClipboard,
+    data: ClipboardData,
+    peer: Option<&str>,
+) -> Result<(), ClipboardError> {
+    let inbox = Inbox::from_env()?;
+    let paths = inbox.store(data, peer)?;
+    if inbox.to_clipboard {
+        cp.write_paths(&paths)?;
+    }
+    Ok(())
+}
+
+/// Clipboard data for files selected in file manager. Single file is sent
+/// as it is, anything else goes as a tree
+fn files_from_paths(paths: Vec<PathBuf>) -> Result<ClipboardData, ClipboardError> {
+    if let [path] = paths.as_slice() {
+        if path.is_file() {
+            let filename = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            return Ok(ClipboardData::FileRef((filename, path.clone())));
+        }
+    }
+    if paths.is_empty() {
+        return Err(ClipboardError::Read(
+            "No files were found on the clipboard".to_string(),
+        ));
+    }
+    FileTree::from_paths(&paths)
+        .map(ClipboardData::Files)
+        .map_err(|err| ClipboardError::Read(format!("Could not read copied files: {:?}", err)))
 }
 
 // Conditional imports
@@ -59,7 +190,87 @@ use windows::WindowsClipboard as PlatformClipboard;
 #[cfg(target_os = "macos")]
 use macos::MacosClipboard as PlatformClipboard;
 
+#[cfg(target_os = "linux")]
+use linux::LinuxClipboard as PlatformClipboard;
+
+/// Env variable to pick clipboard implementation: `system` (default), `memory` or `file`
+const CLIPBOARD_ENV: &str = "COPYXROSS_CLIPBOARD";
+/// Env variable with directory for `file` clipboard
+const CLIPBOARD_DIR_ENV: &str = "COPYXROSS_CLIPBOARD_DIR";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ClipboardKind {
+    Platform,
+    Memory,
+    File(PathBuf),
+}
+
+impl ClipboardKind {
+    pub fn from_env() -> Self {
+        let kind = env::var(CLIPBOARD_ENV).unwrap_or_default();
+        match kind.as_str() {
+            "memory" => ClipboardKind::Memory,
+            "file" => ClipboardKind::File(
+                env::var_os(CLIPBOARD_DIR_ENV)
+                    .map(PathBuf::from)
+                    .unwrap_or(FileClipboard::default_dir()),
+            ),
+            "" | "system" => ClipboardKind::Platform,
+            _ => {
+                let _ = log_into_file(
+                    format!("Unknown clipboard kind {:?}, using system clipboard", kind).as_str(),
+                );
+                ClipboardKind::Platform
+            }
+        }
+    }
+}
+
+/// Clipboard picked at runtime
+pub enum AnyClipboard {
+    Platform(PlatformClipboard),
+    Memory(MemoryClipboard),
+    File(FileClipboard),
+}
+
+impl Clipboard for AnyClipboard {
+    fn init() -> Result<Self, ClipboardError> {
+        new_clipboard_of(ClipboardKind::from_env())
+    }
+    fn write(&self, data: ClipboardData) -> Result<(), ClipboardError> {
+        match self {
+            AnyClipboard::Platform(cp) => cp.write(data),
+            AnyClipboard::Memory(cp) => cp.write(data),
+            AnyClipboard::File(cp) => cp.write(data),
+        }
+    }
+    fn read(&self) -> Result<ClipboardData, ClipboardError> {
+        match self {
+            AnyClipboard::Platform(cp) => cp.read(),
+            AnyClipboard::Memory(cp) => cp.read(),
+            AnyClipboard::File(cp) => cp.read(),
+        }
+    }
+    fn receive(&self, data: ClipboardData, peer: &str) -> Result<(), ClipboardError> {
+        match self {
+            AnyClipboard::Platform(cp) if data.is_file_payload() => {
+                receive_files(cp, data, Some(peer))
+            }
+            cp => cp.write(data),
+        }
+    }
+}
+
+pub fn new_clipboard_of(kind: ClipboardKind) -> Result<AnyClipboard, ClipboardError> {
+    let _ = log_into_file(format!("Using clipboard: {:?}", kind).as_str());
+    match kind {
+        ClipboardKind::Platform => Ok(AnyClipboard::Platform(PlatformClipboard::init()?)),
+        ClipboardKind::Memory => Ok(AnyClipboard::Memory(MemoryClipboard::init()?)),
+        ClipboardKind::File(dir) => Ok(AnyClipboard::File(FileClipboard::new(dir)?)),
+    }
+}
+
 // Factory function to always return the correct implementation
 pub fn new_clipboard() -> Result<impl Clipboard, ClipboardError> {
-    PlatformClipboard::init()
+    AnyClipboard::init()
 }
@@ -0,0 +1,160 @@
+//! Names of files received from peers
+//!
+//! Peer decides what the file is called, so before the name touches the
+//! file system it is reduced to a single safe path component.
+
+use std::env;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// Set to `1` to refuse files with names starting with a dot (`.bashrc`)
+const REJECT_DOTFILES_ENV: &str = "COPYXROSS_REJECT_DOTFILES";
+/// Longest name most file systems accept, in bytes
+const MAX_NAME_LEN: usize = 255;
+/// Longer "extensions" are treated as part of the name when cutting it
+const MAX_EXT_LEN: usize = 16;
+/// Device names Windows reserves in any directory, with any extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum NameError {
+    Empty,
+    Reserved(String),
+    Dotfile(String),
+    /// Path of tree entry leads outside of the tree
+    Traversal(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct NamePolicy {
+    pub reject_dotfiles: bool,
+}
+
+impl NamePolicy {
+    pub fn from_env() -> Self {
+        NamePolicy {
+            reject_dotfiles: matches!(
+                env::var(REJECT_DOTFILES_ENV).as_deref(),
+                Ok("1" | "true" | "yes")
+            ),
+        }
+    }
+}
+
+/// Makes received file name safe to create. Directories are stripped,
+/// Unicode is normalized to NFC, control characters and characters Windows
+/// forbids are replaced, trailing dots and spaces are removed and length is
+/// limited. Reserved device names are rejected, so are dotfiles if policy says so
+pub fn sanitize_filename(name: &str, policy: NamePolicy) -> Result<String, NameError> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .nfc()
+        .map(|c| match c {
+            c if c.is_control() || "<>:\"|?*".contains(c) => '_',
+            c => c,
+        })
+        .collect();
+    let name = name.trim_start_matches(' ').trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        return Err(NameError::Reserved(name.to_string()));
+    }
+    if policy.reject_dotfiles && name.starts_with('.') {
+        return Err(NameError::Dotfile(name.to_string()));
+    }
+    Ok(truncate_name(name))
+}
+
+/// Sanitizes every component of `/` separated path of tree entry. Unlike
+/// single names, components that lead elsewhere are rejected rather than
+/// stripped, since they would change the structure of the tree
+pub fn sanitize_tree_path(path: &str, policy: NamePolicy) -> Result<String, NameError> {
+    let mut parts = vec![];
+    for part in path.split('/') {
+        if part.is_empty() || part == "." || part == ".." || part.contains('\\') {
+            return Err(NameError::Traversal(path.to_string()));
+        }
+        parts.push(sanitize_filename(part, policy)?);
+    }
+    Ok(parts.join("/"))
+}
+
+/// Cuts name to `MAX_NAME_LEN` bytes on character boundary, keeping extension
+fn truncate_name(name: &str) -> String {
+    if name.len() <= MAX_NAME_LEN {
+        return name.to_string();
+    }
+    let (stem, ext) = match name.rfind('.') {
+        Some(i) if i > 0 && name.len() - i <= MAX_EXT_LEN => name.split_at(i),
+        _ => (name, ""),
+    };
+    let mut end = MAX_NAME_LEN - ext.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hostile_names() {
+        let policy = NamePolicy::default();
+        let strict = NamePolicy {
+            reject_dotfiles: true,
+        };
+        let clean = |name: &str| sanitize_filename(name, policy);
+
+        assert_eq!(clean("report.pdf"), Ok("report.pdf".to_string()));
+        assert_eq!(clean("../../.bashrc"), Ok(".bashrc".to_string()));
+        assert_eq!(clean("/etc/passwd"), Ok("passwd".to_string()));
+        assert_eq!(clean("..\\..\\Windows\\win.ini"), Ok("win.ini".to_string()));
+        assert_eq!(clean("C:evil.exe"), Ok("C_evil.exe".to_string()));
+        assert_eq!(clean("a\nb\0c.txt"), Ok("a_b_c.txt".to_string()));
+        assert_eq!(clean("notes.txt. . "), Ok("notes.txt".to_string()));
+        // decomposed "é" becomes the composed one
+        assert_eq!(
+            clean("caf\u{65}\u{301}.txt"),
+            Ok("caf\u{e9}.txt".to_string())
+        );
+
+        for name in ["", "..", "/", "dir/", " . "] {
+            assert_eq!(clean(name), Err(NameError::Empty), "{:?}", name);
+        }
+        for name in ["CON", "nul.txt", "Com1.tar.gz", "LPT9 .log"] {
+            assert!(
+                matches!(clean(name), Err(NameError::Reserved(_))),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(clean("CONSOLE.txt"), Ok("CONSOLE.txt".to_string()));
+        assert!(matches!(
+            sanitize_filename("../.ssh", strict),
+            Err(NameError::Dotfile(_))
+        ));
+
+        let long = format!("{}.txt", "ф".repeat(200));
+        let cut = clean(&long).unwrap();
+        assert!(cut.len() <= MAX_NAME_LEN && cut.ends_with("ф.txt"));
+
+        assert_eq!(
+            sanitize_tree_path("photos/caf\u{65}\u{301}/a?.jpg", policy),
+            Ok("photos/caf\u{e9}/a_.jpg".to_string())
+        );
+        for path in ["../x", "a/../../x", "/abs", "a//b", "a\\..\\b", "docs/aux"] {
+            assert!(sanitize_tree_path(path, policy).is_err(), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,260 @@
+//! Several copied files and directories
+//!
+//! Entries are listed parents first with paths relative to the copied
+//! selection, so the tree can be sent entry by entry and recreated on the
+//! receiving side.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::utils::move_file;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum EntryContent {
+    Dir,
+    Data(Vec<u8>),
+    /// File on disk, read only when it is sent
+    Path(PathBuf),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TreeEntry {
+    /// Relative to the selection, components are separated by `/`
+    pub path: String,
+    /// Unix permission bits, 0 if unknown
+    pub mode: u32,
+    /// File size, 0 for directories
+    pub size: u64,
+    pub content: EntryContent,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct FileTree {
+    pub entries: Vec<TreeEntry>,
+}
+
+impl FileTree {
+    /// Lists selected files and directories with everything inside them.
+    /// Symlinks and special files are skipped
+    pub fn from_paths(paths: &[PathBuf]) -> io::Result<Self> {
+        let mut tree = FileTree::default();
+        for path in paths {
+            let name = path.file_name().ok_or(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("No file name: {:?}", path),
+            ))?;
+            tree.add(path, name.to_string_lossy().into_owned())?;
+        }
+        Ok(tree)
+    }
+
+    fn add(&mut self, path: &Path, rel: String) -> io::Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        if meta.is_dir() {
+            self.entries.push(TreeEntry {
+                path: rel.clone(),
+                mode: file_mode(&meta),
+                size: 0,
+                content: EntryContent::Dir,
+            });
+            let mut children = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+            children.sort_by_key(|child| child.file_name());
+            for child in children {
+                let child_rel = format!("{}/{}", rel, child.file_name().to_string_lossy());
+                self.add(&child.path(), child_rel)?;
+            }
+        } else if meta.is_file() {
+            self.entries.push(TreeEntry {
+                path: rel,
+                mode: file_mode(&meta),
+                size: meta.len(),
+                content: EntryContent::Path(path.to_path_buf()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Summed size of all files
+    pub fn size(&self) -> u64 {
+        self.entries.iter().map(|entry| entry.size).sum()
+    }
+
+    /// Files of the tree that live on disk
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match &entry.content {
+                EntryContent::Path(path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Recreates tree inside directory, files on disk are moved there.
+    /// Every file and directory is created anew, so nothing that is already
+    /// there is replaced or followed if it is a symlink. Returns paths of top
+    /// level entries
+    pub fn write_to(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut top = vec![];
+        let mut written = vec![];
+        let mut created: HashSet<PathBuf> = HashSet::new();
+        fs::create_dir_all(dir)?;
+        for entry in &self.entries {
+            let rel = relative_path(&entry.path).ok_or(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsafe path in file tree: {:?}", entry.path),
+            ))?;
+            let dest = dir.join(&rel);
+            let mut parent = PathBuf::new();
+            for part in rel.parent().into_iter().flat_map(|parent| parent.iter()) {
+                parent.push(part);
+                if created.insert(parent.clone()) {
+                    fs::create_dir(dir.join(&parent))?;
+                }
+            }
+            match &entry.content {
+                EntryContent::Dir => {
+                    if created.insert(rel.clone()) {
+                        fs::create_dir(&dest)?;
+                    }
+                }
+                EntryContent::Data(data) => create_new(&dest)?.write_all(data)?,
+                EntryContent::Path(src) => {
+                    // replaces only the empty file just created
+                    drop(create_new(&dest)?);
+                    move_file(src, &dest)?;
+                }
+            }
+            if rel.components().count() == 1 {
+                top.push(dest.clone());
+            }
+            written.push((dest, entry.mode));
+        }
+        // children first, so read-only directories do not block their contents
+        for (path, mode) in written.into_iter().rev() {
+            set_mode(&path, mode);
+        }
+        Ok(top)
+    }
+}
+
+fn create_new(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
+
+/// Turns `/` separated path into local one. Returns None if the path could
+/// point outside of directory it is recreated in
+fn relative_path(path: &str) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for part in path.split('/') {
+        if part.is_empty() || part == "." || part == ".." || part.contains(['\\', ':', '\0']) {
+            return None;
+        }
+        out.push(part);
+    }
+    Some(out)
+}
+
+#[cfg(unix)]
+fn file_mode(meta: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn file_mode(_meta: &fs::Metadata) -> u32 {
+    0
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    if mode != 0 {
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777));
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tree_from_paths_and_back() {
+        let dir = std::env::temp_dir().join(format!("copyxross-tree-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("photos/empty")).unwrap();
+        fs::write(src.join("photos/b.jpg"), b"jpeg").unwrap();
+        fs::write(src.join("notes.txt"), b"hello").unwrap();
+
+        let tree = FileTree::from_paths(&[src.join("photos"), src.join("notes.txt")]).unwrap();
+        let paths: Vec<&str> = tree.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["photos", "photos/b.jpg", "photos/empty", "notes.txt"]
+        );
+        assert_eq!(tree.size(), 9);
+
+        let dest = dir.join("dest");
+        let top = tree.write_to(&dest).unwrap();
+        assert_eq!(top, [dest.join("photos"), dest.join("notes.txt")]);
+        assert_eq!(fs::read(dest.join("photos/b.jpg")).unwrap(), b"jpeg");
+        assert!(dest.join("photos/empty").is_dir());
+        assert!(!src.join("notes.txt").exists());
+
+        for path in ["../evil", "/etc/passwd", "a//b", "a/./b", "C:\\evil"] {
+            let evil = FileTree {
+                entries: vec![TreeEntry {
+                    path: path.to_string(),
+                    mode: 0,
+                    size: 0,
+                    content: EntryContent::Data(vec![]),
+                }],
+            };
+            assert!(evil.write_to(&dest).is_err(), "{}", path);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_to_replaces_nothing() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("copyxross-tree-keep-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let outside = dir.join("outside");
+        let dest = dir.join("dest");
+        fs::create_dir_all(&outside).unwrap();
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join(".bashrc"), b"mine").unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join(".ssh")).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode();
+        let bashrc_mode = mode(&dest.join(".bashrc"));
+
+        let tree = |path: &str| FileTree {
+            entries: vec![TreeEntry {
+                path: path.to_string(),
+                mode: 0o777,
+                size: 5,
+                content: EntryContent::Data(b"evil\n".to_vec()),
+            }],
+        };
+        assert!(tree(".bashrc").write_to(&dest).is_err());
+        assert!(tree(".ssh/config").write_to(&dest).is_err());
+        assert_eq!(fs::read(dest.join(".bashrc")).unwrap(), b"mine");
+        assert_eq!(mode(&dest.join(".bashrc")), bashrc_mode);
+        assert!(!outside.join("config").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,454 @@
+//! Wayland clipboard through data-control protocols.
+//!
+//! Unlike wl_data_device, data-control lets us read and set the selection
+//! without owning a focused surface. Compositors expose it either as
+//! `ext_data_control_manager_v1` (newer) or `zwlr_data_control_manager_v1`.
+//! Both have identical requests and events, so the implementation is shared
+//! through `data_control_impl!` and instantiated for each of them.
+
+use std::io;
+use std::io::Read;
+use std::os::fd::AsFd;
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+use std::time::Instant;
+
+use wayland_client::globals::registry_queue_init;
+use wayland_client::globals::GlobalList;
+use wayland_client::globals::GlobalListContents;
+use wayland_client::protocol::wl_registry;
+use wayland_client::protocol::wl_seat;
+use wayland_client::Connection;
+use wayland_client::Dispatch;
+use wayland_client::Proxy;
+use wayland_client::QueueHandle;
+
+use super::linux::choose_targets;
+use super::linux::into_clipboard_data;
+use super::linux::selection_contents;
+use super::linux::SelectionContents;
+use super::linux::CONVERT_TIMEOUT;
+use super::linux::TARGET_PRIORITY;
+use super::Clipboard;
+use super::ClipboardData;
+use super::ClipboardError;
+use crate::debug_println;
+use crate::utils::log_into_file;
+
+struct RegistryState;
+
+/// Reads offer pipe until the owner closes it. Owner that sends nothing for
+/// `timeout` is given up on, so a stuck client does not block us
+fn read_pipe(mut reader: impl Read + AsFd, timeout: Duration) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    let mut buf = [0; 64 * 1024];
+    let mut deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for clipboard owner",
+            ));
+        }
+        let mut pollfd = libc::pollfd {
+            fd: reader.as_fd().as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = remaining.as_millis().clamp(1, i32::MAX as u128) as i32;
+        // SAFETY: pollfd points to one valid entry for the duration of the call
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err);
+                }
+            }
+            0 => {}
+            _ => match reader.read(&mut buf)? {
+                0 => return Ok(data),
+                len => {
+                    data.extend_from_slice(&buf[..len]);
+                    deadline = Instant::now() + timeout;
+                }
+            },
+        }
+    }
+}
+
+impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for RegistryState {
+    fn event(
+        _: &mut Self,
+        _: &wl_registry::WlRegistry,
+        _: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+macro_rules! data_control_impl {
+    (
+        $mod_name:ident,
+        $($protocol:ident)::+,
+        $manager_mod:ident::$manager:ident,
+        $device_mod:ident::$device:ident,
+        $source_mod:ident::$source:ident,
+        $offer_mod:ident::$offer:ident
+    ) => {
+        mod $mod_name {
+            use std::collections::HashMap;
+            use std::fs::File;
+            use std::io::Write;
+            use std::os::fd::AsFd;
+            use std::thread;
+
+            use wayland_client::backend::ObjectId;
+            use wayland_client::event_created_child;
+            use wayland_client::globals::GlobalList;
+            use wayland_client::globals::GlobalListContents;
+            use wayland_client::protocol::wl_registry;
+            use wayland_client::protocol::wl_seat;
+            use wayland_client::Connection;
+            use wayland_client::Dispatch;
+            use wayland_client::EventQueue;
+            use wayland_client::Proxy;
+            use wayland_client::QueueHandle;
+
+            use $($protocol)::+::$device_mod::{self, $device};
+            use $($protocol)::+::$manager_mod::$manager;
+            use $($protocol)::+::$offer_mod::{self, $offer};
+            use $($protocol)::+::$source_mod::{self, $source};
+
+            use super::ClipboardError;
+            use crate::debug_println;
+            use crate::utils::log_into_file;
+
+            #[derive(Default)]
+            struct State {
+                // offer id -> mime types it was advertised with
+                offers: HashMap<ObjectId, Vec<String>>,
+                selection: Option<$offer>,
+                // contents served when we own the selection
+                contents: HashMap<String, Vec<u8>>,
+                cancelled: bool,
+            }
+
+            impl Dispatch<wl_registry::WlRegistry, GlobalListContents> for State {
+                fn event(
+                    _: &mut Self,
+                    _: &wl_registry::WlRegistry,
+                    _: wl_registry::Event,
+                    _: &GlobalListContents,
+                    _: &Connection,
+                    _: &QueueHandle<Self>,
+                ) {
+                }
+            }
+
+            wayland_client::delegate_noop!(State: ignore wl_seat::WlSeat);
+            wayland_client::delegate_noop!(State: $manager);
+
+            impl Dispatch<$device, ()> for State {
+                fn event(
+                    state: &mut Self,
+                    _: &$device,
+                    event: $device_mod::Event,
+                    _: &(),
+                    _: &Connection,
+                    _: &QueueHandle<Self>,
+                ) {
+                    match event {
+                        $device_mod::Event::DataOffer { id } => {
+                            state.offers.insert(id.id(), vec![]);
+                        }
+                        $device_mod::Event::Selection { id } => {
+                            state.selection = id;
+                        }
+                        $device_mod::Event::Finished => {
+                            state.cancelled = true;
+                        }
+                        _ => {}
+                    }
+                }
+
+                event_created_child!(State, $device, [
+                    $device_mod::EVT_DATA_OFFER_OPCODE => ($offer, ()),
+                ]);
+            }
+
+            impl Dispatch<$offer, ()> for State {
+                fn event(
+                    state: &mut Self,
+                    offer: &$offer,
+                    event: $offer_mod::Event,
+                    _: &(),
+                    _: &Connection,
+                    _: &QueueHandle<Self>,
+                ) {
+                    if let $offer_mod::Event::Offer { mime_type } = event {
+                        state.offers.entry(offer.id()).or_default().push(mime_type);
+                    }
+                }
+            }
+
+            impl Dispatch<$source, ()> for State {
+                fn event(
+                    state: &mut Self,
+                    _: &$source,
+                    event: $source_mod::Event,
+                    _: &(),
+                    _: &Connection,
+                    _: &QueueHandle<Self>,
+                ) {
+                    match event {
+                        $source_mod::Event::Send { mime_type, fd } => {
+                            let data = state.contents.get(&mime_type);
+                            if let Some(data) = data {
+                                let mut file = File::from(fd);
+                                if let Err(err) = file.write_all(data) {
+                                    let _ = log_into_file(
+                                        format!("Failed to send clipboard data: {:?}", err)
+                                            .as_str(),
+                                    );
+                                }
+                            }
+                        }
+                        $source_mod::Event::Cancelled => {
+                            debug_println!("Lost clipboard ownership");
+                            state.cancelled = true;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            pub fn is_supported(globals: &GlobalList) -> bool {
+                globals.contents().with_list(|list| {
+                    list.iter()
+                        .any(|g| g.interface == <$manager as Proxy>::interface().name)
+                })
+            }
+
+            fn bind(
+                conn: &Connection,
+            ) -> Result<(EventQueue<State>, $manager, $device), ClipboardError> {
+                let (globals, queue) = wayland_client::globals::registry_queue_init::<State>(
+                    conn,
+                )
+                .map_err(|err| {
+                    ClipboardError::Init(format!("Could not read Wayland globals: {:?}", err))
+                })?;
+                let qh = queue.handle();
+                let seat: wl_seat::WlSeat = globals.bind(&qh, 1..=1, ()).map_err(|err| {
+                    ClipboardError::Init(format!("Could not find Wayland seat: {:?}", err))
+                })?;
+                let manager: $manager = globals.bind(&qh, 1..=1, ()).map_err(|err| {
+                    ClipboardError::Init(format!("Data control is not supported: {:?}", err))
+                })?;
+                let device = manager.get_data_device(&seat, &qh, ());
+                Ok((queue, manager, device))
+            }
+
+            /// Receives current selection in the mime type picked by `choose`
+            pub fn read_selection(
+                conn: &Connection,
+                choose: impl Fn(&[String]) -> Vec<String>,
+            ) -> Result<Vec<(String, Vec<u8>)>, ClipboardError> {
+                let (mut queue, _manager, _device) = bind(conn)?;
+                let mut state = State::default();
+                // compositor sends current selection right after device is created
+                queue.roundtrip(&mut state).map_err(|err| {
+                    ClipboardError::Read(format!("Wayland connection error: {:?}", err))
+                })?;
+
+                let offer = state.selection.take().ok_or(ClipboardError::Read(
+                    "Clipboard is empty".to_string(),
+                ))?;
+                let mime_types = state.offers.remove(&offer.id()).unwrap_or_default();
+
+                let mut contents = vec![];
+                for mime_type in choose(&mime_types) {
+                    let (reader, writer) = std::io::pipe().map_err(|err| {
+                        ClipboardError::Read(format!("Could not create pipe: {:?}", err))
+                    })?;
+                    offer.receive(mime_type.clone(), writer.as_fd());
+                    drop(writer);
+                    conn.flush().map_err(|err| {
+                        ClipboardError::Read(format!("Wayland connection error: {:?}", err))
+                    })?;
+
+                    let data = super::read_pipe(reader, super::CONVERT_TIMEOUT).map_err(|err| {
+                        ClipboardError::Read(format!("Failed to read clipboard data: {:?}", err))
+                    })?;
+                    contents.push((mime_type, data));
+                }
+                offer.destroy();
+                Ok(contents)
+            }
+
+            /// Sets the selection and spawns a thread serving it until another
+            /// client replaces it
+            pub fn set_selection(
+                conn: Connection,
+                contents: Vec<(&str, Vec<u8>)>,
+            ) -> Result<(), ClipboardError> {
+                let (mut queue, manager, device) = bind(&conn)?;
+                let qh = queue.handle();
+                let mut state = State::default();
+                let source = manager.create_data_source(&qh, ());
+                for (mime_type, data) in contents {
+                    source.offer(mime_type.to_string());
+                    state.contents.insert(mime_type.to_string(), data);
+                }
+                device.set_selection(Some(&source));
+                conn.flush().map_err(|err| {
+                    ClipboardError::Write(format!("Wayland connection error: {:?}", err))
+                })?;
+
+                thread::spawn(move || {
+                    while !state.cancelled {
+                        if let Err(err) = queue.blocking_dispatch(&mut state) {
+                            let _ = log_into_file(
+                                format!("Wayland clipboard owner error: {:?}", err).as_str(),
+                            );
+                            break;
+                        }
+                    }
+                    source.destroy();
+                });
+                Ok(())
+            }
+        }
+    };
+}
+
+data_control_impl!(
+    ext,
+    wayland_protocols::ext::data_control::v1::client,
+    ext_data_control_manager_v1::ExtDataControlManagerV1,
+    ext_data_control_device_v1::ExtDataControlDeviceV1,
+    ext_data_control_source_v1::ExtDataControlSourceV1,
+    ext_data_control_offer_v1::ExtDataControlOfferV1
+);
+
+data_control_impl!(
+    wlr,
+    wayland_protocols_wlr::data_control::v1::client,
+    zwlr_data_control_manager_v1::ZwlrDataControlManagerV1,
+    zwlr_data_control_device_v1::ZwlrDataControlDeviceV1,
+    zwlr_data_control_source_v1::ZwlrDataControlSourceV1,
+    zwlr_data_control_offer_v1::ZwlrDataControlOfferV1
+);
+
+#[derive(Clone, Copy, Debug)]
+enum DataControl {
+    Ext,
+    Wlr,
+}
+
+/// Every operation opens its own connection, so objects bound for it are
+/// released by the compositor once we are done.
+pub struct WaylandClipboard {
+    protocol: DataControl,
+}
+
+fn connect() -> Result<Connection, ClipboardError> {
+    Connection::connect_to_env().map_err(|err| {
+        ClipboardError::Init(format!(
+            "Could not connect to Wayland compositor: {:?}",
+            err
+        ))
+    })
+}
+
+fn choose_mime_types(mime_types: &[String]) -> Vec<String> {
+    let offered: Vec<&str> = mime_types.iter().map(|m| m.as_str()).collect();
+    choose_targets(&offered)
+        .into_iter()
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+impl WaylandClipboard {
+    pub(super) fn set_contents(&self, contents: SelectionContents) -> Result<(), ClipboardError> {
+        match self.protocol {
+            DataControl::Ext => ext::set_selection(connect()?, contents),
+            DataControl::Wlr => wlr::set_selection(connect()?, contents),
+        }
+    }
+}
+
+impl Clipboard for WaylandClipboard {
+    fn init() -> Result<Self, ClipboardError> {
+        let conn = connect()?;
+        let (globals, _queue): (GlobalList, _) = registry_queue_init::<RegistryState>(&conn)
+            .map_err(|err| {
+                ClipboardError::Init(format!("Could not read Wayland globals: {:?}", err))
+            })?;
+
+        let protocol = if ext::is_supported(&globals) {
+            DataControl::Ext
+        } else if wlr::is_supported(&globals) {
+            DataControl::Wlr
+        } else {
+            return Err(ClipboardError::Init(
+                "Compositor does not support data control protocol".to_string(),
+            ));
+        };
+        if !globals.contents().with_list(|list| {
+            list.iter()
+                .any(|g| g.interface == wl_seat::WlSeat::interface().name)
+        }) {
+            return Err(ClipboardError::Init(
+                "Could not find Wayland seat".to_string(),
+            ));
+        }
+        let _ = log_into_file(format!("Using Wayland {:?} data control", protocol).as_str());
+        Ok(WaylandClipboard { protocol })
+    }
+    fn write(&self, data: ClipboardData) -> Result<(), ClipboardError> {
+        self.set_contents(selection_contents(data)?)
+    }
+    fn read(&self) -> Result<ClipboardData, ClipboardError> {
+        let contents = match self.protocol {
+            DataControl::Ext => ext::read_selection(&connect()?, choose_mime_types)?,
+            DataControl::Wlr => wlr::read_selection(&connect()?, choose_mime_types)?,
+        };
+        let mut reps = vec![];
+        for (mime_type, data) in contents {
+            debug_println!("Wayland clipboard type: {}", mime_type);
+            if let Some((_, s_target)) = TARGET_PRIORITY.iter().find(|(name, _)| *name == mime_type)
+            {
+                reps.push(into_clipboard_data(*s_target, data)?);
+            }
+        }
+        ClipboardData::from_representations(reps).ok_or(ClipboardError::Read(
+            "Could not find known types".to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_read_pipe() {
+        let (reader, mut writer) = io::pipe().unwrap();
+        writer.write_all(b"clipboard").unwrap();
+        drop(writer);
+        assert_eq!(
+            read_pipe(reader, Duration::from_secs(1)).unwrap(),
+            b"clipboard"
+        );
+
+        // owner that keeps the pipe open without writing is given up on
+        let (reader, _writer) = io::pipe().unwrap();
+        let err = read_pipe(reader, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -1,16 +1,18 @@
 use std::ffi::{CString, OsString};
+use std::os::windows::ffi::OsStrExt;
 use std::os::windows::ffi::OsStringExt;
+use std::path::PathBuf;
 use std::ptr::copy_nonoverlapping;
 
 use crate::clipboard::StringType;
 use crate::debug_println;
-use crate::utils::create_file;
 use crate::utils::extract_plain_str_from_html;
-use crate::utils::open_file;
 use crate::utils::windows::WindowsError;
 
-use super::{Clipboard, ClipboardData, ClipboardError};
-use dirs_next::desktop_dir;
+use super::{
+    files_from_paths, receive_files, Clipboard, ClipboardData, ClipboardError, Image, ImageFormat,
+    Inbox,
+};
 use winapi::shared::minwindef::UINT;
 use winapi::shared::ntdef::FALSE;
 use winapi::shared::ntdef::NULL;
@@ -20,6 +22,7 @@ use winapi::um::shellapi::HDROP;
 use winapi::um::winbase::GlobalAlloc;
 use winapi::um::winbase::GlobalFree;
 use winapi::um::winbase::GlobalLock;
+use winapi::um::winbase::GlobalSize;
 use winapi::um::winbase::GlobalUnlock;
 
 use winapi::um::winbase::GMEM_MOVEABLE;
@@ -57,6 +60,24 @@ impl ClipboardType {
     }
 }
 
+const HTML_FORMAT: &str = "HTML Format";
+const RTF_FORMAT: &str = "Rich Text Format";
+const PNG_FORMAT: &str = "PNG";
+
+/// Cuts html out of CF_HTML, which is prefixed with header of offsets
+fn html_from_cf_html(data: &[u8]) -> Vec<u8> {
+    let header = String::from_utf8_lossy(&data[..data.len().min(512)]);
+    let offset = |key: &str| {
+        header
+            .lines()
+            .find_map(|line| line.strip_prefix(key)?.trim().parse::<usize>().ok())
+    };
+    match (offset("StartHTML:"), offset("EndHTML:")) {
+        (Some(start), Some(end)) if start <= end && end <= data.len() => data[start..end].to_vec(),
+        _ => data.to_vec(),
+    }
+}
+
 pub struct WindowsClipboard;
 
 impl WindowsClipboard {
@@ -225,119 +246,186 @@ impl WindowsClipboard {
         }
     }
 
+    /// Reads all dropped paths. Several files or directories are read as a tree
     fn read_file() -> Result<ClipboardData, ClipboardError> {
         unsafe {
             let handle = WindowsClipboard::get_clipboard_data_handle(CF_HDROP)?;
-            let mut file_path = [0_u16; 1024];
-            // Get the number of files
-
-            let file_exists = DragQueryFileW(
-                handle as HDROP,
-                0,
-                file_path.as_mut_ptr(),
-                file_path.len() as u32,
-            );
-            if file_exists == 0 {
-                return Err(ClipboardError::Read(
-                    "No files were found on the clipboard".to_string(),
-                ));
+            // index 0xFFFFFFFF gives the number of files
+            let count = DragQueryFileW(handle as HDROP, 0xFFFFFFFF, std::ptr::null_mut(), 0);
+            let mut paths = vec![];
+            for i in 0..count {
+                let mut file_path = [0_u16; 1024];
+                let len = DragQueryFileW(
+                    handle as HDROP,
+                    i,
+                    file_path.as_mut_ptr(),
+                    file_path.len() as u32,
+                );
+                if len == 0 {
+                    continue;
+                }
+                // Convert UTF-16 buffer to Rust String
+                let file_path = OsString::from_wide(&file_path[..len as usize]);
+                let path = PathBuf::from(file_path);
+                if !path.exists() {
+                    return Err(ClipboardError::Read(format!(
+                        "Could not get file from clipboard: {:?}",
+                        path
+                    )));
+                }
+                paths.push(path);
             }
-
-            // Convert UTF-16 buffer to Rust String
-            let file_path = OsString::from_wide(&file_path)
-                .to_string_lossy()
-                .into_owned();
-            let file_path = file_path.trim_end_matches("\0");
-
-            let file = open_file(&file_path).map_err(|err| {
-                ClipboardError::Read(format!("Could not get file from clipboard: {:?}", err))
-            })?;
-            let file_name = file_path.split("\\").last().unwrap_or("unknown.txt");
-            Ok(ClipboardData::File((file_name.to_string(), file)))
+            files_from_paths(paths)
         }
-        // Cast handle to HDROP
     }
-    fn write_text(text: &[u8], s_type: StringType) -> Result<(), ClipboardError> {
-        unsafe {
-            if EmptyClipboard() == FALSE.into() {
-                return Err(ClipboardError::Write(format!(
-                    "Failed to clear clipboard: {:?}",
-                    WindowsError::from_last_error()
-                )));
-            }
+    /// Writes all text representations at once. Without plain text html is
+    /// accompanied by its plain version, so apps without html support can paste it too
+    fn write_text(texts: Vec<(StringType, Vec<u8>)>) -> Result<(), ClipboardError> {
+        let mut plain_text: Option<String> = None;
+        let mut html_text: Option<String> = None;
+        for (s_type, text) in texts {
+            let text = String::from_utf8(text).map_err(|err| {
+                ClipboardError::Write(format!("Failed to decode string for writing: {:?}", err))
+            })?;
             match s_type {
                 StringType::Utf8Plain => {
-                    let utf16 = WindowsClipboard::convert_to_utf16(&text)?;
-                    let size_in_bytes = utf16.len() * size_of::<u16>();
-
-                    WindowsClipboard::write_str_into_cp(
-                        utf16.as_ptr(),
-                        CF_UNICODETEXT,
-                        size_in_bytes,
-                        utf16.len(),
-                    )
+                    plain_text.get_or_insert(text);
                 }
                 StringType::Html => {
                     // Format HTML according to the Windows Clipboard HTML format specification
-                    let html_content = String::from_utf8(text.to_vec()).map_err(|err| {
-                        ClipboardError::Write(format!(
-                            "Failed to decode string for writing: {:?}",
-                            err
-                        ))
-                    })?;
-
-                    let html_clipboard_format =
-                        WindowsClipboard::prepare_html_string(&html_content);
-
-                    // Convert to UTF-8 bytes
+                    let html_clipboard_format = WindowsClipboard::prepare_html_string(&text);
                     let utf8_bytes = html_clipboard_format.as_bytes();
-
                     let size = utf8_bytes.len();
-                    let format_name = CString::new("HTML Format").map_err(|err| {
-                        ClipboardError::Init(format!(
-                            "Failed to create format name CString: {:?}",
-                            err
-                        ))
-                    })?;
-
-                    let html_format = RegisterClipboardFormatA(format_name.as_ptr());
-                    if html_format == 0 {
-                        return Err(ClipboardError::Write(format!(
-                            "Failed to register clipboard format: {:?}",
-                            WindowsError::from_last_error()
-                        )));
-                    }
-
                     WindowsClipboard::write_str_into_cp(
                         utf8_bytes.as_ptr(),
-                        html_format,
+                        WindowsClipboard::registered_format(HTML_FORMAT)?,
                         size,
                         size,
                     )?;
-
-                    let text = extract_plain_str_from_html(
-                        String::from_utf8(text.to_vec())
-                            .map_err(|err| {
-                                ClipboardError::Write(format!(
-                                    "Cannot create string from utf8: {:?}",
-                                    err
-                                ))
-                            })?
-                            .as_str(),
-                    );
-                    let utf16 = WindowsClipboard::convert_to_utf16(&text.as_bytes())?;
-
-                    let size_in_bytes = utf16.len() * size_of::<u16>();
-
+                    html_text.get_or_insert_with(|| extract_plain_str_from_html(&text));
+                }
+                StringType::Rtf => {
+                    let mut bytes = text.into_bytes();
+                    bytes.push(0);
                     WindowsClipboard::write_str_into_cp(
-                        utf16.as_ptr(),
-                        CF_UNICODETEXT,
-                        size_in_bytes,
-                        utf16.len(),
-                    )
+                        bytes.as_ptr(),
+                        WindowsClipboard::registered_format(RTF_FORMAT)?,
+                        bytes.len(),
+                        bytes.len(),
+                    )?;
                 }
             }
         }
+        if let Some(text) = plain_text.or(html_text) {
+            let utf16 = WindowsClipboard::convert_to_utf16(text.as_bytes())?;
+            let size_in_bytes = utf16.len() * size_of::<u16>();
+
+            WindowsClipboard::write_str_into_cp(
+                utf16.as_ptr(),
+                CF_UNICODETEXT,
+                size_in_bytes,
+                utf16.len(),
+            )?;
+        }
+        Ok(())
+    }
+    /// Clears clipboard, so formats set next replace the previous contents
+    fn empty() -> Result<(), ClipboardError> {
+        if unsafe { EmptyClipboard() } == FALSE.into() {
+            return Err(ClipboardError::Write(format!(
+                "Failed to clear clipboard: {:?}",
+                WindowsError::from_last_error()
+            )));
+        }
+        Ok(())
+    }
+    fn registered_format(name: &str) -> Result<UINT, ClipboardError> {
+        let format_name = CString::new(name).map_err(|err| {
+            ClipboardError::Init(format!("Failed to create format name CString: {:?}", err))
+        })?;
+        let format = unsafe { RegisterClipboardFormatA(format_name.as_ptr()) };
+        if format == 0 {
+            return Err(ClipboardError::Write(format!(
+                "Failed to register clipboard format: {:?}",
+                WindowsError::from_last_error()
+            )));
+        }
+        Ok(format)
+    }
+    /// Reads raw data of format, None if clipboard does not have it
+    fn read_format(format: UINT) -> Result<Option<Vec<u8>>, ClipboardError> {
+        unsafe {
+            if IsClipboardFormatAvailable(format) == FALSE.into() {
+                return Ok(None);
+            }
+            let handle = WindowsClipboard::get_clipboard_data_handle(format)?;
+            let data_ptr = GlobalLock(handle) as *const u8;
+            if data_ptr.is_null() {
+                return Err(ClipboardError::Read("Failed to lock memory.".into()));
+            }
+            let data = std::slice::from_raw_parts(data_ptr, GlobalSize(handle)).to_vec();
+            GlobalUnlock(handle);
+            Ok(Some(data))
+        }
+    }
+    /// Reads null terminated string of registered format
+    fn read_registered(name: &str) -> Result<Option<Vec<u8>>, ClipboardError> {
+        let data = WindowsClipboard::read_format(WindowsClipboard::registered_format(name)?)?;
+        // allocation may be larger than the string
+        Ok(data.map(|data| {
+            let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+            data[..end].to_vec()
+        }))
+    }
+    /// Reads image, preferring PNG that keeps transparency over DIB
+    fn read_image() -> Result<ClipboardData, ClipboardError> {
+        let image = match WindowsClipboard::read_format(WindowsClipboard::registered_format(
+            PNG_FORMAT,
+        )?)? {
+            Some(png) => Image::from_bytes(ImageFormat::Png, png),
+            None => {
+                let dib = WindowsClipboard::read_format(CF_DIB)?.ok_or(ClipboardError::Read(
+                    "Clipboard does not contain an image".to_string(),
+                ))?;
+                Image::from_dib(&dib)
+            }
+        };
+        image
+            .map(ClipboardData::Image)
+            .map_err(|err| ClipboardError::Read(format!("Invalid image: {:?}", err)))
+    }
+    /// Puts image as DIB for most apps and as PNG for the ones that keep transparency
+    fn write_image(image: &Image) -> Result<(), ClipboardError> {
+        let convert_err =
+            |err| ClipboardError::Write(format!("Could not convert image: {:?}", err));
+        let dib = image.to_dib().map_err(convert_err)?;
+        let png = image.convert(ImageFormat::Png).map_err(convert_err)?;
+        WindowsClipboard::write_str_into_cp(dib.as_ptr(), CF_DIB, dib.len(), dib.len())?;
+        WindowsClipboard::write_str_into_cp(
+            png.data.as_ptr(),
+            WindowsClipboard::registered_format(PNG_FORMAT)?,
+            png.data.len(),
+            png.data.len(),
+        )
+    }
+    /// Reads text with its formatted versions, from the most preferred
+    fn read_texts() -> Result<ClipboardData, ClipboardError> {
+        let mut reps = vec![];
+        if let Some(data) = Self::read_registered(HTML_FORMAT)? {
+            reps.push(ClipboardData::String((
+                StringType::Html,
+                html_from_cf_html(&data),
+            )));
+        }
+        if let Some(data) = Self::read_registered(RTF_FORMAT)? {
+            reps.push(ClipboardData::String((StringType::Rtf, data)));
+        }
+        if let Ok(text) = Self::read_text() {
+            reps.push(text);
+        }
+        ClipboardData::from_representations(reps).ok_or(ClipboardError::Read(
+            "Clipboard does not contain text".to_string(),
+        ))
     }
     fn write_str_into_cp<T>(
         data: *const T,
@@ -374,29 +462,28 @@ impl WindowsClipboard {
             Ok(())
         }
     }
-    fn write_file(filename: String, data: &[u8]) -> Result<(), ClipboardError> {
-        let mime_type_opt = filename.split(".").last();
-        if mime_type_opt.is_none() {
-            return Err(ClipboardError::Write(
-                "Failed to write bytes into clipboard. Unknown mime_type".to_string(),
-            ));
-        }
-
-        let deskt_dir = desktop_dir();
-        if deskt_dir.is_none() {
-            return Err(ClipboardError::Write(
-                "Could not find Desktop directory".to_string(),
-            ));
+    /// Sets files on disk as CF_HDROP, like Explorer does on copy
+    fn write_hdrop(paths: &[PathBuf]) -> Result<(), ClipboardError> {
+        // DROPFILES header: offset of file list, drop point, fNC and fWide flags
+        let mut data: Vec<u8> = vec![];
+        data.extend(20u32.to_le_bytes());
+        data.extend([0u8; 12]);
+        data.extend(1u32.to_le_bytes());
+        // null separated wide paths, list ends with an extra null
+        for path in paths {
+            for unit in path.as_os_str().encode_wide().chain([0]) {
+                data.extend(unit.to_le_bytes());
+            }
         }
-
-        let mut deskt_dir = deskt_dir.unwrap();
-        deskt_dir.push(&filename);
-
-        create_file(data, deskt_dir.to_str().unwrap_or(""))
-            .map_err(|err| ClipboardError::Write(format!("Could not write file: {:?}", err)))?;
-
-        debug_println!("Binary data written as {}", mime_type_opt.unwrap());
-        Ok(())
+        data.extend([0u8; 2]);
+        WindowsClipboard::write_str_into_cp(data.as_ptr(), CF_HDROP, data.len(), data.len())
+    }
+    /// Puts files on disk onto clipboard, replacing what was there
+    pub(super) fn write_paths(&self, paths: &[PathBuf]) -> Result<(), ClipboardError> {
+        WindowsClipboard::open()?;
+        let res = WindowsClipboard::empty().and_then(|_| WindowsClipboard::write_hdrop(paths));
+        WindowsClipboard::close()?;
+        res
     }
 }
 #[allow(non_snake_case)]
@@ -405,14 +492,36 @@ impl Clipboard for WindowsClipboard {
         Ok(WindowsClipboard)
     }
     fn write(&self, data: ClipboardData) -> Result<(), ClipboardError> {
+        if data.is_file_payload() {
+            return receive_files(self, data, None);
+        }
+        let (texts, others) = data.split_text();
+        let (images, files): (Vec<_>, Vec<_>) = others
+            .into_iter()
+            .partition(|rep| matches!(rep, ClipboardData::Image(_)));
+        // files next to text or image are stored like any received files,
+        // clipboard gets their paths
+        let paths = match ClipboardData::from_representations(files) {
+            Some(files) => Inbox::from_env()?.store(files, None)?,
+            None => vec![],
+        };
+        if texts.is_empty() && images.is_empty() && paths.is_empty() {
+            return Err(ClipboardError::Write("Nothing to write".to_string()));
+        }
         // Open clipboard (NULL or GetDesktopWindow())
         WindowsClipboard::open()?;
-        let res = match data {
-            ClipboardData::String((s_type, data)) => WindowsClipboard::write_text(&data, s_type),
-            ClipboardData::File((file_name, data)) => {
-                WindowsClipboard::write_file(file_name, &data)
+        let res = WindowsClipboard::empty().and_then(|_| {
+            if !texts.is_empty() {
+                WindowsClipboard::write_text(texts)?;
             }
-        };
+            if let Some(ClipboardData::Image(image)) = images.first() {
+                WindowsClipboard::write_image(image)?;
+            }
+            if !paths.is_empty() {
+                WindowsClipboard::write_hdrop(&paths)?;
+            }
+            Ok(())
+        });
         WindowsClipboard::close()?;
         res
     }
@@ -430,11 +539,9 @@ impl Clipboard for WindowsClipboard {
             Ok(cp_type) => {
                 // Handle different clipboard formats
                 let result = match cp_type {
-                    ClipboardType::TEXT => Self::read_text(),
+                    ClipboardType::TEXT => Self::read_texts(),
                     ClipboardType::FILE => Self::read_file(),
-                    ClipboardType::IMAGE => Err(ClipboardError::Read(
-                        "Clipboard contains an image (DIB)".to_string(),
-                    )),
+                    ClipboardType::IMAGE => Self::read_image(),
                 };
                 WindowsClipboard::close()?;
                 result
@@ -0,0 +1,763 @@
+//! Local control API over Unix domain socket
+//!
+//! One JSON object per line in both directions:
+//!
+//! ```text
+//! -> {"cmd":"peers"}
+//! <- {"ok":true,"peers":[{"addr":"192.168.0.3:53300","name":"office-pc"}]}
+//! -> {"cmd":"pull","peer":"office-pc"}
+//! <- {"ok":true}
+//! ```
+//!
+//! Commands: `peers`, `pull`, `push`, `get`, `send`, `discover`, `status`,
+//! `pair`, `confirm`, `cancel`, `unpair`, `pairings`, `policy`, `allow`,
+//! `deny`, `audit`.
+//! Peer is given by its address, IP, name or device ID. `pair` is answered
+//! with `"code"` once both devices have it, `confirm` is sent on each of them
+//! after the user has compared the codes. `policy` sets `"policy"` of peer's
+//! requests for our clipboard to `allow`, `ask` or `deny`, `allow` and `deny`
+//! answer the request waiting for the user. Clipboard data travels as
+//! `{"kind":"text"|"html"|"rtf"|"file"|"image","name":"a.txt","data":"<base64>"}`.
+//! Format of an image is given by extension of its name (`image.png`).
+//! Several representations of one copy travel as `{"kind":"multi","parts":[..]}`,
+//! the most preferred first. Several files and directories travel as
+//! `{"kind":"files","parts":[{"kind":"dir","name":"photos","mode":493},..]}`,
+//! each part named by its path relative to the copied selection.
+//! Files can be passed by `"path"` instead of `"data"`, so they are streamed
+//! from disk. Files received by `get` are returned by path of a temporary
+//! file, which the client should move or remove.
+
+use std::env;
+use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::clipboard::ClipboardData;
+use crate::clipboard::EntryContent;
+use crate::clipboard::FileTree;
+use crate::clipboard::Image;
+use crate::clipboard::ImageFormat;
+use crate::clipboard::StringType;
+use crate::clipboard::TreeEntry;
+use crate::engine::ConsentPolicy;
+use crate::engine::ControlRequest;
+use crate::engine::ControlResponse;
+use crate::engine::EngineHandle;
+use crate::network::PORT;
+use crate::utils::data_dir;
+use crate::utils::log_into_file;
+use crate::utils::private_dir;
+
+/// Env variable to override control socket path
+const SOCKET_ENV: &str = "COPYXROSS_SOCKET";
+const SOCKET_NAME: &str = "copyxross.sock";
+/// Directory of the socket, in runtime dir or in data dir if there is none
+const SOCKET_RUNTIME_DIR: &str = "copyxross";
+const SOCKET_DATA_DIR: &str = "control";
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+pub enum ControlCommand {
+    Peers,
+    Pull {
+        peer: String,
+    },
+    Push {
+        peer: String,
+    },
+    /// Fetch peer's clipboard
+    Get {
+        peer: String,
+    },
+    /// Send given data to peer
+    Send {
+        peer: String,
+        data: ClipPayload,
+    },
+    Discover,
+    Status,
+    /// Start pairing with peer
+    Pair {
+        peer: String,
+    },
+    /// Confirm code of pairing with peer
+    Confirm {
+        peer: String,
+    },
+    /// Cancel pairing with peer
+    Cancel {
+        peer: String,
+    },
+    /// Forget paired peer, given by name or device ID
+    Unpair {
+        peer: String,
+    },
+    Pairings,
+    /// Set what happens when peer asks for our clipboard
+    Policy {
+        peer: String,
+        policy: ConsentPolicy,
+    },
+    /// Let peer read our clipboard this time
+    Allow {
+        peer: String,
+    },
+    /// Refuse peer's request for our clipboard
+    Deny {
+        peer: String,
+    },
+    /// Recent requests for our clipboard
+    Audit,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ClipPayload {
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// base64 encoded content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    /// File on disk, used instead of data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Representations of `multi` payload or entries of `files`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parts: Option<Vec<ClipPayload>>,
+    /// Permission bits of `files` entry
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+impl From<&ClipboardData> for ClipPayload {
+    fn from(cp_data: &ClipboardData) -> Self {
+        let (kind, name, data) = match cp_data {
+            ClipboardData::String((StringType::Utf8Plain, data)) => ("text", None, data),
+            ClipboardData::String((StringType::Html, data)) => ("html", None, data),
+            ClipboardData::String((StringType::Rtf, data)) => ("rtf", None, data),
+            ClipboardData::File((name, data)) => ("file", Some(name.clone()), data),
+            ClipboardData::Image(image) => ("image", Some(image.file_name()), &image.data),
+            ClipboardData::FileRef((name, path)) => {
+                return ClipPayload {
+                    kind: "file".to_string(),
+                    name: Some(name.clone()),
+                    data: None,
+                    path: Some(path.to_string_lossy().to_string()),
+                    parts: None,
+                    mode: None,
+                }
+            }
+            ClipboardData::Multi(reps) => {
+                return ClipPayload {
+                    kind: "multi".to_string(),
+                    name: None,
+                    data: None,
+                    path: None,
+                    parts: Some(reps.iter().map(ClipPayload::from).collect()),
+                    mode: None,
+                }
+            }
+            ClipboardData::Files(tree) => {
+                return ClipPayload {
+                    kind: "files".to_string(),
+                    name: None,
+                    data: None,
+                    path: None,
+                    parts: Some(tree.entries.iter().map(entry_payload).collect()),
+                    mode: None,
+                }
+            }
+        };
+        ClipPayload {
+            kind: kind.to_string(),
+            name,
+            data: Some(BASE64.encode(data)),
+            path: None,
+            parts: None,
+            mode: None,
+        }
+    }
+}
+
+/// Tree entry as `dir` or `file` part, named by its relative path
+fn entry_payload(entry: &TreeEntry) -> ClipPayload {
+    let (kind, data, path) = match &entry.content {
+        EntryContent::Dir => ("dir", None, None),
+        EntryContent::Data(data) => ("file", Some(BASE64.encode(data)), None),
+        EntryContent::Path(path) => ("file", None, Some(path.to_string_lossy().to_string())),
+    };
+    ClipPayload {
+        kind: kind.to_string(),
+        name: Some(entry.path.clone()),
+        data,
+        path,
+        parts: None,
+        mode: Some(entry.mode),
+    }
+}
+
+fn tree_entry(part: ClipPayload) -> Result<TreeEntry, String> {
+    let path = part.name.ok_or("Entry path is missing".to_string())?;
+    let (size, content) = match (part.kind.as_str(), part.path, part.data) {
+        ("dir", ..) => (0, EntryContent::Dir),
+        ("file", Some(src), _) => {
+            let size = fs::metadata(&src)
+                .map_err(|err| format!("{}: {}", src, err))?
+                .len();
+            (size, EntryContent::Path(PathBuf::from(src)))
+        }
+        ("file", None, Some(data)) => {
+            let data = BASE64
+                .decode(data)
+                .map_err(|err| format!("Invalid data: {}", err))?;
+            (data.len() as u64, EntryContent::Data(data))
+        }
+        ("file", None, None) => return Err("Data is missing".to_string()),
+        (kind, ..) => return Err(format!("Unknown entry kind: {}", kind)),
+    };
+    Ok(TreeEntry {
+        path,
+        mode: part.mode.unwrap_or(0),
+        size,
+        content,
+    })
+}
+
+impl TryFrom<ClipPayload> for ClipboardData {
+    type Error = String;
+    fn try_from(payload: ClipPayload) -> Result<Self, Self::Error> {
+        if payload.kind == "multi" {
+            let reps = payload
+                .parts
+                .unwrap_or_default()
+                .into_iter()
+                .map(ClipboardData::try_from)
+                .collect::<Result<Vec<_>, _>>()?;
+            if reps
+                .iter()
+                .any(|rep| matches!(rep, ClipboardData::Multi(_)))
+            {
+                return Err("Nested multi payload".to_string());
+            }
+            return ClipboardData::from_representations(reps)
+                .ok_or("Parts are missing".to_string());
+        }
+        if payload.kind == "files" {
+            let entries = payload
+                .parts
+                .unwrap_or_default()
+                .into_iter()
+                .map(tree_entry)
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(ClipboardData::Files(FileTree { entries }));
+        }
+        if let ("file", Some(path)) = (payload.kind.as_str(), payload.path) {
+            let name = match payload.name {
+                Some(name) => name,
+                None => Path::new(&path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .ok_or("File name is missing".to_string())?,
+            };
+            return Ok(ClipboardData::FileRef((name, PathBuf::from(path))));
+        }
+        let data = BASE64
+            .decode(payload.data.ok_or("Data is missing".to_string())?)
+            .map_err(|err| format!("Invalid data: {}", err))?;
+        match payload.kind.as_str() {
+            "text" => Ok(ClipboardData::String((StringType::Utf8Plain, data))),
+            "html" => Ok(ClipboardData::String((StringType::Html, data))),
+            "rtf" => Ok(ClipboardData::String((StringType::Rtf, data))),
+            "file" => {
+                let name = payload.name.ok_or("File name is missing".to_string())?;
+                Ok(ClipboardData::File((name, data)))
+            }
+            "image" => {
+                let name = payload.name.unwrap_or("image.png".to_string());
+                let format = name
+                    .rsplit('.')
+                    .next()
+                    .and_then(ImageFormat::from_extension)
+                    .ok_or(format!("Unknown image format: {}", name))?;
+                let image = Image::from_bytes(format, data)
+                    .map_err(|err| format!("Invalid image: {:?}", err))?;
+                Ok(ClipboardData::Image(image))
+            }
+            kind => Err(format!("Unknown kind: {}", kind)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub addr: String,
+    pub name: String,
+    #[serde(default)]
+    pub device_id: String,
+    #[serde(default)]
+    pub os: String,
+    #[serde(default)]
+    pub app_version: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PairingEntry {
+    pub name: String,
+    pub device_id: String,
+    pub fingerprint: String,
+    /// Present while peer is online
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub addr: Option<String>,
+    pub paired: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StatusInfo {
+    pub name: String,
+    pub local_ip: Option<String>,
+    pub peers: usize,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct ControlReply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peers: Option<Vec<PeerInfo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<StatusInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<ClipPayload>,
+    /// Pairing code to compare
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pairings: Option<Vec<PairingEntry>>,
+    /// Audit log lines, oldest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audit: Option<Vec<String>>,
+}
+
+impl ControlReply {
+    pub fn error(err: String) -> Self {
+        ControlReply {
+            ok: false,
+            error: Some(err),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<ControlResponse> for ControlReply {
+    fn from(res: ControlResponse) -> Self {
+        let ok = ControlReply {
+            ok: true,
+            ..Default::default()
+        };
+        match res {
+            ControlResponse::Ok => ok,
+            ControlResponse::Error(err) => ControlReply::error(err),
+            ControlResponse::Peers(peers) => ControlReply {
+                peers: Some(
+                    peers
+                        .into_iter()
+                        .map(|(addr, data)| PeerInfo {
+                            addr: addr.to_string(),
+                            name: data.peer_name,
+                            device_id: data.device_id,
+                            os: data.os,
+                            app_version: data.app_version,
+                        })
+                        .collect(),
+                ),
+                ..ok
+            },
+            ControlResponse::Data(cp_data) => ControlReply {
+                data: Some(ClipPayload::from(&cp_data)),
+                ..ok
+            },
+            ControlResponse::PairingCode(code) => ControlReply {
+                code: Some(code),
+                ..ok
+            },
+            ControlResponse::Pairings(pairings) => ControlReply {
+                pairings: Some(
+                    pairings
+                        .into_iter()
+                        .map(|info| PairingEntry {
+                            name: info.name,
+                            device_id: info.device_id,
+                            fingerprint: info.fingerprint,
+                            addr: info.addr.map(|addr| addr.to_string()),
+                            paired: info.paired,
+                            code: info.code,
+                        })
+                        .collect(),
+                ),
+                ..ok
+            },
+            ControlResponse::Audit(entries) => ControlReply {
+                audit: Some(entries),
+                ..ok
+            },
+            ControlResponse::Status(status) => ControlReply {
+                status: Some(StatusInfo {
+                    name: status.peer_name,
+                    local_ip: status.local_ip.map(|ip| ip.to_string()),
+                    peers: status.peers,
+                }),
+                ..ok
+            },
+        }
+    }
+}
+
+/// `$COPYXROSS_SOCKET`, otherwise `copyxross.sock` in private directory
+/// under runtime (or data) dir
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os(SOCKET_ENV) {
+        return PathBuf::from(path);
+    }
+    dirs_next::runtime_dir()
+        .map(|dir| dir.join(SOCKET_RUNTIME_DIR))
+        .unwrap_or_else(|| data_dir().join(SOCKET_DATA_DIR))
+        .join(SOCKET_NAME)
+}
+
+/// Socket is only as private as its directory, since it can be connected to
+/// before its permissions are narrowed. Missing directory is created for the
+/// current user only, shared one (such as /tmp) is refused
+fn check_socket_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    match fs::symlink_metadata(dir) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => private_dir(dir),
+        Err(err) => Err(err),
+        Ok(meta) if meta.is_dir() && meta.permissions().mode() & 0o077 == 0 => Ok(()),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{:?} is accessible by other users", dir),
+        )),
+    }
+}
+
+/// Accepts control connections while alive, removes socket file on drop
+pub struct ControlServer {
+    path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn start(path: &Path, handle: EngineHandle) -> std::io::Result<Self> {
+        check_socket_dir(path)?;
+        // socket file is left behind if previous instance crashed
+        if path.exists() && UnixStream::connect(path).is_err() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        let _ = log_into_file(format!("Control socket: {}", path.display()).as_str());
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let handle = handle.clone();
+                        thread::spawn(move || serve_connection(stream, &handle));
+                    }
+                    Err(err) => {
+                        let _ = log_into_file(format!("Control socket err: {:?}", err).as_str());
+                    }
+                }
+            }
+        });
+        Ok(ControlServer {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn serve_connection(stream: UnixStream, handle: &EngineHandle) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match serde_json::from_str::<ControlCommand>(&line) {
+            Ok(cmd) => execute(cmd, handle),
+            Err(err) => ControlReply::error(format!("Invalid command: {}", err)),
+        };
+        let Ok(mut out) = serde_json::to_vec(&reply) else {
+            return;
+        };
+        out.push(b'\n');
+        if writer.write_all(&out).is_err() {
+            return;
+        }
+    }
+}
+
+fn execute(cmd: ControlCommand, handle: &EngineHandle) -> ControlReply {
+    let req = match cmd {
+        ControlCommand::Peers => ControlRequest::Peers,
+        ControlCommand::Discover => ControlRequest::Discover,
+        ControlCommand::Status => ControlRequest::Status,
+        ControlCommand::Pairings => ControlRequest::Pairings,
+        ControlCommand::Audit => ControlRequest::Audit,
+        ControlCommand::Policy { peer, policy } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::SetPolicy((addr, policy)),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Allow { peer } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::Consent((addr, true)),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Deny { peer } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::Consent((addr, false)),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Unpair { peer } => ControlRequest::Unpair(peer),
+        ControlCommand::Pair { peer } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::Pair(addr),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Confirm { peer } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::ConfirmPairing(addr),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Cancel { peer } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::CancelPairing(addr),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Pull { peer } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::Pull(addr),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Push { peer } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::Push(addr),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Get { peer } => match resolve_peer(&peer, handle) {
+            Ok(addr) => ControlRequest::Get(addr),
+            Err(err) => return ControlReply::error(err),
+        },
+        ControlCommand::Send { peer, data } => {
+            let addr = match resolve_peer(&peer, handle) {
+                Ok(addr) => addr,
+                Err(err) => return ControlReply::error(err),
+            };
+            match ClipboardData::try_from(data) {
+                Ok(cp_data) => ControlRequest::Send((addr, cp_data)),
+                Err(err) => return ControlReply::error(err),
+            }
+        }
+    };
+    handle.request(req).into()
+}
+
+/// Peer can be given as socket address, IP or peer name
+fn resolve_peer(peer: &str, handle: &EngineHandle) -> Result<SocketAddr, String> {
+    if let Ok(addr) = peer.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    if let Ok(ip) = peer.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, PORT));
+    }
+    match handle.request(ControlRequest::Peers) {
+        ControlResponse::Peers(peers) => peers
+            .into_iter()
+            .find(|(_, data)| {
+                data.peer_name.eq_ignore_ascii_case(peer)
+                    || (!data.device_id.is_empty() && data.device_id == peer)
+            })
+            .map(|(addr, _)| addr)
+            .ok_or(format!("Unknown peer: {}", peer)),
+        ControlResponse::Error(err) => Err(err),
+        res => Err(format!("Unexpected response: {:?}", res)),
+    }
+}
+
+/// Sends single command to running instance
+pub fn send_command(path: &Path, cmd: &ControlCommand) -> std::io::Result<ControlReply> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut line = serde_json::to_vec(cmd)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::PeerData;
+    use crate::engine::SyncMessage;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn test_clip_payload_roundtrip() {
+        let cp_data = ClipboardData::File(("a.bin".to_string(), vec![0, 159, 146, 150]));
+        let payload = ClipPayload::from(&cp_data);
+        assert_eq!(payload.kind, "file");
+        assert_eq!(payload.data.as_deref(), Some("AJ+Slg=="));
+        assert_eq!(ClipboardData::try_from(payload), Ok(cp_data));
+
+        let json = r#"{"kind":"file","path":"/tmp/big.iso"}"#;
+        let payload: ClipPayload = serde_json::from_str(json).unwrap();
+        assert_eq!(
+            ClipboardData::try_from(payload),
+            Ok(ClipboardData::FileRef((
+                "big.iso".to_string(),
+                PathBuf::from("/tmp/big.iso")
+            )))
+        );
+
+        let multi = ClipboardData::Multi(vec![
+            ClipboardData::String((StringType::Html, b"<b>hi</b>".to_vec())),
+            ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec())),
+        ]);
+        let payload = ClipPayload::from(&multi);
+        assert_eq!(payload.parts.as_ref().map(|parts| parts.len()), Some(2));
+        assert_eq!(ClipboardData::try_from(payload), Ok(multi));
+
+        let json = r#"{"kind":"files","parts":[{"kind":"dir","name":"docs","mode":493},
+            {"kind":"file","name":"docs/a.txt","data":"aGk="}]}"#;
+        let payload: ClipPayload = serde_json::from_str(json).unwrap();
+        let files = ClipboardData::try_from(payload).unwrap();
+        let ClipboardData::Files(tree) = &files else {
+            panic!("Expected file tree, got {:?}", files);
+        };
+        assert_eq!(tree.entries[0].mode, 0o755);
+        assert_eq!(tree.entries[1].content, EntryContent::Data(b"hi".to_vec()));
+        assert_eq!(
+            ClipboardData::try_from(ClipPayload::from(&files)),
+            Ok(files)
+        );
+    }
+
+    #[test]
+    fn test_commands_over_socket() {
+        let dir = env::temp_dir().join(format!("copyxross-test-{}", std::process::id()));
+        let path = dir.join(SOCKET_NAME);
+        let (sender, receiver) = channel();
+        let peer_addr: SocketAddr = "192.168.0.3:53300".parse().unwrap();
+
+        // stands in for the engine
+        thread::spawn(move || {
+            while let Ok(SyncMessage::Control((req, reply))) = receiver.recv() {
+                let res = match req {
+                    ControlRequest::Peers => ControlResponse::Peers(vec![(
+                        peer_addr,
+                        PeerData::new("office-pc".to_string(), "device-1".to_string()),
+                    )]),
+                    ControlRequest::Pull(addr) if addr == peer_addr => ControlResponse::Ok,
+                    req => ControlResponse::Error(format!("{:?}", req)),
+                };
+                let _ = reply.send(res);
+            }
+        });
+        let server = ControlServer::start(&path, EngineHandle::new(sender)).unwrap();
+
+        let reply = send_command(&path, &ControlCommand::Peers).unwrap();
+        assert_eq!(
+            reply.peers,
+            Some(vec![PeerInfo {
+                addr: "192.168.0.3:53300".to_string(),
+                name: "office-pc".to_string(),
+                device_id: "device-1".to_string(),
+                os: std::env::consts::OS.to_string(),
+                app_version: env!("CARGO_PKG_VERSION").to_string(),
+            }])
+        );
+        for peer in ["office-pc", "device-1", "192.168.0.3", "192.168.0.3:53300"] {
+            let cmd = ControlCommand::Pull {
+                peer: peer.to_string(),
+            };
+            assert_eq!(
+                send_command(&path, &cmd).unwrap(),
+                ControlReply::from(ControlResponse::Ok)
+            );
+        }
+        let cmd = ControlCommand::Pull {
+            peer: "home-pc".to_string(),
+        };
+        assert_eq!(
+            send_command(&path, &cmd).unwrap().error,
+            Some("Unknown peer: home-pc".to_string())
+        );
+
+        // raw JSON line, as a script would send it
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream.write_all(b"{\"cmd\":\"bogus\"}\n").unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        assert!(reply.starts_with("{\"ok\":false,\"error\":\"Invalid command"));
+
+        let cmd = ControlCommand::Send {
+            peer: "office-pc".to_string(),
+            data: ClipPayload {
+                kind: "text".to_string(),
+                name: None,
+                data: Some("!!".to_string()),
+                path: None,
+                parts: None,
+                mode: None,
+            },
+        };
+        assert!(send_command(&path, &cmd)
+            .unwrap()
+            .error
+            .unwrap()
+            .starts_with("Invalid data"));
+
+        drop(server);
+        assert!(!path.exists());
+        assert_eq!(
+            fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        fs::remove_dir(&dir).unwrap();
+
+        // world writable dir, such as /tmp, is refused
+        let shared = env::temp_dir().join(format!("copyxross-shared-{}", std::process::id()));
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+        let (sender, _receiver) = channel();
+        let err = ControlServer::start(&shared.join(SOCKET_NAME), EngineHandle::new(sender))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        fs::remove_dir(&shared).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Identity key of this install
+//!
+//! Ed25519 key is generated on first start and kept in data directory.
+//! Peers learn its public half from Xcon/Xacn and remember it once paired,
+//! so a device is recognized by its key rather than by name or address.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::Signature;
+use ed25519_dalek::Signer;
+use ed25519_dalek::SigningKey;
+use ed25519_dalek::VerifyingKey;
+use sha2::Digest;
+use sha2::Sha256;
+
+use super::CryptoError;
+use crate::utils::data_dir;
+
+/// Env variable with path to identity key file
+const IDENTITY_ENV: &str = "COPYXROSS_IDENTITY";
+const IDENTITY_FILE: &str = "identity.key";
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", fingerprint(&self.public_key()))
+    }
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        let mut seed = [0; 32];
+        OsRng.fill_bytes(&mut seed);
+        Identity {
+            key: SigningKey::from_bytes(&seed),
+        }
+    }
+
+    /// Identity at `$COPYXROSS_IDENTITY` or in data directory
+    pub fn from_env() -> Result<Self, CryptoError> {
+        let path = match env::var_os(IDENTITY_ENV) {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => data_dir().join(IDENTITY_FILE),
+        };
+        Identity::load_or_create(&path)
+    }
+
+    /// Loads key from file, generating and saving a new one if there is none.
+    /// Key file that can not be read is an error rather than a reason to
+    /// replace the key, since peers would no longer recognize us
+    pub fn load_or_create(path: &Path) -> Result<Self, CryptoError> {
+        match fs::read_to_string(path) {
+            Ok(encoded) => {
+                let seed: [u8; 32] = BASE64
+                    .decode(encoded.trim())
+                    .ok()
+                    .and_then(|seed| seed.try_into().ok())
+                    .ok_or(CryptoError::Key(format!(
+                        "Invalid identity key in {:?}",
+                        path
+                    )))?;
+                Ok(Identity {
+                    key: SigningKey::from_bytes(&seed),
+                })
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                identity.save(path).map_err(|err| {
+                    CryptoError::Key(format!(
+                        "Could not save identity key to {:?}: {}",
+                        path, err
+                    ))
+                })?;
+                Ok(identity)
+            }
+            Err(err) => Err(CryptoError::Key(format!(
+                "Could not read identity key from {:?}: {}",
+                path, err
+            ))),
+        }
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        writeln!(file, "{}", BASE64.encode(self.key.to_bytes()))
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.key.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        self.key.sign(data).to_bytes()
+    }
+
+    /// Private key for TLS
+    pub(super) fn seed(&self) -> [u8; 32] {
+        self.key.to_bytes()
+    }
+}
+
+/// Checks that data was signed with the private half of identity key
+pub fn verify(key: &[u8; PUBLIC_KEY_SIZE], data: &[u8], signature: &[u8; SIGNATURE_SIZE]) -> bool {
+    VerifyingKey::from_bytes(key)
+        .and_then(|key| key.verify_strict(data, &Signature::from_bytes(signature)))
+        .is_ok()
+}
+
+/// Short form of public key for people to compare, `1a2b 3c4d 5e6f 7a8b`
+pub fn fingerprint(key: &[u8]) -> String {
+    let hash = Sha256::digest(key);
+    hash[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
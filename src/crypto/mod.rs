@@ -0,0 +1,25 @@
+//! Protection of traffic between peers
+
+mod identity;
+mod pairing;
+mod seal;
+mod tls;
+mod trust;
+
+pub use identity::{fingerprint, verify, Identity, PUBLIC_KEY_SIZE, SIGNATURE_SIZE};
+pub use pairing::Pairing;
+pub use seal::{sealed_len, Cipher, OpenReader, SealWriter, NONCE_PREFIX_SIZE, SALT_SIZE};
+pub use tls::{certificate_key, TlsConfig, SERVER_NAME};
+pub use trust::{pairing_required, TrustStore, TrustedPeer};
+
+#[derive(Debug, PartialEq)]
+pub enum CryptoError {
+    /// Passphrase or key could not be loaded
+    Key(String),
+    /// Data was changed on the way or sealed with another key
+    Authentication,
+    /// Sealed data is too old or was opened before
+    Replayed,
+    /// Pairing exchange went wrong
+    Pairing(String),
+}
@@ -0,0 +1,218 @@
+//! Pairing of two devices
+//!
+//! Devices run ephemeral X25519 exchange and both show a 6 digit code
+//! derived from its result and from both identity keys. User compares the
+//! codes and confirms on both devices, after which each keeps the identity
+//! key of the other.
+//!
+//! Initiator commits to its ephemeral key before it sees the responder's one
+//! and reveals it last, so nobody in the middle can choose keys that give
+//! both sides the same code. All such an attacker can do is guess, with one
+//! chance in a million.
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use sha2::Digest;
+use sha2::Sha256;
+use x25519_dalek::EphemeralSecret;
+use x25519_dalek::PublicKey;
+
+use super::CryptoError;
+use super::Identity;
+use super::PUBLIC_KEY_SIZE;
+use crate::encode::PairingMessage;
+use crate::encode::PairingStep;
+
+const CODE_CONTEXT: &[u8] = b"copyxross pairing code v1";
+const COMMIT_CONTEXT: &[u8] = b"copyxross pairing commitment v1";
+
+pub struct Pairing {
+    session: [u8; 16],
+    initiator: bool,
+    identity: [u8; PUBLIC_KEY_SIZE],
+    peer_identity: Option<[u8; PUBLIC_KEY_SIZE]>,
+    /// Taken once the exchange is complete
+    secret: Option<EphemeralSecret>,
+    key: [u8; 32],
+    /// Initiator's commitment, kept by responder until the key is revealed
+    commitment: Option<[u8; 32]>,
+    code: Option<String>,
+}
+
+impl Pairing {
+    /// Starts pairing. Returns Commit message for the peer
+    pub fn start(identity: &Identity) -> (Self, PairingMessage) {
+        let mut session = [0; 16];
+        OsRng.fill_bytes(&mut session);
+        let pairing = Pairing::new(identity, session, true, None);
+        let msg = pairing.message(PairingStep::Commit, commitment(&pairing.key));
+        (pairing, msg)
+    }
+
+    /// Answers Commit message of initiator with our key
+    pub fn respond(
+        identity: &Identity,
+        commit: &PairingMessage,
+    ) -> Result<(Self, PairingMessage), CryptoError> {
+        if commit.step != PairingStep::Commit {
+            return Err(CryptoError::Pairing("Pairing has not started".to_string()));
+        }
+        let mut pairing = Pairing::new(identity, commit.session, false, Some(commit.identity));
+        pairing.commitment = Some(commit.value);
+        let msg = pairing.message(PairingStep::Key, pairing.key);
+        Ok((pairing, msg))
+    }
+
+    fn new(
+        identity: &Identity,
+        session: [u8; 16],
+        initiator: bool,
+        peer_identity: Option<[u8; PUBLIC_KEY_SIZE]>,
+    ) -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let key = PublicKey::from(&secret).to_bytes();
+        Pairing {
+            session,
+            initiator,
+            identity: identity.public_key(),
+            peer_identity,
+            secret: Some(secret),
+            key,
+            commitment: None,
+            code: None,
+        }
+    }
+
+    /// Handles next message of the peer. Returns message to answer with.
+    /// Code is known once initiator got Key or responder got Reveal
+    pub fn handle(&mut self, msg: &PairingMessage) -> Result<Option<PairingMessage>, CryptoError> {
+        if msg.session != self.session || self.code.is_some() {
+            return Err(CryptoError::Pairing("Unexpected message".to_string()));
+        }
+        match (self.initiator, msg.step) {
+            (true, PairingStep::Key) => {
+                self.peer_identity = Some(msg.identity);
+                self.exchange(self.key, msg.value)?;
+                Ok(Some(self.message(PairingStep::Reveal, self.key)))
+            }
+            (false, PairingStep::Reveal) => {
+                if Some(msg.identity) != self.peer_identity
+                    || self.commitment != Some(commitment(&msg.value))
+                {
+                    return Err(CryptoError::Pairing(
+                        "Key does not match commitment".to_string(),
+                    ));
+                }
+                self.exchange(msg.value, self.key)?;
+                Ok(None)
+            }
+            (_, PairingStep::Cancel) => Err(CryptoError::Pairing("Peer cancelled".to_string())),
+            _ => Err(CryptoError::Pairing("Unexpected message".to_string())),
+        }
+    }
+
+    /// Derives code from shared secret and everything both sides have sent
+    fn exchange(
+        &mut self,
+        initiator_key: [u8; 32],
+        responder_key: [u8; 32],
+    ) -> Result<(), CryptoError> {
+        let peer_key = if self.initiator {
+            responder_key
+        } else {
+            initiator_key
+        };
+        let secret = self
+            .secret
+            .take()
+            .ok_or(CryptoError::Pairing("Exchange is over".to_string()))?;
+        let shared = secret.diffie_hellman(&PublicKey::from(peer_key));
+        if !shared.was_contributory() {
+            return Err(CryptoError::Pairing("Invalid key".to_string()));
+        }
+        let peer_identity = self.peer_identity.unwrap_or_default();
+        let (initiator_id, responder_id) = if self.initiator {
+            (self.identity, peer_identity)
+        } else {
+            (peer_identity, self.identity)
+        };
+        let hash = Sha256::new()
+            .chain_update(CODE_CONTEXT)
+            .chain_update(self.session)
+            .chain_update(initiator_id)
+            .chain_update(responder_id)
+            .chain_update(initiator_key)
+            .chain_update(responder_key)
+            .chain_update(shared.as_bytes())
+            .finalize();
+        let number = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) % 1_000_000;
+        self.code = Some(format!("{:06}", number));
+        Ok(())
+    }
+
+    fn message(&self, step: PairingStep, value: [u8; 32]) -> PairingMessage {
+        PairingMessage {
+            step,
+            session: self.session,
+            identity: self.identity,
+            value,
+        }
+    }
+
+    /// Tells the peer we are not going on
+    pub fn cancel_message(&self) -> PairingMessage {
+        self.message(PairingStep::Cancel, [0; 32])
+    }
+
+    pub fn session(&self) -> [u8; 16] {
+        self.session
+    }
+
+    /// Code to show to the user, once the exchange is complete
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+
+    /// Identity key of the peer as it took part in the exchange
+    pub fn peer_identity(&self) -> Option<[u8; PUBLIC_KEY_SIZE]> {
+        self.peer_identity
+    }
+}
+
+fn commitment(key: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(COMMIT_CONTEXT)
+        .chain_update(key)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_both_sides_get_the_same_code() {
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let (mut initiator, commit) = Pairing::start(&alice);
+        let (mut responder, key) = Pairing::respond(&bob, &commit).unwrap();
+        assert_eq!(responder.code(), None);
+        let reveal = initiator.handle(&key).unwrap().unwrap();
+        assert_eq!(responder.handle(&reveal).unwrap(), None);
+
+        let code = initiator.code().unwrap();
+        assert_eq!(code.len(), 6);
+        assert_eq!(responder.code(), Some(code));
+        assert_eq!(initiator.peer_identity(), Some(bob.public_key()));
+        assert_eq!(responder.peer_identity(), Some(alice.public_key()));
+        // exchange is over
+        assert!(initiator.handle(&key).is_err());
+
+        // attacker can not swap the key initiator committed to
+        let (mut initiator, commit) = Pairing::start(&alice);
+        let (mut responder, key) = Pairing::respond(&bob, &commit).unwrap();
+        let mut reveal = initiator.handle(&key).unwrap().unwrap();
+        reveal.value = Pairing::start(&alice).0.key;
+        assert!(responder.handle(&reveal).is_err());
+    }
+}
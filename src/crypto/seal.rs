@@ -0,0 +1,527 @@
+//! Encryption with shared passphrase
+//!
+//! Key is derived from passphrase with Argon2id and salt made from identity
+//! key of the sealing install, so one guess at the passphrase can not be
+//! tried against everyone's traffic at once. Salt is sent along, receiver
+//! derives key only for the salt of the peer the connection comes from, so
+//! strangers can not make it run Argon2 or push keys of peers out.
+//!
+//! Data is sealed with ChaCha20-Poly1305 in records of `RECORD_SIZE` bytes,
+//! so files can be streamed. Nonce of each record holds random prefix, record
+//! counter and a flag marking the last record (STREAM construction), so
+//! records can not be reordered, dropped or cut off unnoticed. The first
+//! record starts with time of sealing, so message is opened only within
+//! `SEAL_WINDOW` of it and only once.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::stream::DecryptorBE32;
+use chacha20poly1305::aead::stream::EncryptorBE32;
+use chacha20poly1305::aead::KeyInit;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::ChaCha20Poly1305;
+use chacha20poly1305::Key;
+use sha2::Digest;
+use sha2::Sha256;
+
+use super::CryptoError;
+
+/// Env variable with passphrase shared by all peers
+const PASSPHRASE_ENV: &str = "COPYXROSS_PASSPHRASE";
+/// Env variable with path to file holding the passphrase
+const PASSPHRASE_FILE_ENV: &str = "COPYXROSS_PASSPHRASE_FILE";
+pub const SALT_SIZE: usize = 16;
+const RECORD_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+/// Milliseconds since Unix epoch, in front of sealed data
+const TIMESTAMP_SIZE: usize = 8;
+/// Random part of nonce, the rest is record counter and last record flag
+pub const NONCE_PREFIX_SIZE: usize = 7;
+/// Sealed message is opened only this close to our time, so old ones can
+/// not be replayed once they are forgotten
+const SEAL_WINDOW: Duration = Duration::new(5 * 60, 0);
+const SALT_CONTEXT: &[u8] = b"copyxross seal salt";
+
+/// Clones share derived keys, known peers and opened messages
+#[derive(Clone)]
+pub struct Cipher {
+    passphrase: Arc<String>,
+    /// Salt of messages we seal
+    salt: [u8; SALT_SIZE],
+    /// Address messages are opened from, see [`Cipher::for_sender`]
+    sender: Option<IpAddr>,
+    state: Arc<Mutex<CipherState>>,
+}
+
+#[derive(Default)]
+struct CipherState {
+    /// Keys for our salt and salts of known peers
+    keys: HashMap<[u8; SALT_SIZE], Key>,
+    /// Salt of peer at each address
+    peers: HashMap<IpAddr, [u8; SALT_SIZE]>,
+    /// Timestamp, salt and nonce prefix of messages opened within [`SEAL_WINDOW`]
+    opened: Vec<(u64, [u8; SALT_SIZE], [u8; NONCE_PREFIX_SIZE])>,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher { .. }")
+    }
+}
+
+impl Cipher {
+    /// Cipher sealing with salt of our identity key
+    pub fn from_passphrase(passphrase: &str, identity: &[u8]) -> Result<Self, CryptoError> {
+        let salt = salt_for(identity);
+        let cipher = Cipher {
+            passphrase: Arc::new(passphrase.to_string()),
+            salt,
+            sender: None,
+            state: Arc::default(),
+        };
+        // our own key is needed for every message we seal
+        cipher.key(&salt)?;
+        Ok(cipher)
+    }
+
+    /// Cipher for passphrase from environment. None if no passphrase is set
+    pub fn from_env(identity: &[u8]) -> Result<Option<Self>, CryptoError> {
+        let passphrase = match (env::var(PASSPHRASE_ENV), env::var_os(PASSPHRASE_FILE_ENV)) {
+            (Ok(passphrase), _) => passphrase,
+            (_, Some(path)) => fs::read_to_string(&path).map_err(|err| {
+                CryptoError::Key(format!(
+                    "Could not read passphrase from {:?}: {}",
+                    path, err
+                ))
+            })?,
+            _ => return Ok(None),
+        };
+        match passphrase.trim() {
+            "" => Ok(None),
+            passphrase => Cipher::from_passphrase(passphrase, identity).map(Some),
+        }
+    }
+
+    /// Fresh nonce prefix, one per sealed message
+    pub fn nonce_prefix() -> [u8; NONCE_PREFIX_SIZE] {
+        let mut prefix = [0; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+        prefix
+    }
+
+    pub fn salt(&self) -> [u8; SALT_SIZE] {
+        self.salt
+    }
+
+    /// Sets identity keys of peers at each address. Keys of peers that are
+    /// gone are forgotten
+    pub fn allow_peers(&self, identities: &HashMap<IpAddr, Vec<u8>>) -> Result<(), CryptoError> {
+        let mut state = self.state()?;
+        state.peers = identities
+            .iter()
+            .map(|(ip, identity)| (*ip, salt_for(identity)))
+            .collect();
+        let CipherState { keys, peers, .. } = &mut *state;
+        keys.retain(|salt, _| *salt == self.salt || peers.values().any(|s| s == salt));
+        Ok(())
+    }
+
+    /// Clone opening only messages sealed with our salt or the salt of the
+    /// peer at `ip`
+    pub fn for_sender(&self, ip: IpAddr) -> Cipher {
+        Cipher {
+            sender: Some(ip),
+            ..self.clone()
+        }
+    }
+
+    /// Key for salt, derived on first use. Salt has to be ours or the one
+    /// of the sender
+    fn key(&self, salt: &[u8; SALT_SIZE]) -> Result<Key, CryptoError> {
+        {
+            let state = self.state()?;
+            let known = *salt == self.salt
+                || self
+                    .sender
+                    .is_some_and(|ip| state.peers.get(&ip) == Some(salt));
+            if !known {
+                return Err(CryptoError::Key(
+                    "Sealed with salt of unknown peer".to_string(),
+                ));
+            }
+            if let Some(key) = state.keys.get(salt) {
+                return Ok(*key);
+            }
+        }
+        let mut key = Key::default();
+        Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+            .map_err(|err| CryptoError::Key(format!("Could not derive key: {:?}", err)))?;
+        self.state()?.keys.insert(*salt, key);
+        Ok(key)
+    }
+
+    fn aead(&self, salt: &[u8; SALT_SIZE]) -> Result<ChaCha20Poly1305, CryptoError> {
+        Ok(ChaCha20Poly1305::new(&self.key(salt)?))
+    }
+
+    /// Refuses messages sealed far from our time and ones opened before
+    fn check_fresh(
+        &self,
+        timestamp: u64,
+        salt: &[u8; SALT_SIZE],
+        prefix: &[u8; NONCE_PREFIX_SIZE],
+    ) -> Result<(), CryptoError> {
+        let now = timestamp_now();
+        let window = SEAL_WINDOW.as_millis() as u64;
+        if timestamp.abs_diff(now) > window {
+            return Err(CryptoError::Replayed);
+        }
+        let mut state = self.state()?;
+        state
+            .opened
+            .retain(|(opened, ..)| opened.abs_diff(now) <= window);
+        if state
+            .opened
+            .iter()
+            .any(|(_, s, p)| (s, p) == (salt, prefix))
+        {
+            return Err(CryptoError::Replayed);
+        }
+        state.opened.push((timestamp, *salt, *prefix));
+        Ok(())
+    }
+
+    fn state(&self) -> Result<std::sync::MutexGuard<'_, CipherState>, CryptoError> {
+        self.state
+            .lock()
+            .map_err(|_| CryptoError::Key("Cipher state is poisoned".to_string()))
+    }
+}
+
+/// Salt of install with given identity key
+fn salt_for(identity: &[u8]) -> [u8; SALT_SIZE] {
+    let hash = Sha256::new()
+        .chain_update(SALT_CONTEXT)
+        .chain_update(identity)
+        .finalize();
+    let mut salt = [0; SALT_SIZE];
+    salt.copy_from_slice(&hash[..SALT_SIZE]);
+    salt
+}
+
+/// Milliseconds since Unix epoch
+fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Length of `plain_len` bytes once sealed, timestamp included
+pub fn sealed_len(plain_len: usize) -> usize {
+    let plain_len = plain_len + TIMESTAMP_SIZE;
+    let records = plain_len.div_ceil(RECORD_SIZE).max(1);
+    plain_len + records * TAG_SIZE
+}
+
+/// Seals everything written into it with our salt, after current time.
+/// [`SealWriter::finish`] has to be called to write the last record
+pub struct SealWriter<W: Write> {
+    out: W,
+    encryptor: EncryptorBE32<ChaCha20Poly1305>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> SealWriter<W> {
+    pub fn new(
+        out: W,
+        cipher: &Cipher,
+        prefix: &[u8; NONCE_PREFIX_SIZE],
+    ) -> Result<Self, CryptoError> {
+        let mut buffer = Vec::with_capacity(RECORD_SIZE + 1);
+        buffer.extend(timestamp_now().to_be_bytes());
+        Ok(SealWriter {
+            out,
+            encryptor: EncryptorBE32::from_aead(cipher.aead(&cipher.salt)?, prefix.into()),
+            buffer,
+        })
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        let SealWriter {
+            mut out,
+            encryptor,
+            buffer,
+        } = self;
+        let sealed = encryptor
+            .encrypt_last(buffer.as_slice())
+            .map_err(|_| io::Error::other("Could not seal record"))?;
+        out.write_all(&sealed)?;
+        Ok(out)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        // full record is sealed only once more data follows, the last one is flagged
+        while self.buffer.len() > RECORD_SIZE {
+            let sealed = self
+                .encryptor
+                .encrypt_next(&self.buffer[..RECORD_SIZE])
+                .map_err(|_| io::Error::other("Could not seal record"))?;
+            self.out.write_all(&sealed)?;
+            self.buffer.drain(..RECORD_SIZE);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Opens `sealed_len` bytes of sealed records as they are read
+pub struct OpenReader<R: Read> {
+    inner: R,
+    cipher: Cipher,
+    salt: [u8; SALT_SIZE],
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    /// Taken when the last record is opened
+    decryptor: Option<DecryptorBE32<ChaCha20Poly1305>>,
+    /// Sealed bytes not read yet
+    remaining: usize,
+    record: Vec<u8>,
+    pos: usize,
+    /// Set once timestamp of the first record is checked
+    started: bool,
+    failed: Option<CryptoError>,
+}
+
+impl<R: Read> OpenReader<R> {
+    pub fn new(
+        inner: R,
+        cipher: &Cipher,
+        salt: &[u8; SALT_SIZE],
+        prefix: &[u8; NONCE_PREFIX_SIZE],
+        sealed_len: usize,
+    ) -> Result<Self, CryptoError> {
+        Ok(OpenReader {
+            inner,
+            cipher: cipher.clone(),
+            salt: *salt,
+            prefix: *prefix,
+            decryptor: Some(DecryptorBE32::from_aead(cipher.aead(salt)?, prefix.into())),
+            remaining: sealed_len,
+            record: vec![],
+            pos: 0,
+            started: false,
+            failed: None,
+        })
+    }
+
+    /// Returns Authentication error if any record did not open, Replayed
+    /// if message is old or was opened before
+    pub fn check(&self) -> Result<(), CryptoError> {
+        match &self.failed {
+            Some(CryptoError::Replayed) => Err(CryptoError::Replayed),
+            Some(_) => Err(CryptoError::Authentication),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes timestamp off the first record
+    fn start(&mut self) -> Result<(), CryptoError> {
+        self.started = true;
+        let timestamp = self
+            .record
+            .get(..TIMESTAMP_SIZE)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes)
+            .ok_or(CryptoError::Authentication)?;
+        self.pos = TIMESTAMP_SIZE;
+        self.cipher.check_fresh(timestamp, &self.salt, &self.prefix)
+    }
+
+    fn next_record(&mut self) -> io::Result<()> {
+        let len = self.remaining.min(RECORD_SIZE + TAG_SIZE);
+        let mut sealed = vec![0; len];
+        self.inner.read_exact(&mut sealed)?;
+        self.remaining -= len;
+        let opened = match (self.remaining, self.decryptor.as_mut()) {
+            (0, _) => self
+                .decryptor
+                .take()
+                .map(|d| d.decrypt_last(sealed.as_slice())),
+            (_, Some(decryptor)) => Some(decryptor.decrypt_next(sealed.as_slice())),
+            _ => None,
+        };
+        let opened = match opened {
+            Some(Ok(record)) => {
+                self.record = record;
+                self.pos = 0;
+                match self.started {
+                    true => Ok(()),
+                    false => self.start(),
+                }
+            }
+            _ => Err(CryptoError::Authentication),
+        };
+        opened.map_err(|err| {
+            self.failed = Some(err);
+            io::Error::new(ErrorKind::InvalidData, "Sealed record did not open")
+        })
+    }
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.failed.is_some() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Sealed record did not open",
+            ));
+        }
+        if self.pos == self.record.len() && self.decryptor.is_some() {
+            self.next_record()?;
+        }
+        let n = buf.len().min(self.record.len() - self.pos);
+        buf[..n].copy_from_slice(&self.record[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seal(cipher: &Cipher, prefix: &[u8; NONCE_PREFIX_SIZE], data: &[u8]) -> Vec<u8> {
+        let mut writer = SealWriter::new(vec![], cipher, prefix).unwrap();
+        // odd pieces, so records are cut in the middle of writes
+        for piece in data.chunks(5000) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn open(
+        cipher: &Cipher,
+        salt: &[u8; SALT_SIZE],
+        prefix: &[u8; NONCE_PREFIX_SIZE],
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let mut reader = OpenReader::new(sealed, cipher, salt, prefix, sealed.len())?;
+        let mut out = vec![];
+        let read = reader.read_to_end(&mut out);
+        reader.check()?;
+        read.map_err(|_| CryptoError::Authentication)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_seal_and_open_records() {
+        let ip = IpAddr::from([192, 168, 0, 3]);
+        let known = HashMap::from([(ip, b"laptop key".to_vec())]);
+        let cipher =
+            Cipher::from_passphrase("correct horse battery staple", b"laptop key").unwrap();
+        let other =
+            Cipher::from_passphrase("correct horse battery stapler", b"tablet key").unwrap();
+        other.allow_peers(&known).unwrap();
+        let other = other.for_sender(ip);
+        // same passphrase on another machine
+        let peer = Cipher::from_passphrase("correct horse battery staple", b"desktop key").unwrap();
+        let salt = cipher.salt();
+        assert_ne!(salt, peer.salt());
+        // salt of unknown peer or of a peer at another address is not derived
+        let prefix = Cipher::nonce_prefix();
+        let sealed = seal(&cipher, &prefix, b"hello");
+        assert!(matches!(
+            open(&peer.for_sender(ip), &salt, &prefix, &sealed),
+            Err(CryptoError::Key(_))
+        ));
+        peer.allow_peers(&known).unwrap();
+        assert!(matches!(
+            open(
+                &peer.for_sender(IpAddr::from([192, 168, 0, 9])),
+                &salt,
+                &prefix,
+                &sealed
+            ),
+            Err(CryptoError::Key(_))
+        ));
+        let peer = peer.for_sender(ip);
+
+        for len in [0, 1, RECORD_SIZE, RECORD_SIZE + 1, 3 * RECORD_SIZE - 7] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let prefix = Cipher::nonce_prefix();
+            let sealed = seal(&cipher, &prefix, &data);
+            assert_eq!(sealed.len(), sealed_len(len));
+            assert_eq!(
+                open(&other, &salt, &prefix, &sealed),
+                Err(CryptoError::Authentication)
+            );
+            assert_eq!(
+                open(&peer, &salt, &prefix, &sealed).unwrap(),
+                data,
+                "{}",
+                len
+            );
+        }
+
+        let data = vec![7; 2 * RECORD_SIZE + 10];
+        let prefix = Cipher::nonce_prefix();
+        let sealed = seal(&cipher, &prefix, &data);
+        let mut tampered = sealed.clone();
+        tampered[RECORD_SIZE + 100] ^= 1;
+        assert!(open(&cipher, &salt, &prefix, &tampered).is_err());
+        // dropping the last record leaves a record that is not marked last
+        let prefix = Cipher::nonce_prefix();
+        let sealed = seal(&cipher, &prefix, &data);
+        let cut = &sealed[..2 * (RECORD_SIZE + TAG_SIZE)];
+        assert!(open(&cipher, &salt, &prefix, cut).is_err());
+        assert!(open(&cipher, &salt, &Cipher::nonce_prefix(), &sealed).is_err());
+        assert!(open(&cipher, &peer.salt(), &prefix, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_sealed_data_opens_once() {
+        let cipher =
+            Cipher::from_passphrase("correct horse battery staple", b"laptop key").unwrap();
+        let salt = cipher.salt();
+        let prefix = Cipher::nonce_prefix();
+        let sealed = seal(&cipher, &prefix, b"hello");
+        assert_eq!(open(&cipher, &salt, &prefix, &sealed).unwrap(), b"hello");
+        assert_eq!(
+            open(&cipher.clone(), &salt, &prefix, &sealed),
+            Err(CryptoError::Replayed)
+        );
+
+        let prefix = Cipher::nonce_prefix();
+        let mut writer = SealWriter::new(vec![], &cipher, &prefix).unwrap();
+        let stale = timestamp_now() - 2 * SEAL_WINDOW.as_millis() as u64;
+        writer.buffer[..TIMESTAMP_SIZE].copy_from_slice(&stale.to_be_bytes());
+        writer.write_all(b"hello").unwrap();
+        let sealed = writer.finish().unwrap();
+        assert_eq!(
+            open(&cipher, &salt, &prefix, &sealed),
+            Err(CryptoError::Replayed)
+        );
+    }
+}
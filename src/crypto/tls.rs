@@ -0,0 +1,280 @@
+//! TLS between peers
+//!
+//! Every install presents self-signed certificate of its identity key, so
+//! there is no authority to trust. Client accepts only the key of the peer
+//! it paired with, server accepts any key and leaves it to the engine to
+//! check that the key belongs to the peer that connected.
+
+use std::fmt;
+use std::sync::Arc;
+
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::crypto::ring;
+use rustls::crypto::verify_tls12_signature;
+use rustls::crypto::verify_tls13_signature;
+use rustls::crypto::CryptoProvider;
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::server::danger::ClientCertVerified;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::CertificateError;
+use rustls::ClientConfig;
+use rustls::DigitallySignedStruct;
+use rustls::DistinguishedName;
+use rustls::Error;
+use rustls::ServerConfig;
+use rustls::SignatureScheme;
+
+use super::CryptoError;
+use super::Identity;
+use super::PUBLIC_KEY_SIZE;
+
+/// Name client asks for. Certificates are checked by key, not by name
+pub const SERVER_NAME: &str = "copyxross";
+/// AlgorithmIdentifier of Ed25519
+const ED25519: &[u8] = &[0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70];
+/// OID of commonName
+const COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+
+/// Client and server configurations for one identity
+pub struct TlsConfig {
+    provider: Arc<CryptoProvider>,
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+    server: Arc<ServerConfig>,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TlsConfig { .. }")
+    }
+}
+
+impl TlsConfig {
+    pub fn new(identity: &Identity) -> Result<Self, CryptoError> {
+        let provider = Arc::new(ring::default_provider());
+        let cert = certificate(identity);
+        let key = private_key(identity);
+        let mut server = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .and_then(|builder| {
+                builder
+                    .with_client_cert_verifier(Arc::new(AnyKey {
+                        algorithms: provider.signature_verification_algorithms,
+                    }))
+                    .with_single_cert(vec![cert.clone()], PrivateKeyDer::Pkcs8(key.clone_key()))
+            })
+            .map_err(tls_error)?;
+        // one message per connection, nothing to resume
+        server.send_tls13_tickets = 0;
+        Ok(TlsConfig {
+            provider,
+            cert,
+            key,
+            server: Arc::new(server),
+        })
+    }
+
+    pub fn server(&self) -> Arc<ServerConfig> {
+        self.server.clone()
+    }
+
+    /// Client that talks only to server with given identity key
+    pub fn client(
+        &self,
+        peer_key: [u8; PUBLIC_KEY_SIZE],
+    ) -> Result<Arc<ClientConfig>, CryptoError> {
+        let mut client = ClientConfig::builder_with_provider(self.provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .map_err(tls_error)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedKey {
+                key: peer_key,
+                algorithms: self.provider.signature_verification_algorithms,
+            }))
+            .with_client_auth_cert(
+                vec![self.cert.clone()],
+                PrivateKeyDer::Pkcs8(self.key.clone_key()),
+            )
+            .map_err(tls_error)?;
+        client.resumption = rustls::client::Resumption::disabled();
+        Ok(Arc::new(client))
+    }
+}
+
+fn tls_error(err: Error) -> CryptoError {
+    CryptoError::Key(format!("Could not set up TLS: {}", err))
+}
+
+/// Identity key in certificate, None if it is not a certificate of Ed25519 key
+pub fn certificate_key(cert: &CertificateDer<'_>) -> Option<[u8; PUBLIC_KEY_SIZE]> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    let spki = cert.subject_public_key_info();
+    let key: [u8; PUBLIC_KEY_SIZE] = spki
+        .get(spki.len().checked_sub(PUBLIC_KEY_SIZE)?..)?
+        .try_into()
+        .ok()?;
+    (public_key_info(&key) == *spki).then_some(key)
+}
+
+/// Self-signed X.509 v3 certificate of identity key that never expires
+fn certificate(identity: &Identity) -> CertificateDer<'static> {
+    let name = der(
+        0x30,
+        &der(
+            0x31,
+            &der(
+                0x30,
+                &[COMMON_NAME, &der(0x0c, SERVER_NAME.as_bytes())].concat(),
+            ),
+        ),
+    );
+    let validity = der(
+        0x30,
+        &[der(0x17, b"700101000000Z"), der(0x18, b"99991231235959Z")].concat(),
+    );
+    let tbs = der(
+        0x30,
+        &[
+            // version 3, serial number 1
+            &[0xa0, 0x03, 0x02, 0x01, 0x02, 0x02, 0x01, 0x01],
+            ED25519,
+            &name,
+            &validity,
+            &name,
+            &public_key_info(&identity.public_key()),
+        ]
+        .concat(),
+    );
+    let signature = der(0x03, &[&[0][..], &identity.sign(&tbs)].concat());
+    CertificateDer::from(der(0x30, &[&tbs, ED25519, &signature].concat()))
+}
+
+/// PKCS#8 v1 of identity key
+fn private_key(identity: &Identity) -> PrivatePkcs8KeyDer<'static> {
+    let seed = der(0x04, &der(0x04, &identity.seed()));
+    PrivatePkcs8KeyDer::from(der(
+        0x30,
+        &[&[0x02, 0x01, 0x00][..], ED25519, &seed].concat(),
+    ))
+}
+
+fn public_key_info(key: &[u8; PUBLIC_KEY_SIZE]) -> Vec<u8> {
+    der(
+        0x30,
+        &[ED25519, &der(0x03, &[&[0][..], key].concat())].concat(),
+    )
+}
+
+/// DER element. Certificate parts are short, so two length bytes are enough
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let len = content.len();
+    let mut encoded = vec![tag];
+    match len {
+        0..=0x7f => encoded.push(len as u8),
+        0x80..=0xff => encoded.extend([0x81, len as u8]),
+        _ => encoded.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+    encoded.extend(content);
+    encoded
+}
+
+/// Accepts server only if it has the identity key we paired with
+#[derive(Debug)]
+struct PinnedKey {
+    key: [u8; PUBLIC_KEY_SIZE],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinnedKey {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        match certificate_key(end_entity) {
+            Some(key) if key == self.key => Ok(ServerCertVerified::assertion()),
+            Some(_) => Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            )),
+            None => Err(Error::InvalidCertificate(CertificateError::BadEncoding)),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
+
+/// Requires client certificate of some identity key. Handshake proves the
+/// client holds the key, which peer it is the engine decides
+#[derive(Debug)]
+struct AnyKey {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for AnyKey {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        certificate_key(end_entity)
+            .map(|_| ClientCertVerified::assertion())
+            .ok_or(Error::InvalidCertificate(CertificateError::BadEncoding))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ED25519]
+    }
+}
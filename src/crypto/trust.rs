@@ -0,0 +1,169 @@
+//! Peers the user has paired with
+//!
+//! Kept as JSON in data directory. Peer is trusted only while both its
+//! device ID and identity key match the ones confirmed at pairing.
+
+use std::env;
+use std::path::Path;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::fingerprint;
+use super::CryptoError;
+use super::PUBLIC_KEY_SIZE;
+use crate::utils::data_dir;
+use crate::utils::load_json;
+use crate::utils::save_json;
+
+/// Env variable with path to trusted peers file
+const TRUSTED_PEERS_ENV: &str = "COPYXROSS_TRUSTED_PEERS";
+const TRUSTED_PEERS_FILE: &str = "trusted_peers.json";
+/// Set to `0` to exchange data with peers that are not paired
+const REQUIRE_PAIRING_ENV: &str = "COPYXROSS_REQUIRE_PAIRING";
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct TrustedPeer {
+    pub device_id: String,
+    /// Name at the time of pairing
+    pub name: String,
+    /// Base64 encoded identity key
+    pub key: String,
+    /// RFC 3339 time of pairing
+    pub paired_at: String,
+}
+
+impl TrustedPeer {
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&BASE64.decode(&self.key).unwrap_or_default())
+    }
+}
+
+/// Trusted peers, saved on every change. Store without path lives only in memory
+#[derive(Debug, Default)]
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    peers: Vec<TrustedPeer>,
+}
+
+impl TrustStore {
+    /// Store at `$COPYXROSS_TRUSTED_PEERS` or in data directory
+    pub fn from_env() -> Result<Self, CryptoError> {
+        let path = match env::var_os(TRUSTED_PEERS_ENV) {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => data_dir().join(TRUSTED_PEERS_FILE),
+        };
+        TrustStore::load(&path)
+    }
+
+    /// Loads store from file. Missing file is an empty store
+    pub fn load(path: &Path) -> Result<Self, CryptoError> {
+        let peers = load_json(path).map_err(|err| {
+            CryptoError::Key(format!(
+                "Could not read trusted peers from {:?}: {}",
+                path, err
+            ))
+        })?;
+        Ok(TrustStore {
+            path: Some(path.to_path_buf()),
+            peers,
+        })
+    }
+
+    pub fn peers(&self) -> &[TrustedPeer] {
+        &self.peers
+    }
+
+    /// Old peers without device ID or identity key can not be trusted
+    pub fn is_trusted(&self, device_id: &str, key: &[u8]) -> bool {
+        if device_id.is_empty() || key.len() != PUBLIC_KEY_SIZE {
+            return false;
+        }
+        let key = BASE64.encode(key);
+        self.peers
+            .iter()
+            .any(|peer| peer.device_id == device_id && peer.key == key)
+    }
+
+    /// Remembers peer, replacing earlier pairing with the same device
+    pub fn trust(&mut self, device_id: &str, name: &str, key: &[u8]) -> Result<(), CryptoError> {
+        self.peers.retain(|peer| peer.device_id != device_id);
+        self.peers.push(TrustedPeer {
+            device_id: device_id.to_string(),
+            name: name.to_string(),
+            key: BASE64.encode(key),
+            paired_at: chrono::Local::now().to_rfc3339(),
+        });
+        self.save()
+    }
+
+    /// Forgets peer given by device ID or name. Returns false if it was not paired
+    pub fn forget(&mut self, peer: &str) -> Result<bool, CryptoError> {
+        let before = self.peers.len();
+        self.peers
+            .retain(|p| p.device_id != peer && !p.name.eq_ignore_ascii_case(peer));
+        if self.peers.len() == before {
+            return Ok(false);
+        }
+        self.save().map(|_| true)
+    }
+
+    fn save(&self) -> Result<(), CryptoError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        save_json(path, &self.peers).map_err(|err| {
+            CryptoError::Key(format!(
+                "Could not save trusted peers to {:?}: {}",
+                path, err
+            ))
+        })
+    }
+}
+
+/// Data is exchanged only with paired peers unless turned off
+pub fn pairing_required() -> bool {
+    !matches!(
+        env::var(REQUIRE_PAIRING_ENV).as_deref(),
+        Ok("0" | "false" | "no")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_trust_store_persists() {
+        let path = env::temp_dir().join(format!("copyxross-trust-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let key = [7; PUBLIC_KEY_SIZE];
+
+        let mut store = TrustStore::load(&path).unwrap();
+        assert!(store.peers().is_empty());
+        store.trust("laptop-id", "laptop", &key).unwrap();
+        // pairing again replaces the old key
+        store
+            .trust("laptop-id", "laptop", &[8; PUBLIC_KEY_SIZE])
+            .unwrap();
+        store.trust("desktop-id", "desktop", &key).unwrap();
+
+        let mut store = TrustStore::load(&path).unwrap();
+        assert_eq!(store.peers().len(), 2);
+        assert!(store.is_trusted("laptop-id", &[8; PUBLIC_KEY_SIZE]));
+        assert!(!store.is_trusted("laptop-id", &key));
+        assert!(!store.is_trusted("", &key));
+        assert!(!store.is_trusted("desktop-id", &key[..16]));
+
+        assert_eq!(store.forget("Desktop"), Ok(true));
+        assert_eq!(store.forget("desktop-id"), Ok(false));
+        assert!(!TrustStore::load(&path)
+            .unwrap()
+            .is_trusted("desktop-id", &key));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,65 @@
+//! Headless mode for machines without a desktop (servers, systemd services).
+//!
+//! Peers are only logged, actions go through control socket.
+
+use std::sync::mpsc::Receiver;
+
+use copyxross_core::clipboard::new_clipboard;
+use copyxross_core::crypto::pairing_required;
+use copyxross_core::engine::AuditLog;
+use copyxross_core::engine::ConsentStore;
+use copyxross_core::engine::Engine;
+use copyxross_core::engine::EngineHandle;
+use copyxross_core::engine::LogPeerMenu;
+use copyxross_core::engine::SyncMessage;
+use copyxross_core::engine::SystemClock;
+use copyxross_core::utils::log_into_file;
+
+use crate::transport;
+use crate::trust;
+use crate::wait_for_network;
+
+pub fn run(handle: EngineHandle, c_receiver: Receiver<SyncMessage>) {
+    let _ = log_into_file("Running as daemon");
+
+    // SIGINT/SIGTERM, so peers get our BYE message when service is stopped
+    if let Err(err) = ctrlc::set_handler(move || handle.stop()) {
+        let _ = log_into_file(format!("Failed to set signal handler: {:?}", err).as_str());
+    }
+
+    if !wait_for_network(&c_receiver) {
+        return;
+    }
+
+    let cp = match new_clipboard() {
+        Ok(cp) => cp,
+        Err(err) => {
+            let _ = log_into_file(format!("CLIPBOARD ERR: {:?}", err).as_str());
+            return;
+        }
+    };
+
+    let setup = trust().and_then(|trust| Ok((transport(&trust.0)?, trust)));
+    let ((transport, peer_data), (identity, trust)) = match setup {
+        Ok(setup) => setup,
+        Err(err) => {
+            let _ = log_into_file(format!("{:?}", err).as_str());
+            return;
+        }
+    };
+    let consent = match ConsentStore::from_env() {
+        Ok(consent) => consent,
+        Err(err) => {
+            let _ = log_into_file(err.as_str());
+            return;
+        }
+    };
+    let mut engine = Engine::new(cp, transport, LogPeerMenu, SystemClock, peer_data)
+        .with_identity(identity, trust, pairing_required())
+        .with_consent(consent, AuditLog::from_env());
+    if let Err(err) = engine.start() {
+        let _ = log_into_file(format!("{:?}", err).as_str());
+        return;
+    }
+    engine.run(&c_receiver);
+}
@@ -0,0 +1,129 @@
+//! Largest messages accepted from peers
+//!
+//! Chunk lengths come from the peer, so each kind of data has its own upper
+//! bound, checked before any of it is read. Sizes can be changed with env
+//! variables, in bytes or with `K`, `M` or `G` suffix (`COPYXROSS_MAX_TEXT=16M`).
+
+use std::env;
+
+use crate::utils::format_bytes_size;
+use crate::utils::log_into_file;
+
+use super::ParseErrors;
+
+const MAX_CONTROL_ENV: &str = "COPYXROSS_MAX_CONTROL";
+const MAX_TEXT_ENV: &str = "COPYXROSS_MAX_TEXT";
+const MAX_IMAGE_ENV: &str = "COPYXROSS_MAX_IMAGE";
+const MAX_FILE_ENV: &str = "COPYXROSS_MAX_FILE";
+const MAX_TREE_ENTRIES_ENV: &str = "COPYXROSS_MAX_TREE_ENTRIES";
+/// Names, types, versions and other short fields
+pub const MAX_FIELD_LEN: usize = 4096;
+/// Html, rtf, plain text and image of one copy need only a few
+pub const MAX_REPRESENTATIONS: usize = 16;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Limits {
+    /// Xcon, Xacn, Xcpy and Xdis
+    pub control: usize,
+    /// Text, html or rtf, held in memory
+    pub text: usize,
+    /// Encoded image, held in memory
+    pub image: usize,
+    /// File or tree of files, spooled to disk. Without spool directory files
+    /// are held in memory and limited like images. Length of the whole
+    /// message is u32, so nothing above 4 GiB can be sent anyway
+    pub file: usize,
+    /// Files and directories in one tree
+    pub tree_entries: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            control: 64 * 1024,
+            text: 64 * 1024 * 1024,
+            image: 256 * 1024 * 1024,
+            // whole message length is u32
+            file: u32::MAX as usize,
+            tree_entries: 100_000,
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        let default = Limits::default();
+        Limits {
+            control: env_size(MAX_CONTROL_ENV, default.control),
+            text: env_size(MAX_TEXT_ENV, default.text),
+            image: env_size(MAX_IMAGE_ENV, default.image),
+            file: env_size(MAX_FILE_ENV, default.file),
+            tree_entries: env_size(MAX_TREE_ENTRIES_ENV, default.tree_entries),
+        }
+    }
+
+    /// Longest message of any kind. Control limit covers chunk headers
+    pub fn message(&self) -> usize {
+        self.text
+            .max(self.image)
+            .max(self.file)
+            .saturating_add(self.control)
+    }
+
+    /// Files kept in memory can not be bigger than images
+    pub fn file_in_memory(&self) -> usize {
+        self.file.min(self.image)
+    }
+}
+
+/// Fails with TooBig if `len` exceeds `limit`
+pub fn check_limit(what: &str, len: usize, limit: usize) -> Result<(), ParseErrors> {
+    if len > limit {
+        return Err(ParseErrors::TooBig(format!(
+            "{} of {} exceeds limit of {}",
+            what,
+            format_bytes_size(len),
+            format_bytes_size(limit)
+        )));
+    }
+    Ok(())
+}
+
+fn env_size(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => parse_size(&value).unwrap_or_else(|| {
+            let _ = log_into_file(format!("Invalid {}: {:?}", name, value).as_str());
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// `512`, `64K`, `16M` or `1G`
+fn parse_size(value: &str) -> Option<usize> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last()? {
+        (i, 'k' | 'K') => (&value[..i], 1 << 10),
+        (i, 'm' | 'M') => (&value[..i], 1 << 20),
+        (i, 'g' | 'G') => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    number.trim().parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size(" 64K"), Some(64 * 1024));
+        assert_eq!(parse_size("16m"), Some(16 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        for value in ["", "M", "-1", "1.5M", "ten"] {
+            assert_eq!(parse_size(value), None, "{}", value);
+        }
+        assert!(Limits::default().message() > u32::MAX as usize);
+    }
+}
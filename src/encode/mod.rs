@@ -79,57 +79,224 @@
 //!     </tbody>
 //! </table>
 //!
+//! Chunks with lowercase first letter (`xmta`) are optional: parsers skip
+//! the ones they do not know, while unknown uppercase ones fail the message.
+//! Optional chunks may be sent to peers that speak version 3 or newer.
+//!
+//! `XVER` holds version the message is encoded with. Since version 2 `XVER`
+//! of Xcon/Xacn also carries min and max versions the sender supports, so
+//! peers settle on the highest common one and encode everything with it.
+//!
+//! Since version 4 `XPST` may hold `XMUL` chunk with several representations
+//! of one copy (html, rtf and plain text of the same selection), the most
+//! preferred first. Older peers get only the first representation they know.
+//!
+//! Since version 5 images travel in `XIMG` chunk with format (`XFMT`),
+//! width and height (`XDIM`) and encoded image (`XDAT`). Older peers
+//! receive them as `image.<ext>` file.
+//!
+//! Since version 6 several files and directories travel in `XTRE` chunk.
+//! Each `XENT` entry has relative path (`XPTH`), type `DIR` or `FILE`
+//! (`XTYP`), permission bits (`XMOD`) and for files contents (`XDAT`).
+//! File contents are streamed from disk and spooled there on arrival. Older
+//! peers get a single file as `XFIL`, bigger trees can not be sent to them.
+//!
+//! Since version 7 message may be sealed with passphrase shared by peers.
+//! `XCOP` then holds `XVER` and `XENC` with salt (`XSLT`), nonce prefix
+//! (`XNON`) and the whole regular message, sealed in records (`XDAT`).
+//!
+//! Since version 8 Xcon/Xacn peer data ends with Ed25519 identity key of the
+//! install, and `XPAR` messages carry steps of pairing exchange.
+//!
+//! Since version 9 Xcon, Xacn and Xdis end with `xsig` chunk, signature
+//! of the message made with identity key of the sender.
+//!
+//! First version left `XVER` chunk out of `XCOP` length, so message that
+//! starts with bare version 1 has its length corrected before it is parsed.
+//!
 
+mod limits;
 mod protocol;
+mod signed;
+mod stream;
 mod transferable;
 
+use crate::clipboard::ClipboardData;
+use crate::clipboard::EntryContent;
+use crate::clipboard::StringType;
+use crate::clipboard::TreeEntry;
+use crate::crypto::sealed_len;
+use crate::crypto::Cipher;
+use crate::crypto::CryptoError;
+use crate::crypto::OpenReader;
+use crate::crypto::SealWriter;
+use crate::crypto::NONCE_PREFIX_SIZE;
+use crate::crypto::SALT_SIZE;
 use crate::debug_println;
-use crate::{clipboard::ClipboardData, utils::format_bytes_size};
-use protocol::{
-    encode_chunks, encode_header, encode_size, read_data, read_header, read_header_expected,
-    read_size, Chunk, EncodeError, ReaderOffset,
-};
+use crate::network::MIN_PROTOCOL_VER;
+use crate::utils::format_bytes_size;
+use crate::utils::write_progress;
+use limits::check_limit;
+use protocol::{encode_chunks, encode_header, encode_size, is_critical, Chunk};
 
-pub use protocol::{HeaderType, MessageType, ParseErrors, PeerData};
+pub use limits::Limits;
+pub use protocol::{
+    Capabilities, EncodeError, HeaderType, MessageType, PairingMessage, PairingStep, ParseErrors,
+    PeerData, VersionRange,
+};
+pub use signed::{compose_signed_message, parse_signed_message, SignedBy};
+use std::fs::File;
+use std::io::Cursor;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
 use std::str::FromStr;
+pub use stream::{ChunkHeader, ChunkReader};
+use transferable::clipboard_pieces;
+use transferable::read_clipboard_data;
+use transferable::Piece;
 pub use transferable::Transferable;
 
+const FILE_BUFFER_SIZE: usize = 64 * 1024;
+/// `XCOP` header and `XVER` chunk with bare version
+pub const MESSAGE_HEAD_SIZE: usize = 20;
+/// `XVER` chunk with bare version, left out of `XCOP` length by version 1
+const LEGACY_XVER_LEN: usize = 12;
+/// First version with XMUL and RTF strings
+const MULTI_VER: u32 = 4;
+/// First version with XIMG
+const IMAGE_VER: u32 = 5;
+/// First version with XTRE
+const TREE_VER: u32 = 6;
+/// First version with XENC
+const SEAL_VER: u32 = 7;
+/// First version with XPAR and identity keys
+pub const PAIR_VER: u32 = 8;
+/// First version with signed Xcon, Xacn and Xdis
+pub const SIGN_VER: u32 = 9;
+
 pub fn parse_message(data: &[u8]) -> Result<MessageType, ParseErrors> {
-    let mut reader = ReaderOffset { offset: 0 };
-    read_header_expected(data, &mut reader, "XCOP")?;
-    let file_size = read_size(data, &mut reader)?;
+    read_message(data)
+}
+
+/// Parses message as it arrives from reader
+pub fn read_message<R: Read>(mut reader: R) -> Result<MessageType, ParseErrors> {
+    read_next_message(&mut reader, None)?.ok_or(ParseErrors::OutOfBounds)
+}
+
+/// Parses one message from reader, leaving it at the start of the next one.
+/// Returns None if reader ended before the message started.
+/// With spool directory received files are written there instead of memory
+pub fn read_next_message<R: Read>(
+    reader: &mut R,
+    spool_dir: Option<&Path>,
+) -> Result<Option<MessageType>, ParseErrors> {
+    read_limited_message(reader, spool_dir, &Limits::from_env(), None)
+}
+
+/// Same as [`read_next_message`], refusing messages over given limits.
+/// With cipher clipboard data is accepted only if sealed with it
+pub fn read_limited_message<R: Read>(
+    reader: &mut R,
+    spool_dir: Option<&Path>,
+    limits: &Limits,
+    cipher: Option<&Cipher>,
+) -> Result<Option<MessageType>, ParseErrors> {
+    let Some(mut head) = read_head(reader)? else {
+        return Ok(None);
+    };
+    if let Some(len) = message_len(&head) {
+        let len: u32 = len.try_into().map_err(|_| ParseErrors::OutOfBounds)?;
+        head[4..8].copy_from_slice(&len.to_be_bytes());
+    }
+    let mut chunks = ChunkReader::new(Cursor::new(head).chain(reader));
+    let file_size = match chunks.next_chunk()? {
+        Some(chunk) if chunk.header == HeaderType::Xcop.to_string() => chunk.len,
+        Some(chunk) => {
+            return Err(ParseErrors::UnknownHeader(format!(
+                "Expected header: XCOP. Received instead: {}",
+                chunk.header
+            )))
+        }
+        None => return Ok(None),
+    };
+    check_limit("Message", file_size, limits.message())?;
+    chunks.enter()?;
 
     debug_println!("Reading message. Size: {:?}", format_bytes_size(file_size));
 
-    while reader.offset <= data.len() {
-        let header = read_header(data, &mut reader)?;
-        let header = HeaderType::from_str(&header)?;
-        let size = read_size(data, &mut reader)?;
-        let data = read_data(data, &mut reader, size)?;
+    let msg = read_message_body(&mut chunks, spool_dir, limits, cipher)?;
+    // no reason to skip through gigabytes of padding after small message
+    if !matches!(msg, MessageType::Xpst(_)) {
+        check_limit("Control message", file_size, limits.control)?;
+    }
+    if let Err(err) = chunks.skip_to_end() {
+        discard(&msg);
+        return Err(err);
+    }
+    Ok(Some(msg))
+}
+
+fn read_message_body<R: Read>(
+    chunks: &mut ChunkReader<R>,
+    spool_dir: Option<&Path>,
+    limits: &Limits,
+    cipher: Option<&Cipher>,
+) -> Result<MessageType, ParseErrors> {
+    // messages without XVER come from the very first version
+    let mut versions = VersionRange::single(MIN_PROTOCOL_VER);
+    while let Some(chunk) = chunks.next_chunk()? {
+        let header = match HeaderType::from_str(&chunk.header) {
+            Ok(header) => header,
+            Err(_) if !is_critical(&chunk.header) => {
+                debug_println!("Skipping optional chunk: {}", chunk.header);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
 
         match header {
             HeaderType::Xver => {
-                // let data: [u8; 4] = data.as_slice().try_into().map_err(|err| {
-                //     println!("Could not parse version: {:?}", err);
-                //     ParseErrors::InvalidStructure
-                // })?;
+                versions = read_versions(&chunks.read_field()?)?;
             }
             HeaderType::Xacn => {
-                let data = data.as_slice();
-                let peer_d = PeerData::deserialize(data)?;
+                check_limit("Peer data", chunk.len, limits.control)?;
+                let data = chunks.read_data_to_vec()?;
+                let mut peer_d = PeerData::deserialize(&data)?;
+                peer_d.versions = versions;
                 return Ok(MessageType::Xacn(peer_d));
             }
             HeaderType::Xcon => {
-                let data = data.as_slice();
-                let peer_d = PeerData::deserialize(data)?;
+                check_limit("Peer data", chunk.len, limits.control)?;
+                let data = chunks.read_data_to_vec()?;
+                let mut peer_d = PeerData::deserialize(&data)?;
+                peer_d.versions = versions;
                 return Ok(MessageType::Xcon(peer_d));
             }
             HeaderType::Xcpy => return Ok(MessageType::Xcpy),
+            HeaderType::Xpst if cipher.is_some() => {
+                return Err(ParseErrors::Unauthenticated(
+                    "Clipboard data is not encrypted".to_string(),
+                ))
+            }
             HeaderType::Xpst => {
-                let decoded = ClipboardData::deserialize(data.as_slice())?;
+                chunks.enter()?;
+                let decoded = read_clipboard_data(chunks, spool_dir, limits)?;
                 return Ok(MessageType::Xpst(decoded));
             }
             HeaderType::Xdis => return Ok(MessageType::Xdis),
+            HeaderType::Xpar => {
+                let data = chunks.read_field()?;
+                return Ok(MessageType::Xpar(PairingMessage::deserialize(&data)?));
+            }
+            HeaderType::Xenc => {
+                let cipher = cipher.ok_or(ParseErrors::Unauthenticated(
+                    "Message is encrypted, but no passphrase is set".to_string(),
+                ))?;
+                chunks.enter()?;
+                return read_sealed_message(chunks, spool_dir, limits, cipher);
+            }
             HeaderType::Xcop => {
                 // already handled
                 continue;
@@ -139,43 +306,882 @@ pub fn parse_message(data: &[u8]) -> Result<MessageType, ParseErrors> {
     Ok(MessageType::NoMessage)
 }
 
+/// Reads [`MESSAGE_HEAD_SIZE`] bytes, every message is longer than that.
+/// Returns None if reader ended before the message started
+fn read_head<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, ParseErrors> {
+    let mut head = vec![0; MESSAGE_HEAD_SIZE];
+    let mut read = 0;
+    while read < head.len() {
+        match reader.read(&mut head[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(_) => return Err(ParseErrors::OutOfBounds),
+        }
+    }
+    head.truncate(read);
+    Ok(Some(head))
+}
+
+/// Length of message body after `XCOP` header, given the start of message.
+/// Messages of the first version get their `XVER` chunk counted in
+pub fn message_len(head: &[u8]) -> Option<usize> {
+    if head.get(..4)? != HeaderType::Xcop.to_string().as_bytes() {
+        return None;
+    }
+    let len = u32::from_be_bytes(head.get(4..8)?.try_into().ok()?) as usize;
+    let legacy = head.get(8..MESSAGE_HEAD_SIZE).is_some_and(|xver| {
+        xver[..4] == *HeaderType::Xver.to_string().as_bytes()
+            && xver[4..8] == 4u32.to_be_bytes()
+            && xver[8..] == MIN_PROTOCOL_VER.to_be_bytes()
+    });
+    match legacy {
+        true => len.checked_add(LEGACY_XVER_LEN),
+        false => Some(len),
+    }
+}
+
+/// Opens message sealed in XENC chunk, which has just been entered
+fn read_sealed_message<R: Read>(
+    chunks: &mut ChunkReader<R>,
+    spool_dir: Option<&Path>,
+    limits: &Limits,
+    cipher: &Cipher,
+) -> Result<MessageType, ParseErrors> {
+    chunks.expect_chunk("XSLT")?;
+    let salt: [u8; SALT_SIZE] = chunks
+        .read_field()?
+        .try_into()
+        .map_err(|_| ParseErrors::InvalidStructure)?;
+    chunks.expect_chunk("XNON")?;
+    let prefix: [u8; NONCE_PREFIX_SIZE] = chunks
+        .read_field()?
+        .try_into()
+        .map_err(|_| ParseErrors::InvalidStructure)?;
+    let len = chunks.expect_chunk("XDAT")?.len;
+    let mut opened = OpenReader::new(chunks.data_reader(), cipher, &salt, &prefix, len)
+        .map_err(|err| ParseErrors::Unauthenticated(format!("{:?}", err)))?;
+    // sealed message can not be sealed again. Reader is dyn, so the
+    // recursion does not produce ever longer reader types
+    let msg = read_limited_message(&mut (&mut opened as &mut dyn Read), spool_dir, limits, None);
+    // the rest has to be opened too, or message cut after the inner one would pass
+    let rest = opened.read(&mut [0; 1]);
+    if let Err(err) = opened.check() {
+        if let Ok(Some(msg)) = &msg {
+            discard(msg);
+        }
+        let reason = match err {
+            CryptoError::Replayed => "Message is too old or was replayed",
+            _ => "Could not decrypt message, passphrase differs",
+        };
+        return Err(ParseErrors::Unauthenticated(reason.to_string()));
+    }
+    let msg = msg?.ok_or(ParseErrors::OutOfBounds)?;
+    if !matches!(rest, Ok(0)) {
+        discard(&msg);
+        return Err(ParseErrors::InvalidStructure);
+    }
+    Ok(msg)
+}
+
+/// XVER holds version the message is encoded with. Since version 2
+/// Xcon/Xacn also append min and max versions the sender supports
+fn read_versions(data: &[u8]) -> Result<VersionRange, ParseErrors> {
+    let read_u32 = |offset: usize| -> Result<u32, ParseErrors> {
+        let bytes = data
+            .get(offset..offset + 4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ParseErrors::OutOfBounds)?;
+        Ok(u32::from_be_bytes(bytes))
+    };
+    let ver = read_u32(0)?;
+    if data.len() < 12 {
+        return Ok(VersionRange::single(ver));
+    }
+    Ok(VersionRange {
+        min: read_u32(4)?,
+        max: read_u32(8)?,
+    })
+}
+
+fn encode_versions(message: &MessageType, protocol_ver: u32) -> Vec<u8> {
+    let mut data = u32::to_be_bytes(protocol_ver).to_vec();
+    if let (MessageType::Xcon(peer) | MessageType::Xacn(peer), 2..) = (message, protocol_ver) {
+        data.extend(u32::to_be_bytes(peer.versions.min));
+        data.extend(u32::to_be_bytes(peer.versions.max));
+    }
+    data
+}
+
+/// Removes spooled files of message that will not be used
+pub fn discard(msg: &MessageType) {
+    if let MessageType::Xpst(data) = msg {
+        for path in data.file_paths() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Payload as peer of given version understands it. Returns None if payload
+/// can be sent as it is
+fn legacy_payload(
+    data: &ClipboardData,
+    protocol_ver: u32,
+) -> Result<Option<ClipboardData>, EncodeError> {
+    let downgrade = |rep: &ClipboardData| -> Result<ClipboardData, EncodeError> {
+        Ok(legacy_payload(rep, protocol_ver)?.unwrap_or_else(|| rep.clone()))
+    };
+    match data {
+        ClipboardData::Multi(reps) if protocol_ver < MULTI_VER => {
+            let rep = reps
+                .iter()
+                .find(|rep| legacy_payload(rep, protocol_ver).is_ok())
+                .ok_or(EncodeError::Unsupported)?;
+            downgrade(rep).map(Some)
+        }
+        ClipboardData::Multi(reps)
+            if reps
+                .iter()
+                .any(|rep| !matches!(legacy_payload(rep, protocol_ver), Ok(None))) =>
+        {
+            let reps = reps.iter().map(downgrade).collect::<Result<_, _>>()?;
+            Ok(Some(ClipboardData::Multi(reps)))
+        }
+        ClipboardData::String((StringType::Rtf, _)) if protocol_ver < MULTI_VER => {
+            Err(EncodeError::Unsupported)
+        }
+        // older peers got images as files
+        ClipboardData::Image(image) if protocol_ver < IMAGE_VER => Ok(Some(ClipboardData::File((
+            image.file_name(),
+            image.data.clone(),
+        )))),
+        // single file still goes the old way, whole trees can not
+        ClipboardData::Files(tree) if protocol_ver < TREE_VER => match tree.entries.as_slice() {
+            [TreeEntry {
+                path,
+                content: EntryContent::Path(src),
+                ..
+            }] => Ok(Some(ClipboardData::FileRef((path.clone(), src.clone())))),
+            [TreeEntry {
+                path,
+                content: EntryContent::Data(data),
+                ..
+            }] => Ok(Some(ClipboardData::File((path.clone(), data.clone())))),
+            _ => Err(EncodeError::Unsupported),
+        },
+        _ => Ok(None),
+    }
+}
+
 pub fn compose_message(message: &MessageType, protocol_ver: u32) -> Result<Vec<u8>, EncodeError> {
     let mut result: Vec<u8> = vec![];
+    write_message(message, protocol_ver, &mut result)?;
+    Ok(result)
+}
+
+/// Encodes message into writer. Files referenced by path are streamed from
+/// disk, so their size is limited only by 4 GiB message length.
+/// Output is downgraded to given protocol version
+pub fn write_message(
+    message: &MessageType,
+    protocol_ver: u32,
+    out: &mut impl Write,
+) -> Result<(), EncodeError> {
+    write_pieces(message_pieces(message, protocol_ver)?, out)
+}
+
+/// Encodes message sealed with cipher, so only peers with the same
+/// passphrase can read it. Message is encoded as usual and sealed into
+/// `XDAT` of `XENC` chunk, next to salt (`XSLT`) and nonce prefix (`XNON`)
+pub fn write_sealed_message(
+    message: &MessageType,
+    protocol_ver: u32,
+    cipher: &Cipher,
+    out: &mut impl Write,
+) -> Result<(), EncodeError> {
+    if protocol_ver < SEAL_VER {
+        return Err(EncodeError::Unsupported);
+    }
+    let pieces = message_pieces(message, protocol_ver)?;
+    let sealed_len = sealed_len(pieces.iter().map(Piece::len).sum());
+    let (salt, prefix) = (cipher.salt(), Cipher::nonce_prefix());
+
+    let mut xenc: Vec<u8> = vec![];
+    let chunks = vec![Chunk::new("XSLT", &salt), Chunk::new("XNON", &prefix)];
+    encode_chunks(&chunks, &mut xenc)?;
+    encode_header("XDAT", &mut xenc);
+    encode_size(sealed_len, &mut xenc)?;
+    let mut body: Vec<u8> = vec![];
+    let ver_data = encode_versions(message, protocol_ver);
+    encode_chunks(
+        &vec![Chunk::new(HeaderType::Xver.to_string(), &ver_data)],
+        &mut body,
+    )?;
+    encode_header(HeaderType::Xenc.to_string(), &mut body);
+    encode_size(xenc.len() + sealed_len, &mut body)?;
+    body.extend(xenc);
+    let mut head: Vec<u8> = vec![];
+    encode_header(HeaderType::Xcop.to_string(), &mut head);
+    encode_size(body.len() + sealed_len, &mut head)?;
+    head.extend(body);
+    out.write_all(&head)
+        .map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+
+    let mut sealed = SealWriter::new(out, cipher, &prefix)
+        .map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+    write_pieces(pieces, &mut sealed)?;
+    sealed
+        .finish()
+        .map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+    Ok(())
+}
+
+/// Whole encoded message, files are left on disk
+fn message_pieces(message: &MessageType, protocol_ver: u32) -> Result<Vec<Piece>, EncodeError> {
+    if let (MessageType::Xpst(data), ..TREE_VER) = (message, protocol_ver) {
+        if let Some(legacy) = legacy_payload(data, protocol_ver)? {
+            return message_pieces(&MessageType::Xpst(legacy), protocol_ver);
+        }
+    }
     let mut header: &str = "";
-    let mut bytes: Vec<u8> = vec![];
+    let mut pieces: Vec<Piece> = vec![];
     match message {
         MessageType::Xcon(_data) => {
             header = HeaderType::Xcon.to_string();
-            bytes = _data.serialize()?;
+            pieces.push(Piece::Bytes(peer_bytes(_data, protocol_ver)?));
         }
         MessageType::Xacn(_data) => {
             header = HeaderType::Xacn.to_string();
-            bytes = _data.serialize()?;
+            pieces.push(Piece::Bytes(peer_bytes(_data, protocol_ver)?));
         }
         MessageType::Xcpy => {
             header = HeaderType::Xcpy.to_string();
         }
         MessageType::Xpst(data) => {
             header = HeaderType::Xpst.to_string();
-            bytes = data.serialize()?;
+            pieces = clipboard_pieces(data)?;
         }
         MessageType::Xdis => {
             header = HeaderType::Xdis.to_string();
         }
+        MessageType::Xpar(_) if protocol_ver < PAIR_VER => return Err(EncodeError::Unsupported),
+        MessageType::Xpar(data) => {
+            header = HeaderType::Xpar.to_string();
+            pieces.push(Piece::Bytes(data.serialize()?));
+        }
         MessageType::NoMessage => {}
     }
-    // signature chunk
-    encode_header(HeaderType::Xcop.to_string(), &mut result);
-    encode_size(bytes.len() + 4 + 4, &mut result)?;
+    let pieces_len: usize = pieces.iter().map(Piece::len).sum();
     let ver_header = HeaderType::Xver.to_string();
-    let ver_data = u32::to_be_bytes(protocol_ver);
-    let chunks: Vec<Chunk> = vec![
-        // protocol_ver chunk
-        Chunk::new(ver_header, &ver_data),
-        // main message
-        Chunk::new(header, &bytes),
+    let ver_data = encode_versions(message, protocol_ver);
+    let mut body: Vec<u8> = vec![];
+    // protocol_ver chunk
+    encode_chunks(&vec![Chunk::new(ver_header, &ver_data)], &mut body)?;
+    // main message, its data is written piece by piece afterwards
+    encode_header(header, &mut body);
+    encode_size(pieces_len, &mut body)?;
+
+    // signature chunk, its length covers the rest of the message. First
+    // version did not count XVER in
+    let legacy = match protocol_ver {
+        MIN_PROTOCOL_VER => LEGACY_XVER_LEN,
+        _ => 0,
+    };
+    let mut head: Vec<u8> = vec![];
+    encode_header(HeaderType::Xcop.to_string(), &mut head);
+    encode_size(body.len() + pieces_len - legacy, &mut head)?;
+    head.extend(body);
+    pieces.insert(0, Piece::Bytes(head));
+    Ok(pieces)
+}
+
+/// Peer data as peer of given version expects it, first version knew only
+/// the name
+fn peer_bytes(peer: &PeerData, protocol_ver: u32) -> Result<Vec<u8>, EncodeError> {
+    let mut encoded = peer.serialize()?;
+    if protocol_ver == MIN_PROTOCOL_VER {
+        encoded.truncate(1 + peer.peer_name.len());
+    }
+    Ok(encoded)
+}
+
+/// Writes pieces, streaming files from disk
+fn write_pieces(pieces: Vec<Piece>, out: &mut impl Write) -> Result<(), EncodeError> {
+    let mut result: Vec<u8> = vec![];
+    for piece in pieces {
+        match piece {
+            Piece::Bytes(bytes) => result.extend(bytes),
+            Piece::File((path, len)) => {
+                out.write_all(&result)
+                    .map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+                result.clear();
+                let file =
+                    File::open(&path).map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+                copy_file_data(file, len, out)?;
+            }
+        }
+    }
+    out.write_all(&result)
+        .map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+    Ok(())
+}
+
+/// Copies exactly `len` bytes of file into writer
+fn copy_file_data(file: File, len: usize, out: &mut impl Write) -> Result<(), EncodeError> {
+    let mut file = file.take(len as u64);
+    let mut buffer = vec![0; FILE_BUFFER_SIZE.min(len)];
+    let mut copied = 0;
+    while copied < len {
+        let n = file
+            .read(&mut buffer)
+            .map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+        if n == 0 {
+            return Err(EncodeError::Io(format!(
+                "File changed while sending: got {} of {} bytes",
+                copied, len
+            )));
+        }
+        out.write_all(&buffer[..n])
+            .map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+        copied += n;
+        write_progress(copied, len);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::FileTree;
+    use crate::clipboard::Image;
+    use crate::clipboard::ImageFormat;
+    use crate::clipboard::StringType;
+    use crate::network::PROTOCOL_VER;
+    use crate::utils::Rand;
+    use std::net::IpAddr;
+    use std::net::Ipv4Addr;
+
+    /// Builds message by hand, so chunks unknown to this version can be put in
+    fn container(header: &str, chunks: Vec<Chunk>) -> Vec<u8> {
+        let mut body = vec![];
+        encode_chunks(&chunks, &mut body).unwrap();
+        let mut out = vec![];
+        Chunk::new(header, &body).encode_chunk(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_unknown_optional_chunks_are_skipped() {
+        let xstr = container(
+            "XSTR",
+            vec![
+                Chunk::new("XTYP", b"UTF8P"),
+                Chunk::new("xenc", b"utf-8"),
+                Chunk::new("XDAT", b"hi"),
+                Chunk::new("xsrc", b"editor"),
+            ],
+        );
+        let mut xpst = vec![];
+        Chunk::new("xmta", b"{}").encode_chunk(&mut xpst).unwrap();
+        xpst.extend(&xstr);
+        let ver = 3u32.to_be_bytes();
+
+        let msg = container(
+            "XCOP",
+            vec![
+                Chunk::new("XVER", &ver),
+                Chunk::new("xfut", b"future stuff"),
+                Chunk::new("XPST", &xpst),
+            ],
+        );
+        assert_eq!(
+            parse_message(&msg).unwrap(),
+            MessageType::Xpst(ClipboardData::String((
+                StringType::Utf8Plain,
+                b"hi".to_vec()
+            )))
+        );
+
+        // unknown critical chunk still fails the message
+        let msg = container(
+            "XCOP",
+            vec![
+                Chunk::new("XVER", &ver),
+                Chunk::new("XFUT", b"future stuff"),
+                Chunk::new("XPST", &xpst),
+            ],
+        );
+        assert!(matches!(
+            parse_message(&msg),
+            Err(ParseErrors::UnknownHeader(_))
+        ));
+    }
+
+    #[test]
+    fn test_received_names_are_sanitized() {
+        let file = ClipboardData::File(("../../.bashrc".to_string(), b"rm -rf ~".to_vec()));
+        let msg = compose_message(&MessageType::Xpst(file), PROTOCOL_VER).unwrap();
+        assert_eq!(
+            parse_message(&msg).unwrap(),
+            MessageType::Xpst(ClipboardData::File((
+                ".bashrc".to_string(),
+                b"rm -rf ~".to_vec()
+            )))
+        );
+        let file = ClipboardData::File(("CON".to_string(), vec![]));
+        let msg = compose_message(&MessageType::Xpst(file), PROTOCOL_VER).unwrap();
+        assert!(parse_message(&msg).is_err());
+    }
+
+    #[test]
+    fn test_multi_representations_and_downgrade() {
+        let html = ClipboardData::String((StringType::Html, b"<b>hi</b>".to_vec()));
+        let rtf = ClipboardData::String((StringType::Rtf, br"{\rtf1 \b hi}".to_vec()));
+        let plain = ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec()));
+        let multi = MessageType::Xpst(ClipboardData::Multi(vec![rtf.clone(), html.clone(), plain]));
+        let msg = compose_message(&multi, MULTI_VER).unwrap();
+        assert_eq!(parse_message(&msg).unwrap(), multi);
+
+        // older peers get the first representation they know
+        let msg = compose_message(&multi, MULTI_VER - 1).unwrap();
+        assert_eq!(parse_message(&msg).unwrap(), MessageType::Xpst(html));
+        assert!(matches!(
+            compose_message(&MessageType::Xpst(rtf), 1),
+            Err(EncodeError::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn test_image_roundtrip_and_downgrade() {
+        let image = ClipboardData::Image(Image {
+            format: ImageFormat::Tiff,
+            width: 640,
+            height: 480,
+            data: vec![b'I', b'I', 42, 0],
+        });
+        let msg = MessageType::Xpst(image.clone());
+        assert_eq!(
+            parse_message(&compose_message(&msg, IMAGE_VER).unwrap()).unwrap(),
+            msg
+        );
+
+        // older peers get the image as file, also inside XMUL
+        let file = ClipboardData::File(("image.tiff".to_string(), vec![b'I', b'I', 42, 0]));
+        let html = ClipboardData::String((StringType::Html, b"<img>".to_vec()));
+        let multi = MessageType::Xpst(ClipboardData::Multi(vec![image, html.clone()]));
+        assert_eq!(
+            parse_message(&compose_message(&multi, MULTI_VER).unwrap()).unwrap(),
+            MessageType::Xpst(ClipboardData::Multi(vec![file.clone(), html]))
+        );
+        assert_eq!(
+            parse_message(&compose_message(&multi, 1).unwrap()).unwrap(),
+            MessageType::Xpst(file)
+        );
+    }
+
+    #[test]
+    fn test_tree_streaming_and_downgrade() {
+        let dir = std::env::temp_dir().join(format!("copyxross-xtre-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("spool")).unwrap();
+        let src = dir.join("a.bin");
+        std::fs::write(&src, [7u8; 1000]).unwrap();
+        let entry = |path: &str, content: EntryContent| TreeEntry {
+            path: path.to_string(),
+            mode: 0o644,
+            size: 0,
+            content,
+        };
+        let tree = ClipboardData::Files(FileTree {
+            entries: vec![
+                entry("docs", EntryContent::Dir),
+                entry("docs/a.bin", EntryContent::Path(src.clone())),
+                entry("docs/b.txt", EntryContent::Data(b"hi".to_vec())),
+            ],
+        });
+        let msg = MessageType::Xpst(tree.clone());
+        let mut stream = vec![];
+        write_message(&msg, TREE_VER, &mut stream).unwrap();
+        assert_eq!(compose_message(&msg, TREE_VER).unwrap(), stream);
+
+        let received = read_next_message(&mut stream.as_slice(), Some(&dir.join("spool")))
+            .unwrap()
+            .unwrap();
+        let MessageType::Xpst(ClipboardData::Files(received)) = received else {
+            panic!("Expected file tree, got {:?}", received);
+        };
+        let paths: Vec<&str> = received.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["docs", "docs/a.bin", "docs/b.txt"]);
+        assert_eq!(received.entries[1].size, 1000);
+        let spooled = received.file_paths();
+        assert_eq!(spooled.len(), 2);
+        assert_eq!(std::fs::read(&spooled[0]).unwrap(), [7u8; 1000]);
+
+        // truncated tree leaves nothing in spool
+        for path in spooled {
+            std::fs::remove_file(path).unwrap();
+        }
+        let cut = &stream[..stream.len() - 1];
+        assert!(read_next_message(&mut &cut[..], Some(&dir.join("spool"))).is_err());
+        assert_eq!(std::fs::read_dir(dir.join("spool")).unwrap().count(), 0);
+
+        // older peers get a single file, but not a directory
+        let single = ClipboardData::Files(FileTree {
+            entries: vec![entry("b.txt", EntryContent::Data(b"hi".to_vec()))],
+        });
+        assert_eq!(
+            parse_message(&compose_message(&MessageType::Xpst(single), IMAGE_VER).unwrap())
+                .unwrap(),
+            MessageType::Xpst(ClipboardData::File(("b.txt".to_string(), b"hi".to_vec())))
+        );
+        assert!(matches!(
+            compose_message(&msg, IMAGE_VER),
+            Err(EncodeError::Unsupported)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_sealed_messages() {
+        let dir = std::env::temp_dir().join(format!("copyxross-sealed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cipher = Cipher::from_passphrase("team passphrase", b"laptop key").unwrap();
+        let other = Cipher::from_passphrase("another team", b"laptop key").unwrap();
+        let limits = Limits::default();
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 249) as u8).collect();
+        let src = dir.join("a.bin");
+        std::fs::write(&src, &content).unwrap();
+        let msg = MessageType::Xpst(ClipboardData::FileRef(("a.bin".to_string(), src)));
+
+        let mut sealed = vec![];
+        write_sealed_message(&msg, PROTOCOL_VER, &cipher, &mut sealed).unwrap();
+        // nothing of the file is visible
+        assert!(!sealed.windows(64).any(|w| w == &content[1000..1064]));
+        let read = |data: &[u8], cipher: Option<&Cipher>| {
+            read_limited_message(&mut &data[..], Some(&dir), &limits, cipher)
+        };
+        let Ok(Some(MessageType::Xpst(ClipboardData::FileRef((_, path))))) =
+            read(&sealed, Some(&cipher))
+        else {
+            panic!("Expected spooled file");
+        };
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        std::fs::remove_file(path).unwrap();
+        // the same message does not open twice
+        assert!(matches!(
+            read(&sealed, Some(&cipher)),
+            Err(ParseErrors::Unauthenticated(reason)) if reason.contains("replayed")
+        ));
+
+        for cipher in [Some(&other), None] {
+            assert!(matches!(
+                read(&sealed, cipher),
+                Err(ParseErrors::Unauthenticated(_))
+            ));
+        }
+        let plain = compose_message(&msg, PROTOCOL_VER).unwrap();
+        assert!(matches!(
+            read(&plain, Some(&cipher)),
+            Err(ParseErrors::Unauthenticated(_))
+        ));
+        // discovery and requests are not sealed
+        let xcpy = compose_message(&MessageType::Xcpy, PROTOCOL_VER).unwrap();
+        assert_eq!(read(&xcpy, Some(&cipher)).unwrap(), Some(MessageType::Xcpy));
+
+        // cut off or altered message leaves nothing behind
+        let mut altered = sealed.clone();
+        let last = altered.len() - 1;
+        altered[last] ^= 1;
+        assert!(read(&altered, Some(&cipher)).is_err());
+        let mut cut = sealed.clone();
+        cut.truncate(sealed.len() - 10);
+        assert!(read(&cut, Some(&cipher)).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        assert!(matches!(
+            write_sealed_message(&msg, TREE_VER, &cipher, &mut vec![]),
+            Err(EncodeError::Unsupported)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_limits_are_enforced() {
+        let limits = Limits {
+            control: 64,
+            text: 16,
+            image: 16,
+            file: 1024,
+            tree_entries: 2,
+        };
+        let read = |msg: &MessageType| {
+            let msg = compose_message(msg, PROTOCOL_VER).unwrap();
+            read_limited_message(&mut msg.as_slice(), None, &limits, None)
+        };
+        let text = |len: usize| {
+            MessageType::Xpst(ClipboardData::String((
+                StringType::Utf8Plain,
+                vec![b'a'; len],
+            )))
+        };
+        assert!(read(&text(4)).unwrap().is_some());
+        assert!(matches!(read(&text(100)), Err(ParseErrors::TooBig(_))));
+        // without spool directory files are held in memory, so image limit applies
+        let file = ClipboardData::File(("a.bin".to_string(), vec![0; 100]));
+        assert!(matches!(
+            read(&MessageType::Xpst(file)),
+            Err(ParseErrors::TooBig(_))
+        ));
+        let tree = FileTree {
+            entries: (0..3)
+                .map(|i| TreeEntry {
+                    path: format!("dir{}", i),
+                    mode: 0,
+                    size: 0,
+                    content: EntryContent::Dir,
+                })
+                .collect(),
+        };
+        assert!(matches!(
+            read(&MessageType::Xpst(ClipboardData::Files(tree))),
+            Err(ParseErrors::TooBig(_))
+        ));
+
+        // small message padded with optional chunks
+        let padding = vec![0; 100];
+        let msg = container(
+            "XCOP",
+            vec![Chunk::new("xpad", &padding), Chunk::new("XCPY", &[])],
+        );
+        assert!(matches!(
+            read_limited_message(&mut msg.as_slice(), None, &limits, None),
+            Err(ParseErrors::TooBig(_))
+        ));
+        let name = vec![b'a'; limits::MAX_FIELD_LEN + 1];
+        let msg = container(
+            "XCOP",
+            vec![Chunk::new(
+                "XPST",
+                &container(
+                    "XFIL",
+                    vec![Chunk::new("XFME", &name), Chunk::new("XDAT", b"")],
+                ),
+            )],
+        );
+        assert!(matches!(parse_message(&msg), Err(ParseErrors::TooBig(_))));
+    }
+
+    #[test]
+    fn test_identity_and_pairing_messages() {
+        let peer = PeerData {
+            identity: vec![7; 32],
+            ..PeerData::new("office-pc".to_string(), "id".to_string())
+        };
+        let xcon = MessageType::Xcon(peer.clone());
+        assert_eq!(
+            parse_message(&compose_message(&xcon, PROTOCOL_VER).unwrap()).unwrap(),
+            xcon
+        );
+        // peer data of version 7 ends with capabilities
+        let old = &peer.serialize().unwrap()[..];
+        let old = PeerData::deserialize(&old[..old.len() - 33]).unwrap();
+        assert!(old.identity.is_empty());
+        assert_eq!(old.capabilities, peer.capabilities);
+
+        let xpar = MessageType::Xpar(PairingMessage {
+            step: PairingStep::Reveal,
+            session: [1; 16],
+            identity: [2; 32],
+            value: [3; 32],
+        });
+        let msg = compose_message(&xpar, PAIR_VER).unwrap();
+        assert_eq!(parse_message(&msg).unwrap(), xpar);
+        assert!(matches!(
+            compose_message(&xpar, PAIR_VER - 1),
+            Err(EncodeError::Unsupported)
+        ));
+        let mut bad = msg.clone();
+        // unknown step
+        bad[28] = 9;
+        assert!(parse_message(&bad).is_err());
+    }
+
+    #[test]
+    fn test_signed_datagrams() {
+        let identity = crate::crypto::Identity::generate();
+        let source = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        let xdis =
+            compose_signed_message(&MessageType::Xdis, SIGN_VER, &identity, source, 42).unwrap();
+        let (msg, signed) = parse_signed_message(&xdis, source).unwrap();
+        assert_eq!(msg, MessageType::Xdis);
+        let signed = signed.unwrap();
+        assert_eq!((signed.key, signed.timestamp), (identity.public_key(), 42));
+        // older peers skip the signature
+        assert_eq!(parse_message(&xdis).unwrap(), MessageType::Xdis);
+        let unsigned = compose_message(&MessageType::Xdis, SIGN_VER).unwrap();
+        assert_eq!(
+            parse_signed_message(&unsigned, source).unwrap(),
+            (MessageType::Xdis, None)
+        );
+
+        let xcon = MessageType::Xcon(PeerData::new("pc".to_string(), "id".to_string()));
+        let signed = compose_signed_message(&xcon, SIGN_VER, &identity, source, 42).unwrap();
+        // nor can it be sent from another address
+        let elsewhere = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 9));
+        assert!(parse_signed_message(&signed, elsewhere).is_err());
+        // any change of message, key, timestamp or nonce breaks the signature
+        for i in 8..signed.len() - 64 {
+            let mut bad = signed.clone();
+            bad[i] ^= 1;
+            assert!(
+                !matches!(parse_signed_message(&bad, source), Ok((_, Some(_)))),
+                "byte {} is not signed",
+                i
+            );
+        }
+        let mut cut = signed.clone();
+        cut.truncate(cut.len() - 1);
+        assert!(parse_signed_message(&cut, source).is_err());
+        assert!(matches!(
+            compose_signed_message(&xcon, SIGN_VER - 1, &identity, source, 42),
+            Err(EncodeError::Unsupported)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_input_does_not_panic() {
+        let peer = PeerData {
+            identity: vec![7; 32],
+            ..PeerData::new("office-pc".to_string(), "id".to_string())
+        };
+        let image = Image {
+            format: ImageFormat::Png,
+            width: 1,
+            height: 1,
+            data: vec![1, 2, 3],
+        };
+        let tree = FileTree {
+            entries: vec![TreeEntry {
+                path: "a.txt".to_string(),
+                mode: 0o644,
+                size: 2,
+                content: EntryContent::Data(b"hi".to_vec()),
+            }],
+        };
+        let messages = [
+            MessageType::Xcon(peer.clone()),
+            MessageType::Xacn(peer),
+            MessageType::Xcpy,
+            MessageType::Xpst(ClipboardData::Multi(vec![
+                ClipboardData::String((StringType::Html, b"<b>hi</b>".to_vec())),
+                ClipboardData::Image(image),
+            ])),
+            MessageType::Xpst(ClipboardData::File(("a.txt".to_string(), b"hi".to_vec()))),
+            MessageType::Xpst(ClipboardData::Files(tree)),
+            MessageType::Xpar(PairingMessage {
+                step: PairingStep::Commit,
+                session: [1; 16],
+                identity: [2; 32],
+                value: [3; 32],
+            }),
+        ];
+        let mut rand = Rand::new(20);
+        for msg in &messages {
+            let msg = compose_message(msg, PROTOCOL_VER).unwrap();
+            for len in 0..msg.len() {
+                let _ = parse_message(&msg[..len]);
+            }
+            for _ in 0..500 {
+                let mut bad = msg.clone();
+                for _ in 0..rand.rand_range(1, 4) {
+                    let i = rand.rand() as usize % bad.len();
+                    bad[i] = rand.rand() as u8;
+                }
+                let _ = parse_message(&bad);
+                let _ = parse_signed_message(&bad, IpAddr::V4(Ipv4Addr::LOCALHOST));
+                let _ = ClipboardData::deserialize(&bad[8..]);
+                let _ = PeerData::deserialize(&bad[8..]);
+            }
+        }
+        for _ in 0..1000 {
+            let len = rand.rand_range(0, 64) as usize;
+            let junk: Vec<u8> = (0..len).map(|_| rand.rand() as u8).collect();
+            let _ = parse_message(&junk);
+            let _ = ClipboardData::deserialize(&junk);
+            let _ = PeerData::deserialize(&junk);
+        }
+    }
+    /// Messages as encoded by the first release
+    const FIRST_VERSION_MESSAGES: [&str; 6] = [
+        "58434f500000001258564552000000040000000158434f4e0000000a096f66666963652d7063",
+        "58434f500000000f5856455200000004000000015841434e00000007066c6170746f70",
+        "58434f50000000085856455200000004000000015843505900000000",
+        "58434f50000000085856455200000004000000015844495300000000",
+        "58434f500000002a5856455200000004000000015850535400000022585354520000001a\
+         58545950000000055554463850584441540000000568656c6c6f",
+        "58434f5000000027585645520000000400000001585053540000001f5846494c0000001758464d45\
+         00000005612e74787458444154000000026869",
     ];
-    encode_chunks(&chunks, &mut result)?;
 
-    Ok(result)
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_messages_of_first_version() {
+        let messages: Vec<Vec<u8>> = FIRST_VERSION_MESSAGES.iter().map(|m| from_hex(m)).collect();
+        let parsed: Vec<MessageType> = messages
+            .iter()
+            .map(|msg| parse_message(msg).unwrap())
+            .collect();
+        let MessageType::Xcon(peer) = &parsed[0] else {
+            panic!("{:?}", parsed[0]);
+        };
+        assert_eq!(peer.peer_name, "office-pc");
+        assert!(peer.identity.is_empty());
+        let MessageType::Xacn(peer) = &parsed[1] else {
+            panic!("{:?}", parsed[1]);
+        };
+        assert_eq!(peer.peer_name, "laptop");
+        assert_eq!(parsed[2], MessageType::Xcpy);
+        assert_eq!(parsed[3], MessageType::Xdis);
+        assert_eq!(
+            parsed[4],
+            MessageType::Xpst(ClipboardData::String((
+                StringType::Utf8Plain,
+                b"hello".to_vec()
+            )))
+        );
+        assert_eq!(
+            parsed[5],
+            MessageType::Xpst(ClipboardData::File(("a.txt".to_string(), b"hi".to_vec())))
+        );
+
+        // back to back on a stream every message ends where it should
+        let stream = messages.concat();
+        let mut reader = &stream[..];
+        for expected in &parsed {
+            let msg = read_next_message(&mut reader, None).unwrap().unwrap();
+            assert_eq!(&msg, expected);
+        }
+        assert!(read_next_message(&mut reader, None).unwrap().is_none());
+
+        // and first version is still written the same way
+        let peer = |name: &str| PeerData::new(name.to_string(), "id".to_string());
+        let sent = [
+            MessageType::Xcon(peer("office-pc")),
+            MessageType::Xacn(peer("laptop")),
+            parsed[2].clone(),
+            parsed[3].clone(),
+            parsed[4].clone(),
+            parsed[5].clone(),
+        ];
+        for (msg, expected) in sent.iter().zip(&messages) {
+            assert_eq!(&compose_message(msg, MIN_PROTOCOL_VER).unwrap(), expected);
+        }
+    }
 }
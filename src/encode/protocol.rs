@@ -1,20 +1,132 @@
-use crate::{clipboard::ClipboardData, utils::log_into_file};
+use crate::clipboard::ClipboardData;
+use crate::network::MIN_PROTOCOL_VER;
+use crate::network::PORT;
+use crate::network::PROTOCOL_VER;
+use std::env;
 use std::str::FromStr;
 
-const HEADER_SIZE: usize = 4;
-const LENGTH_SIZE: usize = 4;
+pub const HEADER_SIZE: usize = 4;
+pub const LENGTH_SIZE: usize = 4;
 #[derive(Debug, PartialEq, Clone)]
 pub struct PeerData {
     pub peer_name: String,
+    /// Stable per install, survives IP changes. Empty for old peers
+    pub device_id: String,
+    /// OS family: `linux`, `macos` or `windows`
+    pub os: String,
+    pub app_version: String,
+    /// TCP port peer listens on
+    pub port: u16,
+    pub capabilities: Capabilities,
+    /// Travels in XVER chunk of Xcon/Xacn
+    pub versions: VersionRange,
+    /// Public Ed25519 key of the install. Empty for old peers
+    pub identity: Vec<u8>,
 }
 
-#[derive(Debug, PartialEq)]
+impl PeerData {
+    /// Describes this install
+    pub fn new(peer_name: String, device_id: String) -> Self {
+        PeerData {
+            peer_name,
+            device_id,
+            os: env::consts::OS.to_string(),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            port: PORT,
+            capabilities: Capabilities::supported(),
+            versions: VersionRange::supported(),
+            identity: vec![],
+        }
+    }
+}
+
+/// Set of features peer supports
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const FILES: Capabilities = Capabilities(1);
+    pub const HTML: Capabilities = Capabilities(1 << 1);
+    pub const IMAGES: Capabilities = Capabilities(1 << 2);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 3);
+    pub const ENCRYPTION: Capabilities = Capabilities(1 << 4);
+    /// Accepts TLS connections with certificate of identity key
+    pub const TLS: Capabilities = Capabilities(1 << 5);
+
+    /// Features of this build
+    pub fn supported() -> Self {
+        Capabilities::FILES
+            .with(Capabilities::HTML)
+            .with(Capabilities::IMAGES)
+    }
+    pub fn with(self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Protocol versions peer is able to speak
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VersionRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl VersionRange {
+    /// Versions this build supports
+    pub fn supported() -> Self {
+        VersionRange {
+            min: MIN_PROTOCOL_VER,
+            max: PROTOCOL_VER,
+        }
+    }
+    /// Peers that do not announce range speak only the version of the message
+    pub fn single(ver: u32) -> Self {
+        VersionRange { min: ver, max: ver }
+    }
+    /// Highest version both sides speak
+    pub fn negotiate(&self, other: &VersionRange) -> Option<u32> {
+        let ver = self.max.min(other.max);
+        if ver < self.min.max(other.min) {
+            return None;
+        }
+        Some(ver)
+    }
+}
+
+/// Step of pairing exchange, see [`crate::crypto::Pairing`]
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PairingStep {
+    /// Initiator commits to its key
+    Commit,
+    /// Responder sends its key
+    Key,
+    /// Initiator reveals the key it committed to
+    Reveal,
+    Cancel,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PairingMessage {
+    pub step: PairingStep,
+    /// Random, same for all messages of one pairing
+    pub session: [u8; 16],
+    /// Identity key of the sender
+    pub identity: [u8; 32],
+    /// Commitment or ephemeral key, depending on step
+    pub value: [u8; 32],
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum MessageType {
     Xacn(PeerData),
     Xcon(PeerData),
     Xcpy,
     Xdis,
     Xpst(ClipboardData),
+    Xpar(PairingMessage),
     NoMessage,
 }
 #[derive(Debug, PartialEq)]
@@ -26,6 +138,8 @@ pub enum HeaderType {
     Xcpy,
     Xpst,
     Xdis,
+    Xenc,
+    Xpar,
 }
 
 impl FromStr for HeaderType {
@@ -40,6 +154,8 @@ impl FromStr for HeaderType {
             "XCPY" => Ok(HeaderType::Xcpy),
             "XPST" => Ok(HeaderType::Xpst),
             "XDIS" => Ok(HeaderType::Xdis),
+            "XENC" => Ok(HeaderType::Xenc),
+            "XPAR" => Ok(HeaderType::Xpar),
             _ => Err(ParseErrors::UnknownHeader(format!(
                 "Unknown header: {}",
                 input
@@ -47,6 +163,12 @@ impl FromStr for HeaderType {
         }
     }
 }
+/// Like in PNG, chunks starting with lowercase letter are optional.
+/// Unknown optional chunks are skipped, unknown critical ones fail parsing
+pub fn is_critical(header: &str) -> bool {
+    !header.starts_with(|c: char| c.is_ascii_lowercase())
+}
+
 impl HeaderType {
     pub fn to_string(&self) -> &str {
         match self {
@@ -57,6 +179,8 @@ impl HeaderType {
             Self::Xcpy => "XCPY",
             Self::Xpst => "XPST",
             Self::Xdis => "XDIS",
+            Self::Xenc => "XENC",
+            Self::Xpar => "XPAR",
         }
     }
 }
@@ -66,80 +190,29 @@ pub enum ParseErrors {
     InvalidStructure,
     OutOfBounds,
     UnknownHeader(String),
+    /// Chunk is longer than configured limit for its kind of data
+    TooBig(String),
+    /// Message is not sealed with our passphrase
+    Unauthenticated(String),
 }
 
 #[derive(Debug)]
 pub enum EncodeError {
     TooBig,
     Overflow,
-}
-
-pub struct ReaderOffset {
-    pub offset: usize,
-}
-
-impl ReaderOffset {
-    pub fn increase_by(&mut self, val: usize) {
-        self.offset += val;
-    }
+    /// Payload can not be encoded with protocol version of the peer
+    Unsupported,
+    Io(String),
 }
 
 pub fn check_offset_bounds(data: &[u8], offset: usize, size: usize) -> Result<(), ParseErrors> {
-    if data.len() < offset + size {
+    if data.len() < offset.saturating_add(size) {
         return Err(ParseErrors::OutOfBounds);
     }
 
     Ok(())
 }
 
-pub fn read_header(data: &[u8], o: &mut ReaderOffset) -> Result<String, ParseErrors> {
-    check_offset_bounds(data, o.offset, HEADER_SIZE)?;
-    let header: String = String::from_utf8(data[o.offset..o.offset + HEADER_SIZE].to_vec())
-        .map_err(|err| {
-            let _ =
-                log_into_file(format!("Error occurred while parsing header: {:?}", err).as_str());
-            ParseErrors::InvalidStructure
-        })?;
-    o.increase_by(HEADER_SIZE);
-    Ok(header)
-}
-
-pub fn read_header_expected(
-    data: &[u8],
-    o: &mut ReaderOffset,
-    expected: &str,
-) -> Result<(), ParseErrors> {
-    let header = read_header(data, o)?;
-    if header != expected {
-        return Err(ParseErrors::UnknownHeader(format!(
-            "Expected header: {}. Received instead: {}",
-            expected, header
-        )));
-    }
-    Ok(())
-}
-
-pub fn read_size(data: &[u8], o: &mut ReaderOffset) -> Result<usize, ParseErrors> {
-    check_offset_bounds(data, o.offset, LENGTH_SIZE)?;
-    let slice: [u8; 4] = data[o.offset..o.offset + LENGTH_SIZE]
-        .try_into()
-        .map_err(|err| {
-            let _ = log_into_file(format!("Error occurred while parsing len: {:?}", err).as_str());
-            ParseErrors::InvalidStructure
-        })?;
-    let size: u32 = u32::from_be_bytes(slice);
-    o.increase_by(LENGTH_SIZE);
-    Ok(size as usize)
-}
-
-pub fn read_data(data: &[u8], o: &mut ReaderOffset, size: usize) -> Result<Vec<u8>, ParseErrors> {
-    check_offset_bounds(data, o.offset, size)?;
-    let read = data[o.offset..o.offset + size].to_vec();
-
-    o.increase_by(size);
-    Ok(read)
-}
-
 pub fn encode_data(data: &[u8], out: &mut Vec<u8>) -> Result<(), EncodeError> {
     encode_size(data.len(), out)?;
     out.extend(data);
@@ -147,10 +220,8 @@ pub fn encode_data(data: &[u8], out: &mut Vec<u8>) -> Result<(), EncodeError> {
 }
 
 pub fn encode_size(size: usize, out: &mut Vec<u8>) -> Result<(), EncodeError> {
-    if size as u32 > u32::MAX {
-        return Err(EncodeError::TooBig);
-    }
-    let size: u32 = size.try_into().unwrap();
+    // chunk length is u32, so a single message can not exceed 4 GiB
+    let size: u32 = size.try_into().map_err(|_| EncodeError::TooBig)?;
     let chunk_len = u32::to_be_bytes(size);
     out.extend_from_slice(chunk_len.as_slice());
     Ok(())
@@ -0,0 +1,155 @@
+//! Discovery datagrams signed with identity key
+//!
+//! Xcon, Xacn and Xdis end with optional `xsig` chunk, so peers that do not
+//! know it skip it:
+//!
+//! ```text
+//! key: 32 | timestamp: u64 | nonce: 16 | signature: 64
+//! ```
+//!
+//! Signature covers everything in `XCOP` before `xsig` together with the
+//! key, timestamp (milliseconds since Unix epoch), random nonce and IP
+//! address of the sender. Address is not sent, receiver takes the one
+//! datagram came from, so datagram replayed from elsewhere does not verify.
+//! Whether the key belongs to the peer and the datagram is not a replay is
+//! left to the engine.
+
+use std::net::IpAddr;
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+
+use crate::crypto::verify;
+use crate::crypto::Identity;
+use crate::crypto::PUBLIC_KEY_SIZE;
+use crate::crypto::SIGNATURE_SIZE;
+
+use super::compose_message;
+use super::message_len;
+use super::parse_message;
+use super::protocol::{encode_chunks, Chunk};
+use super::EncodeError;
+use super::MessageType;
+use super::ParseErrors;
+use super::SIGN_VER;
+
+const SIGNATURE_HEADER: &str = "xsig";
+const SIGNATURE_CONTEXT: &[u8] = b"copyxross datagram v1";
+const SIGNATURE_NONCE_SIZE: usize = 16;
+const SIGNATURE_CHUNK_LEN: usize = PUBLIC_KEY_SIZE + 8 + SIGNATURE_NONCE_SIZE + SIGNATURE_SIZE;
+
+/// Verified signature of datagram
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SignedBy {
+    pub key: [u8; PUBLIC_KEY_SIZE],
+    /// Milliseconds since Unix epoch, by the clock of the sender
+    pub timestamp: u64,
+    pub nonce: [u8; SIGNATURE_NONCE_SIZE],
+}
+
+/// Encodes message and signs it with identity key, to be sent from `source`
+pub fn compose_signed_message(
+    message: &MessageType,
+    protocol_ver: u32,
+    identity: &Identity,
+    source: IpAddr,
+    timestamp: u64,
+) -> Result<Vec<u8>, EncodeError> {
+    if protocol_ver < SIGN_VER {
+        return Err(EncodeError::Unsupported);
+    }
+    let mut encoded = compose_message(message, protocol_ver)?;
+    let mut nonce = [0; SIGNATURE_NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let signed = SignedBy {
+        key: identity.public_key(),
+        timestamp,
+        nonce,
+    };
+    let signature = identity.sign(&signed_data(&encoded[8..], &signed, source));
+    let fields = [
+        &signed.key[..],
+        &timestamp.to_be_bytes(),
+        &nonce,
+        &signature,
+    ]
+    .concat();
+    encode_chunks(&vec![Chunk::new(SIGNATURE_HEADER, &fields)], &mut encoded)?;
+    let len: u32 = (encoded.len() - 8)
+        .try_into()
+        .map_err(|_| EncodeError::TooBig)?;
+    encoded[4..8].copy_from_slice(&len.to_be_bytes());
+    Ok(encoded)
+}
+
+/// Parses message that came from `source` and checks its signature. Message
+/// without one is returned as it is, one with invalid signature is an error
+pub fn parse_signed_message(
+    data: &[u8],
+    source: IpAddr,
+) -> Result<(MessageType, Option<SignedBy>), ParseErrors> {
+    let msg = parse_message(data)?;
+    let Some((offset, fields)) = find_signature(data)? else {
+        return Ok((msg, None));
+    };
+    let invalid = |_| ParseErrors::InvalidStructure;
+    let signed = SignedBy {
+        key: fields[..32].try_into().map_err(invalid)?,
+        timestamp: u64::from_be_bytes(fields[32..40].try_into().map_err(invalid)?),
+        nonce: fields[40..56].try_into().map_err(invalid)?,
+    };
+    let signature: [u8; SIGNATURE_SIZE] = fields[56..].try_into().map_err(invalid)?;
+    if !verify(
+        &signed.key,
+        &signed_data(&data[8..offset], &signed, source),
+        &signature,
+    ) {
+        return Err(ParseErrors::Unauthenticated(
+            "Invalid signature".to_string(),
+        ));
+    }
+    Ok((msg, Some(signed)))
+}
+
+/// Offset and contents of `xsig` chunk, which has to be the last one in `XCOP`
+fn find_signature(data: &[u8]) -> Result<Option<(usize, &[u8])>, ParseErrors> {
+    let read_u32 = |offset: usize| -> Result<usize, ParseErrors> {
+        let bytes = data
+            .get(offset..offset + 4)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(ParseErrors::OutOfBounds)?;
+        Ok(u32::from_be_bytes(bytes) as usize)
+    };
+    let len = message_len(data).ok_or(ParseErrors::InvalidStructure)?;
+    let end = len.saturating_add(8).min(data.len());
+    let mut offset = 8;
+    while offset + 8 <= end {
+        let len = read_u32(offset + 4)?;
+        let next = offset.saturating_add(8).saturating_add(len);
+        if &data[offset..offset + 4] == SIGNATURE_HEADER.as_bytes() {
+            if len != SIGNATURE_CHUNK_LEN || next != end {
+                return Err(ParseErrors::InvalidStructure);
+            }
+            return Ok(Some((offset, &data[offset + 8..next])));
+        }
+        offset = next;
+    }
+    Ok(None)
+}
+
+fn signed_data(message: &[u8], signed: &SignedBy, source: IpAddr) -> Vec<u8> {
+    // IPv4 as mapped IPv6, so both are 16 bytes
+    let source = match source.to_canonical() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    };
+    [
+        SIGNATURE_CONTEXT,
+        message,
+        &signed.key,
+        &signed.timestamp.to_be_bytes(),
+        &signed.nonce,
+        &source,
+    ]
+    .concat()
+}
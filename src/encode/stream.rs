@@ -0,0 +1,334 @@
+//! Incremental chunk decoder over [`Read`]
+//!
+//! Chunks are pulled one by one, data of each chunk can be read in pieces or
+//! copied straight into a writer, so large payloads never have to be held in
+//! memory. Container chunks (`XCOP`, `XPST`, `XSTR`, `XFIL`) are entered with
+//! [`ChunkReader::enter`], after which [`ChunkReader::next_chunk`] yields their
+//! children and returns `None` once the container is exhausted.
+
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+
+use super::limits::check_limit;
+use super::limits::MAX_FIELD_LEN;
+use super::protocol::is_critical;
+use super::protocol::HEADER_SIZE;
+use super::protocol::LENGTH_SIZE;
+use super::ParseErrors;
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChunkHeader {
+    pub header: String,
+    pub len: usize,
+}
+
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    /// Bytes consumed so far
+    pos: usize,
+    /// End positions of entered containers
+    ends: Vec<usize>,
+    /// Start and end positions of current chunk data
+    data_start: usize,
+    data_end: Option<usize>,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> Self {
+        ChunkReader {
+            reader,
+            pos: 0,
+            ends: vec![],
+            data_start: 0,
+            data_end: None,
+        }
+    }
+
+    /// Reads next chunk header, skipping unread data of previous chunk.
+    /// Returns None at the end of current container or stream
+    pub fn next_chunk(&mut self) -> Result<Option<ChunkHeader>, ParseErrors> {
+        self.skip_data()?;
+        if let Some(&end) = self.ends.last() {
+            if self.pos == end {
+                self.ends.pop();
+                return Ok(None);
+            }
+            if self.pos + HEADER_SIZE + LENGTH_SIZE > end {
+                return Err(ParseErrors::InvalidStructure);
+            }
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        if !self.fill(&mut header, self.ends.is_empty())? {
+            return Ok(None);
+        }
+        let header =
+            String::from_utf8(header.to_vec()).map_err(|_| ParseErrors::InvalidStructure)?;
+        let mut len = [0u8; LENGTH_SIZE];
+        self.fill(&mut len, false)?;
+        let len = u32::from_be_bytes(len) as usize;
+
+        let data_end = self.pos.checked_add(len).ok_or(ParseErrors::OutOfBounds)?;
+        if let Some(&end) = self.ends.last() {
+            if data_end > end {
+                return Err(ParseErrors::OutOfBounds);
+            }
+        }
+        self.data_start = self.pos;
+        self.data_end = Some(data_end);
+        Ok(Some(ChunkHeader { header, len }))
+    }
+
+    /// Reads next chunk and checks that it has expected header.
+    /// Optional chunks in between are skipped
+    pub fn expect_chunk(&mut self, expected: &str) -> Result<ChunkHeader, ParseErrors> {
+        loop {
+            match self.next_chunk()? {
+                Some(chunk) if chunk.header == expected => return Ok(chunk),
+                Some(chunk) if !is_critical(&chunk.header) => continue,
+                Some(chunk) => {
+                    return Err(ParseErrors::UnknownHeader(format!(
+                        "Expected header: {}. Received instead: {}",
+                        expected, chunk.header
+                    )))
+                }
+                None => return Err(ParseErrors::OutOfBounds),
+            }
+        }
+    }
+
+    /// Treats data of current chunk as nested chunks
+    pub fn enter(&mut self) -> Result<(), ParseErrors> {
+        // only untouched chunk can be entered
+        let end = match self.data_end {
+            Some(end) if self.pos == self.data_start => end,
+            _ => return Err(ParseErrors::InvalidStructure),
+        };
+        self.data_end = None;
+        self.ends.push(end);
+        Ok(())
+    }
+
+    /// Unread data of current chunk
+    pub fn data_left(&self) -> usize {
+        self.data_end.map(|end| self.remaining_in(end)).unwrap_or(0)
+    }
+
+    /// Reads piece of current chunk data. Returns 0 when data is over
+    pub fn read_data(&mut self, buf: &mut [u8]) -> Result<usize, ParseErrors> {
+        let want = buf.len().min(self.data_left());
+        if want == 0 {
+            return Ok(0);
+        }
+        loop {
+            match self.reader.read(&mut buf[..want]) {
+                Ok(0) => return Err(ParseErrors::OutOfBounds),
+                Ok(n) => {
+                    self.pos += n;
+                    return Ok(n);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err(ParseErrors::OutOfBounds),
+            }
+        }
+    }
+
+    /// Reads the rest of current chunk data into memory
+    pub fn read_data_to_vec(&mut self) -> Result<Vec<u8>, ParseErrors> {
+        // grows as data arrives, so bogus length does not allocate everything up front
+        let mut out = Vec::with_capacity(self.data_left().min(COPY_BUFFER_SIZE));
+        self.copy_data(&mut out)?;
+        Ok(out)
+    }
+
+    /// Reads data of short chunk, like name or type
+    pub fn read_field(&mut self) -> Result<Vec<u8>, ParseErrors> {
+        check_limit("Field", self.data_left(), MAX_FIELD_LEN)?;
+        self.read_data_to_vec()
+    }
+
+    /// Copies the rest of current chunk data into writer
+    pub fn copy_data(&mut self, out: &mut impl Write) -> Result<usize, ParseErrors> {
+        let mut buffer = vec![0; COPY_BUFFER_SIZE.min(self.data_left())];
+        let mut copied = 0;
+        loop {
+            let n = self.read_data(&mut buffer)?;
+            if n == 0 {
+                return Ok(copied);
+            }
+            out.write_all(&buffer[..n])
+                .map_err(|_| ParseErrors::InvalidStructure)?;
+            copied += n;
+        }
+    }
+
+    /// Skips the rest of current container and continues after it
+    pub fn leave(&mut self) -> Result<(), ParseErrors> {
+        self.skip_data()?;
+        if let Some(end) = self.ends.pop() {
+            self.data_end = Some(end);
+            self.skip_data()?;
+        }
+        Ok(())
+    }
+
+    /// Skips everything up to the end of the outermost container
+    pub fn skip_to_end(&mut self) -> Result<(), ParseErrors> {
+        if let Some(&end) = self.ends.first() {
+            self.data_end = Some(end);
+            self.skip_data()?;
+            self.ends.clear();
+        }
+        Ok(())
+    }
+
+    pub fn skip_data(&mut self) -> Result<(), ParseErrors> {
+        self.copy_data(&mut std::io::sink())?;
+        self.data_end = None;
+        Ok(())
+    }
+
+    /// Current chunk data as reader
+    pub fn data_reader(&mut self) -> DataReader<'_, R> {
+        DataReader(self)
+    }
+
+    fn remaining_in(&self, end: usize) -> usize {
+        end.saturating_sub(self.pos)
+    }
+
+    /// Fills buffer completely. Returns false if stream ended before first byte
+    fn fill(&mut self, buf: &mut [u8], eof_allowed: bool) -> Result<bool, ParseErrors> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.reader.read(&mut buf[read..]) {
+                Ok(0) if read == 0 && eof_allowed => return Ok(false),
+                Ok(0) => return Err(ParseErrors::OutOfBounds),
+                Ok(n) => read += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(_) => return Err(ParseErrors::OutOfBounds),
+            }
+        }
+        self.pos += read;
+        Ok(true)
+    }
+}
+
+pub struct DataReader<'a, R: Read>(&'a mut ChunkReader<R>);
+
+impl<R: Read> Read for DataReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0
+            .read_data(buf)
+            .map_err(|err| std::io::Error::new(ErrorKind::UnexpectedEof, format!("{:?}", err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ClipboardData;
+    use crate::encode::compose_message;
+    use crate::encode::read_message;
+    use crate::encode::read_next_message;
+    use crate::encode::write_message;
+    use crate::encode::MessageType;
+
+    /// Hands out data one byte at a time, like slow socket
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_walk_chunks_and_stream_data() {
+        let file = ClipboardData::File(("a.bin".to_string(), (0..=255).collect()));
+        // first version does not count XVER into XCOP length
+        let msg = compose_message(&MessageType::Xpst(file.clone()), 2).unwrap();
+        let mut chunks = ChunkReader::new(Trickle(&msg));
+
+        assert_eq!(chunks.expect_chunk("XCOP").unwrap().len, msg.len() - 8);
+        chunks.enter().unwrap();
+        assert_eq!(chunks.expect_chunk("XVER").unwrap().len, 4);
+        chunks.expect_chunk("XPST").unwrap();
+        chunks.enter().unwrap();
+        chunks.expect_chunk("XFIL").unwrap();
+        chunks.enter().unwrap();
+        assert_eq!(chunks.expect_chunk("XFME").unwrap().len, 5);
+        assert_eq!(chunks.read_data_to_vec().unwrap(), b"a.bin");
+        assert_eq!(chunks.expect_chunk("XDAT").unwrap().len, 256);
+        let mut out = vec![];
+        assert_eq!(chunks.copy_data(&mut out).unwrap(), 256);
+        assert_eq!(out, (0..=255).collect::<Vec<u8>>());
+        // XFIL, XPST, XCOP and stream are exhausted
+        for _ in 0..4 {
+            assert_eq!(chunks.next_chunk().unwrap(), None);
+        }
+        assert_eq!(
+            read_message(Trickle(&msg)).unwrap(),
+            MessageType::Xpst(file)
+        );
+
+        // truncated stream and child overflowing its container
+        assert!(matches!(
+            read_message(Trickle(&msg[..msg.len() - 1])),
+            Err(ParseErrors::OutOfBounds)
+        ));
+
+        let mut bad = msg.clone();
+        bad[7] -= 1;
+        let mut chunks = ChunkReader::new(bad.as_slice());
+        chunks.expect_chunk("XCOP").unwrap();
+        chunks.enter().unwrap();
+        chunks.expect_chunk("XVER").unwrap();
+        assert!(matches!(chunks.next_chunk(), Err(ParseErrors::OutOfBounds)));
+    }
+
+    #[test]
+    fn test_stream_file_from_disk_into_spool() {
+        let dir = std::env::temp_dir().join(format!("copyxross-spool-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("source.bin");
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&src, &content).unwrap();
+
+        let file_ref = MessageType::Xpst(ClipboardData::FileRef(("a.bin".to_string(), src)));
+        let mut stream = vec![];
+        write_message(&file_ref, 1, &mut stream).unwrap();
+        write_message(&MessageType::Xdis, 1, &mut stream).unwrap();
+        // same bytes as in-memory encoding
+        let file = MessageType::Xpst(ClipboardData::File(("a.bin".to_string(), content.clone())));
+        let composed = compose_message(&file, 1).unwrap();
+        assert!(stream[..composed.len()] == composed);
+
+        let mut reader = Trickle(&stream);
+        let received = read_next_message(&mut reader, Some(&dir)).unwrap().unwrap();
+        let MessageType::Xpst(ClipboardData::FileRef((name, path))) = received else {
+            panic!("Expected spooled file, got {:?}", received);
+        };
+        assert_eq!(name, "a.bin");
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        assert_eq!(
+            read_next_message(&mut reader, Some(&dir)).unwrap(),
+            Some(MessageType::Xdis)
+        );
+        assert_eq!(read_next_message(&mut reader, Some(&dir)).unwrap(), None);
+
+        // partial file is not left behind
+        let cut = &stream[..stream.len() - 1000];
+        assert!(read_next_message(&mut Trickle(cut), Some(&dir)).is_err());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,133 +1,190 @@
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
 use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 
+use crate::clipboard::sanitize_filename;
+use crate::clipboard::sanitize_tree_path;
 use crate::clipboard::ClipboardData;
+use crate::clipboard::EntryContent;
+use crate::clipboard::FileTree;
+use crate::clipboard::Image;
+use crate::clipboard::ImageFormat;
+use crate::clipboard::NamePolicy;
 use crate::clipboard::StringType;
+use crate::clipboard::TreeEntry;
 use crate::debug_println;
 use crate::utils::format_bytes_size;
 use crate::utils::log_into_file;
 
+use super::limits::check_limit;
+use super::limits::Limits;
+use super::limits::MAX_REPRESENTATIONS;
 use super::protocol::check_offset_bounds;
 use super::protocol::encode_chunks;
-use super::protocol::read_data;
-use super::protocol::read_header_expected;
-use super::protocol::read_size;
+use super::protocol::encode_header;
+use super::protocol::encode_size;
+use super::protocol::is_critical;
 use super::protocol::Chunk;
 use super::protocol::EncodeError;
-use super::protocol::ReaderOffset;
+use super::protocol::HEADER_SIZE;
+use super::protocol::LENGTH_SIZE;
+use super::stream::ChunkHeader;
+use super::stream::ChunkReader;
+use super::Capabilities;
+use super::PairingMessage;
+use super::PairingStep;
 use super::ParseErrors;
 use super::PeerData;
+use super::VersionRange;
+use crate::network::MIN_PROTOCOL_VER;
+use crate::network::PORT;
 
 pub trait Transferable: Sized {
     fn serialize(&self) -> std::result::Result<Vec<u8>, EncodeError>;
     fn deserialize(data: &[u8]) -> std::result::Result<Self, ParseErrors>;
 }
 
+/// PeerData layout, strings are prefixed with u8 length:
+///
+/// ```text
+/// name | device_id | os | app_version | port: u16 | capabilities: u32 | identity
+/// ```
+///
+/// First version sent only the name, so everything after it is optional.
+/// Identity key is u8 length prefixed too, peers before version 8 do not send it
 impl Transferable for PeerData {
     fn serialize(&self) -> std::result::Result<Vec<u8>, EncodeError> {
-        // -24 for String struct, +1 for u8 string len
-        let str_len = self.peer_name.capacity();
-        let mut encoded: Vec<u8> = Vec::with_capacity((size_of::<Self>() - 24) + str_len + 1);
-
-        let str_len: u8 = str_len.try_into().map_err(|err| {
-            let _ = log_into_file(format!("Failed to serialize PeerData: {:?}", err).as_str());
-            EncodeError::Overflow
-        })?;
-        encoded.extend(str_len.to_be_bytes());
-        encoded.extend(self.peer_name.as_bytes());
+        let mut encoded: Vec<u8> = vec![];
+        for s in [
+            &self.peer_name,
+            &self.device_id,
+            &self.os,
+            &self.app_version,
+        ] {
+            let str_len: u8 = s.len().try_into().map_err(|err| {
+                let _ = log_into_file(format!("Failed to serialize PeerData: {:?}", err).as_str());
+                EncodeError::Overflow
+            })?;
+            encoded.push(str_len);
+            encoded.extend(s.as_bytes());
+        }
+        encoded.extend(self.port.to_be_bytes());
+        encoded.extend(self.capabilities.0.to_be_bytes());
+        let key_len: u8 = self
+            .identity
+            .len()
+            .try_into()
+            .map_err(|_| EncodeError::Overflow)?;
+        encoded.push(key_len);
+        encoded.extend(&self.identity);
         Ok(encoded)
     }
     fn deserialize(data: &[u8]) -> std::result::Result<Self, ParseErrors> {
-        // check_offset_bounds(data, 0, 8)?;
-
+        let mut offset = 0;
+        let peer_name = read_short_str(data, &mut offset)?;
         let mut peer_data = PeerData {
-            peer_name: String::new(),
+            peer_name,
+            device_id: String::new(),
+            os: String::new(),
+            app_version: String::new(),
+            port: PORT,
+            // everything the first version could do
+            capabilities: Capabilities::FILES.with(Capabilities::HTML),
+            // filled from XVER chunk by message parser
+            versions: VersionRange::single(MIN_PROTOCOL_VER),
+            identity: vec![],
         };
-
-        let slice: [u8; 1] = data[0..1].try_into().map_err(|err| {
-            let _ = log_into_file(
-                format!("Error occurred while deserializing PeerData: {:?}", err).as_str(),
-            );
-            ParseErrors::InvalidStructure
-        })?;
-        let str_len = u8::from_be_bytes(slice);
-        let str_len: usize = str_len as usize;
-        check_offset_bounds(data, 1, str_len)?;
-
-        let peer_name = String::from_utf8(data[1..=str_len].to_vec()).map_err(|err| {
-            let _ = log_into_file(
-                format!("Error occurred while deserializing PeerData: {:?}", err).as_str(),
-            );
-            ParseErrors::InvalidStructure
-        })?;
-
-        peer_data.peer_name = peer_name;
-
+        if offset == data.len() {
+            return Ok(peer_data);
+        }
+        peer_data.device_id = read_short_str(data, &mut offset)?;
+        peer_data.os = read_short_str(data, &mut offset)?;
+        peer_data.app_version = read_short_str(data, &mut offset)?;
+        let rest: [u8; 6] = data
+            .get(offset..offset + 6)
+            .and_then(|rest| rest.try_into().ok())
+            .ok_or(ParseErrors::OutOfBounds)?;
+        peer_data.port = u16::from_be_bytes([rest[0], rest[1]]);
+        peer_data.capabilities =
+            Capabilities(u32::from_be_bytes([rest[2], rest[3], rest[4], rest[5]]));
+        offset += 6;
+        if offset == data.len() {
+            return Ok(peer_data);
+        }
+        let key_len = data[offset] as usize;
+        check_offset_bounds(data, offset + 1, key_len)?;
+        peer_data.identity = data[offset + 1..offset + 1 + key_len].to_vec();
         Ok(peer_data)
     }
 }
 
-impl Transferable for ClipboardData {
+/// PairingMessage layout:
+///
+/// ```text
+/// step: u8 | session: 16 | identity: 32 | value: 32
+/// ```
+impl Transferable for PairingMessage {
+    fn serialize(&self) -> std::result::Result<Vec<u8>, EncodeError> {
+        let step: u8 = match self.step {
+            PairingStep::Commit => 1,
+            PairingStep::Key => 2,
+            PairingStep::Reveal => 3,
+            PairingStep::Cancel => 4,
+        };
+        let mut encoded = vec![step];
+        encoded.extend(self.session);
+        encoded.extend(self.identity);
+        encoded.extend(self.value);
+        Ok(encoded)
+    }
     fn deserialize(data: &[u8]) -> std::result::Result<Self, ParseErrors> {
-        let mut o: ReaderOffset = ReaderOffset { offset: 0 };
-        let chunk_header = String::from_utf8(read_data(data, &mut o, 4)?).map_err(|err| {
-            ParseErrors::UnknownHeader(format!("Unable to deserialize Clipboard data: {:?}", err))
-        })?;
-
-        match chunk_header.as_str() {
-            "XSTR" => {
-                let len = read_size(data, &mut o)?;
-                debug_println!(
-                    "Reading string from clipboard. Length: {}",
-                    format_bytes_size(len)
-                );
-
-                read_header_expected(data, &mut o, "XTYP")?;
-                let s_type_len = read_size(data, &mut o)?;
-                let s_type =
-                    String::from_utf8(read_data(data, &mut o, s_type_len)?).map_err(|err| {
-                        let _ = log_into_file(
-                            format!("Could not read string data type chunk: {:?}", err).as_str(),
-                        );
-                        ParseErrors::InvalidStructure
-                    })?;
-                let s_type = StringType::from_str(&s_type).map_err(|err| {
-                    let _ = log_into_file(format!("Invalid string type: {:?}", err).as_str());
-                    ParseErrors::InvalidStructure
-                });
+        if data.len() != 81 {
+            return Err(ParseErrors::InvalidStructure);
+        }
+        let step = match data[0] {
+            1 => PairingStep::Commit,
+            2 => PairingStep::Key,
+            3 => PairingStep::Reveal,
+            4 => PairingStep::Cancel,
+            _ => return Err(ParseErrors::InvalidStructure),
+        };
+        let invalid = |_| ParseErrors::InvalidStructure;
+        Ok(PairingMessage {
+            step,
+            session: data[1..17].try_into().map_err(invalid)?,
+            identity: data[17..49].try_into().map_err(invalid)?,
+            value: data[49..81].try_into().map_err(invalid)?,
+        })
+    }
+}
 
-                read_header_expected(data, &mut o, "XDAT")?;
-                let s_data_len = read_size(data, &mut o)?;
-                let string_buff = read_data(data, &mut o, s_data_len)?;
+/// Reads u8 length prefixed string and moves offset past it
+fn read_short_str(data: &[u8], offset: &mut usize) -> std::result::Result<String, ParseErrors> {
+    check_offset_bounds(data, *offset, 1)?;
+    let str_len = data[*offset] as usize;
+    check_offset_bounds(data, *offset + 1, str_len)?;
+    let bytes = &data[*offset + 1..*offset + 1 + str_len];
+    *offset += 1 + str_len;
+    String::from_utf8(bytes.to_vec()).map_err(|err| {
+        let _ = log_into_file(
+            format!("Error occurred while deserializing PeerData: {:?}", err).as_str(),
+        );
+        ParseErrors::InvalidStructure
+    })
+}
 
-                Ok(ClipboardData::String((s_type?, string_buff)))
-            }
-            "XFIL" => {
-                let len = read_size(data, &mut o)?;
-                debug_println!(
-                    "Reading file from clipboard. Length: {}",
-                    format_bytes_size(len)
-                );
-                read_header_expected(data, &mut o, "XFME")?;
-
-                let filename_size = read_size(data, &mut o)?;
-                let filename =
-                    String::from_utf8(read_data(data, &mut o, filename_size)?).map_err(|err| {
-                        let _ = log_into_file(
-                            format!("Failed to read filename string: {:?}", err).as_str(),
-                        );
-                        ParseErrors::InvalidStructure
-                    })?;
-                read_header_expected(data, &mut o, "XDAT")?;
-                let file_len = read_size(data, &mut o)?;
-                let file_data = read_data(data, &mut o, file_len)?;
-
-                Ok(ClipboardData::File((filename, file_data)))
-            }
-            _ => Err(ParseErrors::UnknownHeader(format!(
-                "Invalid clipboard data header: {:?}",
-                chunk_header
-            ))),
-        }
+impl Transferable for ClipboardData {
+    fn deserialize(data: &[u8]) -> std::result::Result<Self, ParseErrors> {
+        read_clipboard_data(&mut ChunkReader::new(data), None, &Limits::from_env())
     }
     fn serialize(&self) -> std::result::Result<Vec<u8>, EncodeError> {
         match self {
@@ -158,6 +215,488 @@ impl Transferable for ClipboardData {
 
                 Ok(encoded)
             }
+            ClipboardData::FileRef((filename, path)) => {
+                // in-memory encoding has to load the file, use write_message to stream it
+                let data = fs::read(path).map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+                ClipboardData::File((filename.clone(), data)).serialize()
+            }
+            ClipboardData::Image(image) => {
+                let mut out: Vec<u8> = vec![];
+                let mut dimensions = u32::to_be_bytes(image.width).to_vec();
+                dimensions.extend(u32::to_be_bytes(image.height));
+                let chunks = vec![
+                    Chunk::new("XFMT", image.format.to_string().as_bytes()),
+                    Chunk::new("XDIM", &dimensions),
+                    Chunk::new("XDAT", &image.data),
+                ];
+                encode_chunks(&chunks, &mut out)?;
+                let mut encoded = vec![];
+                Chunk::new("XIMG", &out).encode_chunk(&mut encoded)?;
+                Ok(encoded)
+            }
+            ClipboardData::Files(_) => {
+                // in-memory encoding loads every file, use write_message to stream them
+                let mut encoded = vec![];
+                for piece in clipboard_pieces(self)? {
+                    match piece {
+                        Piece::Bytes(bytes) => encoded.extend(bytes),
+                        Piece::File((path, len)) => {
+                            let data = fs::read(&path)
+                                .map_err(|err| EncodeError::Io(format!("{:?}", err)))?;
+                            if data.len() != len {
+                                return Err(EncodeError::Io(format!(
+                                    "File changed while sending: {:?}",
+                                    path
+                                )));
+                            }
+                            encoded.extend(data);
+                        }
+                    }
+                }
+                Ok(encoded)
+            }
+            ClipboardData::Multi(reps) => {
+                let mut out: Vec<u8> = vec![];
+                for rep in reps {
+                    if let ClipboardData::Multi(_) = rep {
+                        return Err(EncodeError::Overflow);
+                    }
+                    out.extend(rep.serialize()?);
+                }
+                let mut encoded = vec![];
+                Chunk::new("XMUL", &out).encode_chunk(&mut encoded)?;
+                Ok(encoded)
+            }
+        }
+    }
+}
+
+/// Encodes XFIL chunk up to the file contents, which are written after it
+pub fn file_chunk_prefix(filename: &str, file_len: usize) -> Result<Vec<u8>, EncodeError> {
+    let mut out = vec![];
+    encode_header("XFIL", &mut out);
+    encode_size(
+        HEADER_SIZE + LENGTH_SIZE + filename.len() + HEADER_SIZE + LENGTH_SIZE + file_len,
+        &mut out,
+    )?;
+    Chunk::new("XFME", filename.as_bytes()).encode_chunk(&mut out)?;
+    encode_header("XDAT", &mut out);
+    encode_size(file_len, &mut out)?;
+    Ok(out)
+}
+
+/// Part of encoded clipboard data. File contents are copied from disk
+/// only while the message is written
+pub enum Piece {
+    Bytes(Vec<u8>),
+    File((PathBuf, usize)),
+}
+
+impl Piece {
+    pub fn len(&self) -> usize {
+        match self {
+            Piece::Bytes(bytes) => bytes.len(),
+            Piece::File((_, len)) => *len,
+        }
+    }
+}
+
+fn file_len(path: &Path) -> Result<usize, EncodeError> {
+    fs::metadata(path)
+        .map_err(|err| EncodeError::Io(format!("{:?}", err)))?
+        .len()
+        .try_into()
+        .map_err(|_| EncodeError::TooBig)
+}
+
+/// Encodes clipboard data, leaving contents of files on disk to be streamed
+pub fn clipboard_pieces(data: &ClipboardData) -> Result<Vec<Piece>, EncodeError> {
+    match data {
+        ClipboardData::FileRef((filename, path)) => {
+            let len = file_len(path)?;
+            Ok(vec![
+                Piece::Bytes(file_chunk_prefix(filename, len)?),
+                Piece::File((path.clone(), len)),
+            ])
+        }
+        ClipboardData::Files(tree) => {
+            let mut pieces = vec![];
+            for entry in &tree.entries {
+                pieces.extend(tree_entry_pieces(entry)?);
+            }
+            with_container("XTRE", pieces)
+        }
+        ClipboardData::Multi(reps) => {
+            let mut pieces = vec![];
+            for rep in reps {
+                if let ClipboardData::Multi(_) = rep {
+                    return Err(EncodeError::Overflow);
+                }
+                pieces.extend(clipboard_pieces(rep)?);
+            }
+            with_container("XMUL", pieces)
+        }
+        data => Ok(vec![Piece::Bytes(data.serialize()?)]),
+    }
+}
+
+/// XENT chunk with path (XPTH), type (XTYP: `DIR` or `FILE`),
+/// permission bits (XMOD) and, for files, contents (XDAT)
+fn tree_entry_pieces(entry: &TreeEntry) -> Result<Vec<Piece>, EncodeError> {
+    let (kind, data) = match &entry.content {
+        EntryContent::Dir => ("DIR", None),
+        EntryContent::Data(data) => ("FILE", Some(Piece::Bytes(data.clone()))),
+        EntryContent::Path(path) => ("FILE", Some(Piece::File((path.clone(), file_len(path)?)))),
+    };
+    let mode = u32::to_be_bytes(entry.mode);
+    let mut head = vec![];
+    let chunks = vec![
+        Chunk::new("XPTH", entry.path.as_bytes()),
+        Chunk::new("XTYP", kind.as_bytes()),
+        Chunk::new("XMOD", &mode),
+    ];
+    encode_chunks(&chunks, &mut head)?;
+    let mut pieces = vec![];
+    if let Some(data) = data {
+        encode_header("XDAT", &mut head);
+        encode_size(data.len(), &mut head)?;
+        pieces.push(data);
+    }
+    pieces.insert(0, Piece::Bytes(head));
+    with_container("XENT", pieces)
+}
+
+/// Prepends container chunk header covering all pieces
+fn with_container(header: &str, mut pieces: Vec<Piece>) -> Result<Vec<Piece>, EncodeError> {
+    let mut prefix = vec![];
+    encode_header(header, &mut prefix);
+    encode_size(pieces.iter().map(Piece::len).sum(), &mut prefix)?;
+    pieces.insert(0, Piece::Bytes(prefix));
+    Ok(pieces)
+}
+
+/// Creates file for data being received, readable by this user only
+fn create_spool_file(spool_dir: &Path) -> std::io::Result<(PathBuf, File)> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = spool_dir.join(format!("incoming-{}-{}.part", process::id(), n));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        match options.open(&path) {
+            // left behind by earlier process with the same id
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            file => return Ok((path, file?)),
+        }
+    }
+}
+
+/// Copies file contents into spool directory
+fn spool_file_data<R: Read>(
+    chunks: &mut ChunkReader<R>,
+    spool_dir: &Path,
+) -> std::result::Result<PathBuf, ParseErrors> {
+    let (path, file) = create_spool_file(spool_dir).map_err(|err| {
+        let _ = log_into_file(format!("Could not create spool file: {:?}", err).as_str());
+        ParseErrors::InvalidStructure
+    })?;
+    let mut out = BufWriter::new(file);
+    let copied = chunks
+        .copy_data(&mut out)
+        .and_then(|_| out.flush().map_err(|_| ParseErrors::InvalidStructure));
+    if let Err(err) = copied {
+        drop(out);
+        let _ = fs::remove_file(&path);
+        return Err(err);
+    }
+    Ok(path)
+}
+
+/// Reads XSTR, XFIL, XIMG, XTRE or XMUL chunk with clipboard data.
+/// With spool directory files are written to disk and returned as FileRef
+pub fn read_clipboard_data<R: Read>(
+    chunks: &mut ChunkReader<R>,
+    spool_dir: Option<&Path>,
+    limits: &Limits,
+) -> std::result::Result<ClipboardData, ParseErrors> {
+    let chunk = next_data_chunk(chunks)?.ok_or(ParseErrors::OutOfBounds)?;
+    if chunk.header != "XMUL" {
+        return read_representation(chunks, &chunk, spool_dir, limits);
+    }
+    debug_println!(
+        "Reading several representations. Length: {}",
+        format_bytes_size(chunk.len)
+    );
+    chunks.enter()?;
+    let mut reps = vec![];
+    while let Some(chunk) = next_data_chunk(chunks)? {
+        let rep = check_limit("Representations", reps.len() + 1, MAX_REPRESENTATIONS)
+            .and_then(|_| read_representation(chunks, &chunk, spool_dir, limits));
+        match rep {
+            Ok(rep) => reps.push(rep),
+            Err(err) => {
+                discard_spooled(&reps);
+                return Err(err);
+            }
+        }
+    }
+    ClipboardData::from_representations(reps).ok_or(ParseErrors::InvalidStructure)
+}
+
+/// Next chunk that is not optional, unless it is one we know
+fn next_data_chunk<R: Read>(
+    chunks: &mut ChunkReader<R>,
+) -> std::result::Result<Option<ChunkHeader>, ParseErrors> {
+    while let Some(chunk) = chunks.next_chunk()? {
+        if is_critical(&chunk.header) {
+            return Ok(Some(chunk));
+        }
+    }
+    Ok(None)
+}
+
+fn discard_spooled(reps: &[ClipboardData]) {
+    for path in reps.iter().flat_map(|rep| rep.file_paths()) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Reads XENT chunks of XTRE, which has just been entered
+fn read_tree<R: Read>(
+    chunks: &mut ChunkReader<R>,
+    spool_dir: Option<&Path>,
+    max_entries: usize,
+    max_size: usize,
+) -> std::result::Result<FileTree, ParseErrors> {
+    let mut tree = FileTree::default();
+    while let Some(chunk) = next_data_chunk(chunks)? {
+        let entry = if chunk.header == "XENT" {
+            // entry is only read up to the size left for the whole tree
+            let size_left = max_size.saturating_sub(tree.size() as usize);
+            check_limit("Tree entries", tree.entries.len() + 1, max_entries)
+                .and_then(|_| read_tree_entry(chunks, spool_dir, size_left))
+        } else {
+            Err(ParseErrors::UnknownHeader(format!(
+                "Expected header: XENT. Received instead: {}",
+                chunk.header
+            )))
+        };
+        match entry {
+            Ok(entry) => tree.entries.push(entry),
+            Err(err) => {
+                discard_spooled(&[ClipboardData::Files(tree)]);
+                return Err(err);
+            }
+        }
+    }
+    Ok(tree)
+}
+
+fn read_tree_entry<R: Read>(
+    chunks: &mut ChunkReader<R>,
+    spool_dir: Option<&Path>,
+    max_size: usize,
+) -> std::result::Result<TreeEntry, ParseErrors> {
+    chunks.enter()?;
+    chunks.expect_chunk("XPTH")?;
+    let path = String::from_utf8(chunks.read_field()?).map_err(|err| {
+        let _ = log_into_file(format!("Failed to read tree entry path: {:?}", err).as_str());
+        ParseErrors::InvalidStructure
+    })?;
+    let path = sanitize_tree_path(&path, NamePolicy::from_env()).map_err(|err| {
+        let _ = log_into_file(format!("Refused tree entry path: {:?}", err).as_str());
+        ParseErrors::InvalidStructure
+    })?;
+    chunks.expect_chunk("XTYP")?;
+    let kind = chunks.read_field()?;
+    chunks.expect_chunk("XMOD")?;
+    let mode: [u8; 4] = chunks
+        .read_field()?
+        .try_into()
+        .map_err(|_| ParseErrors::InvalidStructure)?;
+    let mode = u32::from_be_bytes(mode);
+    let entry = match kind.as_slice() {
+        b"DIR" => TreeEntry {
+            path,
+            mode,
+            size: 0,
+            content: EntryContent::Dir,
+        },
+        b"FILE" => {
+            let size = chunks.expect_chunk("XDAT")?.len;
+            check_limit("Files", size, max_size)?;
+            let content = match spool_dir {
+                Some(spool_dir) => EntryContent::Path(spool_file_data(chunks, spool_dir)?),
+                None => EntryContent::Data(chunks.read_data_to_vec()?),
+            };
+            TreeEntry {
+                path,
+                mode,
+                size: size as u64,
+                content,
+            }
+        }
+        _ => return Err(ParseErrors::InvalidStructure),
+    };
+    if let Err(err) = chunks.leave() {
+        if let EntryContent::Path(path) = &entry.content {
+            let _ = fs::remove_file(path);
+        }
+        return Err(err);
+    }
+    Ok(entry)
+}
+
+/// Reads single XSTR, XFIL, XIMG or XTRE representation and leaves its container
+fn read_representation<R: Read>(
+    chunks: &mut ChunkReader<R>,
+    chunk: &ChunkHeader,
+    spool_dir: Option<&Path>,
+    limits: &Limits,
+) -> std::result::Result<ClipboardData, ParseErrors> {
+    let file_limit = match spool_dir {
+        Some(_) => limits.file,
+        None => limits.file_in_memory(),
+    };
+    let data = match chunk.header.as_str() {
+        "XSTR" => {
+            debug_println!(
+                "Reading string from clipboard. Length: {}",
+                format_bytes_size(chunk.len)
+            );
+            chunks.enter()?;
+
+            chunks.expect_chunk("XTYP")?;
+            let s_type = String::from_utf8(chunks.read_field()?).map_err(|err| {
+                let _ = log_into_file(
+                    format!("Could not read string data type chunk: {:?}", err).as_str(),
+                );
+                ParseErrors::InvalidStructure
+            })?;
+            let s_type = StringType::from_str(&s_type).map_err(|err| {
+                let _ = log_into_file(format!("Invalid string type: {:?}", err).as_str());
+                ParseErrors::InvalidStructure
+            });
+
+            check_limit("Text", chunks.expect_chunk("XDAT")?.len, limits.text)?;
+            let string_buff = chunks.read_data_to_vec()?;
+
+            ClipboardData::String((s_type?, string_buff))
+        }
+        "XFIL" => {
+            debug_println!(
+                "Reading file from clipboard. Length: {}",
+                format_bytes_size(chunk.len)
+            );
+            chunks.enter()?;
+
+            chunks.expect_chunk("XFME")?;
+            let filename = String::from_utf8(chunks.read_field()?).map_err(|err| {
+                let _ =
+                    log_into_file(format!("Failed to read filename string: {:?}", err).as_str());
+                ParseErrors::InvalidStructure
+            })?;
+            // peer picks the name, it must not point anywhere but the inbox
+            let filename = sanitize_filename(&filename, NamePolicy::from_env()).map_err(|err| {
+                let _ = log_into_file(format!("Refused file name: {:?}", err).as_str());
+                ParseErrors::InvalidStructure
+            })?;
+            check_limit("File", chunks.expect_chunk("XDAT")?.len, file_limit)?;
+            if let Some(spool_dir) = spool_dir {
+                let path = spool_file_data(chunks, spool_dir)?;
+                ClipboardData::FileRef((filename, path))
+            } else {
+                ClipboardData::File((filename, chunks.read_data_to_vec()?))
+            }
+        }
+        "XIMG" => {
+            debug_println!(
+                "Reading image from clipboard. Length: {}",
+                format_bytes_size(chunk.len)
+            );
+            chunks.enter()?;
+
+            chunks.expect_chunk("XFMT")?;
+            let format = String::from_utf8(chunks.read_field()?)
+                .ok()
+                .and_then(|format| ImageFormat::from_str(&format).ok())
+                .ok_or_else(|| {
+                    let _ = log_into_file("Invalid image format");
+                    ParseErrors::InvalidStructure
+                })?;
+            chunks.expect_chunk("XDIM")?;
+            let dimensions: [u8; 8] = chunks
+                .read_field()?
+                .try_into()
+                .map_err(|_| ParseErrors::InvalidStructure)?;
+            let width =
+                u32::from_be_bytes([dimensions[0], dimensions[1], dimensions[2], dimensions[3]]);
+            let height =
+                u32::from_be_bytes([dimensions[4], dimensions[5], dimensions[6], dimensions[7]]);
+            check_limit("Image", chunks.expect_chunk("XDAT")?.len, limits.image)?;
+            ClipboardData::Image(Image {
+                format,
+                width,
+                height,
+                data: chunks.read_data_to_vec()?,
+            })
+        }
+        "XTRE" => {
+            debug_println!(
+                "Reading files from clipboard. Length: {}",
+                format_bytes_size(chunk.len)
+            );
+            chunks.enter()?;
+            // entries are read until XTRE is exhausted, so it is already left
+            return read_tree(chunks, spool_dir, limits.tree_entries, file_limit)
+                .map(ClipboardData::Files);
+        }
+        _ => {
+            return Err(ParseErrors::UnknownHeader(format!(
+                "Invalid clipboard data header: {:?}",
+                chunk.header
+            )))
         }
+    };
+    if let Err(err) = chunks.leave() {
+        discard_spooled(std::slice::from_ref(&data));
+        return Err(err);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_data_roundtrip_and_legacy_format() {
+        let mut peer = PeerData::new(
+            "office-pc".to_string(),
+            "0b7c2a7e-6f1d-4c4e-9a57-1f9e0c3a6d21".to_string(),
+        );
+        peer.capabilities = peer.capabilities.with(Capabilities::IMAGES);
+        let encoded = peer.serialize().unwrap();
+        let decoded = PeerData::deserialize(&encoded).unwrap();
+        assert_eq!(
+            decoded,
+            PeerData {
+                versions: decoded.versions,
+                ..peer
+            }
+        );
+        assert!(decoded.capabilities.contains(Capabilities::IMAGES));
+        assert!(!decoded.capabilities.contains(Capabilities::ENCRYPTION));
+
+        // first version sent only the name
+        let legacy = PeerData::deserialize(b"\x03old").unwrap();
+        assert_eq!(legacy.peer_name, "old");
+        assert!(legacy.device_id.is_empty());
+        assert_eq!(legacy.port, PORT);
+
+        // the last byte is length of empty identity key, which is optional
+        assert!(PeerData::deserialize(&encoded[..encoded.len() - 2]).is_err());
     }
 }
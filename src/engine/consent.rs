@@ -0,0 +1,215 @@
+//! Consent to serve our clipboard
+//!
+//! Every peer has a policy for requests to read our clipboard: they are
+//! served right away, the user is asked each time, or they are refused.
+//! Each request is written into audit log together with what became of it.
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::utils::data_dir;
+use crate::utils::load_json;
+use crate::utils::log_into_file;
+use crate::utils::save_json;
+
+/// Env variable with policy of peers that have none set
+const CONSENT_ENV: &str = "COPYXROSS_CONSENT";
+/// Env variable with path to policies file
+const CONSENT_FILE_ENV: &str = "COPYXROSS_CONSENT_FILE";
+const CONSENT_FILE: &str = "consent.json";
+/// Env variable with path to audit log
+const AUDIT_LOG_ENV: &str = "COPYXROSS_AUDIT_LOG";
+const AUDIT_LOG_FILE: &str = "audit.log";
+/// Entries of audit log kept in memory for control interface
+const RECENT_ENTRIES: usize = 100;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsentPolicy {
+    Allow,
+    Ask,
+    Deny,
+}
+
+impl FromStr for ConsentPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "allow" => Ok(ConsentPolicy::Allow),
+            "ask" => Ok(ConsentPolicy::Ask),
+            "deny" => Ok(ConsentPolicy::Deny),
+            _ => Err(format!(
+                "Unknown policy {:?}, expected allow, ask or deny",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct PeerPolicy {
+    device_id: String,
+    /// Name at the time policy was set
+    name: String,
+    policy: ConsentPolicy,
+}
+
+/// Policies of peers, saved on every change. Store without path lives only
+/// in memory and, unless told otherwise, serves everyone
+#[derive(Debug)]
+pub struct ConsentStore {
+    path: Option<PathBuf>,
+    /// Policy of peers that have none set
+    default: ConsentPolicy,
+    peers: Vec<PeerPolicy>,
+}
+
+impl Default for ConsentStore {
+    fn default() -> Self {
+        ConsentStore {
+            path: None,
+            default: ConsentPolicy::Allow,
+            peers: vec![],
+        }
+    }
+}
+
+impl ConsentStore {
+    /// Store at `$COPYXROSS_CONSENT_FILE` or in data directory. Peers are
+    /// asked about unless `$COPYXROSS_CONSENT` sets another policy
+    pub fn from_env() -> Result<Self, String> {
+        let default = match env::var(CONSENT_ENV) {
+            Ok(policy) if !policy.is_empty() => policy.parse()?,
+            _ => ConsentPolicy::Ask,
+        };
+        let path = match env::var_os(CONSENT_FILE_ENV) {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => data_dir().join(CONSENT_FILE),
+        };
+        ConsentStore::load(&path, default)
+    }
+
+    /// Loads store from file. Missing file is an empty store
+    pub fn load(path: &Path, default: ConsentPolicy) -> Result<Self, String> {
+        let peers = load_json(path)
+            .map_err(|err| format!("Could not read consent from {:?}: {}", path, err))?;
+        Ok(ConsentStore {
+            path: Some(path.to_path_buf()),
+            default,
+            peers,
+        })
+    }
+
+    /// Store that applies one policy to everyone
+    pub fn with_default(default: ConsentPolicy) -> Self {
+        ConsentStore {
+            default,
+            ..Default::default()
+        }
+    }
+
+    pub fn policy(&self, device_id: &str) -> ConsentPolicy {
+        self.peers
+            .iter()
+            .find(|peer| !device_id.is_empty() && peer.device_id == device_id)
+            .map(|peer| peer.policy)
+            .unwrap_or(self.default)
+    }
+
+    /// Sets policy of peer. Old peers without device ID can not have one
+    pub fn set(
+        &mut self,
+        device_id: &str,
+        name: &str,
+        policy: ConsentPolicy,
+    ) -> Result<(), String> {
+        if device_id.is_empty() {
+            return Err(format!(
+                "{} runs older version, policy can not be set",
+                name
+            ));
+        }
+        self.peers.retain(|peer| peer.device_id != device_id);
+        self.peers.push(PeerPolicy {
+            device_id: device_id.to_string(),
+            name: name.to_string(),
+            policy,
+        });
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        save_json(path, &self.peers)
+            .map_err(|err| format!("Could not save consent to {:?}: {}", path, err))
+    }
+}
+
+/// Requests to read our clipboard and what became of them. Log without
+/// path keeps only recent entries in memory
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    recent: VecDeque<String>,
+}
+
+impl AuditLog {
+    /// Log at `$COPYXROSS_AUDIT_LOG` or in data directory
+    pub fn from_env() -> Self {
+        let path = match env::var_os(AUDIT_LOG_ENV) {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => data_dir().join(AUDIT_LOG_FILE),
+        };
+        AuditLog::open(&path)
+    }
+
+    pub fn open(path: &Path) -> Self {
+        AuditLog {
+            path: Some(path.to_path_buf()),
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Appends `time  peer  address  outcome` line
+    pub fn record(&mut self, peer: &str, ip: IpAddr, outcome: &str) {
+        let entry = format!(
+            "{}\t{}\t{}\t{}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+            peer,
+            ip,
+            outcome
+        );
+        if let Some(path) = &self.path {
+            let written = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(path))
+                .and_then(|mut file| writeln!(file, "{}", entry));
+            if let Err(err) = written {
+                let _ = log_into_file(
+                    format!("Could not write audit log {:?}: {}", path, err).as_str(),
+                );
+            }
+        }
+        if self.recent.len() == RECENT_ENTRIES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(entry);
+    }
+
+    /// Latest entries, oldest first
+    pub fn recent(&self) -> Vec<String> {
+        self.recent.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,2248 @@
+//! Core sync engine
+//!
+//! Keeps track of discovered peers and reacts to protocol messages and
+//! commands from other parts of the app. Everything it touches (clipboard,
+//! network, menu and time) is injected, so the whole state machine can be
+//! driven by hand through [`Engine::handle`].
+
+mod consent;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::clipboard::Clipboard;
+use crate::clipboard::ClipboardData;
+use crate::crypto::fingerprint;
+use crate::crypto::Identity;
+use crate::crypto::Pairing;
+use crate::crypto::TrustStore;
+use crate::crypto::PUBLIC_KEY_SIZE;
+use crate::debug_println;
+use crate::encode::compose_message;
+use crate::encode::compose_signed_message;
+use crate::encode::discard;
+use crate::encode::parse_signed_message;
+use crate::encode::Capabilities;
+use crate::encode::EncodeError;
+use crate::encode::MessageType;
+use crate::encode::PairingMessage;
+use crate::encode::PairingStep;
+use crate::encode::PeerData;
+use crate::encode::SignedBy;
+use crate::encode::PAIR_VER;
+use crate::encode::SIGN_VER;
+use crate::network::NetworkError;
+use crate::network::PeerIdentities;
+use crate::network::Senders;
+use crate::network::Transport;
+use crate::network::BROADCAST_ADDR;
+use crate::network::PORT;
+use crate::network::PROTOCOL_VER;
+use crate::utils::log_into_file;
+
+pub use consent::{AuditLog, ConsentPolicy, ConsentStore};
+
+/// How long to wait after network change before rebinding listeners
+const NW_CHANGE_DEBOUNCE: Duration = Duration::new(2, 0);
+/// Peers are rediscovered every 5 min
+const REDISCOVER_TIMEFRAME: Duration = Duration::new(60 * 5, 0);
+/// Pause between polling iterations of the main loop
+const POLL_INTERVAL: Duration = Duration::new(1, 0);
+/// How long Get request waits for peer's clipboard
+const GET_TIMEOUT: Duration = Duration::new(15, 0);
+/// Time to complete pairing, including comparing the codes
+const PAIRING_TIMEOUT: Duration = Duration::new(2 * 60, 0);
+/// Time to answer peer's request to read our clipboard
+const CONSENT_TIMEOUT: Duration = Duration::new(60, 0);
+/// Signed datagrams are accepted only this close to our time, so old ones
+/// can not be replayed once their nonce is forgotten
+const SIGNATURE_WINDOW: Duration = Duration::new(5 * 60, 0);
+/// Signatures of one key remembered within [`SIGNATURE_WINDOW`], further
+/// datagrams signed with it are dropped until the oldest leave the window
+const MAX_SEEN_SIGNATURES: usize = 1024;
+
+#[derive(Debug)]
+pub enum SyncMessage {
+    Stop,
+    Discover,
+    NetworkChange,
+    Cmd((SocketAddr, MessageType)),
+    /// Request from control interface, answered through the sender
+    Control((ControlRequest, Sender<ControlResponse>)),
+}
+
+/// Peer actions available without task menu
+#[derive(Debug, PartialEq, Clone)]
+pub enum ControlRequest {
+    Peers,
+    /// Ask peer to send its clipboard to us
+    Pull(SocketAddr),
+    /// Send our clipboard to peer
+    Push(SocketAddr),
+    /// Fetch peer's clipboard without writing it into ours
+    Get(SocketAddr),
+    /// Send given data to peer
+    Send((SocketAddr, ClipboardData)),
+    Discover,
+    Status,
+    /// Start pairing with peer, answered with the code once both sides have it
+    Pair(SocketAddr),
+    /// User checked that peer shows the same code
+    ConfirmPairing(SocketAddr),
+    CancelPairing(SocketAddr),
+    /// Forget paired peer given by device ID or name
+    Unpair(String),
+    /// Paired peers and pairings in progress
+    Pairings,
+    /// Sets what happens when peer asks for our clipboard
+    SetPolicy((SocketAddr, ConsentPolicy)),
+    /// Allows or denies peer's request waiting for the user
+    Consent((SocketAddr, bool)),
+    /// Recent requests for our clipboard, oldest first
+    Audit,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EngineStatus {
+    pub peer_name: String,
+    pub local_ip: Option<IpAddr>,
+    pub peers: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PairingInfo {
+    pub name: String,
+    pub device_id: String,
+    /// Fingerprint of identity key
+    pub fingerprint: String,
+    /// Address of the peer while it is online
+    pub addr: Option<SocketAddr>,
+    pub paired: bool,
+    /// Code of pairing in progress, once the exchange is complete
+    pub code: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ControlResponse {
+    Ok,
+    Peers(Vec<(SocketAddr, PeerData)>),
+    Status(EngineStatus),
+    Data(ClipboardData),
+    PairingCode(String),
+    Pairings(Vec<PairingInfo>),
+    Audit(Vec<String>),
+    Error(String),
+}
+
+/// Control interface to running engine
+#[derive(Clone)]
+pub struct EngineHandle {
+    sender: Sender<SyncMessage>,
+}
+
+impl EngineHandle {
+    pub fn new(sender: Sender<SyncMessage>) -> Self {
+        EngineHandle { sender }
+    }
+
+    /// Sends request to engine and waits for its response
+    pub fn request(&self, req: ControlRequest) -> ControlResponse {
+        let (reply_sender, reply_receiver) = channel();
+        if self
+            .sender
+            .send(SyncMessage::Control((req, reply_sender)))
+            .is_err()
+        {
+            return ControlResponse::Error("Engine is not running".to_string());
+        }
+        reply_receiver
+            .recv()
+            .unwrap_or(ControlResponse::Error("Engine is not running".to_string()))
+    }
+
+    pub fn stop(&self) {
+        let _ = self.sender.send(SyncMessage::Stop);
+    }
+}
+
+#[derive(Debug)]
+pub enum EngineEvent {
+    /// Message received via UDP
+    Datagram((SocketAddr, MessageType)),
+    /// Message received via UDP with valid signature
+    Signed((SocketAddr, MessageType, SignedBy)),
+    /// Message received via TCP
+    Stream((SocketAddr, MessageType)),
+    Sync(SyncMessage),
+    /// Periodic check of timers
+    Tick,
+}
+
+/// Place where discovered peers are shown to the user
+pub trait PeerMenu {
+    /// Peer is `paired` if data can be exchanged with it
+    fn add_peer(&self, addr: SocketAddr, peer: &PeerData, paired: bool);
+    fn remove_peer(&self, addr: SocketAddr, peer: &PeerData);
+    fn clear_peers(&self);
+    /// Shows code of pairing with peer, for the user to compare and confirm
+    fn show_pairing(&self, addr: SocketAddr, peer: &PeerData, code: &str);
+    /// Pairing was confirmed, cancelled or has failed
+    fn hide_pairing(&self, addr: SocketAddr, peer: &PeerData, code: &str);
+    /// Asks user whether peer may read our clipboard
+    fn show_consent(&self, addr: SocketAddr, peer: &PeerData);
+    /// Request was answered or has expired
+    fn hide_consent(&self, addr: SocketAddr, peer: &PeerData);
+}
+
+/// Menu for machines without a desktop, peers only go to the log
+pub struct LogPeerMenu;
+
+impl PeerMenu for LogPeerMenu {
+    fn add_peer(&self, addr: SocketAddr, peer: &PeerData, paired: bool) {
+        let state = if paired { "" } else { ", not paired" };
+        let _ =
+            log_into_file(format!("Peer found: {:?} ({}{})", peer.peer_name, addr, state).as_str());
+    }
+    fn remove_peer(&self, addr: SocketAddr, peer: &PeerData) {
+        let _ = log_into_file(format!("Peer left: {:?} ({})", peer.peer_name, addr).as_str());
+    }
+    fn clear_peers(&self) {}
+    fn show_pairing(&self, _addr: SocketAddr, peer: &PeerData, code: &str) {
+        let _ = log_into_file(
+            format!(
+                "Pairing code for {:?}: {}. Confirm with `copyxross pair-confirm {}` if it matches",
+                peer.peer_name, code, peer.device_id
+            )
+            .as_str(),
+        );
+    }
+    fn hide_pairing(&self, _addr: SocketAddr, _peer: &PeerData, _code: &str) {}
+    fn show_consent(&self, _addr: SocketAddr, peer: &PeerData) {
+        let _ = log_into_file(
+            format!(
+                "{:?} asks for clipboard. Answer with `copyxross allow {}` or `copyxross deny {}`",
+                peer.peer_name, peer.device_id, peer.device_id
+            )
+            .as_str(),
+        );
+    }
+    fn hide_consent(&self, _addr: SocketAddr, _peer: &PeerData) {}
+}
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+    /// Wall time, to compare with timestamps of peers
+    fn system_time(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Discovered peer
+struct Peer {
+    /// Changes when peer gets new IP
+    addr: SocketAddr,
+    data: PeerData,
+    /// Negotiated protocol version, everything we send is encoded with it
+    version: u32,
+}
+
+/// Get request waiting for peer to send its clipboard
+struct PendingGet {
+    ip: IpAddr,
+    since: Instant,
+    reply: Sender<ControlResponse>,
+}
+
+/// Pairing waiting for the exchange to complete and for the user to confirm
+struct PendingPairing {
+    addr: SocketAddr,
+    peer: PeerData,
+    pairing: Pairing,
+    since: Instant,
+    /// Pair request waiting for the code, None if peer started pairing
+    reply: Option<Sender<ControlResponse>>,
+}
+
+/// Request for our clipboard waiting for the user to answer
+struct PendingConsent {
+    addr: SocketAddr,
+    peer: PeerData,
+    since: Instant,
+}
+
+pub struct Engine<C: Clipboard, T: Transport, M: PeerMenu, K: Clock> {
+    clipboard: C,
+    transport: T,
+    menu: M,
+    clock: K,
+    my_peer_data: PeerData,
+    my_local_ip: Option<IpAddr>,
+    /// Peers by device ID, by IP for old peers that do not send it
+    connection_map: HashMap<String, Peer>,
+    last_rediscover: Instant,
+    last_nw_change_time: Option<Instant>,
+    pending_gets: Vec<PendingGet>,
+    /// None until pairing is enabled with [`Engine::with_identity`]
+    identity: Option<Identity>,
+    trust: TrustStore,
+    /// Data is exchanged only with paired peers
+    require_pairing: bool,
+    pairings: Vec<PendingPairing>,
+    /// What to do when peers ask for our clipboard
+    consent: ConsentStore,
+    audit: AuditLog,
+    consents: Vec<PendingConsent>,
+    /// Signatures seen within [`SIGNATURE_WINDOW`] by key of known peer, to
+    /// drop replayed datagrams
+    seen_signatures: HashMap<[u8; PUBLIC_KEY_SIZE], Vec<SignedBy>>,
+    stopped: bool,
+}
+
+impl<C: Clipboard, T: Transport, M: PeerMenu, K: Clock> Engine<C, T, M, K> {
+    pub fn new(clipboard: C, transport: T, menu: M, clock: K, my_peer_data: PeerData) -> Self {
+        let now = clock.now();
+        Engine {
+            clipboard,
+            transport,
+            menu,
+            clock,
+            my_peer_data,
+            my_local_ip: None,
+            connection_map: HashMap::new(),
+            last_rediscover: now,
+            last_nw_change_time: None,
+            pending_gets: vec![],
+            identity: None,
+            trust: TrustStore::default(),
+            require_pairing: false,
+            pairings: vec![],
+            consent: ConsentStore::default(),
+            audit: AuditLog::default(),
+            consents: vec![],
+            seen_signatures: HashMap::new(),
+            stopped: false,
+        }
+    }
+
+    /// Enables pairing. Identity key is announced to peers, and with
+    /// `required` data is exchanged only with peers in trust store
+    pub fn with_identity(mut self, identity: Identity, trust: TrustStore, required: bool) -> Self {
+        self.my_peer_data.identity = identity.public_key().to_vec();
+        self.identity = Some(identity);
+        self.trust = trust;
+        self.require_pairing = required;
+        self
+    }
+
+    /// Sets policies for requests to read our clipboard and where they are logged
+    pub fn with_consent(mut self, consent: ConsentStore, audit: AuditLog) -> Self {
+        self.consent = consent;
+        self.audit = audit;
+        self
+    }
+
+    /// Binds listeners and greets everyone in local network
+    pub fn start(&mut self) -> Result<(), NetworkError> {
+        self.my_local_ip = Some(self.transport.bind()?);
+        self.greet();
+        Ok(())
+    }
+
+    /// Runs main loop until Stop message is received
+    pub fn run(&mut self, c_receiver: &Receiver<SyncMessage>) {
+        while !self.stopped {
+            self.clock.sleep(POLL_INTERVAL);
+            self.handle(EngineEvent::Tick);
+            // receive SyncMessages
+            let client_msgs: Vec<SyncMessage> = c_receiver.try_iter().collect();
+            for event in self.poll_transport() {
+                self.handle(event);
+            }
+            // control requests may come in bursts, so handle all of them
+            for msg in client_msgs {
+                self.handle(EngineEvent::Sync(msg));
+            }
+        }
+        self.shutdown();
+    }
+
+    /// Collects and parses everything that came through UDP and TCP since last poll
+    pub fn poll_transport(&mut self) -> Vec<EngineEvent> {
+        let mut events = vec![];
+        // Listen to UDP datagrams
+        if let Some((ip_addr, data)) = self.transport.recv_datagram() {
+            match Engine::<C, T, M, K>::parse(ip_addr.ip(), &data) {
+                Some((msg, Some(signed))) => {
+                    events.push(EngineEvent::Signed((ip_addr, msg, signed)))
+                }
+                Some((msg, None)) => events.push(EngineEvent::Datagram((ip_addr, msg))),
+                None => {}
+            }
+        }
+        // Listen to TCP packets
+        self.transport.allow_senders(self.allowed_senders());
+        self.transport.know_peers(self.peer_identities());
+        match self.transport.recv_messages() {
+            Ok((ip_addr, tls_key, messages)) => {
+                if let Err(err) = self.check_link(ip_addr.ip(), tls_key) {
+                    let _ = log_into_file(format!("Refused data: {}", err).as_str());
+                    messages.iter().for_each(discard);
+                    return events;
+                }
+                for msg in messages {
+                    if msg == MessageType::NoMessage {
+                        let _ = log_into_file("Skipping message. Empty message received");
+                        continue;
+                    }
+                    events.push(EngineEvent::Stream((ip_addr, msg)));
+                }
+            }
+            Err(NetworkError::Blocked) => {}
+            Err(err) => {
+                debug_println!("Tcp read err: {:?}", err);
+            }
+        }
+        events
+    }
+
+    fn parse(ip: IpAddr, data: &[u8]) -> Option<(MessageType, Option<SignedBy>)> {
+        let parsed = parse_signed_message(data, ip).unwrap_or_else(|err| {
+            let _ = log_into_file(format!("Parsing error: {:?}", err).as_str());
+            (MessageType::NoMessage, None)
+        });
+        if parsed.0 == MessageType::NoMessage {
+            let _ = log_into_file("Skipping message. Empty message received");
+            return None;
+        }
+        Some(parsed)
+    }
+
+    pub fn handle(&mut self, event: EngineEvent) {
+        match event {
+            EngineEvent::Tick => self.check_timers(),
+            EngineEvent::Datagram((ip_addr, msg)) => self.handle_datagram(ip_addr, msg, None),
+            EngineEvent::Signed((ip_addr, msg, signed)) => {
+                self.handle_datagram(ip_addr, msg, Some(signed))
+            }
+            EngineEvent::Stream((ip_addr, msg)) => self.handle_stream(ip_addr, msg),
+            EngineEvent::Sync(msg) => self.handle_sync(msg),
+        }
+    }
+
+    /// Says goodbye to peers
+    pub fn shutdown(&mut self) {
+        let _ = log_into_file("Sending BYE message...");
+        self.broadcast(&MessageType::Xdis);
+    }
+
+    fn check_timers(&mut self) {
+        let now = self.clock.now();
+        // rebind listeners when debounce time elapses for nw change
+        if let Some(last_nw_change_time) = self.last_nw_change_time {
+            if now.duration_since(last_nw_change_time) > NW_CHANGE_DEBOUNCE {
+                let _ = log_into_file("Network change detected, binding listeners...");
+                self.last_nw_change_time = None;
+                self.transport.unbind();
+                self.clear_peers();
+                match self.transport.bind() {
+                    Ok(ip) => {
+                        self.my_local_ip = Some(ip);
+                        let _ = log_into_file("Listeners recreated.");
+                        self.greet();
+                    }
+                    Err(err) => {
+                        let _ = log_into_file(format!("{:?}", err).as_str());
+                    }
+                }
+            }
+        }
+        if now.duration_since(self.last_rediscover) > REDISCOVER_TIMEFRAME {
+            self.rediscover();
+        }
+        self.pending_gets.retain(|get| {
+            if now.duration_since(get.since) <= GET_TIMEOUT {
+                return true;
+            }
+            let _ = get
+                .reply
+                .send(ControlResponse::Error("Peer did not respond".to_string()));
+            false
+        });
+        let (expired, pairings): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pairings)
+            .into_iter()
+            .partition(|pending| now.duration_since(pending.since) > PAIRING_TIMEOUT);
+        self.pairings = pairings;
+        for pending in expired {
+            self.close_pairing(pending, "Pairing timed out");
+        }
+        let (expired, consents): (Vec<_>, Vec<_>) = std::mem::take(&mut self.consents)
+            .into_iter()
+            .partition(|pending| now.duration_since(pending.since) > CONSENT_TIMEOUT);
+        self.consents = consents;
+        for pending in expired {
+            self.menu.hide_consent(pending.addr, &pending.peer);
+            self.audit
+                .record(&pending.peer.peer_name, pending.addr.ip(), "not answered");
+        }
+    }
+
+    fn handle_datagram(&mut self, ip_addr: SocketAddr, msg: MessageType, signed: Option<SignedBy>) {
+        if Some(ip_addr.ip()) == self.my_local_ip || ip_addr.ip().is_loopback() {
+            return;
+        }
+        let msg = match self.verify_datagram(ip_addr.ip(), msg, signed) {
+            Ok(msg) => msg,
+            Err(err) => {
+                let _ =
+                    log_into_file(format!("Dropped datagram from {}: {}", ip_addr, err).as_str());
+                return;
+            }
+        };
+        match msg {
+            MessageType::Xacn(data) => {
+                let _ = log_into_file(format!("Ack got: {:?}", data).as_str());
+                self.add_peer(ip_addr, data);
+            }
+            MessageType::Xcon(data) => {
+                let _ = log_into_file(format!("Connection got: {:?}", data).as_str());
+                let Some(version) = self.negotiate(&data) else {
+                    return;
+                };
+                // creating acknowledgment msg to response to the peer
+                let ack_msg =
+                    self.compose_datagram(&MessageType::Xacn(self.my_peer_data.clone()), version);
+                match ack_msg {
+                    Ok(ack_msg) => self.transport.send_datagram(ip_addr, &ack_msg),
+                    Err(err) => {
+                        let _ =
+                            log_into_file(format!("Failed to compose ack msg: {:?}", err).as_str());
+                    }
+                }
+                self.add_peer(ip_addr, data);
+            }
+            MessageType::Xdis => self.remove_peers_at(ip_addr.ip()),
+            MessageType::Xcpy => self.serve_clipboard(ip_addr),
+            MessageType::Xpar(msg) => self.handle_pairing(ip_addr, msg),
+            _ => {}
+        }
+    }
+
+    /// Discovery datagrams of peer known by identity key have to be signed
+    /// with it. Unsigned peer data can not claim a key, so its sender is
+    /// treated like peer older than pairing
+    fn verify_datagram(
+        &mut self,
+        ip: IpAddr,
+        mut msg: MessageType,
+        signed: Option<SignedBy>,
+    ) -> Result<MessageType, String> {
+        if !matches!(
+            msg,
+            MessageType::Xcon(_) | MessageType::Xacn(_) | MessageType::Xdis
+        ) {
+            return Ok(msg);
+        }
+        let key = signed.map(|signed| signed.key);
+        // peer known by key keeps its address until it leaves or is rediscovered
+        if let Some(peer) = self.peer_at(ip) {
+            let owner = peer.data.identity.as_slice();
+            if !owner.is_empty() && key.as_ref().map(|key| &key[..]) != Some(owner) {
+                return Err(format!("not signed by {:?}", peer.data.peer_name));
+            }
+        }
+        if let MessageType::Xcon(data) | MessageType::Xacn(data) = &mut msg {
+            match key {
+                Some(key) if key[..] != data.identity[..] => {
+                    return Err(format!(
+                        "{:?} signed with another identity key",
+                        data.peer_name
+                    ))
+                }
+                Some(_) => {}
+                None => data.identity.clear(),
+            }
+            if let Some(peer) = self.connection_map.get(&data.device_id) {
+                if !peer.data.identity.is_empty() && peer.data.identity != data.identity {
+                    return Err(format!(
+                        "{:?} is known with another identity key",
+                        peer.data.peer_name
+                    ));
+                }
+            }
+        }
+        if let Some(signed) = signed {
+            // greeting binds the key to the peer it announces
+            let binds_key = matches!(msg, MessageType::Xcon(_) | MessageType::Xacn(_));
+            self.check_replay(signed, binds_key)?;
+        }
+        Ok(msg)
+    }
+
+    /// Refuses signatures far from our time and ones seen before. Nonces are
+    /// remembered only for keys of known peers, so datagrams signed with
+    /// made up keys can not push them out
+    fn check_replay(&mut self, signed: SignedBy, binds_key: bool) -> Result<(), String> {
+        let now = self.timestamp();
+        let window = SIGNATURE_WINDOW.as_millis() as u64;
+        if signed.timestamp.abs_diff(now) > window {
+            return Err("signature is too old or clocks differ".to_string());
+        }
+        self.seen_signatures.retain(|_, seen| {
+            seen.retain(|seen| seen.timestamp.abs_diff(now) <= window);
+            !seen.is_empty()
+        });
+        let known = self
+            .connection_map
+            .values()
+            .any(|peer| peer.data.identity[..] == signed.key[..]);
+        if !known && !binds_key {
+            return Ok(());
+        }
+        let seen = self.seen_signatures.entry(signed.key).or_default();
+        if seen.iter().any(|seen| seen.nonce == signed.nonce) {
+            return Err("datagram was replayed".to_string());
+        }
+        if seen.len() == MAX_SEEN_SIGNATURES {
+            return Err("too many datagrams signed with the key".to_string());
+        }
+        seen.push(signed);
+        Ok(())
+    }
+
+    /// Milliseconds since Unix epoch
+    fn timestamp(&self) -> u64 {
+        self.clock
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64
+    }
+
+    /// Xcon, Xacn and Xdis are signed once we have identity key and address
+    /// to send them from, and peer speaks version that reads signatures
+    fn compose_datagram(&self, msg: &MessageType, version: u32) -> Result<Vec<u8>, EncodeError> {
+        match (&self.identity, self.my_local_ip) {
+            (Some(identity), Some(source)) if version >= SIGN_VER => {
+                compose_signed_message(msg, version, identity, source, self.timestamp())
+            }
+            _ => compose_message(msg, version),
+        }
+    }
+
+    /// Handle msg from TCP (usually data to write into CP)
+    fn handle_stream(&mut self, ip_addr: SocketAddr, msg: MessageType) {
+        if let MessageType::Xpst(cp_data) = msg {
+            if let Err(err) = self.check_pairing(ip_addr.ip()) {
+                let _ = log_into_file(format!("Refused data: {}", err).as_str());
+                for path in cp_data.file_paths() {
+                    let _ = fs::remove_file(path);
+                }
+                return;
+            }
+            // data requested by Get goes to the requester instead of clipboard
+            let pending = self
+                .pending_gets
+                .iter()
+                .position(|get| get.ip == ip_addr.ip());
+            // received files stay in spool directory unless they are placed somewhere
+            let spooled = cp_data.file_paths();
+            let placed = if let Some(idx) = pending {
+                let get = self.pending_gets.remove(idx);
+                get.reply.send(ControlResponse::Data(cp_data)).is_ok()
+            } else {
+                let peer_name = self
+                    .peer_at(ip_addr.ip())
+                    .map(|peer| peer.data.peer_name.clone())
+                    .unwrap_or(ip_addr.ip().to_string());
+                self.clipboard
+                    .receive(cp_data, &peer_name)
+                    .map_err(|err| {
+                        let _ = log_into_file(format!("CLIPBOARD ERR: {:?}", err).as_str());
+                    })
+                    .is_ok()
+            };
+            if !placed {
+                for path in spooled {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+
+    fn handle_sync(&mut self, msg: SyncMessage) {
+        match msg {
+            SyncMessage::Cmd((target, MessageType::Xcpy)) => {
+                if let Err(err) = self.request_clipboard(target) {
+                    let _ = log_into_file(err.as_str());
+                }
+            }
+            SyncMessage::Cmd(_) => {}
+            SyncMessage::Stop => {
+                self.stopped = true;
+            }
+            SyncMessage::Discover => self.rediscover(),
+            SyncMessage::NetworkChange => {
+                self.last_nw_change_time = Some(self.clock.now());
+            }
+            SyncMessage::Control((ControlRequest::Get(target), reply)) => {
+                if let Err(err) = self.request_clipboard(target) {
+                    let _ = reply.send(ControlResponse::Error(err));
+                    return;
+                }
+                self.pending_gets.push(PendingGet {
+                    ip: target.ip(),
+                    since: self.clock.now(),
+                    reply,
+                });
+            }
+            SyncMessage::Control((ControlRequest::Pair(target), reply)) => {
+                if let Err(err) = self.start_pairing(target, reply.clone()) {
+                    let _ = reply.send(ControlResponse::Error(err));
+                }
+            }
+            SyncMessage::Control((req, reply)) => {
+                let _ = reply.send(self.control(req));
+            }
+        }
+    }
+
+    fn control(&mut self, req: ControlRequest) -> ControlResponse {
+        match req {
+            ControlRequest::Peers => {
+                let mut peers: Vec<(SocketAddr, PeerData)> = self
+                    .connection_map
+                    .values()
+                    .map(|peer| (peer.addr, peer.data.clone()))
+                    .collect();
+                peers.sort_by_key(|(addr, _)| *addr);
+                ControlResponse::Peers(peers)
+            }
+            ControlRequest::Pull(target) => match self.request_clipboard(target) {
+                Ok(()) => ControlResponse::Ok,
+                Err(err) => ControlResponse::Error(err),
+            },
+            ControlRequest::Push(target) => match self.send_clipboard(target) {
+                Ok(()) => ControlResponse::Ok,
+                Err(err) => ControlResponse::Error(err),
+            },
+            ControlRequest::Send((target, data)) => match self.send_data(target, data) {
+                Ok(()) => ControlResponse::Ok,
+                Err(err) => ControlResponse::Error(err),
+            },
+            ControlRequest::Get(_) | ControlRequest::Pair(_) => {
+                ControlResponse::Error("Request is answered asynchronously".to_string())
+            }
+            ControlRequest::Discover => {
+                self.rediscover();
+                ControlResponse::Ok
+            }
+            ControlRequest::Status => ControlResponse::Status(EngineStatus {
+                peer_name: self.my_peer_data.peer_name.clone(),
+                local_ip: self.my_local_ip,
+                peers: self.connection_map.len(),
+            }),
+            ControlRequest::ConfirmPairing(target) => match self.confirm_pairing(target) {
+                Ok(()) => ControlResponse::Ok,
+                Err(err) => ControlResponse::Error(err),
+            },
+            ControlRequest::CancelPairing(target) => {
+                let Some(idx) = self
+                    .pairings
+                    .iter()
+                    .position(|pending| pending.addr.ip() == target.ip())
+                else {
+                    return ControlResponse::Error(format!("No pairing with {}", target.ip()));
+                };
+                let pending = self.pairings.remove(idx);
+                let _ = self.send_pairing(pending.addr, pending.pairing.cancel_message());
+                self.close_pairing(pending, "Pairing cancelled");
+                ControlResponse::Ok
+            }
+            ControlRequest::Unpair(peer) => match self.trust.forget(&peer) {
+                Ok(true) => {
+                    self.refresh_peers();
+                    ControlResponse::Ok
+                }
+                Ok(false) => ControlResponse::Error(format!("{} is not paired", peer)),
+                Err(err) => ControlResponse::Error(format!("{:?}", err)),
+            },
+            ControlRequest::Pairings => ControlResponse::Pairings(self.pairing_info()),
+            ControlRequest::SetPolicy((target, policy)) => {
+                let Some(peer) = self.peer_at(target.ip()) else {
+                    return ControlResponse::Error(format!("Unknown peer: {}", target.ip()));
+                };
+                let (device_id, name) = (peer.data.device_id.clone(), peer.data.peer_name.clone());
+                match self.consent.set(&device_id, &name, policy) {
+                    Ok(()) => ControlResponse::Ok,
+                    Err(err) => ControlResponse::Error(err),
+                }
+            }
+            ControlRequest::Consent((target, allow)) => match self.answer_consent(target, allow) {
+                Ok(()) => ControlResponse::Ok,
+                Err(err) => ControlResponse::Error(err),
+            },
+            ControlRequest::Audit => ControlResponse::Audit(self.audit.recent()),
+        }
+    }
+
+    /// Asks peer to send its clipboard to us
+    fn request_clipboard(&self, target: SocketAddr) -> Result<(), String> {
+        self.check_pairing(target.ip())?;
+        let data = compose_message(&MessageType::Xcpy, self.peer_version(target.ip()))
+            .map_err(|err| format!("Failed to compose message: {:?}", err))?;
+        self.transport.send_datagram(target, &data);
+        Ok(())
+    }
+
+    /// Serves, refuses or asks the user about peer's request for our
+    /// clipboard, as its policy says
+    fn serve_clipboard(&mut self, ip_addr: SocketAddr) {
+        let peer = self.peer_at(ip_addr.ip()).map(|peer| peer.data.clone());
+        let name = peer
+            .as_ref()
+            .map(|peer| peer.peer_name.clone())
+            .unwrap_or(ip_addr.ip().to_string());
+        if let Err(err) = self.check_pairing(ip_addr.ip()) {
+            let _ = log_into_file(format!("Refused clipboard request: {}", err).as_str());
+            self.audit
+                .record(&name, ip_addr.ip(), "refused, not paired");
+            return;
+        }
+        let device_id = peer.as_ref().map(|peer| peer.device_id.as_str());
+        match self.consent.policy(device_id.unwrap_or_default()) {
+            ConsentPolicy::Allow => self.serve_now(ip_addr, &name, "allowed by policy"),
+            ConsentPolicy::Deny => self.audit.record(&name, ip_addr.ip(), "denied by policy"),
+            ConsentPolicy::Ask => self.ask_consent(ip_addr, &name, peer),
+        }
+    }
+
+    /// Shows request to the user, one per peer at a time. Unknown peers can
+    /// not be shown, so they are refused
+    fn ask_consent(&mut self, ip_addr: SocketAddr, name: &str, peer: Option<PeerData>) {
+        let Some(peer) = peer else {
+            self.audit
+                .record(name, ip_addr.ip(), "refused, unknown peer");
+            return;
+        };
+        if self
+            .consents
+            .iter()
+            .any(|pending| pending.addr.ip() == ip_addr.ip())
+        {
+            self.audit
+                .record(name, ip_addr.ip(), "ignored, already asking");
+            return;
+        }
+        self.menu.show_consent(ip_addr, &peer);
+        self.consents.push(PendingConsent {
+            addr: ip_addr,
+            peer,
+            since: self.clock.now(),
+        });
+    }
+
+    fn serve_now(&mut self, ip_addr: SocketAddr, name: &str, outcome: &str) {
+        match self.send_clipboard(ip_addr) {
+            Ok(()) => self.audit.record(name, ip_addr.ip(), outcome),
+            Err(err) => {
+                let _ = log_into_file(err.as_str());
+                self.audit
+                    .record(name, ip_addr.ip(), &format!("{}, failed: {}", outcome, err));
+            }
+        }
+    }
+
+    /// Answer of the user to request waiting for consent
+    fn answer_consent(&mut self, target: SocketAddr, allow: bool) -> Result<(), String> {
+        let idx = self
+            .consents
+            .iter()
+            .position(|pending| pending.addr.ip() == target.ip())
+            .ok_or(format!("No request from {} waits for consent", target.ip()))?;
+        let pending = self.consents.remove(idx);
+        self.menu.hide_consent(pending.addr, &pending.peer);
+        match allow {
+            true => self.serve_now(pending.addr, &pending.peer.peer_name, "allowed by user"),
+            false => {
+                self.audit
+                    .record(&pending.peer.peer_name, pending.addr.ip(), "denied by user")
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends our clipboard to peer via TCP
+    fn send_clipboard(&mut self, ip_addr: SocketAddr) -> Result<(), String> {
+        let cp_buffer = self
+            .clipboard
+            .read()
+            .map_err(|err| format!("CLIPBOARD ERR: {:?}", err))?;
+        self.send_data(ip_addr, cp_buffer)
+    }
+
+    fn send_data(&mut self, ip_addr: SocketAddr, cp_data: ClipboardData) -> Result<(), String> {
+        self.check_pairing(ip_addr.ip())?;
+        self.check_encryption(ip_addr.ip())?;
+        let version = self.peer_version(ip_addr.ip());
+        let tls_key = self.tls_key(ip_addr.ip());
+        self.transport
+            .send_message(&ip_addr, &MessageType::Xpst(cp_data), version, tls_key)
+            .map_err(|err| format!("Error sending TCP message: {:?}", err))
+    }
+
+    /// Identity key to pin TLS connection to, if both sides accept TLS.
+    /// With pairing required it is the key peer was paired with
+    fn tls_key(&self, ip: IpAddr) -> Option<[u8; PUBLIC_KEY_SIZE]> {
+        let peer = self.peer_at(ip)?;
+        let both = self.my_peer_data.capabilities.contains(Capabilities::TLS)
+            && peer.data.capabilities.contains(Capabilities::TLS);
+        match both {
+            true => peer.data.identity.as_slice().try_into().ok(),
+            false => None,
+        }
+    }
+
+    /// Peer that accepts TLS has to connect with it too, and with the
+    /// identity key it announced. Old peers still connect in the clear
+    fn check_link(&self, ip: IpAddr, tls_key: Option<[u8; PUBLIC_KEY_SIZE]>) -> Result<(), String> {
+        let Some(peer) = self.peer_at(ip) else {
+            return Ok(());
+        };
+        match (tls_key, self.tls_key(ip)) {
+            (Some(key), _) if key[..] == peer.data.identity[..] => Ok(()),
+            (Some(_), _) => Err(format!(
+                "{} connected with another identity key",
+                peer.data.peer_name
+            )),
+            (None, Some(_)) => Err(format!("{} connected without TLS", peer.data.peer_name)),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Data goes only to peers that encrypt like we do, so it is neither
+    /// sent in the clear nor sent to someone who can not read it
+    fn check_encryption(&self, ip: IpAddr) -> Result<(), String> {
+        let Some(peer) = self.peer_at(ip) else {
+            return Ok(());
+        };
+        let ours = self
+            .my_peer_data
+            .capabilities
+            .contains(Capabilities::ENCRYPTION);
+        match (
+            ours,
+            peer.data.capabilities.contains(Capabilities::ENCRYPTION),
+        ) {
+            (true, false) => Err(format!(
+                "{} has no passphrase set, refusing to send unencrypted data",
+                peer.data.peer_name
+            )),
+            (false, true) => Err(format!(
+                "{} expects encrypted data, passphrase is not set",
+                peer.data.peer_name
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Data goes to and comes from paired peers only, unless pairing is not required
+    fn check_pairing(&self, ip: IpAddr) -> Result<(), String> {
+        if !self.require_pairing {
+            return Ok(());
+        }
+        match self.peer_at(ip) {
+            Some(peer) if self.is_paired(&peer.data) => Ok(()),
+            Some(peer) => Err(format!("{} is not paired", peer.data.peer_name)),
+            None => Err(format!("{} is not paired", ip)),
+        }
+    }
+
+    /// With pairing required data is read only from paired peers, over TLS
+    /// with their key if they accept it
+    fn allowed_senders(&self) -> Option<Senders> {
+        if !self.require_pairing {
+            return None;
+        }
+        let senders = self
+            .connection_map
+            .values()
+            .filter(|peer| self.is_paired(&peer.data))
+            .map(|peer| (peer.addr.ip(), self.tls_key(peer.addr.ip())))
+            .collect();
+        Some(senders)
+    }
+
+    /// Identity keys peers announced. Unsigned announcements carry none
+    fn peer_identities(&self) -> PeerIdentities {
+        self.connection_map
+            .values()
+            .map(|peer| (peer.addr.ip(), peer.data.identity.clone()))
+            .collect()
+    }
+
+    fn is_paired(&self, data: &PeerData) -> bool {
+        self.trust.is_trusted(&data.device_id, &data.identity)
+    }
+
+    /// Shown as paired in menu if data can be exchanged with it
+    fn is_usable(&self, data: &PeerData) -> bool {
+        !self.require_pairing || self.is_paired(data)
+    }
+
+    /// Sends Commit to peer, the code is sent to `reply` once peer answers
+    fn start_pairing(
+        &mut self,
+        target: SocketAddr,
+        reply: Sender<ControlResponse>,
+    ) -> Result<(), String> {
+        let identity = self
+            .identity
+            .as_ref()
+            .ok_or("Pairing is not enabled".to_string())?;
+        let peer = self
+            .peer_at(target.ip())
+            .ok_or(format!("Unknown peer: {}", target.ip()))?;
+        if peer.version < PAIR_VER
+            || peer.data.device_id.is_empty()
+            || peer.data.identity.len() != PUBLIC_KEY_SIZE
+        {
+            return Err(format!(
+                "{} runs older version and can not pair",
+                peer.data.peer_name
+            ));
+        }
+        let (addr, data) = (peer.addr, peer.data.clone());
+        let (pairing, commit) = Pairing::start(identity);
+        self.send_pairing(addr, commit)?;
+        self.replace_pairing(PendingPairing {
+            addr,
+            peer: data,
+            pairing,
+            since: self.clock.now(),
+            reply: Some(reply),
+        });
+        Ok(())
+    }
+
+    /// Steps of pairing exchange, only with discovered peers that announce
+    /// the same identity key they pair with
+    fn handle_pairing(&mut self, ip_addr: SocketAddr, msg: PairingMessage) {
+        let Some(identity) = &self.identity else {
+            return;
+        };
+        let Some(peer) = self
+            .peer_at(ip_addr.ip())
+            .filter(|peer| !peer.data.device_id.is_empty() && peer.data.identity == msg.identity)
+        else {
+            let _ = log_into_file(
+                format!("Ignoring pairing message from unknown peer {}", ip_addr).as_str(),
+            );
+            return;
+        };
+        let (addr, data) = (peer.addr, peer.data.clone());
+        if msg.step == PairingStep::Commit {
+            let result = Pairing::respond(identity, &msg)
+                .map_err(|err| format!("{:?}", err))
+                .and_then(|(pairing, key)| {
+                    self.send_pairing(addr, key)?;
+                    Ok(pairing)
+                });
+            match result {
+                Ok(pairing) => {
+                    let _ = log_into_file(
+                        format!("Pairing requested by {:?}", data.peer_name).as_str(),
+                    );
+                    self.replace_pairing(PendingPairing {
+                        addr,
+                        peer: data,
+                        pairing,
+                        since: self.clock.now(),
+                        reply: None,
+                    });
+                }
+                Err(err) => {
+                    let _ = log_into_file(format!("Pairing failed: {}", err).as_str());
+                }
+            }
+            return;
+        }
+        let Some(idx) = self.pairings.iter().position(|pending| {
+            pending.pairing.session() == msg.session && pending.peer.device_id == data.device_id
+        }) else {
+            return;
+        };
+        let answered = self.pairings[idx]
+            .pairing
+            .handle(&msg)
+            .map_err(|err| format!("{:?}", err))
+            .and_then(|answer| match answer {
+                Some(answer) => self.send_pairing(addr, answer),
+                None => Ok(()),
+            });
+        if let Err(err) = answered {
+            let pending = self.pairings.remove(idx);
+            self.close_pairing(pending, &format!("Pairing failed: {}", err));
+            return;
+        }
+        let pending = &mut self.pairings[idx];
+        if let Some(code) = pending.pairing.code() {
+            self.menu.show_pairing(pending.addr, &pending.peer, code);
+            if let Some(reply) = pending.reply.take() {
+                let _ = reply.send(ControlResponse::PairingCode(code.to_string()));
+            }
+        }
+    }
+
+    fn send_pairing(&self, addr: SocketAddr, msg: PairingMessage) -> Result<(), String> {
+        let data = compose_message(&MessageType::Xpar(msg), self.peer_version(addr.ip()))
+            .map_err(|err| format!("Failed to compose pairing message: {:?}", err))?;
+        self.transport.send_datagram(addr, &data);
+        Ok(())
+    }
+
+    /// Keeps one pairing per device, the newest one
+    fn replace_pairing(&mut self, pairing: PendingPairing) {
+        let (replaced, pairings): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pairings)
+            .into_iter()
+            .partition(|pending| pending.peer.device_id == pairing.peer.device_id);
+        self.pairings = pairings;
+        for pending in replaced {
+            self.close_pairing(pending, "Another pairing started");
+        }
+        self.pairings.push(pairing);
+    }
+
+    /// Remembers peer whose code the user has checked
+    fn confirm_pairing(&mut self, target: SocketAddr) -> Result<(), String> {
+        let idx = self
+            .pairings
+            .iter()
+            .position(|pending| {
+                pending.addr.ip() == target.ip() && pending.pairing.code().is_some()
+            })
+            .ok_or(format!("No pairing code to confirm for {}", target.ip()))?;
+        let pending = self.pairings.remove(idx);
+        let key = pending.pairing.peer_identity().unwrap_or_default();
+        let trusted = self
+            .trust
+            .trust(&pending.peer.device_id, &pending.peer.peer_name, &key)
+            .map_err(|err| format!("{:?}", err));
+        let _ = log_into_file(format!("Paired with {:?}", pending.peer.peer_name).as_str());
+        self.close_pairing(pending, "Pairing confirmed");
+        self.refresh_peers();
+        trusted
+    }
+
+    /// Removes code from menu and answers Pair request that still waits
+    fn close_pairing(&self, pending: PendingPairing, reason: &str) {
+        debug_println!(
+            "Pairing with {:?} closed: {}",
+            pending.peer.peer_name,
+            reason
+        );
+        if let Some(code) = pending.pairing.code() {
+            self.menu.hide_pairing(pending.addr, &pending.peer, code);
+        }
+        if let Some(reply) = pending.reply {
+            let _ = reply.send(ControlResponse::Error(reason.to_string()));
+        }
+    }
+
+    /// Shows peers again after they were paired or unpaired
+    fn refresh_peers(&self) {
+        for peer in self.connection_map.values() {
+            self.menu.remove_peer(peer.addr, &peer.data);
+            self.menu
+                .add_peer(peer.addr, &peer.data, self.is_usable(&peer.data));
+        }
+    }
+
+    fn pairing_info(&self) -> Vec<PairingInfo> {
+        let online = |device_id: &str| {
+            self.connection_map
+                .values()
+                .find(|peer| peer.data.device_id == device_id)
+                .map(|peer| peer.addr)
+        };
+        let paired = self.trust.peers().iter().map(|peer| PairingInfo {
+            name: peer.name.clone(),
+            device_id: peer.device_id.clone(),
+            fingerprint: peer.fingerprint(),
+            addr: online(&peer.device_id),
+            paired: true,
+            code: None,
+        });
+        let pending = self.pairings.iter().map(|pending| PairingInfo {
+            name: pending.peer.peer_name.clone(),
+            device_id: pending.peer.device_id.clone(),
+            fingerprint: fingerprint(&pending.peer.identity),
+            addr: Some(pending.addr),
+            paired: false,
+            code: pending.pairing.code().map(str::to_string),
+        });
+        paired.chain(pending).collect()
+    }
+
+    fn add_peer(&mut self, ip_addr: SocketAddr, data: PeerData) {
+        let Some(version) = self.negotiate(&data) else {
+            return;
+        };
+        let port = if data.port == 0 { PORT } else { data.port };
+        let addr = SocketAddr::new(ip_addr.ip(), port);
+        let key = if data.device_id.is_empty() {
+            addr.ip().to_string()
+        } else {
+            data.device_id.clone()
+        };
+        let usable = self.is_usable(&data);
+        if let Some(peer) = self.connection_map.get_mut(&key) {
+            if peer.addr == addr {
+                // greeting again, possibly after upgrade or re-pairing
+                peer.version = version;
+                if peer.data != data {
+                    let old = std::mem::replace(&mut peer.data, data);
+                    self.menu.remove_peer(addr, &old);
+                    self.menu.add_peer(addr, &peer.data, usable);
+                }
+                return;
+            }
+        }
+        // known device got new address or someone else took this one
+        if let Some(peer) = self.connection_map.remove(&key) {
+            self.menu.remove_peer(peer.addr, &peer.data);
+        }
+        self.remove_peers_at(addr.ip());
+        self.menu.add_peer(addr, &data, usable);
+        self.connection_map.insert(
+            key,
+            Peer {
+                addr,
+                data,
+                version,
+            },
+        );
+    }
+
+    fn remove_peers_at(&mut self, ip: IpAddr) {
+        let menu = &self.menu;
+        self.connection_map.retain(|_, peer| {
+            if peer.addr.ip() != ip {
+                return true;
+            }
+            menu.remove_peer(peer.addr, &peer.data);
+            false
+        });
+    }
+
+    fn peer_at(&self, ip: IpAddr) -> Option<&Peer> {
+        self.connection_map
+            .values()
+            .find(|peer| peer.addr.ip() == ip)
+    }
+
+    /// Picks highest protocol version both we and peer speak
+    fn negotiate(&self, data: &PeerData) -> Option<u32> {
+        let version = self.my_peer_data.versions.negotiate(&data.versions);
+        if version.is_none() {
+            let _ = log_into_file(
+                format!(
+                    "Ignoring {:?}: incompatible protocol versions {:?}",
+                    data.peer_name, data.versions
+                )
+                .as_str(),
+            );
+        }
+        version
+    }
+
+    /// Version to talk to peer with. Unknown peers get the oldest one
+    fn peer_version(&self, ip: IpAddr) -> u32 {
+        self.peer_at(ip)
+            .map(|peer| peer.version)
+            .unwrap_or(self.my_peer_data.versions.min)
+    }
+
+    /// Clears peers from menu, keeping pairings and consents that still wait
+    fn clear_peers(&mut self) {
+        self.connection_map.clear();
+        self.menu.clear_peers();
+        for pending in &self.pairings {
+            if let Some(code) = pending.pairing.code() {
+                self.menu.show_pairing(pending.addr, &pending.peer, code);
+            }
+        }
+        for pending in &self.consents {
+            self.menu.show_consent(pending.addr, &pending.peer);
+        }
+    }
+
+    fn rediscover(&mut self) {
+        self.clear_peers();
+        self.greet();
+        self.last_rediscover = self.clock.now();
+    }
+
+    /// Sends greeting message to all peers
+    fn greet(&self) {
+        let _ = log_into_file("Sending greeting message...");
+        self.broadcast(&MessageType::Xcon(self.my_peer_data.clone()));
+    }
+
+    fn broadcast(&self, msg: &MessageType) {
+        match self.compose_datagram(msg, PROTOCOL_VER) {
+            Ok(data) => self.transport.send_datagram(BROADCAST_ADDR, &data),
+            Err(err) => {
+                let _ = log_into_file(format!("Failed to compose message: {:?}", err).as_str());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ClipboardData;
+    use crate::clipboard::MemoryClipboard;
+    use crate::clipboard::StringType;
+    use crate::crypto::Identity;
+    use crate::encode::parse_message;
+    use crate::encode::VersionRange;
+    use crate::network::Received;
+    use std::cell::Cell;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::net::Ipv4Addr;
+
+    const MY_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+    const PEER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 3)), 53300);
+
+    #[derive(Default)]
+    struct FakeTransport {
+        binds: usize,
+        datagrams: RefCell<Vec<(SocketAddr, MessageType)>>,
+        messages: RefCell<Vec<(SocketAddr, MessageType)>>,
+        message_version: Cell<Option<u32>>,
+        message_key: Cell<Option<[u8; PUBLIC_KEY_SIZE]>>,
+        incoming_datagrams: VecDeque<(SocketAddr, Vec<u8>)>,
+        incoming_messages: VecDeque<Received>,
+        senders: Option<Senders>,
+        identities: PeerIdentities,
+    }
+
+    impl Transport for FakeTransport {
+        fn bind(&mut self) -> Result<IpAddr, NetworkError> {
+            self.binds += 1;
+            Ok(MY_IP)
+        }
+        fn unbind(&mut self) {}
+        fn send_datagram(&self, target: SocketAddr, data: &[u8]) {
+            let msg = parse_message(data).unwrap();
+            self.datagrams.borrow_mut().push((target, msg));
+        }
+        fn recv_datagram(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+            self.incoming_datagrams.pop_front()
+        }
+        fn send_message(
+            &self,
+            target: &SocketAddr,
+            message: &MessageType,
+            protocol_ver: u32,
+            tls_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+        ) -> Result<(), NetworkError> {
+            self.message_version.set(Some(protocol_ver));
+            self.message_key.set(tls_key);
+            self.messages.borrow_mut().push((*target, message.clone()));
+            Ok(())
+        }
+        fn recv_messages(&mut self) -> Result<Received, NetworkError> {
+            self.incoming_messages
+                .pop_front()
+                .ok_or(NetworkError::Blocked)
+        }
+        fn allow_senders(&mut self, senders: Option<Senders>) {
+            self.senders = senders;
+        }
+        fn know_peers(&mut self, identities: PeerIdentities) {
+            self.identities = identities;
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeMenu {
+        peers: RefCell<Vec<(SocketAddr, String)>>,
+        paired: RefCell<Vec<SocketAddr>>,
+        codes: RefCell<Vec<(SocketAddr, String)>>,
+        consents: RefCell<Vec<SocketAddr>>,
+    }
+
+    impl PeerMenu for FakeMenu {
+        fn add_peer(&self, addr: SocketAddr, peer: &PeerData, paired: bool) {
+            self.peers.borrow_mut().push((addr, peer.peer_name.clone()));
+            if paired {
+                self.paired.borrow_mut().push(addr);
+            }
+        }
+        fn remove_peer(&self, addr: SocketAddr, _peer: &PeerData) {
+            self.peers.borrow_mut().retain(|(a, _)| *a != addr);
+            self.paired.borrow_mut().retain(|a| *a != addr);
+        }
+        fn clear_peers(&self) {
+            self.peers.borrow_mut().clear();
+            self.paired.borrow_mut().clear();
+            self.codes.borrow_mut().clear();
+            self.consents.borrow_mut().clear();
+        }
+        fn show_pairing(&self, addr: SocketAddr, _peer: &PeerData, code: &str) {
+            self.codes.borrow_mut().push((addr, code.to_string()));
+        }
+        fn hide_pairing(&self, addr: SocketAddr, _peer: &PeerData, code: &str) {
+            self.codes
+                .borrow_mut()
+                .retain(|(a, c)| (*a, c.as_str()) != (addr, code));
+        }
+        fn show_consent(&self, addr: SocketAddr, _peer: &PeerData) {
+            self.consents.borrow_mut().push(addr);
+        }
+        fn hide_consent(&self, addr: SocketAddr, _peer: &PeerData) {
+            self.consents.borrow_mut().retain(|a| *a != addr);
+        }
+    }
+
+    struct ManualClock {
+        now: Cell<Instant>,
+        wall: Cell<SystemTime>,
+    }
+
+    impl ManualClock {
+        fn advance(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+            self.wall.set(self.wall.get() + duration);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+        fn system_time(&self) -> SystemTime {
+            self.wall.get()
+        }
+        fn sleep(&self, duration: Duration) {
+            self.advance(duration);
+        }
+    }
+
+    type TestEngine = Engine<MemoryClipboard, FakeTransport, FakeMenu, ManualClock>;
+
+    fn peer(name: &str) -> PeerData {
+        PeerData::new(name.to_string(), format!("{}-id", name))
+    }
+
+    fn started_engine() -> TestEngine {
+        let clock = ManualClock {
+            now: Cell::new(Instant::now()),
+            wall: Cell::new(SystemTime::now()),
+        };
+        let mut engine = Engine::new(
+            MemoryClipboard::default(),
+            FakeTransport::default(),
+            FakeMenu::default(),
+            clock,
+            peer("me"),
+        );
+        engine.start().unwrap();
+        engine.transport.datagrams.borrow_mut().clear();
+        engine
+    }
+
+    #[test]
+    fn test_greeting_is_acknowledged() {
+        let mut engine = started_engine();
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xcon(peer("peer")),
+        )));
+
+        assert_eq!(
+            *engine.transport.datagrams.borrow(),
+            vec![(PEER_ADDR, MessageType::Xacn(peer("me")))]
+        );
+        assert_eq!(
+            *engine.menu.peers.borrow(),
+            vec![(PEER_ADDR, "peer".to_string())]
+        );
+
+        // repeated greeting does not duplicate menu entry
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("peer")),
+        )));
+        assert_eq!(engine.menu.peers.borrow().len(), 1);
+
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xdis)));
+        assert!(engine.menu.peers.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_protocol_version_negotiation() {
+        let mut engine = started_engine();
+        let old_peer = PeerData {
+            versions: VersionRange::single(1),
+            ..peer("old")
+        };
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xcon(old_peer),
+        )));
+        // ack is downgraded, so it carries only the name
+        assert_eq!(
+            *engine.transport.datagrams.borrow(),
+            vec![(
+                PEER_ADDR,
+                MessageType::Xacn(PeerData {
+                    device_id: String::new(),
+                    os: String::new(),
+                    app_version: String::new(),
+                    capabilities: Capabilities::FILES.with(Capabilities::HTML),
+                    versions: VersionRange::single(1),
+                    ..peer("me")
+                })
+            )]
+        );
+        let data = ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec()));
+        engine.clipboard.set_contents(Some(data));
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert_eq!(engine.transport.message_version.get(), Some(1));
+
+        // peer that dropped our versions is not talked to
+        let future_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 4)), 53300);
+        let future_peer = PeerData {
+            versions: VersionRange {
+                min: PROTOCOL_VER + 1,
+                max: PROTOCOL_VER + 2,
+            },
+            ..peer("future")
+        };
+        engine.transport.datagrams.borrow_mut().clear();
+        engine.handle(EngineEvent::Datagram((
+            future_addr,
+            MessageType::Xcon(future_peer),
+        )));
+        assert!(engine.transport.datagrams.borrow().is_empty());
+        assert_eq!(engine.menu.peers.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_peer_keeps_identity_across_ip_change() {
+        let mut engine = started_engine();
+        let new_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 9)), 53300);
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("laptop")),
+        )));
+        // same device after new DHCP lease
+        engine.handle(EngineEvent::Datagram((
+            new_addr,
+            MessageType::Xacn(peer("laptop")),
+        )));
+        assert_eq!(
+            *engine.menu.peers.borrow(),
+            vec![(new_addr, "laptop".to_string())]
+        );
+
+        // another device took the old address
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("desktop")),
+        )));
+        engine.handle(EngineEvent::Datagram((
+            new_addr,
+            MessageType::Xacn(peer("tablet")),
+        )));
+        assert_eq!(
+            *engine.menu.peers.borrow(),
+            vec![
+                (PEER_ADDR, "desktop".to_string()),
+                (new_addr, "tablet".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_greeting_again_updates_peer() {
+        let mut engine = started_engine();
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("laptop")),
+        )));
+        let mut upgraded = peer("laptop");
+        upgraded.peer_name = "work laptop".to_string();
+        upgraded.app_version = "9.9.9".to_string();
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(upgraded.clone()),
+        )));
+        assert_eq!(
+            *engine.menu.peers.borrow(),
+            vec![(PEER_ADDR, "work laptop".to_string())]
+        );
+        let (reply, response) = std::sync::mpsc::channel();
+        engine.handle(EngineEvent::Sync(SyncMessage::Control((
+            ControlRequest::Peers,
+            reply,
+        ))));
+        assert_eq!(
+            response.recv().unwrap(),
+            ControlResponse::Peers(vec![(PEER_ADDR, upgraded)])
+        );
+    }
+
+    #[test]
+    fn test_own_datagrams_are_ignored() {
+        let mut engine = started_engine();
+        engine.handle(EngineEvent::Datagram((
+            SocketAddr::new(MY_IP, 53300),
+            MessageType::Xcon(peer("me")),
+        )));
+        assert!(engine.transport.datagrams.borrow().is_empty());
+        assert!(engine.menu.peers.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_copy_request_roundtrip() {
+        let mut engine = started_engine();
+        let data = ClipboardData::String((StringType::Utf8Plain, b"secret".to_vec()));
+        engine.clipboard.set_contents(Some(data.clone()));
+
+        // our user asks peer for their clipboard
+        engine.handle(EngineEvent::Sync(SyncMessage::Cmd((
+            PEER_ADDR,
+            MessageType::Xcpy,
+        ))));
+        assert_eq!(
+            *engine.transport.datagrams.borrow(),
+            vec![(PEER_ADDR, MessageType::Xcpy)]
+        );
+
+        // peer asks for ours
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert_eq!(
+            *engine.transport.messages.borrow(),
+            vec![(PEER_ADDR, MessageType::Xpst(data))]
+        );
+
+        // peer sends theirs
+        let pasted = ClipboardData::File(("a.txt".to_string(), b"a".to_vec()));
+        engine.handle(EngineEvent::Stream((
+            PEER_ADDR,
+            MessageType::Xpst(pasted.clone()),
+        )));
+        assert_eq!(engine.clipboard.contents(), Some(pasted));
+    }
+
+    #[test]
+    fn test_data_goes_only_to_peers_with_same_encryption() {
+        let mut engine = started_engine();
+        engine.my_peer_data.capabilities = engine
+            .my_peer_data
+            .capabilities
+            .with(Capabilities::ENCRYPTION);
+        let data = ClipboardData::String((StringType::Utf8Plain, b"secret".to_vec()));
+        engine.clipboard.write(data.clone()).unwrap();
+
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("plain")),
+        )));
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert!(engine.transport.messages.borrow().is_empty());
+
+        let mut encrypted = peer("encrypted");
+        encrypted.capabilities = encrypted.capabilities.with(Capabilities::ENCRYPTION);
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(encrypted),
+        )));
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert_eq!(
+            *engine.transport.messages.borrow(),
+            vec![(PEER_ADDR, MessageType::Xpst(data))]
+        );
+    }
+
+    #[test]
+    fn test_tls_with_peers_that_accept_it() {
+        let mut engine = started_engine();
+        engine.my_peer_data.capabilities = engine.my_peer_data.capabilities.with(Capabilities::TLS);
+        let data = ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec()));
+        engine.clipboard.write(data).unwrap();
+
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("old")),
+        )));
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert_eq!(engine.transport.messages.borrow().len(), 1);
+        assert_eq!(engine.transport.message_key.get(), None);
+
+        let identity = Identity::generate();
+        let key = identity.public_key();
+        let mut new = peer("new");
+        new.capabilities = new.capabilities.with(Capabilities::TLS);
+        new.identity = key.to_vec();
+        let xacn = signed(
+            &identity,
+            PEER_ADDR,
+            MessageType::Xacn(new),
+            engine.timestamp(),
+        );
+        receive(&mut engine, PEER_ADDR, xacn);
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert_eq!(engine.transport.messages.borrow().len(), 2);
+        assert_eq!(engine.transport.message_key.get(), Some(key));
+
+        // data from it has to come over TLS with the key it announced
+        let pasted = MessageType::Xpst(ClipboardData::String((
+            StringType::Utf8Plain,
+            b"from new".to_vec(),
+        )));
+        let impostor = Identity::generate().public_key();
+        for tls_key in [None, Some(impostor), Some(key)] {
+            let received: Received = (PEER_ADDR, tls_key, vec![pasted.clone()]);
+            engine.transport.incoming_messages.push_back(received);
+        }
+        let events: Vec<_> = (0..3).flat_map(|_| engine.poll_transport()).collect();
+        assert_eq!(events.len(), 1);
+        assert!(matches!(&events[0], EngineEvent::Stream((PEER_ADDR, msg)) if *msg == pasted));
+    }
+
+    /// Datagram signed by identity at given time
+    fn signed(identity: &Identity, from: SocketAddr, msg: MessageType, timestamp: u64) -> Vec<u8> {
+        compose_signed_message(&msg, PROTOCOL_VER, identity, from.ip(), timestamp).unwrap()
+    }
+
+    /// Hands datagram to engine the way transport does
+    fn receive(engine: &mut TestEngine, from: SocketAddr, data: Vec<u8>) {
+        engine.transport.incoming_datagrams.push_back((from, data));
+        for event in engine.poll_transport() {
+            engine.handle(event);
+        }
+    }
+
+    /// Engine that requires pairing. Both test engines see each other at PEER_ADDR
+    fn pairing_engine(name: &str) -> TestEngine {
+        let mut engine = started_engine();
+        engine.my_peer_data = peer(name);
+        engine.with_identity(Identity::generate(), TrustStore::default(), true)
+    }
+
+    /// Hands datagrams sent by one engine over to the other
+    fn deliver(from: &TestEngine, to: &mut TestEngine) {
+        let datagrams: Vec<_> = from.transport.datagrams.borrow_mut().drain(..).collect();
+        for (_, msg) in datagrams {
+            to.handle(EngineEvent::Datagram((PEER_ADDR, msg)));
+        }
+    }
+
+    #[test]
+    fn test_pairing_lets_data_through() {
+        let mut me = pairing_engine("me");
+        let mut laptop = pairing_engine("laptop");
+        let xacn = |from: &TestEngine, to: &TestEngine| {
+            let msg = MessageType::Xacn(from.my_peer_data.clone());
+            signed(
+                from.identity.as_ref().unwrap(),
+                PEER_ADDR,
+                msg,
+                to.timestamp(),
+            )
+        };
+        let (to_me, to_laptop) = (xacn(&laptop, &me), xacn(&me, &laptop));
+        receive(&mut me, PEER_ADDR, to_me);
+        receive(&mut laptop, PEER_ADDR, to_laptop);
+        let data = ClipboardData::String((StringType::Utf8Plain, b"secret".to_vec()));
+        laptop.clipboard.set_contents(Some(data.clone()));
+
+        // unpaired peer is listed, but gets nothing and can not paste
+        assert_eq!(me.menu.peers.borrow().len(), 1);
+        assert!(me.menu.paired.borrow().is_empty());
+        laptop.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert!(laptop.transport.messages.borrow().is_empty());
+        let pasted = ClipboardData::String((StringType::Utf8Plain, b"rm -rf ~".to_vec()));
+        me.handle(EngineEvent::Stream((
+            PEER_ADDR,
+            MessageType::Xpst(pasted.clone()),
+        )));
+        assert_eq!(me.clipboard.contents(), None);
+        // nor is its connection read
+        me.poll_transport();
+        assert_eq!(me.transport.senders, Some(Senders::new()));
+        // but its identity is known, so sealed data from it could be opened
+        let identity = laptop.identity.as_ref().unwrap().public_key().to_vec();
+        assert_eq!(
+            me.transport.identities,
+            PeerIdentities::from([(PEER_ADDR.ip(), identity)])
+        );
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        me.handle(EngineEvent::Sync(SyncMessage::Control((
+            ControlRequest::Pair(PEER_ADDR),
+            sender,
+        ))));
+        deliver(&me, &mut laptop); // Commit
+        deliver(&laptop, &mut me); // Key
+        deliver(&me, &mut laptop); // Reveal
+        let Ok(ControlResponse::PairingCode(code)) = receiver.try_recv() else {
+            panic!("Expected pairing code");
+        };
+        assert_eq!(*me.menu.codes.borrow(), vec![(PEER_ADDR, code.clone())]);
+        assert_eq!(*laptop.menu.codes.borrow(), vec![(PEER_ADDR, code)]);
+
+        // until both sides confirm, data is still refused
+        let confirm =
+            |engine: &mut TestEngine| engine.control(ControlRequest::ConfirmPairing(PEER_ADDR));
+        assert_eq!(confirm(&mut me), ControlResponse::Ok);
+        assert_eq!(*me.menu.paired.borrow(), vec![PEER_ADDR]);
+        assert!(me.menu.codes.borrow().is_empty());
+        laptop.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert!(laptop.transport.messages.borrow().is_empty());
+        assert_eq!(confirm(&mut laptop), ControlResponse::Ok);
+        laptop.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert_eq!(
+            *laptop.transport.messages.borrow(),
+            vec![(PEER_ADDR, MessageType::Xpst(data))]
+        );
+        me.handle(EngineEvent::Stream((
+            PEER_ADDR,
+            MessageType::Xpst(pasted.clone()),
+        )));
+        assert_eq!(me.clipboard.contents(), Some(pasted));
+        me.poll_transport();
+        let senders = me.transport.senders.clone().unwrap();
+        assert_eq!(senders.keys().collect::<Vec<_>>(), vec![&PEER_ADDR.ip()]);
+        assert_eq!(
+            confirm(&mut me),
+            ControlResponse::Error(format!("No pairing code to confirm for {}", PEER_ADDR.ip()))
+        );
+
+        let ControlResponse::Pairings(pairings) = me.control(ControlRequest::Pairings) else {
+            panic!("Expected pairings");
+        };
+        assert_eq!(pairings.len(), 1);
+        assert!(pairings[0].paired);
+        assert_eq!(pairings[0].addr, Some(PEER_ADDR));
+
+        // device that copied laptop's ID but not its key is not trusted
+        let fake = Identity::generate();
+        let impostor = MessageType::Xacn(PeerData {
+            identity: fake.public_key().to_vec(),
+            ..laptop.my_peer_data.clone()
+        });
+        let other_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 9)), 53300);
+        // nor can it take laptop's place while laptop is online
+        let xacn = signed(&fake, other_addr, impostor.clone(), me.timestamp());
+        receive(&mut me, other_addr, xacn);
+        assert_eq!(
+            me.control(ControlRequest::Push(other_addr)),
+            ControlResponse::Error(format!("{} is not paired", other_addr.ip()))
+        );
+        let bye = signed(
+            laptop.identity.as_ref().unwrap(),
+            PEER_ADDR,
+            MessageType::Xdis,
+            me.timestamp(),
+        );
+        receive(&mut me, PEER_ADDR, bye);
+        let xacn = signed(&fake, other_addr, impostor, me.timestamp());
+        receive(&mut me, other_addr, xacn);
+        assert_eq!(
+            me.control(ControlRequest::Push(other_addr)),
+            ControlResponse::Error("laptop is not paired".to_string())
+        );
+
+        assert_eq!(
+            me.control(ControlRequest::Unpair("laptop".to_string())),
+            ControlResponse::Ok
+        );
+        assert_eq!(
+            me.control(ControlRequest::Pairings),
+            ControlResponse::Pairings(vec![])
+        );
+    }
+
+    #[test]
+    fn test_spoofed_discovery_is_dropped() {
+        let mut me = pairing_engine("me");
+        let identity = Identity::generate();
+        let laptop = PeerData {
+            identity: identity.public_key().to_vec(),
+            ..peer("laptop")
+        };
+        let now = me.timestamp();
+        receive(
+            &mut me,
+            PEER_ADDR,
+            signed(&identity, PEER_ADDR, MessageType::Xacn(laptop.clone()), now),
+        );
+        let listed = |me: &TestEngine| me.menu.peers.borrow().clone();
+        assert_eq!(listed(&me), vec![(PEER_ADDR, "laptop".to_string())]);
+
+        // anyone can send BYE or greeting from laptop's address, but without its key
+        let spoofed = [
+            compose_message(&MessageType::Xdis, PROTOCOL_VER).unwrap(),
+            signed(&Identity::generate(), PEER_ADDR, MessageType::Xdis, now),
+            compose_message(&MessageType::Xcon(peer("fake")), PROTOCOL_VER).unwrap(),
+        ];
+        for data in spoofed {
+            receive(&mut me, PEER_ADDR, data);
+        }
+        // or claim laptop's device ID from elsewhere
+        let other_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 9)), 53300);
+        let unsigned = compose_message(&MessageType::Xacn(laptop.clone()), PROTOCOL_VER).unwrap();
+        receive(&mut me, other_addr, unsigned);
+        assert_eq!(listed(&me), vec![(PEER_ADDR, "laptop".to_string())]);
+
+        // old datagrams can not be replayed
+        let bye = signed(&identity, PEER_ADDR, MessageType::Xdis, now);
+        receive(&mut me, PEER_ADDR, bye.clone());
+        assert!(listed(&me).is_empty());
+        receive(
+            &mut me,
+            PEER_ADDR,
+            signed(&identity, PEER_ADDR, MessageType::Xacn(laptop.clone()), now),
+        );
+        receive(&mut me, PEER_ADDR, bye);
+        let stale = now - 2 * SIGNATURE_WINDOW.as_millis() as u64;
+        receive(
+            &mut me,
+            PEER_ADDR,
+            signed(&identity, PEER_ADDR, MessageType::Xdis, stale),
+        );
+        assert_eq!(listed(&me), vec![(PEER_ADDR, "laptop".to_string())]);
+
+        // peers older than signatures still come and go, but can not claim a key
+        let old_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 7)), 53300);
+        let unsigned = PeerData {
+            identity: Identity::generate().public_key().to_vec(),
+            ..peer("old")
+        };
+        me.handle(EngineEvent::Datagram((
+            old_addr,
+            MessageType::Xacn(unsigned),
+        )));
+        assert!(me.peer_at(old_addr.ip()).unwrap().data.identity.is_empty());
+        me.handle(EngineEvent::Datagram((old_addr, MessageType::Xdis)));
+        assert!(me.peer_at(old_addr.ip()).is_none());
+
+        // and ours are signed
+        let greeting = me
+            .compose_datagram(&MessageType::Xcon(me.my_peer_data.clone()), PROTOCOL_VER)
+            .unwrap();
+        let (_, signed_by) = parse_signed_message(&greeting, MY_IP).unwrap();
+        assert_eq!(
+            signed_by.map(|signed| signed.key.to_vec()),
+            Some(me.my_peer_data.identity.clone())
+        );
+    }
+
+    #[test]
+    fn test_replayed_discovery_is_dropped() {
+        let mut me = pairing_engine("me");
+        let identity = Identity::generate();
+        let laptop = PeerData {
+            identity: identity.public_key().to_vec(),
+            ..peer("laptop")
+        };
+        let now = me.timestamp();
+        let listed = |me: &TestEngine| me.menu.peers.borrow().clone();
+        let greeting = |laptop: &PeerData| MessageType::Xcon(laptop.clone());
+        receive(
+            &mut me,
+            PEER_ADDR,
+            signed(&identity, PEER_ADDR, greeting(&laptop), now),
+        );
+        assert_eq!(listed(&me), vec![(PEER_ADDR, "laptop".to_string())]);
+
+        // greeting laptop broadcast, replayed from another address
+        let other_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 9)), 53300);
+        let captured = signed(&identity, PEER_ADDR, greeting(&laptop), now);
+        receive(&mut me, other_addr, captured);
+        assert_eq!(listed(&me), vec![(PEER_ADDR, "laptop".to_string())]);
+
+        // datagrams signed with made up keys do not make it forget laptop's
+        let bye = signed(&identity, PEER_ADDR, MessageType::Xdis, now);
+        receive(&mut me, PEER_ADDR, bye.clone());
+        receive(
+            &mut me,
+            PEER_ADDR,
+            signed(&identity, PEER_ADDR, greeting(&laptop), now),
+        );
+        for i in 0..=MAX_SEEN_SIGNATURES as u64 {
+            let mut key = [0; PUBLIC_KEY_SIZE];
+            key[..8].copy_from_slice(&i.to_be_bytes());
+            let flood = SignedBy {
+                key,
+                timestamp: now,
+                nonce: [0; 16],
+            };
+            assert!(me.check_replay(flood, false).is_ok());
+        }
+        receive(&mut me, PEER_ADDR, bye);
+        assert_eq!(listed(&me), vec![(PEER_ADDR, "laptop".to_string())]);
+        assert_eq!(me.seen_signatures.len(), 1);
+    }
+
+    #[test]
+    fn test_get_request() {
+        let mut engine = started_engine();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        engine.handle(EngineEvent::Sync(SyncMessage::Control((
+            ControlRequest::Get(PEER_ADDR),
+            sender.clone(),
+        ))));
+        assert_eq!(
+            *engine.transport.datagrams.borrow(),
+            vec![(PEER_ADDR, MessageType::Xcpy)]
+        );
+        assert!(receiver.try_recv().is_err());
+
+        // answer comes from ephemeral port
+        let data = ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec()));
+        let peer_stream = SocketAddr::new(PEER_ADDR.ip(), 41000);
+        engine.handle(EngineEvent::Stream((
+            peer_stream,
+            MessageType::Xpst(data.clone()),
+        )));
+        assert_eq!(receiver.try_recv(), Ok(ControlResponse::Data(data)));
+        assert_eq!(engine.clipboard.contents(), None);
+
+        // peer never answers
+        engine.handle(EngineEvent::Sync(SyncMessage::Control((
+            ControlRequest::Get(PEER_ADDR),
+            sender,
+        ))));
+        engine.clock.advance(GET_TIMEOUT + Duration::new(1, 0));
+        engine.handle(EngineEvent::Tick);
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(ControlResponse::Error("Peer did not respond".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_control_requests() {
+        let mut engine = started_engine();
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("peer")),
+        )));
+        let data = ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec()));
+        engine.clipboard.set_contents(Some(data.clone()));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let handle = EngineHandle::new(sender);
+        let requests = vec![
+            ControlRequest::Peers,
+            ControlRequest::Push(PEER_ADDR),
+            ControlRequest::Status,
+        ];
+        let threads: Vec<_> = requests
+            .into_iter()
+            .map(|req| {
+                let handle = handle.clone();
+                let thread = thread::spawn(move || handle.request(req));
+                let msg = receiver.recv().unwrap();
+                engine.handle(EngineEvent::Sync(msg));
+                thread
+            })
+            .collect();
+        let responses: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        assert_eq!(
+            responses,
+            vec![
+                ControlResponse::Peers(vec![(PEER_ADDR, peer("peer"))]),
+                ControlResponse::Ok,
+                ControlResponse::Status(EngineStatus {
+                    peer_name: "me".to_string(),
+                    local_ip: Some(MY_IP),
+                    peers: 1,
+                }),
+            ]
+        );
+        assert_eq!(
+            *engine.transport.messages.borrow(),
+            vec![(PEER_ADDR, MessageType::Xpst(data))]
+        );
+    }
+
+    #[test]
+    fn test_consent_to_serve_clipboard() {
+        let mut engine = started_engine().with_consent(
+            ConsentStore::with_default(ConsentPolicy::Ask),
+            AuditLog::default(),
+        );
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("laptop")),
+        )));
+        let data = ClipboardData::String((StringType::Utf8Plain, b"hi".to_vec()));
+        engine.clipboard.set_contents(Some(data.clone()));
+        let control = |engine: &mut TestEngine, req| {
+            let (reply, response) = std::sync::mpsc::channel();
+            engine.handle(EngineEvent::Sync(SyncMessage::Control((req, reply))));
+            response.recv().unwrap()
+        };
+
+        // nothing is sent until the user allows it
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert_eq!(*engine.menu.consents.borrow(), vec![PEER_ADDR]);
+        assert!(engine.transport.messages.borrow().is_empty());
+        let allow = ControlRequest::Consent((PEER_ADDR, true));
+        assert_eq!(control(&mut engine, allow.clone()), ControlResponse::Ok);
+        assert!(engine.menu.consents.borrow().is_empty());
+        assert_eq!(
+            *engine.transport.messages.borrow(),
+            vec![(PEER_ADDR, MessageType::Xpst(data))]
+        );
+        assert!(matches!(
+            control(&mut engine, allow),
+            ControlResponse::Error(_)
+        ));
+
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        let deny = ControlRequest::Consent((PEER_ADDR, false));
+        assert_eq!(control(&mut engine, deny), ControlResponse::Ok);
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        engine.clock.advance(CONSENT_TIMEOUT + POLL_INTERVAL);
+        engine.handle(EngineEvent::Tick);
+        assert!(engine.menu.consents.borrow().is_empty());
+
+        let policy = ControlRequest::SetPolicy((PEER_ADDR, ConsentPolicy::Deny));
+        assert_eq!(control(&mut engine, policy), ControlResponse::Ok);
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        assert!(engine.menu.consents.borrow().is_empty());
+        assert_eq!(engine.transport.messages.borrow().len(), 1);
+
+        let ControlResponse::Audit(entries) = control(&mut engine, ControlRequest::Audit) else {
+            panic!("Expected audit entries");
+        };
+        let outcomes: Vec<_> = entries
+            .iter()
+            .map(|entry| entry.rsplit('\t').next().unwrap())
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                "ignored, already asking",
+                "allowed by user",
+                "denied by user",
+                "not answered",
+                "denied by policy"
+            ]
+        );
+        assert!(entries[0].contains("\tlaptop\t192.168.0.3\t"));
+    }
+
+    #[test]
+    fn test_consent_shown_again_after_rebind() {
+        let mut engine = started_engine().with_consent(
+            ConsentStore::with_default(ConsentPolicy::Ask),
+            AuditLog::default(),
+        );
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("laptop")),
+        )));
+        engine.handle(EngineEvent::Datagram((PEER_ADDR, MessageType::Xcpy)));
+        engine.handle(EngineEvent::Sync(SyncMessage::NetworkChange));
+        engine.clock.advance(NW_CHANGE_DEBOUNCE + POLL_INTERVAL);
+        engine.handle(EngineEvent::Tick);
+        assert!(engine.menu.peers.borrow().is_empty());
+        assert_eq!(*engine.menu.consents.borrow(), vec![PEER_ADDR]);
+    }
+
+    #[test]
+    fn test_periodic_rediscovery() {
+        let mut engine = started_engine();
+        engine.handle(EngineEvent::Datagram((
+            PEER_ADDR,
+            MessageType::Xacn(peer("peer")),
+        )));
+
+        engine.clock.advance(REDISCOVER_TIMEFRAME);
+        engine.handle(EngineEvent::Tick);
+        assert_eq!(engine.menu.peers.borrow().len(), 1);
+
+        engine.clock.advance(Duration::new(1, 0));
+        engine.handle(EngineEvent::Tick);
+        assert!(engine.menu.peers.borrow().is_empty());
+        assert_eq!(
+            *engine.transport.datagrams.borrow(),
+            vec![(BROADCAST_ADDR, MessageType::Xcon(peer("me")))]
+        );
+    }
+
+    #[test]
+    fn test_rebind_after_network_change_debounce() {
+        let mut engine = started_engine();
+        engine.handle(EngineEvent::Sync(SyncMessage::NetworkChange));
+
+        engine.clock.advance(NW_CHANGE_DEBOUNCE);
+        engine.handle(EngineEvent::Tick);
+        assert_eq!(engine.transport.binds, 1);
+
+        engine.clock.advance(Duration::new(1, 0));
+        engine.handle(EngineEvent::Tick);
+        assert_eq!(engine.transport.binds, 2);
+        assert_eq!(
+            *engine.transport.datagrams.borrow(),
+            vec![(BROADCAST_ADDR, MessageType::Xcon(peer("me")))]
+        );
+    }
+
+    #[test]
+    fn test_run_until_stopped() {
+        let mut engine = started_engine();
+        let xcon = compose_message(&MessageType::Xcon(peer("peer")), PROTOCOL_VER).unwrap();
+        engine
+            .transport
+            .incoming_datagrams
+            .push_back((PEER_ADDR, xcon));
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        sender.send(SyncMessage::Stop).unwrap();
+        engine.run(&receiver);
+
+        assert!(engine.stopped);
+        assert_eq!(engine.menu.peers.borrow().len(), 1);
+        assert_eq!(
+            engine.transport.datagrams.borrow().last(),
+            Some(&(BROADCAST_ADDR, MessageType::Xdis))
+        );
+    }
+}
@@ -0,0 +1,13 @@
+//! Core of CopyXross: protocol, transport, peer discovery and clipboard access.
+//!
+//! The tray app is a thin binary on top of this crate. Other tools can use it
+//! to talk to CopyXross peers directly.
+
+pub mod clipboard;
+#[cfg(unix)]
+pub mod control;
+pub mod crypto;
+pub mod encode;
+pub mod engine;
+pub mod network;
+pub mod utils;
@@ -0,0 +1,161 @@
+//! TCP framing
+//!
+//! Every message starts with `XCOP` header and length of the rest of the
+//! message, so the receiver knows where it ends without waiting for the
+//! connection to close. One connection can carry several messages.
+//! First version did not count its `XVER` chunk in that length, so frame
+//! length is taken from the first [`MESSAGE_HEAD_SIZE`] bytes.
+
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+
+use crate::encode::message_len;
+use crate::encode::Limits;
+use crate::encode::MESSAGE_HEAD_SIZE;
+use crate::utils::write_progress;
+
+use super::NetworkError;
+
+/// `XCOP` header + 4-byte length
+pub const FRAME_HEADER_SIZE: usize = 8;
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Returns length of message body after frame header, given the start of
+/// message
+pub fn frame_len(head: &[u8]) -> Result<usize, NetworkError> {
+    match message_len(head) {
+        Some(len)
+            if head.len() >= MESSAGE_HEAD_SIZE && len + FRAME_HEADER_SIZE >= MESSAGE_HEAD_SIZE =>
+        {
+            Ok(len)
+        }
+        _ => Err(NetworkError::Read("Invalid frame header".to_string())),
+    }
+}
+
+/// Writes composed message. Its length must match the one in its header
+pub fn write_frame(writer: &mut impl Write, message: &[u8]) -> Result<(), NetworkError> {
+    let len = frame_len(message)?;
+    if message.len() != FRAME_HEADER_SIZE + len {
+        return Err(NetworkError::Write(format!(
+            "Frame length mismatch: header says {}, message has {}",
+            len,
+            message.len() - FRAME_HEADER_SIZE
+        )));
+    }
+    let mut total_written = 0;
+    while total_written < message.len() {
+        let end = (total_written + READ_CHUNK_SIZE).min(message.len());
+        writer
+            .write_all(&message[total_written..end])
+            .map_err(|err| NetworkError::Write(format!("{:?}", err)))?;
+        total_written = end;
+        write_progress(total_written, message.len());
+    }
+    writer
+        .flush()
+        .map_err(|err| NetworkError::Write(format!("{:?}", err)))
+}
+
+/// Reads next message. Returns None if connection was closed between messages
+pub fn read_frame(reader: &mut impl Read) -> Result<Option<Vec<u8>>, NetworkError> {
+    let mut header = [0u8; MESSAGE_HEAD_SIZE];
+    let mut read = 0;
+    while read < MESSAGE_HEAD_SIZE {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(NetworkError::Read(
+                    "Connection closed inside frame header".to_string(),
+                ))
+            }
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(NetworkError::Read(format!("{:?}", err))),
+        }
+    }
+    let len = frame_len(&header)?;
+    let max_len = Limits::from_env().message();
+    if len > max_len {
+        return Err(NetworkError::Read(format!(
+            "Frame of {} bytes exceeds limit of {}",
+            len, max_len
+        )));
+    }
+
+    // body is read in chunks, so bogus length does not allocate everything up front
+    let mut message = Vec::with_capacity(FRAME_HEADER_SIZE + len.min(READ_CHUNK_SIZE));
+    message.extend_from_slice(&header);
+    let mut buffer = vec![0; READ_CHUNK_SIZE];
+    let mut body_read = MESSAGE_HEAD_SIZE - FRAME_HEADER_SIZE;
+    while body_read < len {
+        let want = (len - body_read).min(READ_CHUNK_SIZE);
+        match reader.read(&mut buffer[..want]) {
+            Ok(0) => {
+                return Err(NetworkError::Read(format!(
+                    "Truncated message: got {} of {} bytes",
+                    body_read, len
+                )))
+            }
+            Ok(n) => {
+                message.extend_from_slice(&buffer[..n]);
+                body_read += n;
+                write_progress(body_read, len);
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(NetworkError::Read(format!("{:?}", err))),
+        }
+    }
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::compose_message;
+    use crate::encode::parse_message;
+    use crate::encode::MessageType;
+    use crate::network::MIN_PROTOCOL_VER;
+    use crate::network::PROTOCOL_VER;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_multiple_frames_and_truncation() {
+        let first = compose_message(&MessageType::Xcpy, PROTOCOL_VER).unwrap();
+        let second = compose_message(&MessageType::Xdis, PROTOCOL_VER).unwrap();
+        let mut stream = vec![];
+        write_frame(&mut stream, &first).unwrap();
+        write_frame(&mut stream, &second).unwrap();
+
+        let mut reader = Cursor::new(stream.clone());
+        let msg = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(parse_message(&msg).unwrap(), MessageType::Xcpy);
+        let msg = read_frame(&mut reader).unwrap().unwrap();
+        assert_eq!(parse_message(&msg).unwrap(), MessageType::Xdis);
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+
+        let mut truncated = Cursor::new(&stream[..first.len() - 1]);
+        assert!(matches!(
+            read_frame(&mut truncated),
+            Err(NetworkError::Read(err)) if err.starts_with("Truncated")
+        ));
+        let mut cut_header = Cursor::new(&stream[..3]);
+        assert!(read_frame(&mut cut_header).is_err());
+        assert!(write_frame(&mut vec![], &first[..first.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_frames_of_first_version() {
+        let first = compose_message(&MessageType::Xcpy, MIN_PROTOCOL_VER).unwrap();
+        let second = compose_message(&MessageType::Xdis, MIN_PROTOCOL_VER).unwrap();
+        let mut stream = vec![];
+        write_frame(&mut stream, &first).unwrap();
+        write_frame(&mut stream, &second).unwrap();
+
+        let mut reader = Cursor::new(stream);
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), first);
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), second);
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+}
@@ -0,0 +1,32 @@
+use std::fs;
+
+use super::NetworkError;
+use super::NetworkListener;
+
+/// Linux has no system callback we can hook into without extra dependencies,
+/// so network changes are picked up by periodic rediscovery instead.
+pub struct Network {
+    _user_cb: Option<Box<dyn Fn()>>,
+}
+
+impl NetworkListener for Network {
+    fn init(cb: Option<Box<dyn Fn()>>) -> Result<Self, NetworkError> {
+        Ok(Network { _user_cb: cb })
+    }
+    fn start_listen(&self) -> Result<(), NetworkError> {
+        Ok(())
+    }
+    fn is_en0_connected() -> bool {
+        let Ok(interfaces) = fs::read_dir("/sys/class/net") else {
+            return false;
+        };
+        interfaces.flatten().any(|int| {
+            if int.file_name() == "lo" {
+                return false;
+            }
+            fs::read_to_string(int.path().join("operstate"))
+                .map(|state| state.trim() == "up")
+                .unwrap_or(false)
+        })
+    }
+}
@@ -1,20 +1,42 @@
+mod frame;
+#[cfg(target_os = "linux")]
+pub mod linux;
 #[cfg(target_os = "macos")]
 pub mod macos;
+mod tls;
 #[cfg(target_os = "windows")]
 pub mod windows;
 
 use std::{
-    io::{ErrorKind, Read, Write},
+    collections::HashMap,
+    io::{BufReader, BufWriter, ErrorKind},
     net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, SendError, Sender},
+        Arc,
+    },
+    thread,
     time::Duration,
 };
 
+use local_ip_address::local_ip;
+use rustls::{ClientConfig, ServerConfig};
+
 use crate::{
+    crypto::{Cipher, TlsConfig, PUBLIC_KEY_SIZE},
     debug_println,
-    encode::{compose_message, MessageType, PeerData},
-    utils::{format_bytes_size, log_into_file, write_progress},
+    encode::{
+        discard, read_limited_message, write_message, write_sealed_message, Limits, MessageType,
+        ParseErrors,
+    },
+    utils::{format_bytes_size, log_into_file, private_dir, spool_dir},
 };
 
+pub use frame::{frame_len, read_frame, write_frame, FRAME_HEADER_SIZE};
+use tls::PeerStream;
+
 #[derive(Debug, PartialEq, Eq)]
 #[allow(dead_code)]
 pub enum NetworkError {
@@ -26,18 +48,41 @@ pub enum NetworkError {
     Unexpected(String),
 }
 
-pub const PROTOCOL_VER: u32 = 1;
+/// Newest protocol version. History:
+///
+/// 1. initial version
+/// 2. XVER of Xcon/Xacn carries range of supported versions
+/// 3. unknown optional (lowercase) chunks are skipped
+/// 4. XMUL with several representations of one copy, RTF strings
+/// 5. XIMG images with format and dimensions
+/// 6. XTRE trees of files and directories
+/// 7. XENC messages sealed with shared passphrase
+/// 8. identity keys in peer data, XPAR pairing messages
+/// 9. Xcon, Xacn and Xdis signed with identity key
+pub const PROTOCOL_VER: u32 = 9;
+/// Oldest version we still talk to, messages for it are encoded byte for byte
+/// as the first release did
+pub const MIN_PROTOCOL_VER: u32 = 1;
 pub const PORT: u16 = 53300;
 
 pub const BROADCAST_IP: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 255);
 pub const BROADCAST_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(BROADCAST_IP), PORT);
 
+/// Stalled TCP transfer is dropped after this
+const TCP_TIMEOUT: Duration = Duration::new(10, 0);
+/// Peers send one message per connection, a few are allowed for batching
+const MAX_TCP_MESSAGES: usize = 16;
+/// Connections read at once, more are dropped
+const MAX_TCP_CONNECTIONS: usize = 8;
+
 pub trait NetworkListener: Sized {
     fn init(cb: Option<Box<dyn Fn()>>) -> Result<Self, NetworkError>;
     fn start_listen(&self) -> Result<(), NetworkError>;
     fn is_en0_connected() -> bool;
 }
 
+#[cfg(target_os = "linux")]
+pub use linux::Network as NetworkChangeListener;
 #[cfg(target_os = "macos")]
 pub use macos::Network as NetworkChangeListener;
 #[cfg(target_os = "windows")]
@@ -112,26 +157,54 @@ where
     }
 }
 
-pub fn send_message_to_peer(peer_addr: &SocketAddr, data: &[u8]) -> Result<(), NetworkError> {
-    let mut handler = TcpStream::connect(peer_addr).map_err(|err| {
+pub fn send_message_to_peer(
+    peer_addr: &SocketAddr,
+    message: &MessageType,
+    protocol_ver: u32,
+    cipher: Option<&Cipher>,
+    tls: Option<Arc<ClientConfig>>,
+) -> Result<(), NetworkError> {
+    send_messages_to_peer(
+        peer_addr,
+        std::slice::from_ref(message),
+        protocol_ver,
+        cipher,
+        tls,
+    )
+}
+
+/// Sends several messages over one connection. Files are streamed from disk.
+/// With cipher every message is sealed, with TLS config connection is TLS
+pub fn send_messages_to_peer(
+    peer_addr: &SocketAddr,
+    messages: &[MessageType],
+    protocol_ver: u32,
+    cipher: Option<&Cipher>,
+    tls: Option<Arc<ClientConfig>>,
+) -> Result<(), NetworkError> {
+    let handler = TcpStream::connect(peer_addr).map_err(|err| {
         NetworkError::Connect(format!("Failed to establish TCP connection: {:?}", err))
     })?;
-    // handler.write_all(data).map_err(|err| {
-    //     NetworkError::Write(format!("Failed to write into TCP handler: {:?}", err))
-    // })?;
-
-    let mut total_written = 0;
-    while total_written < data.len() {
-        match handler.write(&data[total_written..]) {
-            Ok(n) => total_written += n,
-            Err(e) => return Err(NetworkError::Write(format!("{:?}", e))),
+    handler
+        .set_write_timeout(Some(TCP_TIMEOUT))
+        .and_then(|_| handler.set_read_timeout(Some(TCP_TIMEOUT)))
+        .map_err(|err| NetworkError::Connect(format!("{:?}", err)))?;
+
+    let mut writer = BufWriter::new(PeerStream::new(handler, tls)?);
+    for message in messages {
+        match cipher {
+            Some(cipher) => write_sealed_message(message, protocol_ver, cipher, &mut writer),
+            None => write_message(message, protocol_ver, &mut writer),
         }
-        write_progress(total_written, data.len());
+        .map_err(|err| NetworkError::Write(format!("{:?}", err)))?;
     }
-
-    debug_println!("Sent message via TCP: {:?}", format_bytes_size(data.len()));
-
-    Ok(())
+    let handler = writer
+        .into_inner()
+        .map_err(|err| NetworkError::Write(format!("{:?}", err.error())))?;
+    debug_println!("Sent {} message(s) via TCP", messages.len());
+    handler
+        .finish()
+        .map_err(|err| NetworkError::Write(format!("{:?}", err)))
 }
 
 pub fn init_listeners(my_ip: IpAddr) -> Result<(UdpSocket, TcpListener), NetworkError> {
@@ -152,60 +225,441 @@ pub fn init_listeners(my_ip: IpAddr) -> Result<(UdpSocket, TcpListener), Network
     Ok((s, tcp))
 }
 
+/// Accepts one connection and reads all messages from it. With cipher
+/// clipboard data has to be sealed with it. With TLS config peers may
+/// connect with TLS, their identity key is returned with messages.
+/// Received files are written into spool directory
 pub fn listen_to_tcp(
     socket: Option<&TcpListener>,
-    buff: &mut Vec<u8>,
-) -> Result<usize, NetworkError> {
-    if let Some(socket) = socket {
-        let (mut data, _ip) = socket.accept().map_err(|err| {
-            if err.kind() == ErrorKind::TimedOut || err.kind() == ErrorKind::WouldBlock {
-                return NetworkError::Blocked;
+    spool_dir: &Path,
+    cipher: Option<&Cipher>,
+    tls: Option<Arc<ServerConfig>>,
+) -> Result<Received, NetworkError> {
+    let (data, ip) = accept_tcp(socket)?;
+    read_connection(data, ip, spool_dir, cipher, tls, None)
+}
+
+/// Accepts one connection, set up for blocking reads with timeout
+pub fn accept_tcp(socket: Option<&TcpListener>) -> Result<(TcpStream, SocketAddr), NetworkError> {
+    let Some(socket) = socket else {
+        return Err(NetworkError::Read("Tcp socket is not bound!".to_string()));
+    };
+    let (data, ip) = socket.accept().map_err(|err| {
+        if err.kind() == ErrorKind::TimedOut || err.kind() == ErrorKind::WouldBlock {
+            return NetworkError::Blocked;
+        }
+        NetworkError::Read(format!("{:?}", err))
+    })?;
+    // accepted stream may inherit non-blocking mode from listener
+    data.set_nonblocking(false)
+        .and_then(|_| data.set_read_timeout(Some(TCP_TIMEOUT)))
+        .map_err(|err| NetworkError::Read(format!("{:?}", err)))?;
+    Ok((data, ip))
+}
+
+/// Reads all messages of accepted connection. With senders, anyone else is
+/// refused before anything is read. If connection breaks after some
+/// messages were read, those are kept and the error is logged
+pub fn read_connection(
+    data: TcpStream,
+    ip: SocketAddr,
+    spool_dir: &Path,
+    cipher: Option<&Cipher>,
+    tls: Option<Arc<ServerConfig>>,
+    senders: Option<&Senders>,
+) -> Result<Received, NetworkError> {
+    let rejected = |err: NetworkError| {
+        let _ = log_into_file(format!("Rejected connection from {:?}: {:?}", ip, err).as_str());
+        err
+    };
+    let required = match senders.map(|senders| senders.get(&ip.ip())) {
+        Some(None) => return Err(rejected(NetworkError::Blocked)),
+        Some(Some(key)) => *key,
+        None => None,
+    };
+    let (data, key) = tls::accept(data, tls).map_err(rejected)?;
+    if required.is_some() && key != required {
+        return Err(rejected(NetworkError::Read(
+            "Connected with another identity key".to_string(),
+        )));
+    }
+    let cipher = cipher.map(|cipher| cipher.for_sender(ip.ip()));
+    let mut reader = BufReader::new(data);
+    let mut messages = vec![];
+    let limits = Limits::from_env();
+    let err = loop {
+        if messages.len() == MAX_TCP_MESSAGES {
+            break NetworkError::Read(format!("Too many messages in one connection from {:?}", ip));
+        }
+        match read_limited_message(&mut reader, Some(spool_dir), &limits, cipher.as_ref()) {
+            Ok(Some(message)) => {
+                debug_println!("Received message via TCP from {:?}", ip);
+                messages.push(message);
             }
-            NetworkError::Read(format!("{:?}", err))
-        })?;
-        let mut buffer = vec![0; 1024];
-        let mut read: usize = 0;
-        loop {
-            write_progress(read, 0);
-            let res = data.read(&mut buffer);
-
-            if res.is_ok() {
-                let curr_read = res.unwrap();
-                if curr_read == 0 {
-                    break;
-                }
-                read += curr_read;
-
-                buff.extend_from_slice(&buffer[..curr_read]);
-            } else {
-                let err = res.unwrap_err();
-                if let ErrorKind::WouldBlock = err.kind() {
-                    continue;
-                } else {
-                    break;
+            Ok(None) => return Ok((ip, key, messages)),
+            Err(err) => {
+                if let ParseErrors::Unauthenticated(reason) = &err {
+                    let _ = log_into_file(
+                        format!("Rejected message from {:?}: {}", ip, reason).as_str(),
+                    );
                 }
+                break NetworkError::Read(format!(
+                    "Failed to read message from {:?}: {:?}",
+                    ip, err
+                ));
             }
         }
+    };
+    if messages.is_empty() {
+        return Err(err);
+    }
+    let _ = log_into_file(
+        format!(
+            "Keeping {} message(s) of broken connection: {:?}",
+            messages.len(),
+            err
+        )
+        .as_str(),
+    );
+    Ok((ip, key, messages))
+}
 
-        debug_println!(
-            "Received data via TCP from {:?}. Size: {}",
-            _ip,
-            format_bytes_size(read)
-        );
-        Ok(read)
-    } else {
-        Err(NetworkError::Read("Tcp socket is not bound!".to_string()))
+/// Network side of the sync engine
+pub trait Transport {
+    /// Binds listeners and returns local IP address
+    fn bind(&mut self) -> Result<IpAddr, NetworkError>;
+    fn unbind(&mut self);
+    fn send_datagram(&self, target: SocketAddr, data: &[u8]);
+    fn recv_datagram(&mut self) -> Option<(SocketAddr, Vec<u8>)>;
+    /// Sends message encoded with given protocol version. With identity key
+    /// of the peer it goes over TLS, to that key only
+    fn send_message(
+        &self,
+        target: &SocketAddr,
+        message: &MessageType,
+        protocol_ver: u32,
+        tls_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+    ) -> Result<(), NetworkError>;
+    /// Messages from one incoming connection with identity key of the peer
+    /// if it came over TLS. Files in them are already on disk
+    fn recv_messages(&mut self) -> Result<Received, NetworkError>;
+    /// Connections from anyone else are dropped before they are read.
+    /// None accepts everyone
+    fn allow_senders(&mut self, senders: Option<Senders>);
+    /// Identity keys of peers, sealed data is opened only with salt of the
+    /// peer it comes from
+    fn know_peers(&mut self, identities: PeerIdentities);
+}
+
+/// Sender address, its TLS identity key and messages of one connection
+pub type Received = (SocketAddr, Option<[u8; PUBLIC_KEY_SIZE]>, Vec<MessageType>);
+
+/// Addresses data is accepted from, with identity key their TLS connection
+/// has to present
+pub type Senders = HashMap<IpAddr, Option<[u8; PUBLIC_KEY_SIZE]>>;
+
+/// Identity key of peer at each address, empty for peers without one
+pub type PeerIdentities = HashMap<IpAddr, Vec<u8>>;
+
+/// UDP for discovery and commands, TCP for clipboard data
+pub struct NetTransport {
+    socket: Option<UdpSocket>,
+    tcp_listener: Option<TcpListener>,
+    udp_buff: [u8; 1024],
+    /// Incoming files are written here until they are placed
+    spool_dir: PathBuf,
+    /// Seals clipboard data, set when peers share a passphrase
+    cipher: Option<Cipher>,
+    /// Certificate of our identity key, set when peers can be paired
+    tls: Option<TlsConfig>,
+    /// Connections are read on their own threads, so slow peer does not
+    /// hold up the engine. Results come back here
+    received: Receiver<Result<Received, NetworkError>>,
+    received_sender: Sender<Result<Received, NetworkError>>,
+    /// Connections being read right now
+    reading: Arc<AtomicUsize>,
+    senders: Option<Senders>,
+    identities: PeerIdentities,
+}
+
+impl NetTransport {
+    pub fn new() -> Self {
+        let (received_sender, received) = channel();
+        NetTransport {
+            socket: None,
+            tcp_listener: None,
+            udp_buff: [0; 1024],
+            spool_dir: spool_dir(),
+            cipher: None,
+            tls: None,
+            received,
+            received_sender,
+            reading: Arc::new(AtomicUsize::new(0)),
+            senders: None,
+            identities: PeerIdentities::new(),
+        }
+    }
+
+    /// Hands accepted connection over to its own thread
+    fn spawn_reader(&self, data: TcpStream, ip: SocketAddr) {
+        if self.reading.load(Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+            let _ = log_into_file(format!("Too many connections, dropping {:?}", ip).as_str());
+            return;
+        }
+        self.reading.fetch_add(1, Ordering::SeqCst);
+        let reading = self.reading.clone();
+        let sender = self.received_sender.clone();
+        let spool_dir = self.spool_dir.clone();
+        let cipher = self.cipher.clone();
+        let tls = self.tls.as_ref().map(TlsConfig::server);
+        let senders = self.senders.clone();
+        thread::spawn(move || {
+            let result =
+                read_connection(data, ip, &spool_dir, cipher.as_ref(), tls, senders.as_ref());
+            reading.fetch_sub(1, Ordering::SeqCst);
+            // transport is gone, nobody will place the files
+            if let Err(SendError(Ok((_, _, messages)))) = sender.send(result) {
+                messages.iter().for_each(discard);
+            }
+        });
+    }
+
+    pub fn with_cipher(mut self, cipher: Option<Cipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: Option<TlsConfig>) -> Self {
+        self.tls = tls;
+        self
+    }
+}
+
+impl Default for NetTransport {
+    fn default() -> Self {
+        Self::new()
     }
 }
-pub fn send_bye_packet(socket: Option<&UdpSocket>, target: SocketAddr) {
-    let _ = log_into_file("Sending BYE message...");
-    let disconnect_msg = compose_message(&MessageType::Xdis, PROTOCOL_VER).unwrap();
-    send_message_to_socket(socket, target, &disconnect_msg);
+
+impl Transport for NetTransport {
+    fn bind(&mut self) -> Result<IpAddr, NetworkError> {
+        let _ = log_into_file("Binding listeners...");
+        let my_local_ip = local_ip()
+            .map_err(|err| NetworkError::Unexpected(format!("Could not get ip: {:?}", err)))?;
+        let _ = log_into_file(format!("This is my local IP address: {:?}", my_local_ip).as_str());
+        private_dir(&self.spool_dir).map_err(|err| {
+            NetworkError::Init(format!("Could not create spool directory: {:?}", err))
+        })?;
+        let (socket, tcp) = init_listeners(Ipv4Addr::UNSPECIFIED.into())?;
+        self.socket = Some(socket);
+        self.tcp_listener = Some(tcp);
+        Ok(my_local_ip)
+    }
+    fn unbind(&mut self) {
+        self.socket = None;
+        self.tcp_listener = None;
+    }
+    fn send_datagram(&self, target: SocketAddr, data: &[u8]) {
+        send_message_to_socket(self.socket.as_ref(), target, data);
+    }
+    fn recv_datagram(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
+        listen_to_socket(self.socket.as_ref(), &mut self.udp_buff)
+    }
+    fn send_message(
+        &self,
+        target: &SocketAddr,
+        message: &MessageType,
+        protocol_ver: u32,
+        tls_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+    ) -> Result<(), NetworkError> {
+        let tls = match (tls_key, &self.tls) {
+            (None, _) => None,
+            (Some(key), Some(tls)) => Some(
+                tls.client(key)
+                    .map_err(|err| NetworkError::Connect(format!("{:?}", err)))?,
+            ),
+            (Some(_), None) => return Err(NetworkError::Connect("TLS is not set up".to_string())),
+        };
+        send_message_to_peer(target, message, protocol_ver, self.cipher.as_ref(), tls)
+    }
+    fn recv_messages(&mut self) -> Result<Received, NetworkError> {
+        match accept_tcp(self.tcp_listener.as_ref()) {
+            Ok((data, ip)) => self.spawn_reader(data, ip),
+            Err(NetworkError::Blocked) => {}
+            Err(err) => return Err(err),
+        }
+        self.received
+            .try_recv()
+            .map_err(|_| NetworkError::Blocked)?
+    }
+    fn allow_senders(&mut self, senders: Option<Senders>) {
+        self.senders = senders;
+    }
+    fn know_peers(&mut self, identities: PeerIdentities) {
+        if identities == self.identities {
+            return;
+        }
+        if let Some(cipher) = &self.cipher {
+            if let Err(err) = cipher.allow_peers(&identities) {
+                let _ = log_into_file(format!("Could not update peer keys: {:?}", err).as_str());
+            }
+        }
+        self.identities = identities;
+    }
 }
 
-pub fn send_greeting_packet(socket: Option<&UdpSocket>, target: SocketAddr, p_data: PeerData) {
-    let _ = log_into_file("Sending greeting message...");
-    let greeting_message =
-        compose_message(&MessageType::Xcon(p_data), PROTOCOL_VER).unwrap_or_default();
-    send_message_to_socket(socket, target, &greeting_message);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clipboard::ClipboardData;
+    use crate::clipboard::StringType;
+    use crate::crypto::Identity;
+    use crate::encode::compose_message;
+    use std::fs;
+    use std::io::Write;
+    use std::thread;
+
+    #[test]
+    fn test_stream_file_over_tcp() {
+        let dir = std::env::temp_dir().join(format!("copyxross-tcp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("big.bin");
+        let content: Vec<u8> = (0..20_000_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(&src, &content).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let messages = vec![
+            MessageType::Xpst(ClipboardData::FileRef(("big.bin".to_string(), src))),
+            MessageType::Xcpy,
+        ];
+        let sender = thread::spawn(move || {
+            send_messages_to_peer(&addr, &messages, PROTOCOL_VER, None, None)
+        });
+
+        let (_, key, received) = listen_to_tcp(Some(&listener), &dir, None, None).unwrap();
+        assert_eq!(key, None);
+        sender.join().unwrap().unwrap();
+        assert_eq!(received.len(), 2);
+        let MessageType::Xpst(ClipboardData::FileRef((name, path))) = &received[0] else {
+            panic!("Expected spooled file");
+        };
+        assert_eq!(name, "big.bin");
+        assert!(fs::read(path).unwrap() == content);
+        assert_eq!(received[1], MessageType::Xcpy);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_tls_is_pinned_to_identity_key() {
+        let dir = std::env::temp_dir().join(format!("copyxross-tls-{}", std::process::id()));
+        let (me, peer) = (Identity::generate(), Identity::generate());
+        let (my_tls, peer_tls) = (TlsConfig::new(&me).unwrap(), TlsConfig::new(&peer).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let message = MessageType::Xpst(ClipboardData::String((
+            StringType::Utf8Plain,
+            b"over tls".to_vec(),
+        )));
+
+        let client = my_tls.client(peer.public_key()).unwrap();
+        let sent = message.clone();
+        let sender = thread::spawn(move || {
+            send_message_to_peer(&addr, &sent, PROTOCOL_VER, None, Some(client))
+        });
+        let (_, key, received) =
+            listen_to_tcp(Some(&listener), &dir, None, Some(peer_tls.server())).unwrap();
+        sender.join().unwrap().unwrap();
+        assert_eq!(key, Some(me.public_key()));
+        assert_eq!(received, vec![message.clone()]);
+
+        // server with another key is refused before anything is sent
+        let client = my_tls.client(Identity::generate().public_key()).unwrap();
+        let sender = thread::spawn(move || {
+            send_message_to_peer(&addr, &message, PROTOCOL_VER, None, Some(client))
+        });
+        assert!(listen_to_tcp(Some(&listener), &dir, None, Some(peer_tls.server())).is_err());
+        assert!(matches!(
+            sender.join().unwrap(),
+            Err(NetworkError::Connect(_))
+        ));
+        let _ = fs::remove_dir_all(&dir);
+    }
+    #[test]
+    fn test_slow_peer_does_not_hold_up_others() {
+        let dir = std::env::temp_dir().join(format!("copyxross-slow-{}", std::process::id()));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut transport = NetTransport::new();
+        transport.spool_dir = dir.clone();
+        transport.tcp_listener = Some(listener);
+
+        // connects and sends half a message
+        let mut slow = TcpStream::connect(addr).unwrap();
+        let xcpy = compose_message(&MessageType::Xcpy, PROTOCOL_VER).unwrap();
+        slow.write_all(&xcpy[..10]).unwrap();
+        while !matches!(transport.recv_messages(), Err(NetworkError::Blocked))
+            || transport.reading.load(Ordering::SeqCst) == 0
+        {}
+
+        let sender = thread::spawn(move || {
+            send_message_to_peer(&addr, &MessageType::Xdis, PROTOCOL_VER, None, None)
+        });
+        let started = std::time::Instant::now();
+        let received = loop {
+            match transport.recv_messages() {
+                Ok(received) => break received,
+                Err(NetworkError::Blocked) => thread::sleep(Duration::from_millis(10)),
+                Err(err) => panic!("{:?}", err),
+            }
+        };
+        assert!(started.elapsed() < TCP_TIMEOUT);
+        assert_eq!(received.2, vec![MessageType::Xdis]);
+        sender.join().unwrap().unwrap();
+
+        // once it breaks off, message it got through is kept
+        let mut broken = xcpy.clone();
+        broken.extend(b"XCOP");
+        slow.write_all(&broken[10..]).unwrap();
+        drop(slow);
+        let received = loop {
+            match transport.recv_messages() {
+                Ok(received) => break received,
+                Err(NetworkError::Blocked) => thread::sleep(Duration::from_millis(10)),
+                Err(err) => panic!("{:?}", err),
+            }
+        };
+        assert_eq!(received.2, vec![MessageType::Xcpy]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_unknown_sender_is_refused_before_reading() {
+        let dir = std::env::temp_dir().join(format!("copyxross-senders-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let message = MessageType::Xpst(ClipboardData::File(("a.txt".to_string(), vec![7; 100])));
+
+        let sent = message.clone();
+        let sender =
+            thread::spawn(move || send_message_to_peer(&addr, &sent, PROTOCOL_VER, None, None));
+        let (data, ip) = accept_tcp(Some(&listener)).unwrap();
+        let others = Senders::from([(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 9)), None)]);
+        let refused = read_connection(data, ip, &dir, None, None, Some(&others));
+        assert_eq!(refused, Err(NetworkError::Blocked));
+        let _ = sender.join().unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // listed one gets through
+        let sender =
+            thread::spawn(move || send_message_to_peer(&addr, &message, PROTOCOL_VER, None, None));
+        let (data, ip) = accept_tcp(Some(&listener)).unwrap();
+        let listed = Senders::from([(ip.ip(), None)]);
+        let (_, _, received) = read_connection(data, ip, &dir, None, None, Some(&listed)).unwrap();
+        sender.join().unwrap().unwrap();
+        assert_eq!(received.len(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
@@ -0,0 +1,110 @@
+//! TLS over peer connections
+//!
+//! Plain and TLS connections come to the same port. TLS one starts with
+//! handshake record and plain one with `XCOP` header, so the listener tells
+//! them apart by the first byte.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::ClientConfig;
+use rustls::ClientConnection;
+use rustls::ServerConfig;
+use rustls::ServerConnection;
+use rustls::StreamOwned;
+
+use crate::crypto::certificate_key;
+use crate::crypto::PUBLIC_KEY_SIZE;
+use crate::crypto::SERVER_NAME;
+
+use super::NetworkError;
+
+/// Content type of TLS handshake record
+const HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Outgoing connection to peer
+pub enum PeerStream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl PeerStream {
+    /// Wraps connected stream into TLS if there is config for it.
+    /// Handshake is done here, so wrong server key fails before any data is sent
+    pub fn new(stream: TcpStream, tls: Option<Arc<ClientConfig>>) -> Result<Self, NetworkError> {
+        let Some(config) = tls else {
+            return Ok(PeerStream::Plain(stream));
+        };
+        let name = ServerName::try_from(SERVER_NAME)
+            .map_err(|err| NetworkError::Connect(format!("{:?}", err)))?;
+        let conn = ClientConnection::new(config, name)
+            .map_err(|err| NetworkError::Connect(format!("{:?}", err)))?;
+        let mut stream = StreamOwned::new(conn, stream);
+        stream
+            .conn
+            .complete_io(&mut stream.sock)
+            .map_err(|err| NetworkError::Connect(format!("TLS handshake failed: {:?}", err)))?;
+        Ok(PeerStream::Tls(Box::new(stream)))
+    }
+
+    /// Closes our side, TLS one with close_notify, so the peer can tell
+    /// the end of data from connection cut short
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            PeerStream::Plain(stream) => stream.shutdown(Shutdown::Write),
+            PeerStream::Tls(mut stream) => {
+                stream.conn.send_close_notify();
+                stream.flush()?;
+                stream.sock.shutdown(Shutdown::Write)
+            }
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Plain(stream) => stream.write(buf),
+            PeerStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PeerStream::Plain(stream) => stream.flush(),
+            PeerStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Reader of connection data and identity key of the peer if it came over TLS
+pub type Accepted = (Box<dyn Read>, Option<[u8; PUBLIC_KEY_SIZE]>);
+
+/// Takes over accepted connection
+pub fn accept(stream: TcpStream, tls: Option<Arc<ServerConfig>>) -> Result<Accepted, NetworkError> {
+    let mut first = [0u8; 1];
+    let peeked = stream
+        .peek(&mut first)
+        .map_err(|err| NetworkError::Read(format!("{:?}", err)))?;
+    let config = match tls {
+        Some(config) if peeked == 1 && first[0] == HANDSHAKE_RECORD => config,
+        _ => return Ok((Box::new(stream), None)),
+    };
+    let mut conn =
+        ServerConnection::new(config).map_err(|err| NetworkError::Read(format!("{:?}", err)))?;
+    let mut stream = stream;
+    conn.complete_io(&mut stream)
+        .map_err(|err| NetworkError::Read(format!("TLS handshake failed: {:?}", err)))?;
+    let key = conn
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(certificate_key)
+        .filter(|_| !conn.is_handshaking())
+        .ok_or(NetworkError::Read("TLS handshake failed".to_string()))?;
+    Ok((Box::new(StreamOwned::new(conn, stream)), Some(key)))
+}
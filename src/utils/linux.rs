@@ -0,0 +1,26 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use dirs_next::data_local_dir;
+
+pub fn get_host_name() -> String {
+    let name = fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .unwrap_or_default();
+    let name = name.trim();
+    if name.is_empty() {
+        return String::from("Unknown LINUX Machine");
+    }
+    name.to_string()
+}
+
+pub fn get_log_path() -> PathBuf {
+    data_local_dir().unwrap_or(PathBuf::from(""))
+}
+
+pub fn get_asset(filename: &str) -> PathBuf {
+    let exe_path = env::current_exe().unwrap_or(PathBuf::from(""));
+    let exe_dir = exe_path.parent().unwrap_or(&exe_path);
+    exe_dir.join("assets").join(filename)
+}
@@ -12,14 +12,25 @@ pub use windows::get_host_name as get_pc_name;
 #[cfg(target_os = "windows")]
 use windows::{get_asset, get_log_path};
 
+#[cfg(target_os = "linux")]
+pub mod linux;
+#[cfg(target_os = "linux")]
+pub use linux::get_host_name as get_pc_name;
+#[cfg(target_os = "linux")]
+use linux::{get_asset, get_log_path};
+
 use chrono::Local;
+use dirs_next::data_local_dir;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::fs::{metadata, File, OpenOptions};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Mutex, MutexGuard};
 use std::thread;
 use std::time::Duration;
 use std::{env, fs};
+use uuid::Uuid;
 
 const KX: u32 = 123456789;
 const KY: u32 = 362436069;
@@ -87,6 +98,101 @@ pub fn create_file(file: &[u8], path: &str) -> Result<()> {
     Ok(())
 }
 
+/// Renames file, falls back to copy when destination is on another file system
+pub fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+const APP_DIR: &str = "copyxross";
+const SPOOL_DIR: &str = "spool";
+
+/// Where this install keeps its state
+pub fn data_dir() -> PathBuf {
+    data_local_dir().unwrap_or(PathBuf::from("")).join(APP_DIR)
+}
+
+/// Reads JSON file. Missing file is the default value
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> std::io::Result<T> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Writes JSON readable by this user only. Temporary file is renamed over
+/// the old one, so it is never half written
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> std::io::Result<()> {
+    let json = serde_json::to_vec_pretty(value).map_err(std::io::Error::other)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp)?.write_all(&json)?;
+    fs::rename(&tmp, path)
+}
+
+/// Where incoming files are written until they are placed
+pub fn spool_dir() -> PathBuf {
+    data_dir().join(SPOOL_DIR)
+}
+
+/// Creates directory only the current user can enter. Existing one is
+/// narrowed down to that, which fails if someone else owns it
+pub fn private_dir(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        use std::os::unix::fs::PermissionsExt;
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(path)?;
+        if !fs::symlink_metadata(path)?.is_dir() {
+            return Err(std::io::Error::other(format!(
+                "{:?} is not a directory",
+                path
+            )));
+        }
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+    }
+    #[cfg(not(unix))]
+    fs::create_dir_all(path)
+}
+
+const DEVICE_ID_FILE: &str = "device_id";
+
+/// Stable id of this install. Generated on first run and kept in data dir
+pub fn get_device_id() -> String {
+    let path = data_dir().join(DEVICE_ID_FILE);
+    if let Ok(id) = fs::read_to_string(&path) {
+        let id = id.trim();
+        if Uuid::parse_str(id).is_ok() {
+            return id.to_string();
+        }
+    }
+    let id = Uuid::new_v4().to_string();
+    let saved = path
+        .parent()
+        .map(fs::create_dir_all)
+        .unwrap_or(Ok(()))
+        .and_then(|_| fs::write(&path, &id));
+    if let Err(err) = saved {
+        let _ = log_into_file(format!("Could not save device id: {:?}", err).as_str());
+    }
+    id
+}
+
 pub fn format_bytes_size(size: usize) -> String {
     if size < 1024 {
         format!("{} B", size)
@@ -104,6 +210,7 @@ pub fn write_progress(curr: usize, total: usize) {
     )
 }
 
+#[allow(dead_code)]
 pub fn get_asset_path(file: &str) -> Result<PathBuf> {
     if cfg!(debug_assertions) {
         let mut curr_dir = env::current_dir()?;
@@ -158,7 +265,8 @@ pub fn log_into_file(str: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn attempt_get_lock<T>(p: &Mutex<T>) -> std::result::Result<MutexGuard<T>, ()> {
+#[allow(clippy::result_unit_err)]
+pub fn attempt_get_lock<T>(p: &Mutex<T>) -> std::result::Result<MutexGuard<'_, T>, ()> {
     let mut attempts = 0;
     let max_attempts = 5;
 
@@ -225,4 +333,26 @@ mod tests {
             "Styled"
         );
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = env::temp_dir().join(format!("copyxross-private-{}", std::process::id()));
+        let nested = dir.join("a").join("b");
+        private_dir(&nested).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&nested), 0o700);
+
+        // existing directory is narrowed down
+        fs::set_permissions(&nested, fs::Permissions::from_mode(0o777)).unwrap();
+        private_dir(&nested).unwrap();
+        assert_eq!(mode(&nested), 0o700);
+
+        // symlink is not followed
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&nested, &link).unwrap();
+        assert!(private_dir(&link).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
 }